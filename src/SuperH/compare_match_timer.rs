@@ -18,8 +18,10 @@ use metaemu::machine::StepState;
 
 use crate::backend;
 use crate::backend::compare_match_timer::FunName as CMTFunName;
+use crate::snapshot::PeripheralSnapshot;
 use std::convert::TryInto;
 use log::{info};
+use serde::{Serialize, Deserialize};
 
 #[derive(Debug, Error)]
 pub enum SuperHCMTError {
@@ -128,6 +130,32 @@ where E:  Send + Sync
 	}
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompareMatchTimerSnapshot {
+	backend: (backend::CompareMatchTimer, backend::CompareMatchTimer),
+	interrupt: (backend::Interrupt, backend::Interrupt),
+}
+
+impl<S, E> PeripheralSnapshot for CompareMatchTimer<S, E>
+where
+	S: AsState<PCodeState<u8, Endian>>,
+	E:  Send + Sync + 'static
+{
+	type Snapshot = CompareMatchTimerSnapshot;
+
+	fn snapshot(&self) -> Self::Snapshot {
+		CompareMatchTimerSnapshot {
+			backend: self.backend.clone(),
+			interrupt: self.interrupt.clone(),
+		}
+	}
+
+	fn restore(&mut self, snapshot: &Self::Snapshot) {
+		self.backend = snapshot.backend.clone();
+		self.interrupt = snapshot.interrupt.clone();
+	}
+}
+
 impl <S: 'static, E> HookConcrete for CompareMatchTimer<S, E>
 where
 	S: AsState<PCodeState<u8, Endian>>,
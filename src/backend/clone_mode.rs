@@ -0,0 +1,17 @@
+/// How a peripheral behaves when the hook holding it is cloned, e.g. when metaemu
+/// forks a machine state with `ClonableHookConcrete`.
+///
+/// Peripherals that only hold register state (timers, interrupts) always clone as a
+/// snapshot. Polling peripherals are snapshot cloned unless they are wrapped in a
+/// `polling::SharedPeripheral`, in which case every fork drives the same device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CloneMode {
+    Snapshot,   // The clone gets a deep copy of the state; forks never observe each other
+    Shared,     // The clone refers to the same state as the original
+}
+
+impl Default for CloneMode {
+    fn default() -> Self {
+        Self::Snapshot
+    }
+}
@@ -9,7 +9,9 @@ use metaemu::state::{
 	StateOps,
 };
 use fugue::bytes::{Order};
+use serde::{Serialize, Deserialize};
 
+use crate::snapshot::PeripheralSnapshot;
 use std::convert::TryInto;
 
 
@@ -18,7 +20,7 @@ use std::convert::TryInto;
 // If matched and counting forward, then overflow, 
 // if counting backwards, via versa
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct CompareMatchTimer {
 	counter_start: bool,
 	current_tick: u128,
@@ -53,7 +55,7 @@ impl Default for CompareMatchTimer {
 	}
 	
 }
-#[derive(Clone, Debug)] 
+#[derive(Clone, Debug, Serialize, Deserialize)] 
 #[allow(non_camel_case_types)]
 pub enum FunName {
 	is_enabled,
@@ -355,6 +357,17 @@ impl CompareMatchTimer {
 
 }
 
+impl PeripheralSnapshot for CompareMatchTimer {
+	type Snapshot = CompareMatchTimer;
+
+	fn snapshot(&self) -> Self::Snapshot {
+		self.clone()
+	}
+
+	fn restore(&mut self, snapshot: &Self::Snapshot) {
+		*self = snapshot.clone();
+	}
+}
 
 #[cfg(test)]
 mod test {
@@ -403,4 +416,23 @@ mod test {
 		}
 	
 	}
+
+	#[test]
+	fn snapshot_restore_test() -> Result<(), String> {
+		let mut cmt = CompareMatchTimer::default();
+		cmt.set_compare_against(0x10);
+		cmt.set_enable(true);
+		cmt.tick();
+
+		let snapshot = cmt.snapshot();
+		cmt.tick();
+		cmt.tick();
+		cmt.restore(&snapshot);
+
+		if cmt.get_current_tick() == 1 && cmt.is_enabled() && cmt.get_compare_against() == 0x10 {
+			Ok(())
+		} else {
+			Err(String::from("Timer state not restored from snapshot"))
+		}
+	}
 }
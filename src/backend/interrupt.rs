@@ -12,6 +12,9 @@ use metaemu::state::{
 use metaemu::machine::StepState;
 use fugue::bytes::{Order};
 use metaemu::hooks::types::Error as HookError;
+use serde::{Serialize, Deserialize};
+
+use crate::snapshot::PeripheralSnapshot;
 
 #[derive(Debug, Error)]
 pub enum InterruptError {
@@ -86,7 +89,7 @@ impl <O: InterruptHandlerOverrider>InterruptHandler<O> {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Interrupt  {
     name: String,
 	enabled: bool,
@@ -143,3 +146,38 @@ impl Interrupt {
     }
 
 }
+
+impl PeripheralSnapshot for Interrupt {
+    type Snapshot = Interrupt;
+
+    fn snapshot(&self) -> Self::Snapshot {
+        self.clone()
+    }
+
+    fn restore(&mut self, snapshot: &Self::Snapshot) {
+        *self = snapshot.clone();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn snapshot_saved_as_json() -> Result<(), String> {
+        let mut interrupt = Interrupt::new("INTRCANGERR");
+        interrupt.set_enable(true);
+        interrupt.set_triggered(true);
+        interrupt.set_priority(3);
+        interrupt.add_trigger_count();
+        let json = serde_json::to_string(&interrupt.snapshot()).map_err(|e| e.to_string())?;
+
+        let mut restored = Interrupt::new("INTRCANGERR");
+        restored.restore(&serde_json::from_str::<Interrupt>(&json).map_err(|e| e.to_string())?);
+        if restored.is_enabled() && restored.is_triggered() && restored.get_priority() == 3 && restored.get_trigger_count() == 1 {
+            Ok(())
+        } else {
+            Err(format!("Interrupt not restored from {}", json))
+        }
+    }
+}
@@ -4,6 +4,8 @@
 
 pub mod compare_match_timer;
 mod interrupt;
+mod clone_mode;
+pub use clone_mode::CloneMode;
 pub use compare_match_timer::CompareMatchTimer;
 pub use interrupt::Interrupt;
 pub use interrupt::InterruptError;
@@ -0,0 +1,225 @@
+// Control-flow graph recovered from the instructions executed, one node per instruction
+//
+// Edges are recorded between consecutive instructions of the same function. A call adds an
+// edge from the call to its return site instead of into the callee, so the nodes reachable
+// from a function entry are the instructions of that function and helpers called from a
+// loop do not break it up. The graph only holds paths that were executed, which includes
+// the body of a polling loop once it has been entered. The dominators of the last entry
+// asked for are kept until an edge that was not known yet is added.
+
+use std::collections::{BTreeSet, HashMap, VecDeque};
+
+#[derive(Debug, Clone, Default)]
+pub struct ControlFlowGraph {
+    successors: HashMap<u64, BTreeSet<u64>>,
+    predecessors: HashMap<u64, BTreeSet<u64>>,
+    cached_dominators: Option<(u64, Dominators)>,
+}
+
+// Dominator sets of the nodes reachable from an entry
+#[derive(Debug, Clone)]
+pub struct Dominators {
+    dominators: HashMap<u64, BTreeSet<u64>>,
+}
+
+impl Dominators {
+    // Whether every path from the entry to node passes through dominator
+    pub fn dominates(&self, dominator: u64, node: u64) -> bool {
+        self.dominators.get(&node).is_some_and(|d| d.contains(&dominator))
+    }
+
+    pub fn is_reachable(&self, node: u64) -> bool {
+        self.dominators.contains_key(&node)
+    }
+}
+
+impl ControlFlowGraph {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_edge(&mut self, from: u64, to: u64) {
+        if self.successors.entry(from).or_default().insert(to) {
+            self.predecessors.entry(to).or_default().insert(from);
+            self.cached_dominators = None;
+        }
+    }
+
+    // Number of edges on the shortest path from node to the closest of targets, None if
+    // no target is reachable
+    pub fn distance(&self, node: u64, targets: &[u64]) -> Option<usize> {
+        let mut visited = BTreeSet::from([node]);
+        let mut queue = VecDeque::from([(node, 0)]);
+        while let Some((node, distance)) = queue.pop_front() {
+            if targets.contains(&node) {
+                return Some(distance);
+            }
+            for succ in self.successors.get(&node).into_iter().flatten() {
+                if visited.insert(*succ) {
+                    queue.push_back((*succ, distance + 1));
+                }
+            }
+        }
+        None
+    }
+
+    // Nodes reachable from entry in reverse postorder
+    fn reverse_postorder(&self, entry: u64) -> Vec<u64> {
+        let mut visited = BTreeSet::new();
+        let mut postorder = Vec::new();
+        let mut stack = vec![(entry, false)];
+        while let Some((node, done)) = stack.pop() {
+            if done {
+                postorder.push(node);
+                continue;
+            }
+            if !visited.insert(node) {
+                continue;
+            }
+            stack.push((node, true));
+            for succ in self.successors.get(&node).into_iter().flatten() {
+                if !visited.contains(succ) {
+                    stack.push((*succ, false));
+                }
+            }
+        }
+        postorder.reverse();
+        postorder
+    }
+
+    pub fn dominators(&mut self, entry: u64) -> &Dominators {
+        if !matches!(&self.cached_dominators, Some((cached, _)) if *cached == entry) {
+            self.cached_dominators = Some((entry, self.compute_dominators(entry)));
+        }
+        &self.cached_dominators.as_ref().unwrap().1
+    }
+
+    fn compute_dominators(&self, entry: u64) -> Dominators {
+        let order = self.reverse_postorder(entry);
+        let all: BTreeSet<u64> = order.iter().copied().collect();
+        let mut dominators: HashMap<u64, BTreeSet<u64>> = order.iter()
+            .map(|node| (*node, if *node == entry { BTreeSet::from([entry]) } else { all.clone() }))
+            .collect();
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for node in order.iter().skip(1) {
+                let mut dom: Option<BTreeSet<u64>> = None;
+                for pred in self.predecessors.get(node).into_iter().flatten() {
+                    if let Some(pred_dom) = dominators.get(pred) {
+                        dom = Some(match dom {
+                            Some(dom) => dom.intersection(pred_dom).copied().collect(),
+                            None => pred_dom.clone(),
+                        });
+                    }
+                }
+                let mut dom = dom.unwrap_or_default();
+                dom.insert(*node);
+                if dominators[node] != dom {
+                    dominators.insert(*node, dom);
+                    changed = true;
+                }
+            }
+        }
+        Dominators { dominators }
+    }
+
+    // Edges tail -> header reachable from the entry where header dominates tail
+    pub fn back_edges(&self, dominators: &Dominators) -> Vec<(u64, u64)> {
+        let mut edges = Vec::new();
+        for (tail, successors) in self.successors.iter() {
+            for header in successors.iter() {
+                if dominators.is_reachable(*tail) && dominators.dominates(*header, *tail) {
+                    edges.push((*tail, *header));
+                }
+            }
+        }
+        edges.sort_unstable();
+        edges
+    }
+
+    // Nodes of the natural loop of the back edge tail -> header: the header and the nodes
+    // that reach tail without passing through header
+    pub fn natural_loop(&self, tail: u64, header: u64) -> BTreeSet<u64> {
+        let mut nodes = BTreeSet::from([header]);
+        let mut stack = vec![tail];
+        while let Some(node) = stack.pop() {
+            if !nodes.insert(node) {
+                continue;
+            }
+            stack.extend(self.predecessors.get(&node).into_iter().flatten().copied());
+        }
+        nodes
+    }
+
+    // The innermost loop of the function at entry containing all of nodes
+    pub fn innermost_loop(&mut self, entry: u64, nodes: &[u64]) -> Option<BTreeSet<u64>> {
+        self.dominators(entry);
+        let dominators = &self.cached_dominators.as_ref().unwrap().1;
+        self.back_edges(dominators).into_iter()
+            .map(|(tail, header)| self.natural_loop(tail, header))
+            .filter(|body| nodes.iter().all(|node| body.contains(node)))
+            .min_by_key(|body| body.len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn graph(edges: &[(u64, u64)]) -> ControlFlowGraph {
+        let mut cfg = ControlFlowGraph::new();
+        for (from, to) in edges.iter() {
+            cfg.add_edge(*from, *to);
+        }
+        cfg
+    }
+
+    #[test]
+    fn loops() -> Result<(), String> {
+        // do { sts = STS; } while (!(sts & READY)); with the read at 4
+        let mut do_while = graph(&[(0, 4), (4, 8), (8, 12), (12, 4), (12, 16)]);
+        let dominators = do_while.dominators(0).clone();
+        if do_while.back_edges(&dominators) != [(12, 4)] || !dominators.dominates(4, 12) {
+            return Err(String::from("do-while back edge not found"));
+        }
+
+        // while (!(STS & READY)) { helper(); } where 12 calls the helper and returns to 16
+        let mut while_loop = graph(&[(0, 4), (4, 8), (8, 24), (8, 12), (12, 16), (16, 20), (20, 4)]);
+        let body = while_loop.innermost_loop(0, &[4, 8]).ok_or("while loop not found")?;
+        if body != BTreeSet::from([4, 8, 12, 16, 20]) || body.contains(&24) {
+            return Err(format!("Unexpected while loop body {:?}", body));
+        }
+
+        // for (i = 0; i < N; i++) { while (!(STS & READY)); } the inner loop is 8..12
+        let mut nested = graph(&[(0, 4), (4, 8), (8, 12), (12, 8), (12, 16), (16, 4), (16, 20)]);
+        let inner = nested.innermost_loop(0, &[8]).ok_or("inner loop not found")?;
+        let outer = nested.innermost_loop(0, &[4, 8]).ok_or("outer loop not found")?;
+        if inner != BTreeSet::from([8, 12]) || outer != BTreeSet::from([4, 8, 12, 16]) {
+            return Err(format!("Unexpected nested loops {:?} {:?}", inner, outer));
+        }
+        if do_while.innermost_loop(0, &[16]).is_some() {
+            return Err(String::from("The exit is not part of a loop"));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn new_edges_update_dominators() -> Result<(), String> {
+        let mut cfg = graph(&[(0, 4), (4, 8)]);
+        if !cfg.dominators(0).dominates(4, 8) {
+            return Err(String::from("4 does not dominate 8"));
+        }
+        // Known edges keep the dominators, a path around 4 changes them
+        cfg.add_edge(4, 8);
+        if !cfg.dominators(0).dominates(4, 8) {
+            return Err(String::from("Known edge changed the dominators"));
+        }
+        cfg.add_edge(0, 8);
+        if cfg.dominators(0).dominates(4, 8) {
+            return Err(String::from("Dominators not updated for the new edge"));
+        }
+        Ok(())
+    }
+}
@@ -1,18 +1,32 @@
 use crate::bypass::solver::ConstraintSolver;
-use std::{collections::HashMap, any::TypeId};
+use crate::bypass::goal::SolvingGoal;
+use crate::bypass::cfg::ControlFlowGraph;
+use crate::bypass::knowledge::{AccessRead, AccessSite, KnowledgeBase, call_stack_hash};
+use crate::bypass::solver_backend::{SolverConfig, SolverKind, SolverStatistics};
+use crate::bypass::smtlib;
+use crate::bypass::state::OperandState;
+use crate::polling::{ReadRule, SynthesizedModel};
+use crate::backend::CloneMode;
+use crate::snapshot::PeripheralSnapshot;
+use crate::replay::{InputEvent, InputReplay};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::sync::RwLock;
 use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 use fugue::fp::BitVec;
 use fugue::{ir::{
     Address,
     il::ecode::Location,
     il::pcode::{Operand, PCodeOp, }
-}, bytes::{Order, BE, LE}
+}, bytes::Order
 };
-use fugue::bytes::{ByteCast};
+use fugue_concolic::expr::SymExpr;
 use metaemu::concrete::hooks::{ClonableHookConcrete, HookConcrete};
 use metaemu::hooks::types::{HookStepAction, HookOutcome, Error};
+use metaemu::state::pcode::Error as PCodeError;
+use thiserror::Error as ThisError;
 // use metaemu::state::IntoStateValues;
 use metaemu::state::{
     State,
@@ -20,19 +34,6 @@ use metaemu::state::{
 use metaemu::machine::StepState;
 use serde::{Serialize, Deserialize};
     
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct SolvingResult {
-    pub target_addr: Address,
-    pub value: u64,
-    pub size: usize,
-}
-
-impl std::cmp::PartialEq for SolvingResult {
-    fn eq(&self, eq2: &SolvingResult) -> bool {
-        self.target_addr == eq2.target_addr && self.value == eq2.value
-    }
-}
-
 
 #[derive(Debug)]
 pub struct DummyPeripheral<S, O: Order, E> {
@@ -43,20 +44,48 @@ pub struct DummyPeripheral<S, O: Order, E> {
     pcode_counter: u128,
 
     solving_started: bool,
-    solving_results_cache_enable: bool,
-    solving_results: Arc<RwLock<HashMap<Address, SolvingResult>>>,
+    clone_mode: CloneMode,      // Whether forks copy or share the knowledge
+    knowledge: Arc<RwLock<KnowledgeBase>>,  // Every value solved, by access site
+    knowledge_reuse: bool,                  // Whether known values are reused instead of solving
+    session_reuse: Option<(AccessSite, u64, usize)>,    // Known (site, value, size) the session checks instead of solving
+    stale_sites: HashSet<AccessRead>,       // Reads whose known value did not leave the loop, solved instead
     solver_default_vars: HashMap<String, u128>, // <name, values>
     solver: ConstraintSolver<O>,
-    forgive_jump: u32,
-    forgive_fun_call: i32,
-    forgive_branch_condition: u32,
+
+    // Loop detection: the control flow executed in the active functions and the functions
+    // solving sessions were in, by function entry, and the call frames
+    cfgs: HashMap<u64, ControlFlowGraph>,
+    session_functions: BTreeSet<u64>,
+    previous_pc: Option<u64>,           // The instruction the next one follows, None when entering a call
+    fallthrough: Option<u64>,           // The instruction after the current one
+    call_stack: Vec<(u64, u64)>,        // (call instruction, entry of the calling function) of each active call
+    frame_entry: Option<u64>,           // Entry of the current function, None until its first instruction
+    session_depth: usize,               // call_stack.len() of the function the solving session is in
+    session_anchor: u64,                // The read, or the call leading to it, in that function
+    pending_exit: Option<(u64, u64, SymExpr, bool)>,   // (PC, destination, condition, taken) of a branch that may leave the loop
+    session_branches: u32,
 
     last_mem_read_event: (Address, Address, usize, u128),  // PC, ReadAddress, size in byte, EventCounter
+    mem_read_events: Vec<(Address, Address, usize, u128)>, // Every read since the solving started, in order
+    read_sites: Vec<AccessSite>,                            // The sites of mem_read_events
     last_reg_write_event: (Address, u128),
+    last_mmio_write: Option<(Address, u64)>,                // The last value stored to the peripheral ranges
 
+    replay: InputReplay,    // Record the values read from the peripheral, or replay them instead of solving
+    goal: SolvingGoal,      // The branch outcomes solved for, leaving polling loops by default
+    solver_config: SolverConfig,
+    statistics: SolverStatistics,   // Of all solving sessions so far
 }
+
+// With CloneMode::Snapshot (the default) a fork starts with a copy of the knowledge
+// base and never sees values solved by other forks. With CloneMode::Shared all forks
+// read and extend the same knowledge base.
 impl <S, O: Order, E> Clone for DummyPeripheral<S, O, E> {
     fn clone(&self) -> Self {
+        let knowledge = match self.clone_mode {
+            CloneMode::Shared => self.knowledge.clone(),
+            CloneMode::Snapshot => Arc::new(RwLock::new(self.knowledge.read().unwrap().clone())),
+        };
         DummyPeripheral {
             address_range_list: self.address_range_list.clone(),
             state: PhantomData,
@@ -64,14 +93,31 @@ impl <S, O: Order, E> Clone for DummyPeripheral<S, O, E> {
             pcode_counter: self.pcode_counter,
             event_counter: self.event_counter,
             solving_started: self.solving_started,
-            solving_results_cache_enable: self.solving_results_cache_enable,
             solver_default_vars: self.solver_default_vars.clone(),
             last_mem_read_event: self.last_mem_read_event.clone(),     // (The address that it read data from, event_counter)
+            mem_read_events: self.mem_read_events.clone(),
+            read_sites: self.read_sites.clone(),
             last_reg_write_event: self.last_reg_write_event.clone(),
-            solving_results: self.solving_results.clone(),
-            forgive_jump: self.forgive_jump,
-            forgive_fun_call: self.forgive_fun_call,
-            forgive_branch_condition: self.forgive_branch_condition,
+            last_mmio_write: self.last_mmio_write,
+            replay: self.replay.clone(),
+            goal: self.goal.clone(),
+            solver_config: self.solver_config.clone(),
+            statistics: self.statistics,
+            clone_mode: self.clone_mode,
+            knowledge,
+            knowledge_reuse: self.knowledge_reuse,
+            session_reuse: self.session_reuse,
+            stale_sites: self.stale_sites.clone(),
+            cfgs: self.cfgs.clone(),
+            session_functions: self.session_functions.clone(),
+            previous_pc: self.previous_pc,
+            fallthrough: self.fallthrough,
+            call_stack: self.call_stack.clone(),
+            frame_entry: self.frame_entry,
+            session_depth: self.session_depth,
+            session_anchor: self.session_anchor,
+            pending_exit: self.pending_exit.clone(),
+            session_branches: self.session_branches,
 
             solver: self.solver.clone(),
         }
@@ -90,15 +136,31 @@ where S: State,
             pcode_counter: 0,
             event_counter: 0,
             solving_started: false,
-            solving_results_cache_enable: false,
             solver_default_vars: HashMap::new(),
             last_mem_read_event: (Address::from(0u32), Address::from(0u32), 0, 0),     // (The address that it read data from, event_counter)
+            mem_read_events: Vec::new(),
+            read_sites: Vec::new(),
             last_reg_write_event: (Address::from(0u32), 0),
-            solving_results: Arc::new(RwLock::new(HashMap::<Address, SolvingResult>::new())),
-            forgive_jump: 0,
-            forgive_fun_call: 0,
-            forgive_branch_condition: 0,
-            
+            last_mmio_write: None,
+            replay: InputReplay::passthrough(),
+            goal: SolvingGoal::default(),
+            solver_config: SolverConfig::default(),
+            statistics: SolverStatistics::default(),
+            clone_mode: CloneMode::default(),
+            knowledge: Arc::new(RwLock::new(KnowledgeBase::new())),
+            knowledge_reuse: false,
+            session_reuse: None,
+            stale_sites: HashSet::new(),
+            cfgs: HashMap::new(),
+            session_functions: BTreeSet::new(),
+            previous_pc: None,
+            fallthrough: None,
+            call_stack: Vec::new(),
+            frame_entry: None,
+            session_depth: 0,
+            session_anchor: 0,
+            pending_exit: None,
+            session_branches: 0,
 
             solver: ConstraintSolver::new(),
 
@@ -106,8 +168,53 @@ where S: State,
         }
     }
 
-    pub fn enable_solving_result_cache(&mut self, enable: bool){
-        self.solving_results_cache_enable = enable;
+    pub fn set_clone_mode(&mut self, mode: CloneMode) {
+        self.clone_mode = mode;
+    }
+
+    pub fn get_clone_mode(&self) -> CloneMode {
+        self.clone_mode
+    }
+
+    // Record every value read from the peripheral range, or replay a recorded
+    // run without invoking the solver
+    pub fn set_replay(&mut self, replay: InputReplay) {
+        self.replay = replay;
+    }
+
+    pub fn replay(&self) -> &InputReplay {
+        &self.replay
+    }
+
+    // Solve the peripheral values for branches toward or away from given PCs instead of
+    // only leaving polling loops, e.g. to drive the firmware into its error handling
+    pub fn set_goal(&mut self, goal: SolvingGoal) {
+        self.goal = goal;
+    }
+
+    pub fn goal(&self) -> &SolvingGoal {
+        &self.goal
+    }
+
+    // The solver settings below apply from the next solving session on
+    pub fn set_solver_backend(&mut self, backend: SolverKind) {
+        self.solver_config.backend = backend;
+    }
+
+    // Give up on a query after timeout, a read left unsolved keeps its emulated value.
+    // Boolector cannot be interrupted and ignores it.
+    pub fn set_solver_timeout(&mut self, timeout: Option<Duration>) {
+        self.solver_config.timeout = timeout;
+    }
+
+    // Write the p-code trace and the query of every read that could not be solved to
+    // directory as SMT-LIB2, for investigating loops that were not bypassed
+    pub fn set_failure_dump_dir<P: Into<PathBuf>>(&mut self, directory: Option<P>) {
+        self.solver_config.failure_dumps = directory.map(Into::into);
+    }
+
+    pub fn solver_statistics(&self) -> SolverStatistics {
+        self.statistics
     }
 
     pub fn add_address_range<A>(&mut self, addr_range: (A, A)) where A: Into<Address> {
@@ -120,52 +227,325 @@ where S: State,
         self.solver_default_vars.insert(name.to_string(), value);
     }
 
-    pub fn get_solving_result(&self) -> Arc<RwLock<HashMap<Address, SolvingResult>>>{
-        return self.solving_results.clone();
+    pub fn knowledge(&self) -> Arc<RwLock<KnowledgeBase>> {
+        self.knowledge.clone()
+    }
+
+    // Reuse the value solved most often at the same site, in this run or a loaded one,
+    // instead of solving the read again. Sites where a reused value does not leave the
+    // loop are solved again for the rest of the run.
+    pub fn enable_knowledge_reuse(&mut self, enable: bool) {
+        self.knowledge_reuse = enable;
+    }
+
+    // Add the solutions of a previous run, reused with enable_knowledge_reuse
+    pub fn load_knowledge<P: AsRef<Path>>(&mut self, path: P) -> std::io::Result<()> {
+        let loaded = KnowledgeBase::load(path)?;
+        self.knowledge.write().unwrap().merge(&loaded);
+        Ok(())
+    }
+
+    pub fn save_knowledge<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        self.knowledge.read().unwrap().save(path)
+    }
+
+    // Write the next recorded value for a read of size bytes at address
+    fn replay_read<M: OperandState>(&mut self, state: &mut M, address: Address, size: usize) -> Result<(), DummyPeripheralError> {
+        let event = self.replay.next_event(&address).map_err(DummyPeripheralError::ReplayDiverged)?;
+        if event.value.len() != size {
+            return Err(DummyPeripheralError::ReplayDiverged(format!("read of {} with size {}, recorded size {}", address, size, event.value.len())));
+        }
+        state.set_bytes(address, &event.value)?;
+        log::debug!("Replay value of memory: {} value:{:?}", address, event.value);
+        Ok(())
+    }
+
+    fn access_site(&self, address: Address, pc: Address) -> AccessSite {
+        AccessSite {
+            address,
+            pc,
+            call_stack: call_stack_hash(self.call_stack.iter().map(|(call_pc, _)| *call_pc)),
+            last_write: self.last_mmio_write,
+        }
+    }
+
+    // The knowledge base as read rules for a SynthesizedPeripheral: the most used value of
+    // each load after each write, over all call stacks, and of each register for reads at
+    // sites that were never solved
+    pub fn synthesize_model(&self) -> SynthesizedModel {
+        type Uses = HashMap<(u64, usize), u64>;
+        let mut by_site: HashMap<(Address, Option<Address>, Option<(Address, u64)>), Uses> = HashMap::new();
+        for entry in self.knowledge.read().unwrap().entries() {
+            let keys = [
+                (entry.site.address, Some(entry.site.pc), entry.site.last_write),
+                (entry.site.address, None, None),
+            ];
+            for key in keys {
+                let uses = by_site.entry(key).or_default();
+                for solution in entry.solutions.iter() {
+                    *uses.entry((solution.value, solution.size)).or_default() += solution.uses;
+                }
+            }
+        }
+
+        let mut rules: Vec<ReadRule> = by_site.into_iter()
+            .filter_map(|((address, pc, after_write), uses)| {
+                // Ties go to the smaller value so the model does not depend on iteration order
+                let ((value, size), _) = uses.into_iter().max_by_key(|((value, _), uses)| (*uses, std::cmp::Reverse(*value)))?;
+                Some(ReadRule { address, pc, after_write, value, size })
+            })
+            .collect();
+        rules.sort_by_key(|r| (u64::from(r.address), r.pc.is_none(), r.pc.map(u64::from), r.after_write.map(|(a, v)| (u64::from(a), v))));
+        SynthesizedModel { rules }
+    }
+
+    pub fn save_model<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        self.synthesize_model().save(path)
     }
 }
 
 
-impl<S: 'static, O, E> HookConcrete for DummyPeripheral<S, O, E>
-where S: State + StateOps,
-      O: Order,
-        E: std::error::Error + Send + Sync + 'static,
-{
-    type State = PCodeState<u8, O>;        // TOOD: make it useful for universal endian
-    type Error = E;
-    type Outcome = String;
+// The solving session in progress is not part of the snapshot; a restored
+// DummyPeripheral starts solving again at the next load in range.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DummyPeripheralSnapshot {
+    event_counter: u128,
+    pcode_counter: u128,
+    solver_default_vars: HashMap<String, u128>,
+    last_mem_read_event: (Address, Address, usize, u128),
+    last_reg_write_event: (Address, u128),
+    #[serde(default)]
+    knowledge: KnowledgeBase,
+    #[serde(default)]
+    last_mmio_write: Option<(Address, u64)>,
+    #[serde(default)]
+    statistics: SolverStatistics,
+}
 
+impl<S, O: Order, E> PeripheralSnapshot for DummyPeripheral<S, O, E> {
+    type Snapshot = DummyPeripheralSnapshot;
 
-    fn hook_architectural_step(
-        &mut self,
-        _state: &mut Self::State,
-        _address: &Address,
-        _operation: &StepState,
-    ) -> Result<HookOutcome<HookStepAction<Self::Outcome>>, Error<Self::Error>> {
+    fn snapshot(&self) -> Self::Snapshot {
+        DummyPeripheralSnapshot {
+            event_counter: self.event_counter,
+            pcode_counter: self.pcode_counter,
+            solver_default_vars: self.solver_default_vars.clone(),
+            last_mem_read_event: self.last_mem_read_event,
+            last_reg_write_event: self.last_reg_write_event,
+            knowledge: self.knowledge.read().unwrap().clone(),
+            last_mmio_write: self.last_mmio_write,
+            statistics: self.statistics,
+        }
+    }
+
+    fn restore(&mut self, snapshot: &Self::Snapshot) {
+        self.event_counter = snapshot.event_counter;
+        self.pcode_counter = snapshot.pcode_counter;
+        self.solver_default_vars = snapshot.solver_default_vars.clone();
+        self.last_mem_read_event = snapshot.last_mem_read_event;
+        self.last_reg_write_event = snapshot.last_reg_write_event;
+        *self.knowledge.write().unwrap() = snapshot.knowledge.clone();
+        self.last_mmio_write = snapshot.last_mmio_write;
+        self.statistics = snapshot.statistics;
+
+        self.solving_started = false;
+        self.mem_read_events.clear();
+        self.read_sites.clear();
+        self.session_reuse = None;
+        self.pending_exit = None;
+        self.session_branches = 0;
+        // The call frames of the restored run are not known, the recovered graph stays valid
+        self.previous_pc = None;
+        self.call_stack.clear();
+        self.frame_entry = None;
+        self.solver = ConstraintSolver::with_config(self.solver_config.clone());
+    }
+}
+
+// Conditional branches in a solving session before giving up on finding the loop
+const MAX_SESSION_BRANCHES: u32 = 32;
+
+// Loop detection on the recovered control-flow graph of the function the solving session
+// is in. A branch to a node dominating it closes a loop; the loop is a polling loop if
+// its natural loop contains the read. Do-while loops are left by not taking the closing
+// branch, while and for loops by the branch seen first in the body that can leave it.
+impl<S, O, E> DummyPeripheral<S, O, E>
+where S: State,
+    O: Order,
+    E: std::error::Error + Send + Sync + 'static{
+    fn decide_branch<M: OperandState>(&mut self, state: &mut M, dest: u64, condition: &Operand) {
+        let current_pc = state.program_counter().unwrap();
+        let pc = u64::from(current_pc);
+        let entry = self.frame_entry.unwrap_or(self.session_anchor);
+        let condition_sym = self.solver.condition(state, condition);
+        let concrete = state.condition(condition).unwrap();
+
+        // The goal decides branches it has a preference for
+        let fallthrough = Address::from(self.fallthrough.unwrap_or(pc));
+        let cfg = self.cfgs.entry(entry).or_default();
+        if let Some(taken) = self.goal.expected_condition(current_pc, Address::from(dest), fallthrough, cfg) {
+            log::debug!("PC: {} Solving for the branch to {:#x} to be {}", current_pc, dest, if taken { "taken" } else { "not taken" });
+            self.finish_session(state, &condition_sym, taken, concrete);
+            return;
+        }
+
+        // The branch closes a loop around the read
+        let anchor = self.session_anchor;
+        self.session_functions.insert(entry);
+        let cfg = self.cfgs.entry(entry).or_default();
+        if cfg.dominators(entry).dominates(dest, pc) {
+            let body = cfg.natural_loop(pc, dest);
+            if body.contains(&anchor) {
+                log::debug!("{:#x} -> {:#x} closes the polling loop", pc, dest);
+                self.exit_loop(state, &body, Some((&condition_sym, concrete)));
+                return;
+            }
+        }
+
+        // The branch leaves a loop around the read that has been executed before
+        if let Some(body) = cfg.innermost_loop(entry, &[pc, anchor]) {
+            if !body.contains(&dest) {
+                log::debug!("{:#x} -> {:#x} leaves the polling loop", pc, dest);
+                self.finish_session(state, &condition_sym, true, concrete);
+                return;
+            }
+        }
+
+        // A known value taking the first branch on the reads leaves the loop, as when it was
+        // solved. If it stays in the loop, the branch is the exit found when the loop closes.
+        if self.session_reuse.is_some() && concrete && !smtlib::variables([&condition_sym]).is_empty() {
+            log::debug!("{:#x} -> {:#x} taken with the known value", pc, dest);
+            self.finish_session(state, &condition_sym, true, concrete);
+            return;
+        }
+
+        // Not known yet, this may be the exit of a loop that is closed later on
+        if self.pending_exit.is_none() {
+            self.pending_exit = Some((pc, dest, condition_sym, concrete));
+        }
+        self.session_branches += 1;
+        if self.session_branches > MAX_SESSION_BRANCHES {
+            self.solving_started = false;
+            log::debug!("No loop around the read at {:#x} found, probably not a polling loop", self.session_anchor);
+        }
+    }
+
+    // An unconditional branch to a node dominating it closes a loop
+    fn close_loop<M: OperandState>(&mut self, state: &mut M, dest: u64) {
+        let pc = u64::from(state.program_counter().unwrap());
+        let entry = self.frame_entry.unwrap_or(self.session_anchor);
+        self.session_functions.insert(entry);
+        let cfg = self.cfgs.entry(entry).or_default();
+        if cfg.dominators(entry).dominates(dest, pc) {
+            let body = cfg.natural_loop(pc, dest);
+            if body.contains(&self.session_anchor) {
+                log::debug!("{:#x} -> {:#x} closes the polling loop", pc, dest);
+                self.exit_loop(state, &body, None);
+            }
+        }
+    }
+
+    // closing is the condition of the branch closing the loop and whether it was taken
+    fn exit_loop<M: OperandState>(&mut self, state: &mut M, body: &BTreeSet<u64>, closing: Option<(&SymExpr, bool)>) {
+        match self.pending_exit.take() {
+            // Leave through the destination if it is outside the loop, or through the
+            // fall-through if the branch was taken and the loop was still closed
+            Some((pc, dest, condition, taken)) if body.contains(&pc) && (!body.contains(&dest) || taken) => {
+                self.finish_session(state, &condition, !body.contains(&dest), taken);
+            },
+            _ => match closing {
+                Some((condition, taken)) => self.finish_session(state, condition, false, taken),
+                None => {
+                    self.solving_started = false;
+                    log::debug!("No branch leaving the loop at {:#x} found", self.session_anchor);
+                },
+            },
+        }
+    }
+
+    fn write_value<M: OperandState>(state: &mut M, address: Address, value: u64, size: usize) {
+        let mut bytes = [0u8; 8];
+        match size {
+            1 => bytes[0] = value as u8,
+            2 => O::write_u16(&mut bytes[..2], value as u16),
+            4 => O::write_u32(&mut bytes[..4], value as u32),
+            8 => O::write_u64(&mut bytes, value),
+            _ => {
+                log::error!("Unexpected value size ({}) for last load event", size);
+                return;
+            }
+        }
+        state.set_bytes(address, &bytes[..size]).unwrap();
+    }
+
+    // Solve the reads for condition to be taken and write the values to the peripheral,
+    // unless the known value of the session already did, concrete is the branch executed
+    fn finish_session<M: OperandState>(&mut self, state: &mut M, condition: &SymExpr, taken: bool, concrete: bool) {
+        self.solving_started = false;       // Mark the end of the solving
+        if let Some((site, value, size)) = self.session_reuse.take() {
+            if concrete == taken {
+                self.statistics.cache_hits += 1;
+                self.knowledge.write().unwrap().record(site, value, size);
+                return;
+            }
+            // The reused value kept the loop going, the read is solved for the rest of the run
+            log::debug!("Known value of memory: {} did not leave the loop, solving it", site.address);
+            self.stale_sites.insert(site.read());
+        }
+        let solve_result = self.solver.solve_condition(condition, taken as u64);
+        self.statistics.merge(&self.solver.take_statistics());
+        let solve_result = match solve_result {
+            Some(solve_result) => solve_result,
+            None => {
+                log::warn!("Cound not solve this value, condition {}", condition);
+                return;
+            },
+        };
+        for (k, v) in solve_result {
+            let value_to_set = match v {
+                Some(value) => value,
+                None => {
+                    log::warn!("Cound not solve this value, address {}", k);
+                    continue;
+                },
+            };
+            log::info!("solving result: ({}, {:#x})", k, value_to_set);
+
+            // Reads of the register at other widths are parts of the widest one
+            let size = self.solver.target_size(&k).unwrap_or_else(|| self.mem_read_events.iter()
+                .rev()
+                .find(|(_pc, addr, _size, _counter)| *addr == k)
+                .map_or(self.last_mem_read_event.2, |(_pc, _addr, size, _counter)| *size));
+            Self::write_value(state, k, value_to_set, size);
+            let state_values = state.view_bytes(k, size).unwrap();
+            log::info!("writting to {}, with {:?}, size: {}", k, state_values, size);
+
+            let mut knowledge = self.knowledge.write().unwrap();
+            for site in self.read_sites.iter().filter(|site| site.address == k) {
+                knowledge.record(*site, value_to_set, size);
+            }
+        }
+    }
+}
+
+// The hooks, on any state the bypass can read and write
+impl<S, O, E> DummyPeripheral<S, O, E>
+where S: State,
+    O: Order,
+    E: std::error::Error + Send + Sync + 'static{
+    // The instruction at pc is executed next, followed by the one at fallthrough
+    fn instruction_step(&mut self, pc: u64, fallthrough: u64) {
         self.event_counter += 1;
+        self.fallthrough = Some(fallthrough);
+        if let (Some(previous), Some(entry)) = (self.previous_pc, self.frame_entry) {
+            self.cfgs.entry(entry).or_default().add_edge(previous, pc);
+        }
+        self.previous_pc = Some(pc);
+        self.frame_entry.get_or_insert(pc);
         // log::trace!("PC {}", address);
-        Ok(HookStepAction::Pass.into())
     }
 
-    // 0x95e
-    fn hook_operation_step(
-        &mut self,
-        state: &mut Self::State,
-        _location: &Location,
-        operation: &PCodeOp,
-    ) -> Result<HookOutcome<HookStepAction<Self::Outcome>>, Error<Self::Error>>  {
-        
-        // Todo: check endian api
-        let is_little_endian = if TypeId::of::<O>() == TypeId::of::<LE>() {
-            true
-        } else if TypeId::of::<O>() == TypeId::of::<BE>() {
-            false
-        } else {
-            panic!("Unsupported endian");
-        };
-            
-        
-        // let is_little_endian = true; //state.endian().is_little();
+    fn operation_step<M: OperandState>(&mut self, state: &mut M, operation: &PCodeOp) -> HookStepAction<String> {
         // let op = pcode_istate.current().unwrap();
         match operation{
             /////////////////////
@@ -173,223 +553,204 @@ where S: State + StateOps,
             // Start when a value is loading from the target memory
             // Stop when a branch happens
             PCodeOp::Load {source, destination, space: _} =>{
-                let source_offset =  state.get_address(source).unwrap();
+                let source_offset =  state.pointer(source).unwrap();
                 // let source_offset = if is_little_endian {
                 //     state.state_ref().read_address::<LE>(source).unwrap()
                 // } else {
                 //     state.state_ref().read_address::<BE>(source).unwrap()
                 // };
                 // Check if the source address falls into the peripheral range
-                for (min, max) in &self.address_range_list {
-                    if *min<= source_offset && source_offset<= *max {
-                        log::debug!("Observe load instruction within range src_offset:{} src: {}, dest: {}", source_offset, source, destination);
-                        // Check if this regisiter has been solved before if have been solved, then load the previous result
-                        if self.solving_results.read().unwrap().contains_key(&source_offset) && self.solving_results_cache_enable{
-                            let last_result = self.solving_results.read().unwrap().get(&source_offset).unwrap().clone();
-                            log::debug!("Load cached result of memory: {} value:{:#x}", source_offset, last_result.value);
-                            
-                            if is_little_endian {
-                                let mut value_bytes = [0; 8];
-                                last_result.value.into_bytes::<LE>(&mut value_bytes);
-                                match destination.size() {
-                                    1 => {state.set_values(source_offset, &value_bytes[0..0]).unwrap();},
-                                    4 => {state.set_values(source_offset, &value_bytes[0..4]).unwrap();},
-                                    8 => {state.set_values(source_offset, &value_bytes[0..8]).unwrap();},
-                                    _ => { panic!("Unexpected value size for last load event");}
-                                };
-                            } else {
-                                let mut value_bytes = [0; 8];
-                                last_result.value.into_bytes::<BE>(&mut value_bytes);
-                                match destination.size() {
-                                    1 => {state.set_values(source_offset, &value_bytes[0..0]).unwrap();},
-                                    4 => {state.set_values(source_offset, &value_bytes[0..0]).unwrap();},
-                                    8 => {state.set_values(source_offset, &value_bytes[0..0]).unwrap();},
-                                    _ => { panic!("Unexpected value size for last load event");}
-                                };
-                            }
-                        }else {
-                            // if not found in the previous result list, then start solving
+                if self.address_range_list.iter().any(|(min, max)| *min <= source_offset && source_offset <= *max) {
+                    log::debug!("Observe load instruction within range src_offset:{} src: {}, dest: {}", source_offset, source, destination);
+                    if self.replay.is_replaying() {
+                        // Feed back the recorded value, the solver is not used in replay mode
+                        if let Err(error) = self.replay_read(state, source_offset, destination.size()) {
+                            log::error!("DummyPeripheral: {}", error);
+                            return HookStepAction::Halt(error.to_string());
+                        }
+                    } else {
+                        let site = self.access_site(source_offset, state.program_counter().unwrap());
+                        let known = if self.solving_started || !self.knowledge_reuse || self.stale_sites.contains(&site.read()) {
+                            None
+                        } else {
+                            self.knowledge.read().unwrap().known(&site)
+                        };
+                        if self.solving_started {
+                            // Another register read before the deciding branch, e.g. an error
+                            // register checked along with the status. The solver keeps all of
+                            // them and solves for a joint assignment.
+                            let current_pc = state.program_counter().unwrap();
+                            self.last_mem_read_event = (current_pc, source_offset.clone(), destination.size(), self.event_counter);
+                            self.mem_read_events.push(self.last_mem_read_event);
+                            self.read_sites.push(site);
+                        } else {
+                            // start solving
                             self.pcode_counter = 0;
                             // record this memory read event
-                            let current_pc = state.program_counter_value().unwrap();
+                            let current_pc = state.program_counter().unwrap();
                             // let current_pc = if is_little_endian {
                             //     state.state_ref().read_program_counter::<O>().unwrap()
                             // } else {
                             //     state.state_ref().read_program_counter::<BE>().unwrap()
                             // };
                             self.last_mem_read_event = (current_pc, source_offset.clone(), destination.size(), self.event_counter);
+                            self.mem_read_events = vec![self.last_mem_read_event];
+                            self.read_sites = vec![site];
 
                             log::debug!("create new solver");
                             self.solving_started = true;            // mark the start of solving
-                            self.forgive_jump = 0;
-                            self.solver = ConstraintSolver::new();  // Create new solver
+                            self.session_depth = self.call_stack.len();
+                            self.session_anchor = u64::from(current_pc);
+                            self.pending_exit = None;
+                            self.session_branches = 0;
+                            self.solver = ConstraintSolver::with_config(self.solver_config.clone());  // Create new solver
                             self.solver.set_default_variables(&self.solver_default_vars);   // set the default variables
+                            self.solver.set_mmio_ranges(&self.address_range_list);
+
+                            // Solved at this site before, in this run or a previous one. The
+                            // session only solves if the value does not leave the loop.
+                            self.session_reuse = known.as_ref().map(|solution| (site, solution.value, solution.size));
+                            if let Some(solution) = known {
+                                log::debug!("Reuse known value of memory: {} value:{:#x} uses:{}", source_offset, solution.value, solution.uses);
+                                Self::write_value(state, source_offset, solution.value, destination.size());
+                            }
                         }
                         // don't care endian for debugging message for now
-                        let pc = state.program_counter_value().unwrap();
+                        let pc = state.program_counter().unwrap();
                         log::debug!("PC: {}\tLoad Source: {:?}, {}", pc, source, source_offset);
+
+                        // The hook runs before the load, so this is the value the firmware reads
+                        if self.replay.is_recording() {
+                            let value = state.view_bytes(source_offset, destination.size()).unwrap();
+                            self.replay.record_event(InputEvent {
+                                timestamp: self.event_counter,
+                                pc,
+                                address: source_offset,
+                                value,
+                            });
+                        }
                     }
                 }
-
-
-
             },
-            PCodeOp::Store { source: _, destination, space: _} => {
+            PCodeOp::Store { source, destination, space: _} => {
                 // If storing sth to that memory, then it is not a reg checking loop
-                let (_pc, last_addr, _size, _last_counter) = self.last_mem_read_event;
-                let dest_addr = state.get_address(destination).unwrap();
-                if last_addr == dest_addr {
+                let dest_addr = state.pointer(destination).unwrap();
+                if self.address_range_list.iter().any(|(min, max)| *min <= dest_addr && dest_addr <= *max) {
+                    // Reads after a command are modelled separately, see AccessSite::last_write
+                    self.last_mmio_write = state.value(source).map(|value| (dest_addr, value));
+                }
+                if self.mem_read_events.iter().any(|(_pc, addr, _size, _counter)| *addr == dest_addr) {
                     self.solving_started = false;
                 }
             },
-            PCodeOp::IBranch { destination } =>{
+            PCodeOp::IBranch { destination: _ } =>{
                 // TODO for C166
             },
             PCodeOp::CBranch { destination, condition } =>{
-                if self.solving_started {
-                    let dest_addr = if let Operand::Address { value, size: _ } = destination {
-                        value.offset()
+                if self.solving_started && self.call_stack.len() == self.session_depth {
+                    if let Operand::Address { value, size: _ } = destination {
+                        self.decide_branch(state, value.offset(), condition);
                     } else {
                         // dest can be constant -> it's doing internal branching.
                         // Don't care about this case for peripheral solving
-                        // don't care endian for debugging message for now
-                        let pc = state.program_counter_value().unwrap();
-                        log::warn!("PC: {} Destination {:?} is not an address", pc, destination);
-                        0u64
-                    };
-                    // TODO: improve loop detection
-                    let (pc, last_addr, last_size, _last_counter) = self.last_mem_read_event;
-                    let last_pc = u64::from(pc);
-
-                    // check if it is a loop
-                    let mut is_loop = false;
-                    if dest_addr == last_pc {
-                        // If it is branching to the last memory loading instruction,
-                        // then it may be a loop check for peripheral
-                        log::debug!("{} {} Branched to the last memory loading instruction", dest_addr, last_pc);
-                        is_loop = true;
-                        self.solving_started = false;       // Mark the end of the solving
-                    } else if dest_addr < last_pc{
-                        // don't care endian for debugging message for now
-                        let pc = state.program_counter_value().unwrap();
-                        log::debug!("dest:{:?} last_read at PC {:?}, current PC {:?} Backward jumping, should be a loop", dest_addr, last_pc, pc);
-                        is_loop = true;
-                        self.solving_started = false;       // Mark the end of the solving
-                    } else {
-                        // TODO: Forware jumping inside the same function, probably still a loop. 
-                        // self.solving_started = false;       // Mark the end of the solving
-                        self.forgive_jump += 1;
-                        if self.forgive_jump > 1 {
-                            self.solving_started = false;
-                            log::debug!("{:?} {:?} Forward jumping reached forgiven value, probably not a loop", dest_addr, last_pc);
-                        } else {
-                            self.forgive_branch_condition = state.get_operand::<u8>(condition).unwrap() as u32;
-                            log::debug!("{:?} {:?} Forward jumping forgiven", dest_addr, last_pc);
-                            // Do not add more constraint, since other constraints on the rest of block may always be true
-                        }
-                    }
-
-                    // if loop detected then use the solver to get the expected value
-                    if is_loop {
-                        let expected_value :u32 = if self.forgive_jump == 0 {
-                            // If no jump is forgiven, the 1 condition will branch back to the starting of the loop
-                            // expect 0 to break the loop
-                            0       
-                        } else {
-                            // If there is a CBranch jump forgiven,
-                            // we break the 1st CBranch after the load
-                            // To branch to other places, flip the condition
-                            if self.forgive_branch_condition == 0 {1} else {0}
-                        };
-                        let solve_result = self.solver.solve(state.as_ref(), condition, expected_value as u64);
-                        if let None =  solve_result{
-                            log::warn!("Cound not solve this value, condition {}", condition);
-                        } else{
-                            for (k, v) in solve_result.unwrap() {
-                                if let None = v {
-                                    log::warn!("Cound not solve this value, address {}", k);
-                                    continue;
-                                } 
-                                log::info!("solving result: ({}, {:?})", k, v);
-
-
-                                let value_to_set = v.unwrap();
-                                // convert to BE byte array or LE byte array
-                                // let mut value_bytes = [0; 8];
-                                // v.unwrap().into_bytes::<O>(&mut value_bytes);
-                                // if is_little_endian {
-                                //     v.unwrap().into_bytes::<LE>(&mut value_bytes);
-                                // } else {
-                                //     v.unwrap().into_bytes::<BE>(&mut value_bytes);
-                                // }
-                                // v.unwrap().into_values::<O>(&mut value_bytes);
-                                
-
-                                // let state_values_mut = state.view_values_mut(k, last_size).unwrap();
-                                match last_size {
-                                    1 => state.write::<_, O, _>(k, value_to_set as u8).unwrap(),
-                                    2 => state.write::<_, O, _>(k, value_to_set as u16).unwrap(),
-                                    4 => state.write::<_, O, _>(k, value_to_set as u32).unwrap(),
-                                    8 => state.write::<_, O, _>(k, value_to_set as u64).unwrap(),
-                                    _ => {log::error!("Unexpected value size ({}) for last load event", last_size);}
-                                }
-                                let state_values = state.view_values(k, last_size).unwrap();
-                                log::info!("writting to {}, with {:?}, size: {}", k, state_values, last_size);
-
-
-                                // let mut val_test: [u8; 8] = [0; 8];
-                                // state.get_values(k, &mut val_test).unwrap();
-                                // log::debug!("tttttttest{:?}", val_test);
-                                // Cache the solving result
-                                if self.solving_results_cache_enable {
-                                    self.solving_results.write().unwrap().insert(last_addr, SolvingResult{target_addr: last_addr, value: v.unwrap(), size: last_size});
-                                }
-                            }
-                        }
+                        let pc = state.program_counter().unwrap();
+                        log::trace!("PC: {} Destination {:?} is not an address", pc, destination);
                     }
                 }
-
             },
-            PCodeOp::ICall{destination: _} => {
-                if self.solving_started{
-                    self.forgive_fun_call += 1;
-                    if self.forgive_fun_call != 0 {
-                        self.solving_started = false;
-                        log::debug!("Function returns reached forgiven value, probably not a loop");
-                    } else {
-                        log::debug!("Function return has been forgiven");
+            PCodeOp::Branch { destination } => {
+                if self.solving_started && self.call_stack.len() == self.session_depth {
+                    if let Operand::Address { value, size: _ } = destination {
+                        self.close_loop(state, value.offset());
                     }
                 }
             },
+            PCodeOp::Call { destination: _ } | PCodeOp::ICall { destination: _ } => {
+                // Helpers called from the loop are followed, their instructions are not part of it
+                let pc = u64::from(state.program_counter().unwrap());
+                self.call_stack.push((pc, self.frame_entry.unwrap_or(pc)));
+                self.frame_entry = None;
+                self.previous_pc = None;
+            },
             PCodeOp::Return { destination: _ } => {
-                if self.solving_started{
-                    self.forgive_fun_call -= 1;
-                    if self.forgive_fun_call != 0 {
-                        self.solving_started = false;
-                        log::debug!("Function returns reached forgiven value, probably not a loop");
-                    } else {
-                        log::debug!("Function return has been forgiven");
+                // Only the graphs of functions with a solving session are kept after they return
+                if let Some(entry) = self.frame_entry.filter(|entry| !self.session_functions.contains(entry)) {
+                    self.cfgs.remove(&entry);
+                }
+                let depth = self.call_stack.len();
+                let call = self.call_stack.pop();
+                self.previous_pc = call.map(|(call_pc, _)| call_pc);
+                self.frame_entry = call.map(|(_, caller_entry)| caller_entry);
+                if self.solving_started && depth == self.session_depth {
+                    match call {
+                        // The read was in a helper such as is_ready(), the loop is in the caller
+                        Some((call_pc, _)) if depth > 0 => {
+                            log::debug!("Following the solving session to the caller at {:#x}", call_pc);
+                            self.session_depth -= 1;
+                            self.session_anchor = call_pc;
+                            self.pending_exit = None;
+                        },
+                        _ => {
+                            self.solving_started = false;
+                            log::debug!("Returned from the function of the read, probably not a loop");
+                        },
                     }
                 }
-                // self.solving_started = false;
             },
             _ => {
 
             }
         }
 
-        if self.solving_started  && self.forgive_jump == 0 && self.forgive_fun_call >= 0{
-            // Do not add more constraint when a jump is forgiven, 
-            // since other constraints on the rest of block may always be true
-
+        if self.solving_started {
             // If solving started, add current pcode to the solver to build the tree
-            self.solver.add_pcode(operation.clone(), state.as_ref());
+            self.solver.add_pcode(operation.clone(), state);
         }
 
         self.pcode_counter += 1;
         // Use state changed HookResult?
-        return Ok(HookStepAction::Pass.into());
+        HookStepAction::Pass
+    }
+}
+
+// Why DummyPeripheral halted the run, given as the outcome of the hook since E is the
+// error type of the whole machine
+#[derive(Debug, ThisError)]
+pub enum DummyPeripheralError {
+    #[error(transparent)]
+    PCode(#[from] PCodeError),
+    #[error("Replay diverged from the recorded run: {0}")]
+    ReplayDiverged(String),
+}
+
+impl<S: 'static, O, E> HookConcrete for DummyPeripheral<S, O, E>
+where S: State + StateOps,
+      O: Order,
+        E: std::error::Error + Send + Sync + 'static,
+{
+    type State = PCodeState<u8, O>;        // TOOD: make it useful for universal endian
+    type Error = E;
+    type Outcome = String;
+
 
+    fn hook_architectural_step(
+        &mut self,
+        _state: &mut Self::State,
+        address: &Address,
+        operation: &StepState,
+    ) -> Result<HookOutcome<HookStepAction<Self::Outcome>>, Error<Self::Error>> {
+        self.instruction_step(u64::from(*address), operation.fallthrough().offset());
+        Ok(HookStepAction::Pass.into())
+    }
+
+    // 0x95e
+    fn hook_operation_step(
+        &mut self,
+        state: &mut Self::State,
+        _location: &Location,
+        operation: &PCodeOp,
+    ) -> Result<HookOutcome<HookStepAction<Self::Outcome>>, Error<Self::Error>>  {
+        Ok(self.operation_step(state, operation).into())
     }
 
 }
@@ -397,4 +758,341 @@ impl<S: 'static, O,  E> ClonableHookConcrete for DummyPeripheral<S, O, E>
 where S: State + StateOps,
       O: Order,
       E: std::error::Error + Send + Sync + 'static{
-}
\ No newline at end of file
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::BTreeMap;
+    use fugue::bytes::LE;
+    use fugue::ir::AddressSpaceId;
+    use crate::bypass::solver_backend::SolverOutcome;
+
+    type TestPeripheral = DummyPeripheral<PCodeState<u8, LE>, LE, std::io::Error>;
+
+    fn record(peripheral: &TestPeripheral, addr: u32, value: u64) {
+        let site = AccessSite { address: Address::from(addr), pc: Address::from(0x1000u32), call_stack: 0, last_write: None };
+        peripheral.knowledge().write().unwrap().record(site, value, 4);
+    }
+
+    #[test]
+    fn snapshot_clone_does_not_leak() -> Result<(), String> {
+        let mut peripheral = TestPeripheral::new();
+        peripheral.set_clone_mode(CloneMode::Snapshot);
+        record(&peripheral, 0xffd00000, 1);
+
+        let fork = peripheral.clone();
+        record(&fork, 0xffd00004, 2);
+
+        let entries = peripheral.knowledge().read().unwrap().entries();
+        if entries.len() != 1 || entries[0].site.address != Address::from(0xffd00000u32) {
+            return Err(String::from("Fork leaked solved values into the original"));
+        }
+        if fork.knowledge().read().unwrap().len() != 2 {
+            return Err(String::from("Fork did not keep the values solved before forking"));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot_saved_as_json() -> Result<(), String> {
+        let mut peripheral = TestPeripheral::new();
+        let site = AccessSite { address: Address::from(0xffd00000u32), pc: Address::from(0x1000u32), call_stack: 0, last_write: None };
+        peripheral.knowledge().write().unwrap().record(site, 0x80, 1);
+        peripheral.last_mmio_write = Some((Address::from(0xffd00004u32), 3));
+        peripheral.event_counter = 7;
+        peripheral.statistics.record(SolverOutcome::Sat(0x80), Duration::from_millis(1));
+        let json = serde_json::to_string(&peripheral.snapshot()).map_err(|e| e.to_string())?;
+
+        let mut restored = TestPeripheral::new();
+        restored.restore(&serde_json::from_str::<DummyPeripheralSnapshot>(&json).map_err(|e| e.to_string())?);
+        if *restored.knowledge().read().unwrap() != *peripheral.knowledge().read().unwrap()
+            || restored.last_mmio_write != peripheral.last_mmio_write
+            || restored.event_counter != 7
+            || restored.solver_statistics() != peripheral.solver_statistics() {
+            return Err(format!("Peripheral not restored from {}", json));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn shared_clone_shares_results() -> Result<(), String> {
+        let mut peripheral = TestPeripheral::new();
+        peripheral.set_clone_mode(CloneMode::Shared);
+
+        let fork = peripheral.clone();
+        record(&fork, 0xffd00004, 2);
+
+        if peripheral.knowledge().read().unwrap().len() != 1 {
+            return Err(String::from("Shared clone does not share solved values"));
+        }
+        Ok(())
+    }
+
+    const STS: u64 = 0xffd00000;
+    const READY: u64 = 0x1;
+
+    // Memory of a firmware run without an emulator, operands are constants or memory
+    #[derive(Default)]
+    struct TestState {
+        pc: u64,
+        memory: BTreeMap<u64, u8>,
+    }
+
+    impl OperandState for TestState {
+        fn program_counter(&self) -> Option<Address> {
+            Some(Address::from(self.pc))
+        }
+
+        fn pointer(&self, operand: &Operand) -> Option<Address> {
+            self.value(operand).map(Address::from)
+        }
+
+        fn value(&self, operand: &Operand) -> Option<u64> {
+            match operand {
+                Operand::Constant { value, size: _ } => Some(*value),
+                Operand::Address { value, size } => self.view_bytes(*value, *size)
+                    .map(|bytes| bytes.iter().rev().fold(0, |value, byte| value << 8 | *byte as u64)),
+                _ => None,
+            }
+        }
+
+        fn condition(&self, operand: &Operand) -> Option<bool> {
+            self.value(operand).map(|value| value != 0)
+        }
+
+        fn view_bytes(&self, address: Address, size: usize) -> Option<Vec<u8>> {
+            Some((0..size as u64).map(|i| self.memory.get(&(u64::from(address) + i)).copied().unwrap_or(0)).collect())
+        }
+
+        fn set_bytes(&mut self, address: Address, values: &[u8]) -> Result<(), PCodeError> {
+            for (i, value) in values.iter().enumerate() {
+                self.memory.insert(u64::from(address) + i as u64, *value);
+            }
+            Ok(())
+        }
+    }
+
+    impl TestState {
+        fn ready(&self) -> bool {
+            self.memory.get(&STS).copied().unwrap_or(0) as u64 & READY != 0
+        }
+
+        // Execute operation after the hooks saw it, for the operations used below
+        fn execute(&mut self, operation: &PCodeOp) {
+            let value = |operand: &Operand| self.value(operand).unwrap();
+            let (result, result_value) = match operation {
+                PCodeOp::Load { source, destination, space: _ } => {
+                    let address = self.pointer(source).unwrap();
+                    (destination, value(&Operand::Address { value: address, size: destination.size() }))
+                },
+                PCodeOp::IntAnd { result, operands } => (result, value(&operands[0]) & value(&operands[1])),
+                PCodeOp::IntEq { result, operands } => (result, (value(&operands[0]) == value(&operands[1])) as u64),
+                PCodeOp::IntNotEq { result, operands } => (result, (value(&operands[0]) != value(&operands[1])) as u64),
+                _ => return,
+            };
+            if let Operand::Address { value: address, size } = result {
+                self.set_bytes(*address, &result_value.to_le_bytes()[..*size]).unwrap();
+            }
+        }
+    }
+
+    fn polling_peripheral() -> TestPeripheral {
+        let mut peripheral = TestPeripheral::new();
+        peripheral.add_address_range((Address::from(STS), Address::from(STS + 0xff)));
+        peripheral.set_solver_backend(SolverKind::Native { max_bits: 8 });
+        peripheral
+    }
+
+    // One byte temporary in RAM
+    fn temp(index: u64) -> Operand {
+        Operand::Address { value: Address::from(0x3000 + index), size: 1 }
+    }
+
+    fn constant(value: u64, size: usize) -> Operand {
+        Operand::Constant { value, size }
+    }
+
+    fn code(pc: u64) -> Operand {
+        Operand::Address { value: Address::from(pc), size: 4 }
+    }
+
+    fn cbranch(destination: u64, condition: Operand) -> PCodeOp {
+        PCodeOp::CBranch { destination: code(destination), condition }
+    }
+
+    // T0 = STS; T1 = T0 & READY; T2 = busy (T1 == 0); T3 = ready (T1 != 0)
+    fn poll() -> Vec<PCodeOp> {
+        vec![
+            PCodeOp::Load { source: constant(STS, 4), destination: temp(0), space: AddressSpaceId::default_id(0) },
+            PCodeOp::IntAnd { result: temp(1), operands: [temp(0), constant(READY, 1)] },
+            PCodeOp::IntEq { result: temp(2), operands: [temp(1), constant(0, 1)] },
+            PCodeOp::IntNotEq { result: temp(3), operands: [temp(1), constant(0, 1)] },
+        ]
+    }
+
+    // Execute the instructions of trace, (PC, p-code) each, with the hooks called before
+    // every instruction and operation as the emulator does
+    fn run(peripheral: &mut TestPeripheral, state: &mut TestState, trace: &[(u64, Vec<PCodeOp>)]) -> Result<(), String> {
+        for (pc, operations) in trace.iter() {
+            state.pc = *pc;
+            peripheral.instruction_step(*pc, pc + 4);
+            for operation in operations.iter() {
+                if let HookStepAction::Halt(reason) = peripheral.operation_step(state, operation) {
+                    return Err(reason);
+                }
+                state.execute(operation);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn do_while_leaves_through_closing_branch() -> Result<(), String> {
+        // do { sts = STS; } while (!(sts & READY));
+        let mut peripheral = polling_peripheral();
+        let mut state = TestState::default();
+        run(&mut peripheral, &mut state, &[(0x100, vec![]), (0x104, poll()), (0x108, vec![cbranch(0x104, temp(2))])])?;
+        if !state.ready() || peripheral.solving_started {
+            return Err(format!("STS {:?} solved for the closing branch", state.memory.get(&STS)));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn while_with_helper_leaves_through_pending_exit() -> Result<(), String> {
+        // while (!(STS & READY)) { helper(); } with the exit branch before the call
+        let mut peripheral = polling_peripheral();
+        let mut state = TestState::default();
+        run(&mut peripheral, &mut state, &[(0x200, vec![]), (0x204, poll()), (0x208, vec![cbranch(0x218, temp(3))])])?;
+        if !matches!(peripheral.pending_exit, Some((0x208, 0x218, _, false))) || state.ready() {
+            return Err(String::from("Exit branch not kept until the loop is closed"));
+        }
+
+        // The branch in the helper is not part of the loop
+        run(&mut peripheral, &mut state, &[
+            (0x20c, vec![PCodeOp::Call { destination: code(0x300) }]),
+            (0x300, vec![cbranch(0x308, temp(2))]),
+            (0x304, vec![PCodeOp::Return { destination: code(0x210) }]),
+        ])?;
+        if peripheral.session_branches != 1 || state.ready() {
+            return Err(String::from("Branch in the helper decided the session"));
+        }
+
+        run(&mut peripheral, &mut state, &[(0x210, vec![PCodeOp::Branch { destination: code(0x204) }])])?;
+        if !state.ready() || peripheral.solving_started {
+            return Err(format!("STS {:?} solved for the pending exit", state.memory.get(&STS)));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn nested_loops_leave_the_inner_loop() -> Result<(), String> {
+        // for (;;) { while (!(STS & READY)); } the outer loop is closed at 0x410
+        let mut peripheral = polling_peripheral();
+        let mut state = TestState::default();
+        let inner = [(0x404, vec![]), (0x408, poll()), (0x40c, vec![cbranch(0x408, temp(2))])];
+        run(&mut peripheral, &mut state, &[(0x400, vec![])])?;
+        for iteration in 0..2 {
+            state.memory.insert(STS, 0);
+            run(&mut peripheral, &mut state, &inner)?;
+            if !state.ready() || peripheral.solving_started {
+                return Err(format!("Inner loop not left in iteration {}", iteration));
+            }
+            run(&mut peripheral, &mut state, &[(0x410, vec![PCodeOp::Branch { destination: code(0x404) }])])?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn session_follows_helper_to_caller() -> Result<(), String> {
+        // do { } while (!is_ready()); where is_ready() returns STS & READY in T4
+        let mut peripheral = polling_peripheral();
+        let mut state = TestState::default();
+        run(&mut peripheral, &mut state, &[
+            (0x500, vec![]),
+            (0x504, vec![PCodeOp::Call { destination: code(0x600) }]),
+            (0x600, vec![
+                PCodeOp::Load { source: constant(STS, 4), destination: temp(0), space: AddressSpaceId::default_id(0) },
+                PCodeOp::IntAnd { result: temp(4), operands: [temp(0), constant(READY, 1)] },
+            ]),
+            (0x604, vec![PCodeOp::Return { destination: code(0x508) }]),
+        ])?;
+        if !peripheral.solving_started || peripheral.session_depth != 0 || peripheral.session_anchor != 0x504 {
+            return Err(String::from("Session not moved to the call in the caller"));
+        }
+
+        run(&mut peripheral, &mut state, &[
+            (0x508, vec![PCodeOp::IntEq { result: temp(2), operands: [temp(4), constant(0, 1)] }]),
+            (0x50c, vec![cbranch(0x504, temp(2))]),
+        ])?;
+        if !state.ready() || peripheral.solving_started {
+            return Err(format!("STS {:?} solved in the caller", state.memory.get(&STS)));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn session_without_loop_gives_up() -> Result<(), String> {
+        // A read followed by forward branches only, e.g. a state machine dispatching on STS
+        let mut peripheral = polling_peripheral();
+        let mut state = TestState::default();
+        run(&mut peripheral, &mut state, &[(0x700, vec![]), (0x704, poll())])?;
+        let branch = |i: u64| (0x708 + 4 * i, vec![cbranch(0x900 + 4 * i, temp(3))]);
+        let trace: Vec<(u64, Vec<PCodeOp>)> = (0..MAX_SESSION_BRANCHES as u64).map(branch).collect();
+        run(&mut peripheral, &mut state, &trace)?;
+        if !peripheral.solving_started {
+            return Err(String::from("Gave up before MAX_SESSION_BRANCHES branches"));
+        }
+        run(&mut peripheral, &mut state, &[branch(MAX_SESSION_BRANCHES as u64)])?;
+        if peripheral.solving_started || state.ready() {
+            return Err(String::from("Solved a read without a loop around it"));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn known_value_leaving_inner_loop_is_reused() -> Result<(), String> {
+        // for (;;) { do { sts = STS; } while (!(sts & READY)); } the peripheral is busy again
+        // at every outer iteration, the known value is not stale when the load is reached again
+        let mut peripheral = polling_peripheral();
+        peripheral.enable_knowledge_reuse(true);
+        let mut state = TestState::default();
+        run(&mut peripheral, &mut state, &[(0x800, vec![])])?;
+        for iteration in 0..3 {
+            state.memory.insert(STS, 0);
+            run(&mut peripheral, &mut state, &[(0x804, vec![]), (0x808, poll()), (0x80c, vec![cbranch(0x808, temp(2))])])?;
+            if !state.ready() || peripheral.solving_started {
+                return Err(format!("Inner loop not left in iteration {}", iteration));
+            }
+            run(&mut peripheral, &mut state, &[(0x810, vec![PCodeOp::Branch { destination: code(0x804) }])])?;
+        }
+
+        let statistics = peripheral.solver_statistics();
+        if statistics.queries != 1 || statistics.cache_hits != 2 || !peripheral.stale_sites.is_empty() {
+            return Err(format!("Known value not reused {:?}", statistics));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn known_value_staying_in_loop_is_solved() -> Result<(), String> {
+        // do { sts = STS; } while (!(sts & READY)); with a busy value known from a bad run
+        let mut peripheral = polling_peripheral();
+        peripheral.enable_knowledge_reuse(true);
+        let site = peripheral.access_site(Address::from(STS), Address::from(0x904u64));
+        peripheral.knowledge().write().unwrap().record(site, 0, 1);
+        let mut state = TestState::default();
+        run(&mut peripheral, &mut state, &[(0x900, vec![]), (0x904, poll()), (0x908, vec![cbranch(0x904, temp(2))])])?;
+
+        let statistics = peripheral.solver_statistics();
+        if !state.ready() || statistics.queries != 1 || statistics.cache_hits != 0 || !peripheral.stale_sites.contains(&site.read()) {
+            return Err(format!("Stale value not solved {:?}", statistics));
+        }
+        if !peripheral.knowledge().read().unwrap().solutions(&site).iter().any(|s| s.value == 1) {
+            return Err(format!("Solved value not recorded {:?}", peripheral.knowledge().read().unwrap().entries()));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,167 @@
+use std::cmp::{Ordering, Reverse};
+use std::fmt;
+use std::sync::Arc;
+
+use fugue::ir::Address;
+
+use super::cfg::ControlFlowGraph;
+
+// One outcome of a conditional branch that depends on peripheral values
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BranchOutcome {
+    pub pc: Address,                // The branch instruction
+    pub taken: bool,
+    pub target: Address,            // The next instruction: the branch destination if taken, the fall-through if not
+}
+
+pub type BranchScore = Arc<dyn Fn(&BranchOutcome) -> i64 + Send + Sync>;
+
+// The branch outcomes DummyPeripheral solves the peripheral values for. Branches a goal
+// has no preference for are solved as ExitLoop does.
+//
+// let goal = SolvingGoal::Reach {
+//     preferred: vec![Address::from(0x1234u32)],     // the error handler
+//     forbidden: vec![],
+// };
+#[derive(Clone, Default)]
+pub enum SolvingGoal {
+    // Leave polling loops
+    #[default]
+    ExitLoop,
+    // Continue towards preferred PCs rather than elsewhere, and never directly at a
+    // forbidden PC, whether that means taking the branch or falling through. Outcomes
+    // are ranked by the distance to the closest preferred PC, then to the closest
+    // forbidden PC, in the control-flow graph recovered so far. PCs that were not
+    // executed yet are only recognised as the immediate next instruction.
+    Reach { preferred: Vec<Address>, forbidden: Vec<Address> },
+    // Solve for the outcome with the higher score, equal scores are no preference
+    Score(BranchScore),
+}
+
+impl fmt::Debug for SolvingGoal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SolvingGoal::ExitLoop => write!(f, "ExitLoop"),
+            SolvingGoal::Reach { preferred, forbidden } => f.debug_struct("Reach")
+                .field("preferred", preferred)
+                .field("forbidden", forbidden)
+                .finish(),
+            SolvingGoal::Score(_) => write!(f, "Score(..)"),
+        }
+    }
+}
+
+impl SolvingGoal {
+    pub fn score<F>(score: F) -> Self
+    where F: Fn(&BranchOutcome) -> i64 + Send + Sync + 'static {
+        SolvingGoal::Score(Arc::new(score))
+    }
+
+    // Whether the branch at pc to target should be taken rather than falling through to
+    // fallthrough, None if the goal has no preference. cfg is the graph of the function
+    // the branch is in.
+    pub fn expected_condition(&self, pc: Address, target: Address, fallthrough: Address, cfg: &ControlFlowGraph) -> Option<bool> {
+        let ordering = match self {
+            SolvingGoal::ExitLoop => return None,
+            SolvingGoal::Reach { preferred, forbidden } => {
+                let preferred: Vec<u64> = preferred.iter().map(|pc| u64::from(*pc)).collect();
+                let forbidden: Vec<u64> = forbidden.iter().map(|pc| u64::from(*pc)).collect();
+                // Higher is better: not forbidden, closer to a preferred PC, further from
+                // (or never reaching) a forbidden PC
+                let rank = |next: Address| {
+                    let next = u64::from(next);
+                    (
+                        !forbidden.contains(&next),
+                        cfg.distance(next, &preferred).map(Reverse),
+                        cfg.distance(next, &forbidden).unwrap_or(usize::MAX),
+                    )
+                };
+                rank(target).cmp(&rank(fallthrough))
+            },
+            SolvingGoal::Score(score) => {
+                let taken = score(&BranchOutcome { pc, taken: true, target });
+                let not_taken = score(&BranchOutcome { pc, taken: false, target: fallthrough });
+                taken.cmp(&not_taken)
+            },
+        };
+        match ordering {
+            Ordering::Greater => Some(true),
+            Ordering::Less => Some(false),
+            Ordering::Equal => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn branch_preferences() -> Result<(), String> {
+        let pc = Address::from(0x100u32);
+        let next = Address::from(0x104u32);
+        let error = Address::from(0x200u32);
+        let retry = Address::from(0x80u32);
+        let other = Address::from(0x300u32);
+
+        // (target, fall-through, expected)
+        let goal = SolvingGoal::Reach { preferred: vec![error], forbidden: vec![retry] };
+        let cases = [
+            (error, next, Some(true)),
+            (retry, next, Some(false)),
+            (other, next, None),
+            // The preferred or forbidden PC is the fall-through
+            (other, error, Some(false)),
+            (other, retry, Some(true)),
+            (error, retry, Some(true)),
+            (retry, error, Some(false)),
+        ];
+        let cfg = ControlFlowGraph::new();
+        for (target, fallthrough, expected) in cases.iter() {
+            if goal.expected_condition(pc, *target, *fallthrough, &cfg) != *expected {
+                return Err(format!("Branch to {} falling through to {} expected {:?}", target, fallthrough, expected));
+            }
+        }
+
+        // Stay in the loop: prefer the branch back to the read, or falling through to it
+        let goal = SolvingGoal::score(move |outcome| if outcome.target == retry { 1 } else { 0 });
+        if goal.expected_condition(pc, retry, next, &cfg) != Some(true) || goal.expected_condition(pc, error, next, &cfg).is_some() {
+            return Err(String::from("Unexpected scored branch preference"));
+        }
+        if goal.expected_condition(pc, error, retry, &cfg) != Some(false) {
+            return Err(String::from("Scored fall-through not preferred"));
+        }
+        if SolvingGoal::default().expected_condition(pc, error, next, &cfg).is_some() {
+            return Err(String::from("ExitLoop has no branch preference"));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn reach_through_the_graph() -> Result<(), String> {
+        let pc = Address::from(0x100u32);
+        let error = 0x200u64;
+        let retry = 0x80u64;
+        let goal = SolvingGoal::Reach { preferred: vec![Address::from(error)], forbidden: vec![Address::from(retry)] };
+
+        // 0x104 reaches the error handler through 0x108, 0x300 only through a longer path,
+        // 0x400 goes back to the retry and 0x500 leads nowhere known
+        let mut cfg = ControlFlowGraph::new();
+        for (from, to) in [(0x104, 0x108), (0x108, error), (0x300, 0x304), (0x304, 0x308), (0x308, error), (0x400, retry)] {
+            cfg.add_edge(from, to);
+        }
+        let cases = [
+            (0x104u64, 0x500u64, Some(true)),
+            (0x500, 0x104, Some(false)),
+            (0x104, 0x300, Some(true)),
+            (0x400, 0x500, Some(false)),
+            (0x500, 0x504, None),
+        ];
+        for (target, fallthrough, expected) in cases.iter() {
+            if goal.expected_condition(pc, Address::from(*target), Address::from(*fallthrough), &cfg) != *expected {
+                return Err(format!("Branch to {:#x} falling through to {:#x} expected {:?}", target, fallthrough, expected));
+            }
+        }
+        Ok(())
+    }
+}
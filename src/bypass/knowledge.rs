@@ -0,0 +1,213 @@
+// Values solved for peripheral reads, kept across runs
+//
+// Every solution is stored for the site of the read: the peripheral address, the PC of
+// the load, a hash of the call instructions on the call stack and the last write to the
+// peripheral, so a driver function polling the same register from two callers, or after
+// two different commands, has two entries. The last write only separates the reads of a
+// synthesized model, values are reused at the same load and call stack whatever was
+// written before, so the solutions are indexed by the read first. With knowledge reuse
+// enabled, a run loading the knowledge base of a previous run over the same firmware
+// reuses the values instead of solving.
+//
+// The file is JSON so the solutions can be reviewed and turned into hand-written models.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+
+use fugue::ir::Address;
+use serde::{Serialize, Deserialize};
+
+// Where a peripheral value was read
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct AccessSite {
+    pub address: Address,   // Peripheral address read
+    pub pc: Address,        // PC of the load
+    pub call_stack: u64,    // call_stack_hash of the calls active at the load
+    #[serde(default)]
+    pub last_write: Option<(Address, u64)>,     // The last value written to the peripheral before the load
+}
+
+// The load of an access site with its calls: (peripheral address, PC, call stack hash)
+pub type AccessRead = (Address, Address, u64);
+
+impl AccessSite {
+    // The same load with the same calls, whatever was written before
+    pub fn read(&self) -> AccessRead {
+        (self.address, self.pc, self.call_stack)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Solution {
+    pub value: u64,
+    pub size: usize,        // Bytes read
+    pub uses: u64,          // Times the value was solved or reused for the site
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KnowledgeEntry {
+    pub site: AccessSite,
+    pub solutions: Vec<Solution>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "Vec<KnowledgeEntry>", into = "Vec<KnowledgeEntry>")]
+pub struct KnowledgeBase {
+    reads: HashMap<AccessRead, HashMap<Option<(Address, u64)>, Vec<Solution>>>,   // By read, then by last write
+}
+
+// FNV-1a over the call instructions, stable across runs and toolchains
+pub fn call_stack_hash<I: IntoIterator<Item = u64>>(calls: I) -> u64 {
+    calls.into_iter()
+        .flat_map(|call| call.to_le_bytes())
+        .fold(0xcbf29ce484222325, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+impl KnowledgeBase {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let reader = BufReader::new(File::open(path)?);
+        serde_json::from_reader(reader).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        let writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(writer, self).map_err(std::io::Error::other)
+    }
+
+    // Add a solved value for site, or count another use of a value solved before
+    pub fn record(&mut self, site: AccessSite, value: u64, size: usize) {
+        let solutions = self.reads.entry(site.read()).or_default().entry(site.last_write).or_default();
+        match solutions.iter_mut().find(|s| s.value == value && s.size == size) {
+            Some(solution) => solution.uses += 1,
+            None => solutions.push(Solution { value, size, uses: 1 }),
+        }
+    }
+
+    // The most used value for the read at site after any write. Its use is recorded once the
+    // value is known to work.
+    pub fn known(&self, site: &AccessSite) -> Option<Solution> {
+        let mut uses: HashMap<(u64, usize), u64> = HashMap::new();
+        for solution in self.reads.get(&site.read())?.values().flatten() {
+            *uses.entry((solution.value, solution.size)).or_default() += solution.uses;
+        }
+        uses.into_iter()
+            .max_by_key(|((value, _), uses)| (*uses, std::cmp::Reverse(*value)))
+            .map(|((value, size), uses)| Solution { value, size, uses })
+    }
+
+    pub fn solutions(&self, site: &AccessSite) -> &[Solution] {
+        self.reads.get(&site.read())
+            .and_then(|writes| writes.get(&site.last_write))
+            .map_or(&[], |solutions| solutions.as_slice())
+    }
+
+    // Number of sites
+    pub fn len(&self) -> usize {
+        self.reads.values().map(|writes| writes.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.reads.is_empty()
+    }
+
+    // Sites ordered by peripheral address, PC, call stack and last write
+    pub fn entries(&self) -> Vec<KnowledgeEntry> {
+        let mut entries: Vec<KnowledgeEntry> = self.reads.iter()
+            .flat_map(|(&(address, pc, call_stack), writes)| writes.iter().map(move |(last_write, solutions)| KnowledgeEntry {
+                site: AccessSite { address, pc, call_stack, last_write: *last_write },
+                solutions: solutions.clone(),
+            }))
+            .collect();
+        entries.sort_by_key(|e| (
+            u64::from(e.site.address),
+            u64::from(e.site.pc),
+            e.site.call_stack,
+            e.site.last_write.map(|(address, value)| (u64::from(address), value)),
+        ));
+        entries
+    }
+
+    // Add the solutions of other, e.g. of a forked run
+    pub fn merge(&mut self, other: &KnowledgeBase) {
+        for entry in other.entries() {
+            for solution in entry.solutions.iter() {
+                let known = self.reads.entry(entry.site.read()).or_default().entry(entry.site.last_write).or_default();
+                match known.iter_mut().find(|s| s.value == solution.value && s.size == solution.size) {
+                    Some(known) => known.uses = known.uses.max(solution.uses),
+                    None => known.push(solution.clone()),
+                }
+            }
+        }
+    }
+}
+
+impl From<Vec<KnowledgeEntry>> for KnowledgeBase {
+    fn from(entries: Vec<KnowledgeEntry>) -> Self {
+        let mut knowledge = Self::new();
+        for entry in entries {
+            knowledge.reads.entry(entry.site.read()).or_default().insert(entry.site.last_write, entry.solutions);
+        }
+        knowledge
+    }
+}
+
+impl From<KnowledgeBase> for Vec<KnowledgeEntry> {
+    fn from(knowledge: KnowledgeBase) -> Self {
+        knowledge.entries()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn record_reuse_and_reload() -> Result<(), String> {
+        let site = AccessSite {
+            address: Address::from(0xffd00000u32),
+            pc: Address::from(0x1000u32),
+            call_stack: call_stack_hash([0x2000, 0x3000]),
+            last_write: None,
+        };
+        let other_caller = AccessSite { call_stack: call_stack_hash([0x2100, 0x3000]), ..site };
+        if site == other_caller {
+            return Err(String::from("Call stacks of different callers hash the same"));
+        }
+
+        let mut knowledge = KnowledgeBase::new();
+        knowledge.record(site, 1, 4);
+        knowledge.record(site, 3, 4);
+        knowledge.record(site, 3, 4);
+        if knowledge.known(&site).map(|s| (s.value, s.uses)) != Some((3, 2)) || knowledge.known(&other_caller).is_some() {
+            return Err(format!("Unexpected solutions {:?}", knowledge.entries()));
+        }
+
+        // The same read after a command knows the values solved without it, and their uses
+        // after the command are counted on their own
+        let after_command = AccessSite { last_write: Some((Address::from(0xffd00010u32), 1)), ..site };
+        knowledge.record(after_command, 1, 4);
+        knowledge.record(after_command, 1, 4);
+        if knowledge.known(&after_command).map(|s| (s.value, s.uses)) != Some((3, 2))
+            || knowledge.solutions(&after_command) != [Solution { value: 1, size: 4, uses: 2 }]
+            || knowledge.len() != 2 {
+            return Err(format!("Unexpected solutions after a command {:?}", knowledge.entries()));
+        }
+        let sites: Vec<AccessSite> = knowledge.entries().iter().map(|e| e.site).collect();
+        if sites != vec![site, after_command] {
+            return Err(format!("Unordered sites {:?}", sites));
+        }
+
+        let json = serde_json::to_string(&knowledge).map_err(|e| e.to_string())?;
+        let reloaded: KnowledgeBase = serde_json::from_str(&json).map_err(|e| e.to_string())?;
+        if reloaded != knowledge || serde_json::to_string(&reloaded).map_err(|e| e.to_string())? != json {
+            return Err(format!("Reloaded {:?} from {}", reloaded, json));
+        }
+        Ok(())
+    }
+}
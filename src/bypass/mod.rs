@@ -1,4 +1,15 @@
+mod cfg;
 mod dummy_peripheral;
+mod goal;
+mod knowledge;
+mod smtlib;
 mod solver;
+mod solver_backend;
+mod state;
+mod storage;
 
-pub use self::dummy_peripheral::*;
\ No newline at end of file
+pub use self::dummy_peripheral::*;
+pub use self::goal::*;
+pub use self::knowledge::*;
+pub use self::state::OperandState;
+pub use self::solver_backend::{SolverBackend, SolverConfig, SolverKind, SolverOutcome, SolverStatistics};
@@ -0,0 +1,180 @@
+// SMT-LIB2 text of the symbolic expressions built by ConstraintSolver
+//
+// Every expression is printed as a bit-vector, relations become one bit wide through
+// ite, so constraints are asserted as being different from zero. Expressions using
+// operations without a QF_BV equivalent (floating point) cannot be printed.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use fugue::ir::il::ecode::{BinOp, BinRel, Cast, UnOp};
+use fugue_concolic::expr::SymExpr;
+
+// Symbol of the variable a query asks the value of
+pub const TARGET: &str = "|target|";
+
+// The symbol of a variable, quoted since names contain characters such as '-'
+pub fn symbol(var: &SymExpr) -> String {
+    format!("|{}|", var.to_string().replace(['|', '\\'], "_"))
+}
+
+// The free variables of exprs by symbol, with their width in bits
+pub fn variables<'a, I: IntoIterator<Item = &'a SymExpr>>(exprs: I) -> BTreeMap<String, u32> {
+    let mut vars = BTreeMap::new();
+    let mut stack: Vec<&SymExpr> = exprs.into_iter().collect();
+    while let Some(expr) = stack.pop() {
+        match expr {
+            SymExpr::IVar(_) => {
+                vars.insert(symbol(expr), expr.bits() as u32);
+            },
+            SymExpr::UnOp { expr, .. } | SymExpr::UnRel { expr, .. } | SymExpr::Cast { expr, .. } | SymExpr::Extract { expr, .. } => stack.push(expr),
+            SymExpr::BinOp { lexpr, rexpr, .. } | SymExpr::BinRel { lexpr, rexpr, .. } | SymExpr::Concat { lexpr, rexpr } => {
+                stack.push(lexpr);
+                stack.push(rexpr);
+            },
+            _ => (),
+        }
+    }
+    vars
+}
+
+fn literal(value: u64, bits: u32) -> String {
+    format!("(_ bv{} {})", value, bits)
+}
+
+fn rel(op: &str, l: String, r: String) -> String {
+    format!("(ite ({} {} {}) #b1 #b0)", op, l, r)
+}
+
+// The bit-vector term of expr, None if it cannot be expressed in QF_BV
+pub fn term(expr: &SymExpr) -> Option<String> {
+    let bits = expr.bits() as u32;
+    Some(match expr {
+        SymExpr::Val(bv) => literal(bv.to_u64()?, bits),
+        SymExpr::IVar(_) => symbol(expr),
+        SymExpr::UnOp { op, expr } => {
+            let e = term(expr)?;
+            match op {
+                UnOp::NOT => format!("(bvnot {})", e),
+                UnOp::NEG => format!("(bvneg {})", e),
+                UnOp::POPCOUNT => {
+                    // Sum of the bits of the operand, at the width of the result
+                    let width = expr.bits() as u32;
+                    let summands: Vec<String> = (0..width)
+                        .map(|i| format!("((_ zero_extend {}) ((_ extract {} {}) {}))", bits - 1, i, i, e))
+                        .collect();
+                    if summands.len() == 1 { summands[0].clone() } else { format!("(bvadd {})", summands.join(" ")) }
+                },
+                _ => return None,
+            }
+        },
+        SymExpr::BinOp { op, lexpr, rexpr } => {
+            let name = match op {
+                BinOp::AND => "bvand",
+                BinOp::OR => "bvor",
+                BinOp::XOR => "bvxor",
+                BinOp::ADD => "bvadd",
+                BinOp::SUB => "bvsub",
+                BinOp::MUL => "bvmul",
+                BinOp::DIV => "bvudiv",
+                BinOp::SDIV => "bvsdiv",
+                BinOp::REM => "bvurem",
+                BinOp::SREM => "bvsrem",
+                BinOp::SHL => "bvshl",
+                BinOp::SHR => "bvlshr",
+                BinOp::SAR => "bvashr",
+            };
+            format!("({} {} {})", name, term(lexpr)?, term(rexpr)?)
+        },
+        SymExpr::BinRel { op, lexpr, rexpr } => {
+            let (l, r) = (term(lexpr)?, term(rexpr)?);
+            let width = lexpr.bits() as u32;
+            match op {
+                BinRel::EQ => rel("=", l, r),
+                BinRel::NEQ => rel("distinct", l, r),
+                BinRel::LT => rel("bvult", l, r),
+                BinRel::LE => rel("bvule", l, r),
+                BinRel::SLT => rel("bvslt", l, r),
+                BinRel::SLE => rel("bvsle", l, r),
+                // Unsigned overflow of l + r: the carry out of a one bit wider sum
+                BinRel::CARRY => format!("((_ extract {} {}) (bvadd ((_ zero_extend 1) {}) ((_ zero_extend 1) {})))", width, width, l, r),
+                // Signed overflow of l + r and l - r: the sign of the result differs from what the operands allow
+                BinRel::SCARRY => format!(
+                    "(ite (and (= ((_ extract {w} {w}) {l}) ((_ extract {w} {w}) {r})) (distinct ((_ extract {w} {w}) {l}) ((_ extract {w} {w}) (bvadd {l} {r})))) #b1 #b0)",
+                    w = width - 1, l = l, r = r),
+                BinRel::SBORROW => format!(
+                    "(ite (and (distinct ((_ extract {w} {w}) {l}) ((_ extract {w} {w}) {r})) (distinct ((_ extract {w} {w}) {l}) ((_ extract {w} {w}) (bvsub {l} {r})))) #b1 #b0)",
+                    w = width - 1, l = l, r = r),
+            }
+        },
+        SymExpr::Cast { expr: inner, cast } => {
+            let e = term(inner)?;
+            let from = inner.bits() as u32;
+            match cast {
+                Cast::Bool => format!("(ite (= {} {}) #b0 #b1)", e, literal(0, from)),
+                Cast::Signed(_) if bits > from => format!("((_ sign_extend {}) {})", bits - from, e),
+                Cast::Unsigned(_) if bits > from => format!("((_ zero_extend {}) {})", bits - from, e),
+                Cast::Signed(_) | Cast::Unsigned(_) | Cast::Low(_) => format!("((_ extract {} 0) {})", bits - 1, e),
+                Cast::High(_) => format!("((_ extract {} {}) {})", from - 1, from - bits, e),
+                _ => return None,
+            }
+        },
+        SymExpr::Extract { expr, lsb, msb } => format!("((_ extract {} {}) {})", *msb as u32 - 1, *lsb as u32, term(expr)?),
+        SymExpr::Concat { lexpr, rexpr } => format!("(concat {} {})", term(lexpr)?, term(rexpr)?),
+        _ => return None,
+    })
+}
+
+// A script asking for a value of target satisfying every constraint, read back with
+// (get-value (|target|))
+pub fn query(target: &SymExpr, constraints: &[SymExpr]) -> Option<String> {
+    let mut script = String::from("(set-logic QF_BV)\n(set-option :produce-models true)\n");
+    for (var, bits) in variables(std::iter::once(target).chain(constraints.iter())) {
+        writeln!(script, "(declare-const {} (_ BitVec {}))", var, bits).ok()?;
+    }
+    writeln!(script, "(declare-const {} (_ BitVec {}))", TARGET, target.bits()).ok()?;
+    writeln!(script, "(assert (= {} {}))", TARGET, term(target)?).ok()?;
+    for constraint in constraints.iter() {
+        writeln!(script, "(assert (distinct {} {}))", term(constraint)?, literal(0, constraint.bits() as u32)).ok()?;
+    }
+    script.push_str("(check-sat)\n");
+    Some(script)
+}
+
+// The value of the reply to (get-value (|target|)), e.g. ((|target| #x0000002a))
+pub fn parse_value(reply: &str) -> Option<u64> {
+    let value = reply.split(TARGET).nth(1)?.trim().trim_end_matches(')').trim();
+    if let Some(hex) = value.strip_prefix("#x") {
+        u64::from_str_radix(hex, 16).ok()
+    } else if let Some(bin) = value.strip_prefix("#b") {
+        u64::from_str_radix(bin, 2).ok()
+    } else {
+        // (_ bv42 32)
+        value.strip_prefix("(_ bv")?.split_whitespace().next()?.parse().ok()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use fugue_concolic::expr::IVar;
+
+    #[test]
+    fn polling_query() -> Result<(), String> {
+        // (STS & 0x80) == 0x80
+        let sts = SymExpr::ivar(IVar::new_named("ffd00000-1", 32));
+        let ready = SymExpr::eq(SymExpr::and(sts.clone(), SymExpr::val_sized(0x80, 32)), SymExpr::val_sized(0x80, 32));
+        let script = query(&sts, &[ready]).ok_or("Query not printable")?;
+        if !script.contains(&format!("(declare-const {} (_ BitVec 32))", symbol(&sts))) || !script.contains("bvand") {
+            return Err(format!("Unexpected query {}", script));
+        }
+
+        let replies = [("((|target| #x00000080))", 0x80), ("((|target| #b1010))", 10), ("((|target| (_ bv42 32)))", 42)];
+        for (reply, expected) in replies.iter() {
+            if parse_value(reply) != Some(*expected) {
+                return Err(format!("Parsed {:?} from {}", parse_value(reply), reply));
+            }
+        }
+        Ok(())
+    }
+}
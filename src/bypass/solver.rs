@@ -1,18 +1,23 @@
-use fugue_concolic_solver_boolector::SolverContext;
-use fugue_concolic::backend::ValueSolver;
 use std::marker::PhantomData;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
+use std::fs;
+use std::fmt::Write;
+use std::path::PathBuf;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 use log;
 
 use fugue_concolic::expr::{
     SymExpr, IVar,
 };
 // use fugue_concolic::value::Value;
-use metaemu::state::{
-    pcode::PCodeState, };
 use fugue::bytes::{Order};
 
+use super::state::OperandState;
+use super::storage::{self, ByteStore};
+use super::smtlib;
+use super::solver_backend::{SolverConfig, SolverOutcome, SolverStatistics};
+
 use fugue::ir::{
     Address,
     il::pcode::{Operand, PCodeOp}
@@ -22,27 +27,23 @@ use fugue::ir::{
 struct Variables {
     name: String,       // The name of the variable
     version: u64,       // For each write operation, update the version number
-    sym_expr: Vec<SymExpr>,    // The symbolic value hold by the variable
 }
 
 impl Variables {
-    pub fn new(operand: &Operand, sym_expr: SymExpr) -> Self {
+    pub fn new(operand: &Operand) -> Self {
         // Either update the variable in the var_list or insert a new one
         if let Operand::Constant { value: _, size: _ } = *operand {
             panic!("Constant operand ({:#?}) should not be instered to variable list", operand);
         }
-        let init_sym_expr_vec = vec![sym_expr];
         Self {
             name: Variables::gen_variable_name(operand).unwrap(),
             version: 0,
-            sym_expr: init_sym_expr_vec,
         }
     }
 
-    pub fn update(&mut self, sym_expr: SymExpr) {
+    // The symbolic value itself is kept in the ByteStore of the solver
+    pub fn update(&mut self) {
         self.version += 1;
-        self.sym_expr.push(sym_expr);
-        // self.sym_expr = sym_expr;
     }
 
     // When the variable is rewritten, update its version number and name
@@ -61,8 +62,8 @@ impl Variables {
                 Some(format!("{}", value))
             },
             Operand::Register { name, offset: _, size: _ } => {
-                // Use its name as key for registers
-                // TODO: Is offset needed to be added to the name?
+                // Use its name as key for registers, values are stored by offset
+                // so sub-registers with other names still overlap
                 Some(name.to_string())
             },
             Operand::Variable { space, offset, size: _ } => {
@@ -75,20 +76,21 @@ impl Variables {
             }
         }
     }
-
-    pub fn get_sym_expr(&self) -> &SymExpr {
-        &self.sym_expr.last().unwrap()
-    }
 }
-type StateValueType = u8;
 
 #[derive(Clone)]
 pub struct ConstraintSolver <O: Order> {
     // pm : PathManager,
     default_variables : HashMap<String, u128>,      // <Name of the default variable>: <value of the default variable>
     var_list: HashMap<String, Variables>,           // To keep track of regisiters and variables
-    var_to_solve: HashMap<String, (SymExpr, Address)>,    // The variable to be solved, added when load happens
-                                                                        //<Name of the variable>:(Symbex::variable, Address of the regisiter)
+    storage: ByteStore,                             // The bytes of regisiters, variables and memory written so far
+    mmio_ranges: Vec<(Address, Address)>,          // Loads from these ranges are solved, all loads if empty
+    path_constraints: Vec<SymExpr>,                // Must hold for a solution to follow the emulated path
+    var_to_solve: BTreeMap<Address, SymExpr>,       // The variable to be solved for each register, added when load happens
+                                                    // as wide as the widest read of the register, solved by address
+    config: SolverConfig,                           // The backend queries are sent to and their limits
+    statistics: SolverStatistics,                   // Of the queries since the last take_statistics
+    trace: Vec<String>,                             // The p-code added, kept for failure dumps only
     // exp_to_solve: Vec<muexe_symbex::SymExpr>,    // The expression to solve
     order: PhantomData<O>,
 }
@@ -100,6 +102,8 @@ impl<O: Order> fmt::Debug for ConstraintSolver<O> {
         f.debug_struct("ConstraintSolver")
         //  .field("pm", &self.pm)
          .field("builder", &String::from("TODO"))
+         .field("config", &self.config)
+         .field("statistics", &self.statistics)
          .finish()
     }
 }
@@ -108,11 +112,21 @@ impl <O> ConstraintSolver <O>
 where O: Order
 {
     pub fn new() -> Self {
+        Self::with_config(SolverConfig::default())
+    }
+
+    pub fn with_config(config: SolverConfig) -> Self {
         Self{
             // pm: PathManager::new(),
             default_variables: HashMap::new(),
             var_list: HashMap::new(),
-            var_to_solve: HashMap::new(),
+            storage: ByteStore::new(storage::is_big_endian::<O>()),
+            mmio_ranges: Vec::new(),
+            path_constraints: Vec::new(),
+            var_to_solve: BTreeMap::new(),
+            config,
+            statistics: SolverStatistics::default(),
+            trace: Vec::new(),
             order: PhantomData,
             // exp_to_solve: Vec::new(),
         }
@@ -127,6 +141,10 @@ where O: Order
         self.default_variables = vars.clone();
     }
 
+    pub fn set_mmio_ranges(&mut self, ranges: &[(Address, Address)]) {
+        self.mmio_ranges = ranges.to_vec();
+    }
+
     fn var_list_insert(&mut self, operand: &Operand, expr: Option<SymExpr>) -> SymExpr{
         // Either update the variable in the var_list or insert a new one
         // return the latest value of the variable after insersion
@@ -135,7 +153,7 @@ where O: Order
         }
 
         let key = Variables::gen_variable_name(&operand).unwrap();
-        if self.var_list.contains_key(&key) {
+        let var = if self.var_list.contains_key(&key) {
             // If the variable exist,
             // then create a new symbel variable to represent its new state
             let var_in_list = self.var_list.get_mut(&key).unwrap();
@@ -143,107 +161,176 @@ where O: Order
 
             // If the value of the variable is specified, then use it
             let var_updated = expr.unwrap_or(
-                // let var_ecode = ;
                 // If the value of the variable is not specified, then create new one
                 SymExpr::ivar(IVar::new_named(&updated_name,
                     operand.size() as u32*8))
             );
-            // And update its value in var_list
-            var_in_list.update(var_updated.clone());
+            // And update its version in var_list
+            var_in_list.update();
             var_updated
 
         } else{
             // If the variable doesn't exist, create new variable and insert it directly to var_list
-            let key = Variables::gen_variable_name(operand).unwrap();
             // If the value of the variable is specified, then use it
             // If not, create a new variable
             let var = expr.unwrap_or(SymExpr::ivar(IVar::new_named(&key.clone(), operand.size() as u32*8)));
             // Add it to var_list
-            self.var_list.insert(key, Variables::new(operand, var.clone()));
+            self.var_list.insert(key, Variables::new(operand));
             var
-        }
+        };
+
+        // Registers and variables overlapping the operand see the new bytes
+        let (space, offset) = storage::location(operand).unwrap();
+        self.storage.write(&space, offset, var.clone());
+        var
     }
 
 
-    fn var_list_get(&mut self, state: &PCodeState<StateValueType, O>, operand: &Operand) -> &SymExpr {
-        let var_name =Variables::gen_variable_name(operand).unwrap();
+    fn var_list_get<M: OperandState>(&mut self, state: &M, operand: &Operand) -> SymExpr {
+        let var_name = Variables::gen_variable_name(operand).unwrap();
+        let (space, offset) = storage::location(operand).unwrap();
+        let size = operand.size();
 
-        // Search the variable list for it
-        if self.var_list.contains_key(&var_name) {
-            // If found in the variable list which has been previously written to
-            // Then return the symbolic expression of it
-            return self.var_list.get(&var_name).unwrap().get_sym_expr()
+        if self.storage.is_unwritten(&space, offset, size) {
+            if let Some(default_value) = self.default_variables.get(&var_name) {
+                // Check if it is in the default variable list
+                // Create a constant based on its default value and set it to the var
+                log::trace!("Variable({:?}) found in the default variable list", operand);
+                let var_value = SymExpr::val_sized(*default_value as u64, size * 8);
+                return self.var_list_insert(operand, Some(var_value));
+            }
         }
 
-        let default_value_some = self.default_variables.get(&var_name).clone();
-        if let Some(default_value)  = default_value_some {
-            // Check if it is in the default variable list
-            // Create a constant based on its default value and set it to the var
-            log::trace!("Variable({:?}) found in the default variable list", operand);
-            if let Operand::Constant { value: _, size: _ } = operand {
-                panic!("Operand {:?} is not a variable, but a constant", operand);
-            } else {
-                let operand_size_bits = operand.size() as u32 * 8;
-                let var_value = SymExpr::val_sized(*default_value as u64, operand_size_bits.try_into().unwrap());
-                self.var_list_insert(operand, Some(var_value));
-                return self.var_list.get(&var_name).unwrap().get_sym_expr();
-            }
-        }else {
-            // If not in the default variable list and not in the variable list and it's a regisiter
-            // Then get the concrete value from the regisiter
-            if let Operand::Register { name: _, offset: _, size } = operand {
-                let reg_symbex = match size {
-                    1 => {SymExpr::val(state.get_operand::<StateValueType>(operand).unwrap() as u8)}
-                    2 => {SymExpr::val(state.get_operand::<StateValueType>(operand).unwrap() as u16)}
-                    4 => {SymExpr::val(state.get_operand::<StateValueType>(operand).unwrap() as u32)}
-                    8 => {SymExpr::val(state.get_operand::<StateValueType>(operand).unwrap() as u64)}
-                    _ => {panic!("Unexpected size: {:?}", size);}
-                };
-                self.var_list_insert(operand, Some(reg_symbex));
-                return self.var_list.get(&var_name).unwrap().get_sym_expr();
+        // Bytes that have not been written take the concrete value in the state for
+        // registers and memory, temporaries that were never written are unconstrained
+        let big_endian = self.storage.big_endian();
+        self.storage.read_with(&space, offset, size, || {
+            let concrete = match operand {
+                Operand::Register { .. } if size <= 8 => state.value(operand),
+                Operand::Address { value, size: _ } if size <= 8 => {
+                    state.view_bytes(*value, size).map(|bytes| {
+                        let fold = |value: u64, byte: &u8| value << 8 | *byte as u64;
+                        if big_endian { bytes.iter().fold(0, fold) } else { bytes.iter().rev().fold(0, fold) }
+                    })
+                },
+                _ => None,
+            };
+            match concrete {
+                Some(value) => SymExpr::val_sized(value, size * 8),
+                None => {
+                    log::debug!("Variable({:?}), name({:?}) has no value, it is unconstrained", operand, var_name);
+                    SymExpr::ivar(IVar::new_named(&var_name, size as u32 * 8))
+                },
             }
+        })
+    }
+
+    fn is_mmio(&self, address: &Address) -> bool {
+        self.mmio_ranges.is_empty() || self.mmio_ranges.iter().any(|(min, max)| min <= address && address <= max)
+    }
 
-            panic!("Variable({:?}), name({:?}) not in the list, default_vars: {:?}", operand, var_name, self.default_variables);
+    // Loads and stores through a pointer computed from symbolic values are concretised to the
+    // address the emulated run accessed. The solution is constrained to keep that address, so
+    // it cannot make the access alias a different location than the one tracked.
+    fn concretise_pointer<M: OperandState>(&mut self, state: &M, pointer: &Operand, address: Address) {
+        let (space, offset) = match storage::location(pointer) {
+            Some(location) => location,
+            None => return,
+        };
+        if self.storage.is_unwritten(&space, offset, pointer.size()) {
+            // Not computed in the polling window, the pointer is concrete
+            return;
         }
+        let pointer_sym = self.symexpr_from_operand_read(state, pointer);
+        let address_sym = SymExpr::val_sized(u64::from(address), pointer_sym.bits() as usize);
+        self.path_constraints.push(SymExpr::eq(pointer_sym, address_sym));
+    }
+
+    // A register keeps its value while the polling window is evaluated, reads of the same
+    // register share one value to solve and narrower reads see a part of it. Every other
+    // read is a new one.
+    fn register_read(&mut self, memory: &Operand, address: Address) -> SymExpr {
+        let bits = memory.size() as u32 * 8;
+        match self.var_to_solve.get(&address).cloned() {
+            Some(widest) if widest.bits() as u32 >= bits => self.register_part(widest, bits),
+            narrower => {
+                let var_src = self.var_list_insert(memory, None);
+                if let Some(narrower) = narrower {
+                    // The reads so far see part of the wider value
+                    let part = self.register_part(var_src.clone(), narrower.bits() as u32);
+                    self.path_constraints.push(SymExpr::eq(narrower, part));
+                }
+                // Mark it as a target variable to be solved
+                self.var_to_solve.insert(address, var_src.clone());
+                var_src
+            },
+        }
+    }
 
+    // The bits bits at the address of a register value, the least significant ones on
+    // little-endian targets and the most significant ones on big-endian ones
+    fn register_part(&self, value: SymExpr, bits: u32) -> SymExpr {
+        let value_bits = value.bits() as u32;
+        if bits == value_bits {
+            value
+        } else if self.storage.big_endian() {
+            SymExpr::extract(value, value_bits - bits, value_bits)
+        } else {
+            SymExpr::extract(value, 0, bits)
+        }
+    }
 
+    // Size in bytes of the value solved for the register at address, that of its widest read
+    pub fn target_size(&self, address: &Address) -> Option<usize> {
+        self.var_to_solve.get(address).map(|var| var.bits() as usize / 8)
     }
 
     // Generate corresponding SymExpr for reading Operand operations
-    fn symexpr_from_operand_read(&mut self, state: &PCodeState<StateValueType, O>, operand: &Operand) -> SymExpr {
+    fn symexpr_from_operand_read<M: OperandState>(&mut self, state: &M, operand: &Operand) -> SymExpr {
         match operand {
             Operand::Constant { value, size } => {
                 // byte size to bit size
                 SymExpr::val_sized(*value, *size * 8)     // TODO: Check if we can use BitVec in the Operand here
             }
             _ => {
-                self.var_list_get(state, operand).clone()
+                self.var_list_get(state, operand)
             }
         }
     }
 
 
 
-    pub fn add_pcode(&mut self, instruction: PCodeOp, state: &PCodeState<StateValueType, O>){
+    // The statistics of the queries so far, counted from zero again afterwards
+    pub fn take_statistics(&mut self) -> SolverStatistics {
+        std::mem::take(&mut self.statistics)
+    }
+
+    pub fn add_pcode<M: OperandState>(&mut self, instruction: PCodeOp, state: &M){
+        if self.config.failure_dumps.is_some() {
+            let pc = state.program_counter().map(|pc| pc.to_string()).unwrap_or_default();
+            self.trace.push(format!("{}: {:?}", pc, instruction));
+        }
         match instruction.clone(){
             // Move
             PCodeOp::Load{source, destination, space: _} => {
                 // When loading a variable from target memory, create a new variable to solve
 
-                // The source will always be address
-                let source_address = state.get_address(&source).unwrap(); // Read the real address
-                let var_src = self.var_list_insert(&source, None);
+                // The source is the pointer, the value is read from the memory it points to
+                let source_address = state.pointer(&source).unwrap(); // Read the real address
+                self.concretise_pointer(state, &source, source_address);
+                let memory = Operand::Address { value: source_address, size: destination.size() };
 
                 log::trace!("source_address: {}", source_address);
 
-                // Mark it as a target variable to be solved
-                self.var_to_solve.insert(Variables::gen_variable_name(&source).unwrap(), (var_src.clone(), source_address));
-
-                // slice the source variable to match the destination size then insert
-                let dest = var_src.extract_low(destination.size() as u32 * 8);
+                let var_src = if self.is_mmio(&source_address) {
+                    self.register_read(&memory, source_address)
+                } else {
+                    // Locals, globals and structs see what was stored to them in the polling window
+                    self.var_list_get(state, &memory)
+                };
 
                 // Creat dest and load src into it
-                self.var_list_insert(&destination, Some(dest));
+                self.var_list_insert(&destination, Some(var_src));
 
                 log::trace!("Load: {:?} <- {:?}", destination, source);
             },
@@ -269,241 +356,80 @@ where O: Order
                // No effect for building the tree
             },
             PCodeOp::Store { source, destination, space: _ } => {
+                // The destination is the pointer, the value is written to the memory it points to
                 let src_sym = self.symexpr_from_operand_read(state, &source);
-                self.var_list_insert(&destination, Some(src_sym));
+                let destination_address = state.pointer(&destination).unwrap();
+                self.concretise_pointer(state, &destination, destination_address);
+                let memory = Operand::Address { value: destination_address, size: source.size() };
+                self.var_list_insert(&memory, Some(src_sym));
             },
             ////////////////////////////////////////////////
-            // Bitwise Operations
-            PCodeOp::IntAnd{result, operands} => {
-                // get the operands
-                let op1 = &operands[0];
-                let op2 = &operands[1];
-
-                let op1_sym = self.symexpr_from_operand_read(state, op1);
-                let op2_sym = self.symexpr_from_operand_read(state, op2);
-
-                let result_sym = SymExpr::and(op1_sym, op2_sym);
-                // insert result
-                self.var_list_insert(&result, Some(result_sym));
-
-            },
-            PCodeOp::BoolAnd { result, operands } => {
-                let op1_sym = self.symexpr_from_operand_read(state, &operands[0]);
-                let op2_sym = self.symexpr_from_operand_read(state, &operands[1]);
-                let result_sym = SymExpr::bool_and(op1_sym, op2_sym);
-
-                // insert result
-                self.var_list_insert(&result, Some(result_sym));
-
+            // Not modelled exactly, over-approximated by a fresh unconstrained variable
+            PCodeOp::FloatAdd { result, .. } | PCodeOp::FloatSub { result, .. }
+                | PCodeOp::FloatMul { result, .. } | PCodeOp::FloatDiv { result, .. }
+                | PCodeOp::FloatNeg { result, .. } | PCodeOp::FloatAbs { result, .. }
+                | PCodeOp::FloatSqrt { result, .. } | PCodeOp::FloatCeiling { result, .. }
+                | PCodeOp::FloatFloor { result, .. } | PCodeOp::FloatRound { result, .. }
+                | PCodeOp::FloatIsNaN { result, .. } | PCodeOp::FloatEq { result, .. }
+                | PCodeOp::FloatNotEq { result, .. } | PCodeOp::FloatLess { result, .. }
+                | PCodeOp::FloatLessEq { result, .. } | PCodeOp::FloatOfInt { result, .. }
+                | PCodeOp::FloatOfFloat { result, .. } | PCodeOp::FloatTruncate { result, .. } => {
+                log::debug!("Over-approximating {:?} with a fresh variable", instruction);
+                self.var_list_insert(&result, None);
             },
-            PCodeOp::IntOr { result, operands } => {
-                let op1_sym = self.symexpr_from_operand_read(state, &operands[0]);
-                let op2_sym = self.symexpr_from_operand_read(state, &operands[1]);
-
-                let result_sym = SymExpr::or(op1_sym, op2_sym);
-
-                // insert result
-                self.var_list_insert(&result, Some(result_sym));
-
+            // CALLOTHER: the effect of the user-defined operation is unknown
+            PCodeOp::Intrinsic { result: Some(result), .. } => {
+                log::debug!("Over-approximating {:?} with a fresh variable", instruction);
+                self.var_list_insert(&result, None);
             },
-            PCodeOp::BoolXor { result, operands } => {
-                let op1_sym = self.symexpr_from_operand_read(state, &operands[0]);
-                let op2_sym = self.symexpr_from_operand_read(state, &operands[1]);
-
-                let result_sym = SymExpr::bool_xor(op1_sym, op2_sym);
-
-                // insert result
-                self.var_list_insert(&result, Some(result_sym));
-            },
-            PCodeOp::IntXor { result, operands } => {
-                let op1_sym = self.symexpr_from_operand_read(state, &operands[0]);
-                let op2_sym = self.symexpr_from_operand_read(state, &operands[1]);
-
-                let result_sym = SymExpr::xor(op1_sym, op2_sym);
-
-                // insert result
-                self.var_list_insert(&result, Some(result_sym));
-            },
-            PCodeOp::IntLeftShift { result, operands } => {
-                let op1_sym = self.symexpr_from_operand_read(state, &operands[0]);
-                let op2_sym = self.symexpr_from_operand_read(state, &operands[1]);
-                let result_sym = SymExpr::shl(op1_sym, op2_sym);
-
-                // insert result
-                self.var_list_insert(&result, Some(result_sym));
-            },
-            PCodeOp::IntRightShift { result, operands } => {
-                let op1_sym = self.symexpr_from_operand_read(state, &operands[0]);
-                let op2_sym = self.symexpr_from_operand_read(state, &operands[1]);
-                let result_sym = SymExpr::shr(op1_sym, op2_sym);
-
-                // insert result
-                self.var_list_insert(&result, Some(result_sym));
+            PCodeOp::Intrinsic { result: None, .. } => (),
+            PCodeOp::Skip => (),
+            PCodeOp::IBranch { destination: _ } | PCodeOp::ICall { destination: _ } => {
+                // No effect for building the tree
             },
-            PCodeOp::IntSRightShift { result, operands } => {
-                let op1_sym = self.symexpr_from_operand_read(state, &operands[0]);
-                let op2_sym = self.symexpr_from_operand_read(state, &operands[1]);
 
-                let result_sym = SymExpr::signed_shr(op1_sym, op2_sym);
-
-                // insert result
-                self.var_list_insert(&result, Some(result_sym));
-            },
-            PCodeOp::IntNot { result, operand } => {
-                let op1_sym = self.symexpr_from_operand_read(state, &operand);
-                let result_sym = SymExpr::not(op1_sym);
-                // insert result
-                self.var_list_insert(&result, Some(result_sym));
-            },
-            ////////////////////////////////////////////////
-            // Change size
-            PCodeOp::IntZExt { result, operand } => {
-                let op_sym = self.symexpr_from_operand_read(state, &operand);
-                let result_sym = SymExpr::zero_extend(op_sym, result.size() as u32*8); // Byte count to bit count
-                self.var_list_insert(&result, Some(result_sym));
-            },
-            PCodeOp::IntSExt { result, operand } => {
-                let op_sym = self.symexpr_from_operand_read(state, &operand);
-                let result_sym = SymExpr::sign_extend(op_sym, result.size() as u32*8); // Byte count to bit count
-                self.var_list_insert(&result, Some(result_sym));
-            },
-            PCodeOp::Subpiece { result, operand, amount } => {
-                let op_sym = self.symexpr_from_operand_read(state, &operand);
-                // Parse the *amount* argument and extract bits in *operands* according to it
-                if let Operand::Constant { value, size: _ } = amount {
-                    // Fill up to the size of the output,
-                    // so get the smaller size between the amount and the result
-                    let bits_perserve = (operand.size() as u64 - value) * 8;    // Convert bytes to throw away to bits to perserve
-                    let bits_result = result.size() as u64 *8;
-                    let bits_smaller = std::cmp::min(bits_perserve, bits_result);
-                    // TODO: use LE/BE to determie which side to start counting
-                    let result_sym = SymExpr::extract(op_sym, 0, bits_smaller as u32);
+            operation => match operation_value(&operation, |operand| self.symexpr_from_operand_read(state, operand)) {
+                Some((result, result_sym)) => {
                     self.var_list_insert(&result, Some(result_sym));
-                } else {
-                    panic!("Should not happen: Subpiece: amount is not a constant {:?}", instruction);
-                }
-            },
-            ////////////////////////////////////////////////
-            // Logical Operation
-            PCodeOp::IntEq{result, operands} => {
-                let op1_sym = self.symexpr_from_operand_read(state, &operands[0]);
-                let op2_sym = self.symexpr_from_operand_read(state, &operands[1]);
-
-                let result_sym = SymExpr::eq(op1_sym, op2_sym);
-
-                // insert result
-                self.var_list_insert(&result, Some(result_sym));
-            },
-            PCodeOp::IntNotEq { result, operands } => {
-                let op1_sym = self.symexpr_from_operand_read(state, &operands[0]);
-                let op2_sym = self.symexpr_from_operand_read(state, &operands[1]);
-
-                let result_sym = SymExpr::ne(op1_sym, op2_sym);
-
-                // insert result
-                self.var_list_insert(&result, Some(result_sym));
-            },
-            PCodeOp::IntSLess { result, operands } => {
-                let op1_sym = self.symexpr_from_operand_read(state, &operands[0]);
-                let op2_sym = self.symexpr_from_operand_read(state, &operands[1]);
-
-                let result_sym = SymExpr::slt(op1_sym, op2_sym);
-
-                // insert result
-                self.var_list_insert(&result, Some(result_sym));
-
-            },
-            PCodeOp::IntLess { result, operands } => {
-                let op1_sym = self.symexpr_from_operand_read(state, &operands[0]);
-                let op2_sym = self.symexpr_from_operand_read(state, &operands[1]);
-
-                let result_sym = SymExpr::lt(op1_sym, op2_sym);
-
-                // insert result
-                self.var_list_insert(&result, Some(result_sym));
-
-            },
-            ////////////////////////////////////////////////
-            // Arithmetic
-            PCodeOp::IntSub { result, operands } => {
-                let op1_sym = self.symexpr_from_operand_read(state, &operands[0]);
-                let op2_sym = self.symexpr_from_operand_read(state, &operands[1]);
-
-                // works for both signed and unsigned
-                let result_sym = SymExpr::sub(op1_sym, op2_sym);
-
-                // insert result
-                self.var_list_insert(&result, Some(result_sym));
-
-            },
-            PCodeOp::IntAdd { result, operands } => {
-                let op1_sym = self.symexpr_from_operand_read(state, &operands[0]);
-                let op2_sym = self.symexpr_from_operand_read(state, &operands[1]);
-                // Works for both signed and unsigned
-                let result_sym = SymExpr::add(op1_sym, op2_sym);
-
-                // insert result
-                self.var_list_insert(&result, Some(result_sym));
-            },
-            PCodeOp::IntNeg { result, operand } => {
-                let op1_sym = self.symexpr_from_operand_read(state, &operand);
-                let result_sym = SymExpr::neg(op1_sym);
-
-                self.var_list_insert(&result, Some(result_sym));
-            },
-            PCodeOp::IntCarry { result, operands } =>{
-                let op1_sym = self.symexpr_from_operand_read(state, &operands[0]);
-                let op2_sym = self.symexpr_from_operand_read(state, &operands[1]);
-                // Works for both signed and unsigned
-                let result_sym = SymExpr::carry(op1_sym, op2_sym);
-
-                // insert result
-                self.var_list_insert(&result, Some(result_sym));
-            },
-            PCodeOp::IntSCarry { result, operands } =>{
-                let op1_sym = self.symexpr_from_operand_read(state, &operands[0]);
-                let op2_sym = self.symexpr_from_operand_read(state, &operands[1]);
-                // Works for both signed and unsigned
-                let result_sym = SymExpr::signed_carry(op1_sym, op2_sym);
-
-                // insert result
-                self.var_list_insert(&result, Some(result_sym));
+                },
+                None => {
+                    // Operations without an output
+                    let pc = state.program_counter().unwrap();
+                    log::warn!("PC {} Instruction({:?}) has no effect on the solver.", pc, instruction);
+                },
             },
-            PCodeOp::BoolOr { result, operands } => {
-                let op1_sym = self.symexpr_from_operand_read(state, &operands[0]);
-                let op2_sym = self.symexpr_from_operand_read(state, &operands[1]);
-                // Works for both signed and unsigned
-                let result_sym = SymExpr::or(op1_sym, op2_sym);
-                // insert result
-                self.var_list_insert(&result, Some(result_sym));
-            }
-            PCodeOp::Skip => (),
-
-            _ => {
-                let pc = state.program_counter_value().unwrap();
-                panic!("PC {} Instruction({:?}) not yet supported by solver.", pc, instruction);
-            }
         }
     }
 
 
-    pub fn solve(&mut self, state: &PCodeState<StateValueType, O>, operand: &Operand, expected_value: u64) -> Option<HashMap::<Address, Option<u64>>>{
+    // The current symbolic value of a branch condition, to be solved for later with
+    // solve_condition after more p-code has been added
+    pub fn condition<M: OperandState>(&mut self, state: &M, operand: &Operand) -> SymExpr {
+        self.symexpr_from_operand_read(state, operand)
+    }
+
+    pub fn solve<M: OperandState>(&mut self, state: &M, operand: &Operand, expected_value: u64) -> Option<HashMap::<Address, Option<u64>>>{
         // Solve an expression as specified by operand
         // operand: the operand to be solved
 
         // Get the symexpr of the operand
         let op_sym = self.symexpr_from_operand_read(state, operand);
+        self.solve_condition(&op_sym, expected_value)
+    }
 
+    pub fn solve_condition(&mut self, op_sym: &SymExpr, expected_value: u64) -> Option<HashMap::<Address, Option<u64>>>{
         let size_op_sym =op_sym.bits(); 
 
         // Generate the expected value
         let expected_sym = SymExpr::val_sized(expected_value, size_op_sym as usize);
 
         // Build AST for the final expression
-        let mut solver_context = SolverContext::new_independent();
+        let mut backend = self.config.backend.backend();
         
         // Add constraint that the expected value is equal to the operand
-        let constraint = op_sym.eq(expected_sym);
+        let constraint = op_sym.clone().eq(expected_sym);
+        let mut constraints = vec![constraint.clone()];
+        constraints.extend(self.path_constraints.iter().cloned());
         // let solve_result = op_sym.solve(&mut solver_context, &[constraint]);
 
         // Solve the vars in var_to_solve list
@@ -511,19 +437,27 @@ where O: Order
         // The solving results to be returned: a list of (address, value)
         let mut return_res = HashMap::<Address, Option<u64>>::new();
 
-        for (expr, addr) in self.var_to_solve.values(){
+        // The registers are solved one after another by address, each solution is added as a
+        // constraint for the next ones so that together they are a joint assignment and the
+        // same query gives the same assignment on every run
+        for (addr, expr) in self.var_to_solve.iter() {
             log::debug!("Solving target: {}", expr);
             log::debug!("Solving constraint: {}", constraint);
-            let solve_res = expr.solve(&mut solver_context, &[constraint.clone()]);
-            match solve_res{
-                Some(val) => {
-                    let val_u64 = val.to_u64();
+            let start = Instant::now();
+            let outcome = backend.solve(expr, &constraints, self.config.timeout);
+            self.statistics.record(outcome, start.elapsed());
+            match outcome {
+                SolverOutcome::Sat(val_u64) => {
                     log::debug!("Solver: solution found for address {}", addr);
-                    return_res.insert(*addr, val_u64);
+                    constraints.push(SymExpr::eq(expr.clone(), SymExpr::val_sized(val_u64, expr.bits() as usize)));
+                    return_res.insert(*addr, Some(val_u64));
                 },
-                None => {
+                _ => {
                     // solution not found for the variable
-                    log::warn!("Solver: No solution found for address {}", addr);
+                    match self.dump_failure(*addr, expr, &constraints, outcome) {
+                        Some(path) => log::warn!("Solver: No solution found for address {} ({:?}), query dumped to {}", addr, outcome, path.display()),
+                        None => log::warn!("Solver: No solution found for address {} ({:?})", addr, outcome),
+                    }
                     return_res.insert(*addr, None);
                 }
             }
@@ -535,4 +469,279 @@ where O: Order
             return Some(return_res);
         }
     }
+
+    // Write the p-code trace and the query for expr to the failure dump directory, if set.
+    // The query is a script for any SMT-LIB2 solver, the trace is in comments.
+    fn dump_failure(&self, addr: Address, expr: &SymExpr, constraints: &[SymExpr], outcome: SolverOutcome) -> Option<PathBuf> {
+        let directory = self.config.failure_dumps.as_ref()?;
+        let mut dump = String::new();
+        writeln!(dump, "; No value for the read of {} ({:?}) with {:?}", addr, outcome, self.config.backend).ok()?;
+        writeln!(dump, "; p-code trace of the solving session").ok()?;
+        for line in self.trace.iter() {
+            writeln!(dump, ";   {}", line).ok()?;
+        }
+        match smtlib::query(expr, constraints) {
+            Some(query) => {
+                dump.push_str(&query);
+                writeln!(dump, "(get-value ({}))", smtlib::TARGET).ok()?;
+            },
+            None => {
+                // Operations without a QF_BV equivalent, the constraints as built instead
+                writeln!(dump, "; The query has operations SMT-LIB2 cannot express, constraints:").ok()?;
+                for constraint in constraints.iter() {
+                    writeln!(dump, ";   {}", constraint).ok()?;
+                }
+            },
+        }
+
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_nanos());
+        let path = directory.join(format!("unsolved-{:x}-{}.smt2", u64::from(addr), nanos));
+        fs::create_dir_all(directory)
+            .and_then(|_| fs::write(&path, dump))
+            .map_err(|e| log::warn!("Solver: cannot write {}: {}", path.display(), e))
+            .ok()?;
+        Some(path)
+    }
+}
+
+// The output of an arithmetic, logical, bitwise or size changing operation and its value in
+// terms of the operands given by read, None for other operations
+fn operation_value(instruction: &PCodeOp, mut read: impl FnMut(&Operand) -> SymExpr) -> Option<(Operand, SymExpr)> {
+    let (result, result_sym) = match instruction {
+        ////////////////////////////////////////////////
+        // Bitwise Operations
+        PCodeOp::IntAnd { result, operands } => (result, SymExpr::and(read(&operands[0]), read(&operands[1]))),
+        PCodeOp::BoolAnd { result, operands } => (result, SymExpr::bool_and(read(&operands[0]), read(&operands[1]))),
+        PCodeOp::IntOr { result, operands } => (result, SymExpr::or(read(&operands[0]), read(&operands[1]))),
+        PCodeOp::BoolOr { result, operands } => (result, SymExpr::or(read(&operands[0]), read(&operands[1]))),
+        PCodeOp::IntXor { result, operands } => (result, SymExpr::xor(read(&operands[0]), read(&operands[1]))),
+        PCodeOp::BoolXor { result, operands } => (result, SymExpr::bool_xor(read(&operands[0]), read(&operands[1]))),
+        PCodeOp::IntLeftShift { result, operands } => (result, SymExpr::shl(read(&operands[0]), read(&operands[1]))),
+        PCodeOp::IntRightShift { result, operands } => (result, SymExpr::shr(read(&operands[0]), read(&operands[1]))),
+        PCodeOp::IntSRightShift { result, operands } => (result, SymExpr::signed_shr(read(&operands[0]), read(&operands[1]))),
+        PCodeOp::IntNot { result, operand } => (result, SymExpr::not(read(operand))),
+        PCodeOp::BoolNot { result, operand } => {
+            let op_sym = read(operand);
+            // Booleans are 0 or 1, flip the lowest bit
+            let one = SymExpr::val_sized(1, op_sym.bits() as usize);
+            (result, SymExpr::xor(op_sym, one))
+        },
+        PCodeOp::PopCount { result, operand } => {
+            let op_sym = read(operand);
+            // Sum of the bits of the operand, each extended to the size of the result
+            let bits_result = result.size() as u32 * 8;
+            let result_sym = (0..op_sym.bits() as u32)
+                .map(|bit| SymExpr::zero_extend(SymExpr::extract(op_sym.clone(), bit, bit + 1), bits_result))
+                .fold(SymExpr::val_sized(0, bits_result as usize), |sum, bit| SymExpr::add(sum, bit));
+            (result, result_sym)
+        },
+        ////////////////////////////////////////////////
+        // Change size
+        PCodeOp::IntZExt { result, operand } => (result, SymExpr::zero_extend(read(operand), result.size() as u32 * 8)), // Byte count to bit count
+        PCodeOp::IntSExt { result, operand } => (result, SymExpr::sign_extend(read(operand), result.size() as u32 * 8)), // Byte count to bit count
+        PCodeOp::Subpiece { result, operand, amount } => {
+            let op_sym = read(operand);
+            // Parse the *amount* argument and extract bits in *operands* according to it
+            if let Operand::Constant { value, size: _ } = amount {
+                // amount is the number of least significant bytes thrown away, which does not
+                // depend on the byte order of the target. Fill up to the size of the output,
+                // so get the smaller size between the bytes left and the result
+                let bits_lsb = *value as u32 * 8;
+                let bits_perserve = (operand.size() as u32 * 8).saturating_sub(bits_lsb);
+                let bits_result = result.size() as u32 * 8;
+                let bits_smaller = std::cmp::min(bits_perserve, bits_result);
+                let mut result_sym = SymExpr::extract(op_sym, bits_lsb, bits_lsb + bits_smaller);
+                if bits_smaller < bits_result {
+                    result_sym = SymExpr::zero_extend(result_sym, bits_result);
+                }
+                (result, result_sym)
+            } else {
+                panic!("Should not happen: Subpiece: amount is not a constant {:?}", instruction);
+            }
+        },
+        ////////////////////////////////////////////////
+        // Logical Operation
+        PCodeOp::IntEq { result, operands } => (result, SymExpr::eq(read(&operands[0]), read(&operands[1]))),
+        PCodeOp::IntNotEq { result, operands } => (result, SymExpr::ne(read(&operands[0]), read(&operands[1]))),
+        PCodeOp::IntLess { result, operands } => (result, SymExpr::lt(read(&operands[0]), read(&operands[1]))),
+        PCodeOp::IntSLess { result, operands } => (result, SymExpr::slt(read(&operands[0]), read(&operands[1]))),
+        PCodeOp::IntLessEq { result, operands } => {
+            let (op1_sym, op2_sym) = (read(&operands[0]), read(&operands[1]));
+            (result, SymExpr::or(SymExpr::lt(op1_sym.clone(), op2_sym.clone()), SymExpr::eq(op1_sym, op2_sym)))
+        },
+        PCodeOp::IntSLessEq { result, operands } => {
+            let (op1_sym, op2_sym) = (read(&operands[0]), read(&operands[1]));
+            (result, SymExpr::or(SymExpr::slt(op1_sym.clone(), op2_sym.clone()), SymExpr::eq(op1_sym, op2_sym)))
+        },
+        ////////////////////////////////////////////////
+        // Arithmetic, add, sub and mul work for both signed and unsigned
+        PCodeOp::IntAdd { result, operands } => (result, SymExpr::add(read(&operands[0]), read(&operands[1]))),
+        PCodeOp::IntSub { result, operands } => (result, SymExpr::sub(read(&operands[0]), read(&operands[1]))),
+        PCodeOp::IntMul { result, operands } => (result, SymExpr::mul(read(&operands[0]), read(&operands[1]))),
+        PCodeOp::IntDiv { result, operands } => (result, SymExpr::div(read(&operands[0]), read(&operands[1]))),
+        PCodeOp::IntSDiv { result, operands } => (result, SymExpr::signed_div(read(&operands[0]), read(&operands[1]))),
+        PCodeOp::IntRem { result, operands } => (result, SymExpr::rem(read(&operands[0]), read(&operands[1]))),
+        PCodeOp::IntSRem { result, operands } => (result, SymExpr::signed_rem(read(&operands[0]), read(&operands[1]))),
+        PCodeOp::IntNeg { result, operand } => (result, SymExpr::neg(read(operand))),
+        PCodeOp::IntCarry { result, operands } => (result, SymExpr::carry(read(&operands[0]), read(&operands[1]))),
+        PCodeOp::IntSCarry { result, operands } => (result, SymExpr::signed_carry(read(&operands[0]), read(&operands[1]))),
+        PCodeOp::IntSBorrow { result, operands } => (result, SymExpr::signed_borrow(read(&operands[0]), read(&operands[1]))),
+        _ => return None,
+    };
+    Some((result.clone(), result_sym))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use fugue::bytes::LE;
+    use crate::bypass::solver_backend::{evaluate, SolverKind};
+
+    fn constant(value: u64, size: usize) -> Operand {
+        Operand::Constant { value, size }
+    }
+
+    // Value of the output of instruction, with constant operands
+    fn output_value(instruction: PCodeOp) -> Result<u64, String> {
+        let (_, result_sym) = operation_value(&instruction, |operand| match operand {
+            Operand::Constant { value, size } => SymExpr::val_sized(*value, *size * 8),
+            _ => panic!("Operand {:?} is not a constant", operand),
+        }).ok_or_else(|| format!("{:?} has no output", instruction))?;
+        evaluate(&result_sym, &HashMap::new())
+            .map(|(value, _)| value)
+            .ok_or_else(|| format!("Cannot evaluate {} of {:?}", result_sym, instruction))
+    }
+
+    #[test]
+    fn arithmetic_operations() -> Result<(), String> {
+        let result = Operand::Address { value: Address::from(0x1000u64), size: 4 };
+        let byte = Operand::Address { value: Address::from(0x1000u64), size: 1 };
+        let minus_100 = (-100i32) as u32 as u64;
+        let cases = [
+            (PCodeOp::IntMul { result: result.clone(), operands: [constant(7, 4), constant(6, 4)] }, 42),
+            (PCodeOp::IntDiv { result: result.clone(), operands: [constant(100, 4), constant(7, 4)] }, 14),
+            (PCodeOp::IntSDiv { result: result.clone(), operands: [constant(minus_100, 4), constant(7, 4)] }, (-14i32) as u32 as u64),
+            (PCodeOp::IntRem { result: result.clone(), operands: [constant(100, 4), constant(7, 4)] }, 2),
+            (PCodeOp::IntSRem { result: result.clone(), operands: [constant(minus_100, 4), constant(7, 4)] }, (-2i32) as u32 as u64),
+            (PCodeOp::PopCount { result: byte.clone(), operand: constant(0xF0F1, 2) }, 9),
+            (PCodeOp::BoolNot { result: byte.clone(), operand: constant(1, 1) }, 0),
+            (PCodeOp::BoolNot { result: byte.clone(), operand: constant(0, 1) }, 1),
+        ];
+        for (instruction, expected) in cases.iter() {
+            let value = output_value(instruction.clone())?;
+            if value != *expected {
+                return Err(format!("{:?} gave {:#x} instead of {:#x}", instruction, value, expected));
+            }
+        }
+        if operation_value(&PCodeOp::Skip, |_| SymExpr::val_sized(0, 8)).is_some() {
+            return Err(String::from("Skip has an output"));
+        }
+        Ok(())
+    }
+
+    const STS: u64 = 0xffd00000;
+    const ERR: u64 = 0xffd00004;
+
+    // Exhaustive for max_bits bits of registers
+    fn native_solver(max_bits: u32) -> ConstraintSolver<LE> {
+        ConstraintSolver::with_config(SolverConfig { backend: SolverKind::Native { max_bits }, ..SolverConfig::default() })
+    }
+
+    fn register(solver: &mut ConstraintSolver<LE>, address: u64, size: usize) -> SymExpr {
+        let address = Address::from(address);
+        solver.register_read(&Operand::Address { value: address, size }, address)
+    }
+
+    fn bit_set(value: SymExpr, bit: u32) -> SymExpr {
+        let bits = value.bits() as usize;
+        SymExpr::ne(SymExpr::and(value, SymExpr::val_sized(1 << bit, bits)), SymExpr::val_sized(0, bits))
+    }
+
+    fn bit_clear(value: SymExpr, bit: u32) -> SymExpr {
+        let bits = value.bits() as usize;
+        SymExpr::eq(SymExpr::and(value, SymExpr::val_sized(1 << bit, bits)), SymExpr::val_sized(0, bits))
+    }
+
+    #[test]
+    fn registers_are_solved_jointly() -> Result<(), String> {
+        // if ((STS & READY) && !(ERR & FAULT)), STS read twice
+        let mut solver = native_solver(16);
+        let ready = bit_set(register(&mut solver, STS, 1), 0);
+        let no_fault = bit_clear(register(&mut solver, ERR, 1), 1);
+        let again = bit_set(register(&mut solver, STS, 1), 0);
+        let condition = SymExpr::and(SymExpr::and(ready, no_fault), again);
+
+        let solution = solver.solve_condition(&condition, 1).ok_or("No solution")?;
+        match (solution.get(&Address::from(STS)), solution.get(&Address::from(ERR))) {
+            (Some(Some(sts)), Some(Some(err))) if sts & 1 != 0 && err & 2 == 0 && solution.len() == 2 => Ok(()),
+            _ => Err(format!("Solution {:?} does not satisfy both registers", solution)),
+        }
+    }
+
+    #[test]
+    fn reads_at_other_widths_share_the_register() -> Result<(), String> {
+        // Narrow read first, then the halfword: the byte is tied to the low byte of the halfword
+        let mut solver = native_solver(24);
+        let low = register(&mut solver, STS, 1);
+        let halfword = register(&mut solver, STS, 2);
+        let condition = SymExpr::and(SymExpr::eq(low, SymExpr::val_sized(1, 8)), bit_set(halfword, 8));
+        let solution = solver.solve_condition(&condition, 1).ok_or("No solution")?;
+        if solution.get(&Address::from(STS)) != Some(&Some(0x101)) || solver.target_size(&Address::from(STS)) != Some(2) {
+            return Err(format!("Solved {:?} for a byte read of 1 and bit 8 of the halfword", solution));
+        }
+
+        // The halfword first, then the byte: a part of the same value
+        let mut solver = native_solver(16);
+        let halfword = register(&mut solver, STS, 2);
+        let low = register(&mut solver, STS, 1);
+        if low.to_string() == halfword.to_string() || low.bits() != 8 {
+            return Err(format!("Byte read gave {}", low));
+        }
+        let condition = SymExpr::and(bit_set(low, 0), bit_set(halfword, 8));
+        match solver.solve_condition(&condition, 1).and_then(|solution| solution.get(&Address::from(STS)).copied().flatten()) {
+            Some(sts) if sts & 0x101 == 0x101 => Ok(()),
+            solution => Err(format!("Solved {:?} for bits 0 and 8", solution)),
+        }
+    }
+
+    #[test]
+    fn unsolved_reads_are_dumped() -> Result<(), String> {
+        let directory = std::env::temp_dir().join(format!("bypass-dumps-{}", std::process::id()));
+        let mut solver = ConstraintSolver::<LE>::with_config(SolverConfig {
+            backend: SolverKind::Native { max_bits: 16 },
+            failure_dumps: Some(directory.clone()),
+            ..SolverConfig::default()
+        });
+        let sts = register(&mut solver, STS, 1);
+        let condition = SymExpr::and(bit_set(sts.clone(), 0), bit_clear(sts, 0));
+        let solution = solver.solve_condition(&condition, 1);
+
+        let dumps: Vec<(String, String)> = fs::read_dir(&directory)
+            .map_err(|e| e.to_string())?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| (entry.file_name().to_string_lossy().into_owned(), fs::read_to_string(entry.path()).unwrap_or_default()))
+            .collect();
+        fs::remove_dir_all(&directory).ok();
+        if solution != Some(HashMap::from([(Address::from(STS), None)])) {
+            return Err(format!("Solved {:?} for contradicting bits", solution));
+        }
+        match dumps.as_slice() {
+            [(name, dump)] if name.starts_with("unsolved-ffd00000-") && dump.contains("(Unsat)") && dump.contains("(check-sat)") => Ok(()),
+            _ => Err(format!("Unexpected dumps {:?}", dumps)),
+        }
+    }
+
+    #[test]
+    fn stores_are_forwarded_to_loads() -> Result<(), String> {
+        // The status is copied to a word on the stack and its low byte tested from there
+        let mut solver = native_solver(16);
+        let sts = register(&mut solver, STS, 1);
+        let slot = Operand::Address { value: Address::from(0x3ff0u64), size: 4 };
+        solver.var_list_insert(&slot, Some(SymExpr::zero_extend(sts, 32)));
+        let copy = solver.storage.read_with(storage::RAM_SPACE, 0x3ff0, 1, || SymExpr::val_sized(0, 8));
+        match solver.solve_condition(&bit_set(copy, 7), 1).and_then(|solution| solution.get(&Address::from(STS)).copied().flatten()) {
+            Some(sts) if sts & 0x80 != 0 => Ok(()),
+            solution => Err(format!("Solved {:?} for bit 7 of the copy", solution)),
+        }
+    }
 }
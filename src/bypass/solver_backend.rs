@@ -0,0 +1,529 @@
+// The solvers ConstraintSolver can query for peripheral values
+//
+// Boolector is linked in with the "boolector" feature (on by default). Any solver reading
+// SMT-LIB2 from standard input can be run as a subprocess, with presets for Z3 and
+// Bitwuzla, and the native backend needs no solver at all: it tries every value of the
+// variables when they are narrow enough and otherwise values derived from the constants
+// of the constraints, which finds the masks and ready values polling loops compare with.
+//
+// let mut peripheral = DummyPeripheral::new();
+// peripheral.set_solver_backend(SolverKind::z3());
+// peripheral.set_solver_timeout(Some(Duration::from_secs(5)));
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+#[cfg(feature = "boolector")]
+use std::sync::Once;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use fugue::ir::il::ecode::{BinOp, BinRel, Cast, UnOp};
+use fugue_concolic::expr::SymExpr;
+use serde::{Serialize, Deserialize};
+
+use super::smtlib;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SolverOutcome {
+    Sat(u64),
+    Unsat,
+    Unknown,    // The backend gave up or ran out of time
+}
+
+pub trait SolverBackend {
+    // A value of target for which every constraint holds. Backends stop at timeout if
+    // they can, Boolector cannot be interrupted.
+    fn solve(&mut self, target: &SymExpr, constraints: &[SymExpr], timeout: Option<Duration>) -> SolverOutcome;
+}
+
+pub type SolverFactory = Arc<dyn Fn() -> Box<dyn SolverBackend> + Send + Sync>;
+
+// A new backend is created for every query
+#[derive(Clone)]
+pub enum SolverKind {
+    #[cfg(feature = "boolector")]
+    Boolector,
+    // A solver process reading an SMT-LIB2 script from standard input
+    SmtLib2 { program: String, args: Vec<String> },
+    // Search without a solver, exhaustive for at most max_bits bits of variables
+    Native { max_bits: u32 },
+    Custom(SolverFactory),
+}
+
+// How ConstraintSolver queries its backend
+#[derive(Debug, Clone, Default)]
+pub struct SolverConfig {
+    pub backend: SolverKind,
+    pub timeout: Option<Duration>,          // Per query, no limit if None
+    pub failure_dumps: Option<PathBuf>,     // Directory for an SMT-LIB2 dump of every query that found no value
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SolverStatistics {
+    pub queries: u64,
+    pub sat: u64,
+    pub unsat: u64,
+    pub unknown: u64,
+    pub time: Duration,         // Spent in the backend
+    pub cache_hits: u64,        // Reads answered from the knowledge base without a query
+}
+
+impl SolverStatistics {
+    pub fn record(&mut self, outcome: SolverOutcome, time: Duration) {
+        self.queries += 1;
+        self.time += time;
+        match outcome {
+            SolverOutcome::Sat(_) => self.sat += 1,
+            SolverOutcome::Unsat => self.unsat += 1,
+            SolverOutcome::Unknown => self.unknown += 1,
+        }
+    }
+
+    pub fn merge(&mut self, other: &SolverStatistics) {
+        self.queries += other.queries;
+        self.sat += other.sat;
+        self.unsat += other.unsat;
+        self.unknown += other.unknown;
+        self.time += other.time;
+        self.cache_hits += other.cache_hits;
+    }
+}
+
+impl Default for SolverKind {
+    #[cfg(feature = "boolector")]
+    fn default() -> Self {
+        SolverKind::Boolector
+    }
+
+    #[cfg(not(feature = "boolector"))]
+    fn default() -> Self {
+        SolverKind::native()
+    }
+}
+
+impl fmt::Debug for SolverKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(feature = "boolector")]
+            SolverKind::Boolector => write!(f, "Boolector"),
+            SolverKind::SmtLib2 { program, args } => f.debug_struct("SmtLib2")
+                .field("program", program)
+                .field("args", args)
+                .finish(),
+            SolverKind::Native { max_bits } => f.debug_struct("Native").field("max_bits", max_bits).finish(),
+            SolverKind::Custom(_) => write!(f, "Custom(..)"),
+        }
+    }
+}
+
+impl SolverKind {
+    pub fn z3() -> Self {
+        SolverKind::SmtLib2 { program: String::from("z3"), args: vec![String::from("-in"), String::from("-smt2")] }
+    }
+
+    pub fn bitwuzla() -> Self {
+        SolverKind::SmtLib2 { program: String::from("bitwuzla"), args: vec![String::from("--lang"), String::from("smt2")] }
+    }
+
+    pub fn native() -> Self {
+        SolverKind::Native { max_bits: 16 }
+    }
+
+    pub fn custom<F>(factory: F) -> Self
+    where F: Fn() -> Box<dyn SolverBackend> + Send + Sync + 'static {
+        SolverKind::Custom(Arc::new(factory))
+    }
+
+    pub fn backend(&self) -> Box<dyn SolverBackend> {
+        match self {
+            #[cfg(feature = "boolector")]
+            SolverKind::Boolector => Box::new(BoolectorBackend::new()),
+            SolverKind::SmtLib2 { program, args } => Box::new(SmtLib2Backend { program: program.clone(), args: args.clone() }),
+            SolverKind::Native { max_bits } => Box::new(NativeBackend { max_bits: *max_bits }),
+            SolverKind::Custom(factory) => factory(),
+        }
+    }
+}
+
+#[cfg(feature = "boolector")]
+pub struct BoolectorBackend {
+    context: fugue_concolic_solver_boolector::SolverContext,
+}
+
+#[cfg(feature = "boolector")]
+impl BoolectorBackend {
+    pub fn new() -> Self {
+        Self {
+            context: fugue_concolic_solver_boolector::SolverContext::new_independent(),
+        }
+    }
+}
+
+#[cfg(feature = "boolector")]
+impl Default for BoolectorBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Boolector runs every query to the end, a timeout is only reported once
+#[cfg(feature = "boolector")]
+static BOOLECTOR_TIMEOUT_WARNING: Once = Once::new();
+
+#[cfg(feature = "boolector")]
+impl SolverBackend for BoolectorBackend {
+    // Boolector does not tell why it found no value, e.g. unsat or a value wider than 64
+    // bits, so no value is unknown rather than unsat
+    fn solve(&mut self, target: &SymExpr, constraints: &[SymExpr], timeout: Option<Duration>) -> SolverOutcome {
+        use fugue_concolic::backend::ValueSolver;
+        if let Some(timeout) = timeout {
+            BOOLECTOR_TIMEOUT_WARNING.call_once(|| {
+                log::warn!("Solver: Boolector cannot be interrupted, the timeout of {:?} is ignored, use SolverKind::z3() or SolverKind::native() to enforce it", timeout)
+            });
+        }
+        match target.solve(&mut self.context, constraints).and_then(|value| value.to_u64()) {
+            Some(value) => SolverOutcome::Sat(value),
+            None => SolverOutcome::Unknown,
+        }
+    }
+}
+
+pub struct SmtLib2Backend {
+    program: String,
+    args: Vec<String>,
+}
+
+// Interval at which a solver process is checked for having finished
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+impl SmtLib2Backend {
+    // The reply of the solver to script, None if it could not be run or was killed at timeout
+    fn run(&self, script: &str, timeout: Option<Duration>) -> Option<String> {
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|e| log::warn!("Solver: cannot run {}: {}", self.program, e))
+            .ok()?;
+        // A solver exiting before reading the whole script is not left behind
+        if let Err(e) = child.stdin.take()?.write_all(script.as_bytes()) {
+            log::warn!("Solver: cannot write the query to {}: {}", self.program, e);
+            child.kill().ok();
+            child.wait().ok();
+            return None;
+        }
+
+        if let Some(timeout) = timeout {
+            let deadline = Instant::now() + timeout;
+            while child.try_wait().ok()?.is_none() {
+                if Instant::now() >= deadline {
+                    log::debug!("Solver: {} killed after {:?}", self.program, timeout);
+                    child.kill().ok();
+                    child.wait().ok();
+                    return None;
+                }
+                thread::sleep(POLL_INTERVAL);
+            }
+        }
+        let output = child.wait_with_output().ok()?;
+        Some(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+}
+
+impl SolverBackend for SmtLib2Backend {
+    fn solve(&mut self, target: &SymExpr, constraints: &[SymExpr], timeout: Option<Duration>) -> SolverOutcome {
+        let mut script = match smtlib::query(target, constraints) {
+            Some(script) => script,
+            None => return SolverOutcome::Unknown,
+        };
+        script.push_str(&format!("(get-value ({}))\n(exit)\n", smtlib::TARGET));
+
+        let reply = match self.run(&script, timeout) {
+            Some(reply) => reply,
+            None => return SolverOutcome::Unknown,
+        };
+        match reply.lines().next().map(str::trim) {
+            Some("sat") => smtlib::parse_value(&reply).map_or(SolverOutcome::Unknown, SolverOutcome::Sat),
+            Some("unsat") => SolverOutcome::Unsat,
+            _ => SolverOutcome::Unknown,
+        }
+    }
+}
+
+// Upper bound on the assignments the native backend evaluates for one query
+const NATIVE_MAX_ASSIGNMENTS: u64 = 1 << 20;
+// Assignments evaluated between checks of the timeout
+const NATIVE_TIMEOUT_CHECK: u64 = 1 << 12;
+
+pub struct NativeBackend {
+    max_bits: u32,
+}
+
+impl NativeBackend {
+    // Values worth trying for a variable of bits bits wider than max_bits
+    fn candidates(bits: u32, constants: &BTreeSet<u64>) -> Vec<u64> {
+        let mask = mask(bits);
+        let mut values = BTreeSet::from([0, mask]);
+        for bit in 0..bits {
+            values.insert(1 << bit);
+            values.insert(mask & !(1 << bit));
+        }
+        for constant in constants.iter() {
+            for value in [*constant, !*constant, constant.wrapping_add(1), constant.wrapping_sub(1)] {
+                values.insert(value & mask);
+            }
+        }
+        values.into_iter().collect()
+    }
+}
+
+impl SolverBackend for NativeBackend {
+    // Unsat only if every assignment was tried and evaluated
+    fn solve(&mut self, target: &SymExpr, constraints: &[SymExpr], timeout: Option<Duration>) -> SolverOutcome {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let vars: Vec<(String, u32)> = smtlib::variables(std::iter::once(target).chain(constraints.iter())).into_iter().collect();
+        if vars.iter().any(|(_, bits)| *bits > 64) {
+            return SolverOutcome::Unknown;
+        }
+        let mut constants = BTreeSet::new();
+        for constraint in constraints.iter() {
+            collect_constants(constraint, &mut constants);
+        }
+        let exhaustive = vars.iter().map(|(_, bits)| *bits).sum::<u32>() <= self.max_bits;
+        let domains: Vec<Vec<u64>> = vars.iter()
+            .map(|(_, bits)| if exhaustive { (0..=mask(*bits)).collect() } else { Self::candidates(*bits, &constants) })
+            .collect();
+
+        // Every combination of the domains, the first variable varying fastest
+        let mut indices = vec![0usize; domains.len()];
+        let mut env: HashMap<String, u64> = HashMap::new();
+        let mut complete = exhaustive;
+        for count in 0..NATIVE_MAX_ASSIGNMENTS {
+            if count % NATIVE_TIMEOUT_CHECK == 0 && deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                log::debug!("Solver: native search timed out after {} assignments", count);
+                return SolverOutcome::Unknown;
+            }
+            for ((name, _), (domain, index)) in vars.iter().zip(domains.iter().zip(indices.iter())) {
+                env.insert(name.clone(), domain[*index]);
+            }
+            let mut holds = true;
+            for constraint in constraints.iter() {
+                match evaluate(constraint, &env) {
+                    Some((value, _)) if value != 0 => (),
+                    Some(_) => {
+                        holds = false;
+                        break;
+                    },
+                    None => {
+                        complete = false;
+                        holds = false;
+                        break;
+                    },
+                }
+            }
+            if holds {
+                return evaluate(target, &env).map_or(SolverOutcome::Unknown, |(value, _)| SolverOutcome::Sat(value));
+            }
+            let next = indices.iter_mut().zip(domains.iter()).position(|(index, domain)| {
+                *index += 1;
+                if *index == domain.len() {
+                    *index = 0;
+                    false
+                } else {
+                    true
+                }
+            });
+            if next.is_none() {
+                return if complete { SolverOutcome::Unsat } else { SolverOutcome::Unknown };
+            }
+        }
+        log::debug!("Solver: native search gave up after {} assignments", NATIVE_MAX_ASSIGNMENTS);
+        SolverOutcome::Unknown
+    }
+}
+
+fn mask(bits: u32) -> u64 {
+    if bits >= 64 { u64::MAX } else { (1u64 << bits) - 1 }
+}
+
+fn sign_extend(value: u64, bits: u32) -> i64 {
+    if bits == 0 || bits >= 64 {
+        value as i64
+    } else {
+        ((value << (64 - bits)) as i64) >> (64 - bits)
+    }
+}
+
+fn collect_constants(expr: &SymExpr, constants: &mut BTreeSet<u64>) {
+    match expr {
+        SymExpr::Val(bv) => {
+            if let Some(value) = bv.to_u64() {
+                constants.insert(value);
+            }
+        },
+        SymExpr::UnOp { expr, .. } | SymExpr::UnRel { expr, .. } | SymExpr::Cast { expr, .. } | SymExpr::Extract { expr, .. } => collect_constants(expr, constants),
+        SymExpr::BinOp { lexpr, rexpr, .. } | SymExpr::BinRel { lexpr, rexpr, .. } | SymExpr::Concat { lexpr, rexpr } => {
+            collect_constants(lexpr, constants);
+            collect_constants(rexpr, constants);
+        },
+        _ => (),
+    }
+}
+
+// (value, bits) of expr with the variables of env, None for operations over 64 bits wide
+// or without a bit-vector meaning
+pub fn evaluate(expr: &SymExpr, env: &HashMap<String, u64>) -> Option<(u64, u32)> {
+    let bits = expr.bits() as u32;
+    if bits > 64 {
+        return None;
+    }
+    let value = match expr {
+        SymExpr::Val(bv) => bv.to_u64()?,
+        SymExpr::IVar(_) => *env.get(&smtlib::symbol(expr))?,
+        SymExpr::UnOp { op, expr } => {
+            let (v, _) = evaluate(expr, env)?;
+            match op {
+                UnOp::NOT => !v,
+                UnOp::NEG => v.wrapping_neg(),
+                UnOp::POPCOUNT => v.count_ones() as u64,
+                _ => return None,
+            }
+        },
+        SymExpr::BinOp { op, lexpr, rexpr } => {
+            let (l, width) = evaluate(lexpr, env)?;
+            let (r, _) = evaluate(rexpr, env)?;
+            // Division by zero is all ones and the remainder the dividend, as in SMT-LIB
+            match op {
+                BinOp::AND => l & r,
+                BinOp::OR => l | r,
+                BinOp::XOR => l ^ r,
+                BinOp::ADD => l.wrapping_add(r),
+                BinOp::SUB => l.wrapping_sub(r),
+                BinOp::MUL => l.wrapping_mul(r),
+                BinOp::DIV => l.checked_div(r).unwrap_or(u64::MAX),
+                BinOp::REM => l.checked_rem(r).unwrap_or(l),
+                BinOp::SDIV => match sign_extend(r, width) {
+                    0 => if sign_extend(l, width) < 0 { 1 } else { u64::MAX },
+                    r => sign_extend(l, width).wrapping_div(r) as u64,
+                },
+                BinOp::SREM => match sign_extend(r, width) {
+                    0 => l,
+                    r => sign_extend(l, width).wrapping_rem(r) as u64,
+                },
+                BinOp::SHL => if r >= width as u64 { 0 } else { l << r },
+                BinOp::SHR => if r >= width as u64 { 0 } else { l >> r },
+                BinOp::SAR => (sign_extend(l, width) >> r.min(63)) as u64,
+            }
+        },
+        SymExpr::BinRel { op, lexpr, rexpr } => {
+            let (l, width) = evaluate(lexpr, env)?;
+            let (r, _) = evaluate(rexpr, env)?;
+            let (sl, sr) = (sign_extend(l, width), sign_extend(r, width));
+            let sign = |v: u64| (v >> (width - 1)) & 1;
+            (match op {
+                BinRel::EQ => l == r,
+                BinRel::NEQ => l != r,
+                BinRel::LT => l < r,
+                BinRel::LE => l <= r,
+                BinRel::SLT => sl < sr,
+                BinRel::SLE => sl <= sr,
+                BinRel::CARRY => (l as u128 + r as u128) > mask(width) as u128,
+                BinRel::SCARRY => sign(l) == sign(r) && sign(l) != sign(l.wrapping_add(r) & mask(width)),
+                BinRel::SBORROW => sign(l) != sign(r) && sign(l) != sign(l.wrapping_sub(r) & mask(width)),
+            }) as u64
+        },
+        SymExpr::Cast { expr: inner, cast } => {
+            let (v, from) = evaluate(inner, env)?;
+            match cast {
+                Cast::Bool => (v != 0) as u64,
+                Cast::Signed(_) => sign_extend(v, from) as u64,
+                Cast::Unsigned(_) | Cast::Low(_) => v,
+                Cast::High(_) => v >> (from - bits),
+                _ => return None,
+            }
+        },
+        SymExpr::Extract { expr, lsb, .. } => evaluate(expr, env)?.0 >> (*lsb as u32),
+        SymExpr::Concat { lexpr, rexpr } => {
+            let (high, _) = evaluate(lexpr, env)?;
+            let (low, low_bits) = evaluate(rexpr, env)?;
+            if low_bits >= 64 { low } else { (high << low_bits) | low }
+        },
+        _ => return None,
+    };
+    Some((value & mask(bits), bits))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use fugue_concolic::expr::IVar;
+
+    #[test]
+    fn native_polling_loops() -> Result<(), String> {
+        let mut backend = SolverKind::native().backend();
+
+        // V850 style: while (!(STS & 0x80)); with a byte wide status register, solved exhaustively
+        let sts = SymExpr::ivar(IVar::new_named("ffd00000-1", 8));
+        let ready = SymExpr::ne(SymExpr::and(sts.clone(), SymExpr::val_sized(0x80, 8)), SymExpr::val_sized(0, 8));
+        match backend.solve(&sts, &[ready.clone()], None) {
+            SolverOutcome::Sat(value) if value & 0x80 != 0 => (),
+            outcome => return Err(format!("Solved {:?} for the ready bit", outcome)),
+        }
+        let busy = SymExpr::eq(SymExpr::and(sts.clone(), SymExpr::val_sized(0x80, 8)), SymExpr::val_sized(0, 8));
+        if backend.solve(&sts, &[ready.clone(), busy], None) != SolverOutcome::Unsat {
+            return Err(String::from("Every byte value was tried, the constraints are unsat"));
+        }
+
+        // A 32 bit register compared with a ready value, found among the candidates
+        let status = SymExpr::ivar(IVar::new_named("ffd00004-1", 32));
+        let done = SymExpr::eq(SymExpr::and(status.clone(), SymExpr::val_sized(0xff00, 32)), SymExpr::val_sized(0x5a00, 32));
+        match backend.solve(&status, &[done.clone()], None) {
+            SolverOutcome::Sat(value) if value & 0xff00 == 0x5a00 => (),
+            outcome => return Err(format!("Solved {:?} for the ready value", outcome)),
+        }
+
+        // Not every value was tried, contradicting constraints are not known to be unsat
+        let not_done = SymExpr::ne(SymExpr::and(status.clone(), SymExpr::val_sized(0xff00, 32)), SymExpr::val_sized(0x5a00, 32));
+        if backend.solve(&status, &[done, not_done], None) != SolverOutcome::Unknown {
+            return Err(String::from("Unexpected outcome for contradicting word constraints"));
+        }
+        if backend.solve(&sts, &[ready], Some(Duration::ZERO)) != SolverOutcome::Unknown {
+            return Err(String::from("The search did not stop at the timeout"));
+        }
+
+        let mut statistics = SolverStatistics::default();
+        statistics.record(SolverOutcome::Sat(1), Duration::from_millis(2));
+        statistics.record(SolverOutcome::Unknown, Duration::from_millis(3));
+        if (statistics.queries, statistics.sat, statistics.unknown, statistics.time) != (2, 1, 1, Duration::from_millis(5)) {
+            return Err(format!("Unexpected statistics {:?}", statistics));
+        }
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn smtlib2_timeout() -> Result<(), String> {
+        // A solver that never answers is killed at the timeout
+        let sts = SymExpr::ivar(IVar::new_named("ffd00000-1", 8));
+        let ready = SymExpr::ne(SymExpr::and(sts.clone(), SymExpr::val_sized(0x80, 8)), SymExpr::val_sized(0, 8));
+        let mut backend = SolverKind::SmtLib2 { program: String::from("sleep"), args: vec![String::from("10")] }.backend();
+        let start = Instant::now();
+        let outcome = backend.solve(&sts, &[ready.clone()], Some(Duration::from_millis(100)));
+        if outcome != SolverOutcome::Unknown || start.elapsed() >= Duration::from_secs(5) {
+            return Err(format!("Solved {:?} after {:?}", outcome, start.elapsed()));
+        }
+
+        // A solver that cannot be run gives no answer either
+        let mut backend = SolverKind::SmtLib2 { program: String::from("/nonexistent/solver"), args: Vec::new() }.backend();
+        if backend.solve(&sts, &[ready], None) != SolverOutcome::Unknown {
+            return Err(String::from("Missing solver did not give Unknown"));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,53 @@
+// The parts of the emulator state the bypass reads and writes
+//
+// DummyPeripheral and the constraint solver only need the program counter, the values of
+// operands and the bytes of memory. Implemented for the p-code state of the emulator, the
+// hooks can also be driven by a plain memory without a lifted program.
+
+use fugue::bytes::Order;
+use fugue::ir::{Address, il::pcode::Operand};
+use metaemu::state::pcode::{Error as PCodeError, PCodeState};
+
+pub trait OperandState {
+    // The instruction being executed
+    fn program_counter(&self) -> Option<Address>;
+
+    // The address held by a pointer operand
+    fn pointer(&self, operand: &Operand) -> Option<Address>;
+
+    // The value of an operand of up to 8 bytes
+    fn value(&self, operand: &Operand) -> Option<u64>;
+
+    // Whether a branch condition holds
+    fn condition(&self, operand: &Operand) -> Option<bool>;
+
+    fn view_bytes(&self, address: Address, size: usize) -> Option<Vec<u8>>;
+
+    fn set_bytes(&mut self, address: Address, values: &[u8]) -> Result<(), PCodeError>;
+}
+
+impl<O: Order> OperandState for PCodeState<u8, O> {
+    fn program_counter(&self) -> Option<Address> {
+        self.program_counter_value().ok()
+    }
+
+    fn pointer(&self, operand: &Operand) -> Option<Address> {
+        self.get_address(operand).ok()
+    }
+
+    fn value(&self, operand: &Operand) -> Option<u64> {
+        self.get_operand::<u64>(operand).ok()
+    }
+
+    fn condition(&self, operand: &Operand) -> Option<bool> {
+        self.get_operand::<u8>(operand).ok().map(|value| value != 0)
+    }
+
+    fn view_bytes(&self, address: Address, size: usize) -> Option<Vec<u8>> {
+        self.view_values(address, size).ok().map(|values| values.to_vec())
+    }
+
+    fn set_bytes(&mut self, address: Address, values: &[u8]) -> Result<(), PCodeError> {
+        self.set_values(address, values)
+    }
+}
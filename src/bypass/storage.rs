@@ -0,0 +1,222 @@
+// Byte-precise storage of the symbolic values held by registers, temporaries and memory
+//
+// Every byte is stored separately, so overlapping partial accesses see the bytes written
+// last: writing a word register and reading its low half, or writing both bytes of a
+// C166 word register (RL0, RH0) and reading R0. A byte remembers the value it was split
+// from, and reading back a run of bytes of one value yields that value (or a slice of it)
+// instead of a concatenation of single bytes.
+//
+// Byte order follows the target. The least significant byte of a value at offset is
+// stored at offset on little-endian targets (V850, C166) and at offset + size - 1 on
+// big-endian ones (SuperH), in the register space as well as in memory.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use fugue::bytes::Order;
+use fugue::ir::il::pcode::Operand;
+use fugue_concolic::expr::SymExpr;
+
+pub const REGISTER_SPACE: &str = "register";
+pub const RAM_SPACE: &str = "ram";
+
+// The space and offset an operand is stored at, None for constants
+pub fn location(operand: &Operand) -> Option<(String, u64)> {
+    match operand {
+        Operand::Address { value, size: _ } => Some((String::from(RAM_SPACE), u64::from(*value))),
+        Operand::Register { name: _, offset, size: _ } => Some((String::from(REGISTER_SPACE), *offset as u64)),
+        Operand::Variable { space, offset, size: _ } => Some((format!("{:?}", space), *offset as u64)),
+        _ => None,
+    }
+}
+
+pub fn is_big_endian<O: Order>() -> bool {
+    let mut probe = [0u8; 2];
+    O::write_u16(&mut probe, 1);
+    probe[0] == 0
+}
+
+// Offsets of the bytes of a value of size bytes at offset, least significant byte first
+pub fn byte_offsets(offset: u64, size: usize, big_endian: bool) -> impl Iterator<Item = u64> {
+    (0..size as u64).map(move |i| if big_endian { offset + size as u64 - 1 - i } else { offset + i })
+}
+
+#[derive(Debug, Clone)]
+struct Byte {
+    value: Arc<SymExpr>,
+    index: u32,             // Byte of value, 0 is the least significant
+}
+
+// Consecutive bytes of one value, least significant first
+#[derive(Debug, Clone)]
+struct Segment {
+    value: Arc<SymExpr>,
+    index: u32,
+    len: u32,
+}
+
+impl Segment {
+    fn expr(&self) -> SymExpr {
+        if self.index == 0 && self.len * 8 == self.value.bits() as u32 {
+            (*self.value).clone()
+        } else {
+            SymExpr::extract((*self.value).clone(), self.index * 8, (self.index + self.len) * 8)
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ByteStore {
+    big_endian: bool,
+    bytes: HashMap<(String, u64), Byte>,
+}
+
+impl ByteStore {
+    pub fn new(big_endian: bool) -> Self {
+        Self {
+            big_endian,
+            bytes: HashMap::new(),
+        }
+    }
+
+    pub fn big_endian(&self) -> bool {
+        self.big_endian
+    }
+
+    // Store value in value.bits() / 8 bytes at offset of space
+    pub fn write(&mut self, space: &str, offset: u64, value: SymExpr) {
+        let size = (value.bits() as usize).div_ceil(8);
+        let value = Arc::new(value);
+        for (index, byte_offset) in byte_offsets(offset, size, self.big_endian).enumerate() {
+            self.bytes.insert((space.to_owned(), byte_offset), Byte { value: value.clone(), index: index as u32 });
+        }
+    }
+
+    // Whether no byte of the size bytes at offset has been written
+    pub fn is_unwritten(&self, space: &str, offset: u64, size: usize) -> bool {
+        byte_offsets(offset, size, self.big_endian).all(|o| !self.bytes.contains_key(&(space.to_owned(), o)))
+    }
+
+    // Read size bytes at offset of space. Bytes never written are taken from the value
+    // returned by unwritten, which is only called if there are any.
+    pub fn read_with<F>(&self, space: &str, offset: u64, size: usize, unwritten: F) -> SymExpr
+    where F: FnOnce() -> SymExpr {
+        let mut fallback: Option<Arc<SymExpr>> = None;
+        let mut unwritten = Some(unwritten);
+        let mut segments: Vec<Segment> = Vec::new();
+        for (i, byte_offset) in byte_offsets(offset, size, self.big_endian).enumerate() {
+            let byte = match self.bytes.get(&(space.to_owned(), byte_offset)) {
+                Some(byte) => byte.clone(),
+                None => {
+                    let value = fallback.get_or_insert_with(|| Arc::new((unwritten.take().unwrap())()));
+                    Byte { value: value.clone(), index: i as u32 }
+                },
+            };
+            match segments.last_mut() {
+                Some(last) if Arc::ptr_eq(&last.value, &byte.value) && last.index + last.len == byte.index => last.len += 1,
+                _ => segments.push(Segment { value: byte.value, index: byte.index, len: 1 }),
+            }
+        }
+        segments.iter().rev()
+            .map(Segment::expr)
+            .reduce(|high, low| SymExpr::concat(high, low))
+            .unwrap_or_else(|| SymExpr::val_sized(0, 0))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::Cell;
+    use fugue::bytes::{BE, LE};
+    use fugue_concolic::expr::IVar;
+    use crate::bypass::smtlib;
+    use crate::bypass::solver_backend::evaluate;
+
+    fn var(name: &str, bits: u32) -> SymExpr {
+        SymExpr::ivar(IVar::new_named(name, bits))
+    }
+
+    // Values of the variables by symbol, check fills bytes never written from "unwritten"
+    fn values(vars: &[(&str, u32, u64)]) -> HashMap<String, u64> {
+        vars.iter().map(|(name, bits, value)| (smtlib::symbol(&var(name, *bits)), *value)).collect()
+    }
+
+    // Value of the expression read_with returns for size bytes at offset
+    fn check(store: &ByteStore, offset: u64, size: usize, env: &HashMap<String, u64>, expected: u64) -> Result<SymExpr, String> {
+        let read = store.read_with(REGISTER_SPACE, offset, size, || var("unwritten", size as u32 * 8));
+        match evaluate(&read, env) {
+            Some((value, bits)) if value == expected && bits as usize == size * 8 => Ok(read),
+            found => Err(format!("Read of {} bytes at {:#x} gave {} = {:x?}, expected {:#x}", size, offset, read, found, expected)),
+        }
+    }
+
+    #[test]
+    fn v850_little_endian() -> Result<(), String> {
+        // r6 at register offset 0x18, the low halfword is at the same offset
+        let mut store = ByteStore::new(is_big_endian::<LE>());
+        let env = values(&[("r6", 32, 0x44332211), ("b", 8, 0xAA), ("unwritten", 32, 0xDDCCBBAA)]);
+        store.write(REGISTER_SPACE, 0x18, var("r6", 32));
+        let r6 = check(&store, 0x18, 4, &env, 0x44332211)?;
+        if r6.to_string() != var("r6", 32).to_string() {
+            return Err(format!("Reading all of r6 gave {}", r6));
+        }
+        check(&store, 0x18, 2, &env, 0x2211)?;
+        check(&store, 0x1a, 2, &env, 0x4433)?;
+
+        // Overwriting the low byte keeps the upper three
+        store.write(REGISTER_SPACE, 0x18, var("b", 8));
+        check(&store, 0x18, 4, &env, 0x443322AA)?;
+
+        // The upper half of r6 and two bytes never written, filled from their own bytes of
+        // the fallback value
+        check(&store, 0x1a, 4, &env, 0xDDCC4433)?;
+        check(&store, 0x1c, 4, &env, 0xDDCCBBAA)?;
+        Ok(())
+    }
+
+    #[test]
+    fn superh_big_endian() -> Result<(), String> {
+        // The least significant halfword of r0 is at offset 2
+        let mut store = ByteStore::new(is_big_endian::<BE>());
+        let env = values(&[("r0", 32, 0x44332211), ("h", 16, 0xBEEF), ("unwritten", 32, 0xDDCCBBAA)]);
+        store.write(REGISTER_SPACE, 0, var("r0", 32));
+        check(&store, 2, 2, &env, 0x2211)?;
+        check(&store, 0, 2, &env, 0x4433)?;
+
+        // A 16 bit store to the upper half
+        store.write(REGISTER_SPACE, 0, var("h", 16));
+        check(&store, 0, 4, &env, 0xBEEF2211)?;
+
+        // r0 followed by two bytes never written, the less significant ones on big-endian
+        check(&store, 2, 4, &env, 0x2211BBAA)?;
+        Ok(())
+    }
+
+    #[test]
+    fn c166_byte_registers() -> Result<(), String> {
+        // RL0 and RH0 are the low and high bytes of the word register R0
+        let mut store = ByteStore::new(is_big_endian::<LE>());
+        let env = values(&[("rl0", 8, 0x34), ("rh0", 8, 0x12)]);
+        store.write(REGISTER_SPACE, 0, var("rl0", 8));
+        store.write(REGISTER_SPACE, 1, var("rh0", 8));
+
+        // The fallback is only built when a byte was never written
+        let calls = Cell::new(0);
+        let r0 = store.read_with(REGISTER_SPACE, 0, 2, || {
+            calls.set(calls.get() + 1);
+            var("unwritten", 16)
+        });
+        if calls.get() != 0 || evaluate(&r0, &env) != Some((0x1234, 16)) {
+            return Err(format!("Unexpected R0 {}", r0));
+        }
+        store.read_with(REGISTER_SPACE, 1, 2, || {
+            calls.set(calls.get() + 1);
+            var("unwritten", 16)
+        });
+        if calls.get() != 1 {
+            return Err(format!("Fallback built {} times for a partially written read", calls.get()));
+        }
+        Ok(())
+    }
+}
@@ -15,8 +15,10 @@ use metaemu::hooks::types::{HookStepAction,HookAction, HookOutcome, Error as Hoo
 use metaemu::machine::StepState;
 use crate::backend;
 use crate::backend::compare_match_timer::FunName as CMTFunName;
+use crate::snapshot::PeripheralSnapshot;
 use std::convert::TryInto;
 use log::{info};
+use serde::{Serialize, Deserialize};
 use metaemu::state::pcode::Error as PCodeError;
 
 #[derive(Debug, Error)]
@@ -79,6 +81,28 @@ impl<S: AsState<PCodeState<u8, Endian>>> GeneralPurposeTimer<S> {
 	}
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GeneralPurposeTimerSnapshot {
+	backend_cmt6: backend::CompareMatchTimer,
+	interrupt: backend::Interrupt,
+}
+
+impl<S: AsState<PCodeState<u8, Endian>>> PeripheralSnapshot for GeneralPurposeTimer<S> {
+	type Snapshot = GeneralPurposeTimerSnapshot;
+
+	fn snapshot(&self) -> Self::Snapshot {
+		GeneralPurposeTimerSnapshot {
+			backend_cmt6: self.backend_cmt6.clone(),
+			interrupt: self.interrupt.clone(),
+		}
+	}
+
+	fn restore(&mut self, snapshot: &Self::Snapshot) {
+		self.backend_cmt6 = snapshot.backend_cmt6.clone();
+		self.interrupt = snapshot.interrupt.clone();
+	}
+}
+
 impl <S: 'static> HookConcrete for GeneralPurposeTimer<S>
 where
 	S: AsState<PCodeState<u8, Endian>> + StateOps
@@ -0,0 +1,35 @@
+use std::collections::VecDeque;
+
+use log::debug;
+
+use super::{CanBus, CanFrame, Error};
+
+// The bus of a snapshot cloned controller: it delivers the frames that were pending when
+// the controller was cloned and nothing else. Frames sent on it reach no other node.
+#[derive(Debug, Clone, Default)]
+pub struct DetachedBus {
+    pending: VecDeque<CanFrame>,
+}
+
+impl DetachedBus {
+    pub fn new<I: IntoIterator<Item = CanFrame>>(pending: I) -> Self {
+        Self {
+            pending: pending.into_iter().collect(),
+        }
+    }
+}
+
+impl CanBus for DetachedBus {
+    fn send(&mut self, frame: &CanFrame) -> Result<(), Error> {
+        debug!("CAN frame sent on a detached bus: {:?}", frame);
+        Ok(())
+    }
+
+    fn try_recv(&mut self, _now: u64) -> Result<Option<CanFrame>, Error> {
+        Ok(self.pending.pop_front())
+    }
+
+    fn fork(&self) -> Result<Box<dyn CanBus>, Error> {
+        Ok(Box::new(self.clone()))
+    }
+}
@@ -0,0 +1,437 @@
+use std::collections::{HashMap, VecDeque};
+use std::path::Path;
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+use super::{CanBus, CanFrame, Error};
+use super::log::{self, LogEntry, LogReader, LogWriter};
+
+// When a replayed frame is delivered to the controller
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timing {
+    // One frame each virtual time step, as fast as the controller polls the bus
+    AsFastAsPolled,
+    // The recorded inter-frame gaps, steps_per_second virtual steps per captured second.
+    // The first frame is delivered at the first poll.
+    Recorded { steps_per_second: u64 },
+}
+
+// Entries of a capture shared by a FileSource and its forks. Entries are read from the
+// file when the source furthest ahead needs them and dropped once every source is past
+// them, so only the part of the capture between the slowest and the fastest source is
+// kept in memory.
+#[derive(Debug)]
+struct Capture {
+    reader: Box<dyn LogReader>,
+    entries: VecDeque<LogEntry>,
+    first: usize,                       // Capture index of the first entry kept
+    positions: HashMap<usize, usize>,   // Next capture index of every source
+    next_source: usize,
+}
+
+impl Capture {
+    // Entry at capture index, None past the end of the capture
+    fn get(&mut self, index: usize) -> Result<Option<&LogEntry>, Error> {
+        while self.first + self.entries.len() <= index {
+            match self.reader.read_entry()? {
+                Some(entry) => self.entries.push_back(entry),
+                None => return Ok(None),
+            }
+        }
+        Ok(self.entries.get(index - self.first))
+    }
+
+    fn add_source(&mut self, position: usize) -> usize {
+        let source = self.next_source;
+        self.next_source += 1;
+        self.positions.insert(source, position);
+        source
+    }
+
+    fn remove_source(&mut self, source: usize) {
+        self.positions.remove(&source);
+        self.trim();
+    }
+
+    fn advance(&mut self, source: usize, position: usize) {
+        self.positions.insert(source, position);
+        self.trim();
+    }
+
+    // Drop the entries every source is past
+    fn trim(&mut self) {
+        let slowest = self.positions.values().min().copied().unwrap_or(usize::MAX);
+        while self.first < slowest && self.entries.pop_front().is_some() {
+            self.first += 1;
+        }
+    }
+}
+
+// Restriction of the frames a FileSource replays
+#[derive(Debug, Clone)]
+enum Filter {
+    Channels(Vec<String>),
+    Ids(Vec<u32>),
+    IdMask(u32, u32),
+}
+
+impl Filter {
+    fn matches(&self, entry: &LogEntry) -> bool {
+        match self {
+            Filter::Channels(channels) => channels.iter().any(|c| *c == entry.channel),
+            Filter::Ids(ids) => ids.contains(&entry.frame.id),
+            Filter::IdMask(id, mask) => entry.frame.id & mask == id & mask,
+        }
+    }
+}
+
+// Replays a capture file into a controller. Frames sent by the controller are dropped,
+// or written to a capture with record_to for diffing against the original. The capture
+// is read while it is replayed.
+//
+// let source = FileSource::open("drive.blf")?
+//     .channels(&["1"])
+//     .ids(&[0x123, 0x7E8])
+//     .with_timing(Timing::Recorded { steps_per_second: 10_000_000 })
+//     .record_to(FileSink::create("replayed.blf", "1")?);
+#[derive(Debug)]
+pub struct FileSource {
+    capture: Arc<Mutex<Capture>>,
+    source: usize,                  // Key of this source in the capture
+    position: usize,                // Capture index of the next entry
+    filters: Vec<Filter>,
+    timing: Timing,
+    start: Option<(u64, u64)>,      // (virtual time, capture time) of the first delivery
+    last_delivery: Option<u64>,
+    sink: Option<FileSink>,         // Receives the frames sent by the controller
+}
+
+impl FileSource {
+    // Read a candump (.log), pcap (.pcap), Vector ASC (.asc) or BLF (.blf) capture
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Self::from_reader(log::open_reader(path)?)
+    }
+
+    pub fn from_reader(reader: Box<dyn LogReader>) -> Result<Self, Error> {
+        let mut capture = Capture {
+            reader,
+            entries: VecDeque::new(),
+            first: 0,
+            positions: HashMap::new(),
+            next_source: 0,
+        };
+        let source = capture.add_source(0);
+        Ok(Self {
+            capture: Arc::new(Mutex::new(capture)),
+            source,
+            position: 0,
+            filters: Vec::new(),
+            timing: Timing::AsFastAsPolled,
+            start: None,
+            last_delivery: None,
+            sink: None,
+        })
+    }
+
+    // Write the frames sent by the controller to sink
+    pub fn record_to(mut self, sink: FileSink) -> Self {
+        self.sink = Some(sink);
+        self
+    }
+
+    pub fn with_timing(mut self, timing: Timing) -> Self {
+        self.timing = timing;
+        self
+    }
+
+    // Only replay frames captured on one of channels, e.g. "can0" or "1" for Vector logs
+    pub fn channels<C: AsRef<str>>(mut self, channels: &[C]) -> Self {
+        self.filters.push(Filter::Channels(channels.iter().map(|c| c.as_ref().to_owned()).collect()));
+        self
+    }
+
+    // Only replay frames with one of ids
+    pub fn ids(mut self, ids: &[u32]) -> Self {
+        self.filters.push(Filter::Ids(ids.to_vec()));
+        self
+    }
+
+    // Only replay frames whose id matches id in the bits set in mask
+    pub fn id_mask(mut self, id: u32, mask: u32) -> Self {
+        self.filters.push(Filter::IdMask(id, mask));
+        self
+    }
+
+    // Number of frames left to replay, reads the rest of the capture
+    pub fn remaining(&self) -> Result<usize, Error> {
+        let mut capture = self.capture.lock();
+        let mut remaining = 0;
+        let mut index = self.position;
+        while let Some(entry) = capture.get(index)? {
+            if self.filters.iter().all(|filter| filter.matches(entry)) {
+                remaining += 1;
+            }
+            index += 1;
+        }
+        Ok(remaining)
+    }
+
+    // Capture time of the next frame passing the filters, skipping the others
+    fn peek(&mut self) -> Result<Option<u64>, Error> {
+        let mut capture = self.capture.lock();
+        loop {
+            match capture.get(self.position)? {
+                Some(entry) if self.filters.iter().all(|filter| filter.matches(entry)) => return Ok(Some(entry.time_us)),
+                Some(_) => self.position += 1,
+                None => return Ok(None),
+            }
+        }
+    }
+
+    // Whether the next frame is due at virtual time now
+    fn due(&mut self, now: u64) -> Result<bool, Error> {
+        let next = match self.peek()? {
+            Some(time_us) => time_us,
+            None => return Ok(false),
+        };
+        Ok(match self.timing {
+            Timing::AsFastAsPolled => self.last_delivery != Some(now),
+            Timing::Recorded { steps_per_second } => {
+                let (start, first) = *self.start.get_or_insert((now, next));
+                let offset = (next.saturating_sub(first) as u128 * steps_per_second as u128 / 1_000_000) as u64;
+                start + offset <= now
+            },
+        })
+    }
+}
+
+impl Drop for FileSource {
+    fn drop(&mut self) {
+        self.capture.lock().remove_source(self.source);
+    }
+}
+
+impl CanBus for FileSource {
+    fn send(&mut self, frame: &CanFrame) -> Result<(), Error> {
+        match self.sink.as_mut() {
+            Some(sink) => sink.send(frame),
+            None => Ok(()),
+        }
+    }
+
+    fn try_recv(&mut self, now: u64) -> Result<Option<CanFrame>, Error> {
+        if !self.due(now)? {
+            return Ok(None);
+        }
+        self.last_delivery = Some(now);
+        let mut capture = self.capture.lock();
+        let frame = capture.get(self.position)?.map(|entry| entry.frame.clone().at(now));
+        self.position += 1;
+        capture.advance(self.source, self.position);
+        Ok(frame)
+    }
+
+    // The fork replays the rest of the capture, the frames it sends are not recorded
+    fn fork(&self) -> Result<Box<dyn CanBus>, Error> {
+        let source = self.capture.lock().add_source(self.position);
+        Ok(Box::new(Self {
+            capture: self.capture.clone(),
+            source,
+            position: self.position,
+            filters: self.filters.clone(),
+            timing: self.timing,
+            start: self.start,
+            last_delivery: self.last_delivery,
+            sink: None,
+        }))
+    }
+}
+
+// Frames written between flushes of a FileSink by default
+const FLUSH_INTERVAL: usize = 1024;
+
+// Writes every frame sent by the controller to a capture file. Virtual time is
+// converted to capture time with steps_per_second. The file is flushed every
+// flush_interval frames and when the sink is dropped.
+#[derive(Debug)]
+pub struct FileSink {
+    writer: Box<dyn LogWriter>,
+    channel: String,
+    steps_per_second: u64,
+    flush_interval: usize,
+    unflushed: usize,       // Frames written since the last flush
+}
+
+impl FileSink {
+    // Write a candump (.log), pcap (.pcap), Vector ASC (.asc) or BLF (.blf) capture,
+    // channel is the interface name or, for Vector logs, the channel number logged
+    pub fn create<P: AsRef<Path>>(path: P, channel: &str) -> Result<Self, Error> {
+        Ok(Self::from_writer(log::create_writer(path)?, channel))
+    }
+
+    pub fn from_writer(writer: Box<dyn LogWriter>, channel: &str) -> Self {
+        Self {
+            writer,
+            channel: channel.to_owned(),
+            steps_per_second: 1_000_000,
+            flush_interval: FLUSH_INTERVAL,
+            unflushed: 0,
+        }
+    }
+
+    pub fn set_steps_per_second(&mut self, steps: u64) {
+        self.steps_per_second = steps.max(1);
+    }
+
+    // Flush after every frames frames, each flush of a BLF file writes a compressed
+    // container and rewrites the file header
+    pub fn set_flush_interval(&mut self, frames: usize) {
+        self.flush_interval = frames.max(1);
+    }
+
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.unflushed = 0;
+        self.writer.flush()
+    }
+}
+
+impl Drop for FileSink {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            ::log::warn!("Flushing the capture file of {} failed: {}", self.channel, e);
+        }
+    }
+}
+
+impl CanBus for FileSink {
+    fn send(&mut self, frame: &CanFrame) -> Result<(), Error> {
+        let time_us = (frame.timestamp as u128 * 1_000_000 / self.steps_per_second as u128) as u64;
+        self.writer.write_entry(&LogEntry {
+            channel: self.channel.clone(),
+            time_us,
+            frame: frame.clone(),
+        })?;
+        self.unflushed += 1;
+        if self.unflushed >= self.flush_interval {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn try_recv(&mut self, _now: u64) -> Result<Option<CanFrame>, Error> {
+        Ok(None)
+    }
+
+    // Two emulators writing to one file would interleave their frames
+    fn fork(&self) -> Result<Box<dyn CanBus>, Error> {
+        Err(Error::NotForkable(format!("capture file of {}", self.channel)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::log::candump::{CandumpReader, CandumpWriter};
+
+    fn source() -> Result<FileSource, String> {
+        let capture = "(0.000000) can0 100#01\n\
+                       (0.000000) can1 200#02\n\
+                       (0.000010) can0 101#03\n\
+                       (0.000100) can0 102#04\n";
+        FileSource::from_reader(Box::new(CandumpReader::new(capture.as_bytes()))).map_err(|e| e.to_string())
+    }
+
+    fn ids(source: &mut FileSource, now: u64) -> Result<Vec<u32>, String> {
+        let mut ids = Vec::new();
+        while let Some(frame) = source.try_recv(now).map_err(|e| e.to_string())? {
+            ids.push(frame.id);
+        }
+        Ok(ids)
+    }
+
+    #[test]
+    fn recorded_timing_and_filters() -> Result<(), String> {
+        // 10 steps per microsecond, the first frame is delivered at step 50
+        let mut source = source()?.channels(&["can0"]).with_timing(Timing::Recorded { steps_per_second: 10_000_000 });
+        let cases: [(u64, &[u32]); 4] = [(50, &[0x100]), (149, &[]), (150, &[0x101]), (1050, &[0x102])];
+        for (now, expected) in cases.iter() {
+            let received = ids(&mut source, *now)?;
+            if received != *expected {
+                return Err(format!("At {} received {:x?}, expected {:x?}", now, received, expected));
+            }
+        }
+
+        let mut source = source()?.id_mask(0x100, 0x700);
+        if ids(&mut source, 0)? != [0x100] || ids(&mut source, 1)? != [0x101] {
+            return Err(String::from("Expected one frame per step"));
+        }
+        Ok(())
+    }
+
+    // Capture that can not be read past its first frame
+    #[derive(Debug)]
+    struct Truncated(usize);
+
+    impl LogReader for Truncated {
+        fn read_entry(&mut self) -> Result<Option<LogEntry>, Error> {
+            self.0 += 1;
+            match self.0 {
+                1 => Ok(Some(LogEntry { channel: String::from("can0"), time_us: 0, frame: CanFrame::new(0x100, &[0x01]) })),
+                entry => Err(Error::Parse(entry, String::from("truncated"))),
+            }
+        }
+    }
+
+    #[test]
+    fn read_while_replaying() -> Result<(), String> {
+        let mut source = FileSource::from_reader(Box::new(Truncated(0))).map_err(|e| e.to_string())?;
+        let mut fork = source.fork().map_err(|e| e.to_string())?;
+        for bus in [&mut source as &mut dyn CanBus, fork.as_mut()] {
+            match bus.try_recv(0) {
+                Ok(Some(frame)) if frame.id == 0x100 => (),
+                received => return Err(format!("Received {:?} instead of the first frame", received)),
+            }
+        }
+        if source.try_recv(1).is_ok() {
+            return Err(String::from("Error after the first frame not reported"));
+        }
+        Ok(())
+    }
+
+    // A Write whose contents can be read after the writer is gone
+    #[derive(Debug, Clone, Default)]
+    struct SharedBuffer(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn replay_and_record() -> Result<(), String> {
+        let buffer = SharedBuffer::default();
+        let sink = FileSink::from_writer(Box::new(CandumpWriter::new(buffer.clone())), "can0");
+        let mut bus = source()?.channels(&["can0"]).record_to(sink);
+
+        if ids(&mut bus, 0)? != [0x100] {
+            return Err(String::from("Replay with a sink attached delivered the wrong frames"));
+        }
+        bus.send(&CanFrame::new(0x7E8, &[0x02, 0x10]).at(1_000_000)).map_err(|e| e.to_string())?;
+        let mut fork = bus.fork().map_err(|e| e.to_string())?;
+        fork.send(&CanFrame::new(0x7E9, &[0x03]).at(2_000_000)).map_err(|e| e.to_string())?;
+        drop(bus);
+
+        let written = String::from_utf8(buffer.0.lock().unwrap().clone()).map_err(|e| e.to_string())?;
+        if written.lines().count() != 1 || !written.contains("can0 7E8#0210") {
+            return Err(format!("Unexpected capture {:?}", written));
+        }
+        Ok(())
+    }
+}
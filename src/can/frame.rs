@@ -0,0 +1,104 @@
+use serde::{Serialize, Deserialize};
+
+pub const SFF_MASK: u32 = 0x7FF;
+pub const EFF_MASK: u32 = 0x1FFFFFFF;
+
+// Payload length of each DLC, DLCs above 8 are only meaningful for CAN FD frames
+const DLC_LENGTHS: [usize; 16] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 12, 16, 20, 24, 32, 48, 64];
+
+// Number of payload bytes of dlc
+pub fn dlc_to_len(dlc: u8, fd: bool) -> usize {
+    let len = DLC_LENGTHS[(dlc & 0xF) as usize];
+    if fd { len } else { len.min(8) }
+}
+
+// Smallest DLC whose payload holds len bytes
+pub fn len_to_dlc(len: usize) -> u8 {
+    DLC_LENGTHS.iter().position(|l| *l >= len).unwrap_or(15) as u8
+}
+
+// A frame on the bus, independent of the controller that sent or receives it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CanFrame {
+    pub id: u32,            // 11 bit standard or 29 bit extended identifier
+    pub extended: bool,
+    pub remote: bool,
+    pub dlc: u8,
+    pub data: Vec<u8>,
+    pub fd: bool,
+    pub brs: bool,
+    pub esi: bool,
+    pub timestamp: u64,     // Virtual time the frame was sent at
+}
+
+impl CanFrame {
+    // Identifiers above 0x7FF are treated as extended identifiers
+    pub fn new(id: u32, data: &[u8]) -> Self {
+        Self::with_format(id, id & !SFF_MASK != 0, data)
+    }
+
+    // Classical data frame with an explicit identifier format
+    pub fn with_format(id: u32, extended: bool, data: &[u8]) -> Self {
+        let data = &data[..data.len().min(8)];
+        Self {
+            id: id & if extended { EFF_MASK } else { SFF_MASK },
+            extended,
+            remote: false,
+            dlc: data.len() as u8,
+            data: data.to_vec(),
+            fd: false,
+            brs: false,
+            esi: false,
+            timestamp: 0,
+        }
+    }
+
+    // Classical remote frame requesting dlc bytes
+    pub fn new_remote(id: u32, extended: bool, dlc: u8) -> Self {
+        Self {
+            remote: true,
+            dlc: dlc.min(8),
+            ..Self::with_format(id, extended, &[])
+        }
+    }
+
+    // CAN FD frame, the payload is padded to the next valid length
+    pub fn new_fd(id: u32, extended: bool, brs: bool, data: &[u8]) -> Self {
+        let data = &data[..data.len().min(64)];
+        let dlc = len_to_dlc(data.len());
+        let mut payload = data.to_vec();
+        payload.resize(dlc_to_len(dlc, true), 0);
+        Self {
+            dlc,
+            data: payload,
+            fd: true,
+            brs,
+            ..Self::with_format(id, extended, &[])
+        }
+    }
+
+    pub fn at(mut self, timestamp: u64) -> Self {
+        self.timestamp = timestamp;
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn dlc_table() -> Result<(), String> {
+        let cases = [(8u8, false, 8usize), (9, false, 8), (9, true, 12), (13, true, 32), (15, true, 64)];
+        for (dlc, fd, len) in cases.iter() {
+            if dlc_to_len(*dlc, *fd) != *len {
+                return Err(format!("DLC {} (FD: {}) is not {} bytes", dlc, fd, len));
+            }
+        }
+        let frame = CanFrame::new_fd(0x10, true, true, &[0xAA; 17]);
+        if frame.dlc != 11 || frame.data.len() != 20 || !frame.extended {
+            return Err(format!("Unexpected FD frame {:?}", frame));
+        }
+        Ok(())
+    }
+}
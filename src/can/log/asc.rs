@@ -0,0 +1,265 @@
+// Vector ASCII log files (.asc) as written by CANalyzer and CANoe
+//
+// Classical frames:  "   1.234567 1  123x           Rx   d 2 01 02"
+// Remote frames:     "   1.234567 1  7DF            Rx   r"
+// CAN FD frames:     "   1.234567 CANFD   1 Rx    123  1 0 9 12 01 02 ... <duration> <length> <flags> ..."
+//
+// Channels are numbered from 1. Events other than CAN frames (error frames, statistics,
+// trigger blocks) are skipped.
+
+use std::fmt::Debug;
+use std::io::{BufRead, Write};
+
+use crate::can::{CanFrame, Error};
+use super::{LogEntry, LogReader, LogWriter};
+
+// Flags field of CAN FD lines
+const FD_EDL: u32 = 0x1000;
+const FD_BRS: u32 = 0x2000;
+const FD_ESI: u32 = 0x4000;
+const FD_REMOTE: u32 = 0x0010;
+
+const HEADER: &str = "date Thu Jan  1 12:00:00.000 am 1970\n\
+                      base hex  timestamps absolute\n\
+                      no internal events logged\n\
+                      // version 9.0.0\n\
+                      Begin Triggerblock Thu Jan  1 12:00:00.000 am 1970\n   \
+                      0.000000 Start of measurement\n";
+
+// Seconds with up to 6 decimals to microseconds, None if s is not a time
+fn parse_time(s: &str) -> Option<u64> {
+    let (secs, fraction) = s.split_once('.').unwrap_or((s, "0"));
+    if secs.is_empty() || !secs.bytes().all(|b| b.is_ascii_digit()) || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let usecs = format!("{:0<6}", fraction)[..6].parse::<u64>().ok()?;
+    Some(secs.parse::<u64>().ok()? * 1_000_000 + usecs)
+}
+
+#[derive(Debug)]
+pub struct AscReader<R> {
+    reader: R,
+    line: usize,
+    hex: bool,              // "base hex" or "base dec"
+    relative: bool,         // "timestamps relative", every time is relative to the previous event
+    last_time_us: u64,
+}
+
+impl<R: BufRead> AscReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            line: 0,
+            hex: true,
+            relative: false,
+            last_time_us: 0,
+        }
+    }
+
+    fn parse_number(&self, s: &str) -> Result<u32, String> {
+        let radix = if self.hex { 16 } else { 10 };
+        u32::from_str_radix(s, radix).map_err(|e| format!("{}: {}", s, e))
+    }
+
+    fn parse_data(&self, fields: &[&str], len: usize) -> Result<Vec<u8>, String> {
+        if fields.len() < len {
+            return Err(format!("{} data bytes expected, found {}", len, fields.len()));
+        }
+        fields[..len].iter().map(|b| self.parse_number(b).map(|b| b as u8)).collect()
+    }
+
+    // Identifier with an optional 'x' suffix marking extended identifiers
+    fn parse_id(&self, s: &str) -> Result<(u32, bool), String> {
+        match s.strip_suffix('x').or_else(|| s.strip_suffix('X')) {
+            Some(id) => Ok((self.parse_number(id)?, true)),
+            None => Ok((self.parse_number(s)?, false)),
+        }
+    }
+
+    // "<channel> <id> <dir> d <dlc> <data>" or "<channel> <id> <dir> r [<dlc>]"
+    fn parse_classic(&self, fields: &[&str]) -> Result<Option<(String, CanFrame)>, String> {
+        if fields.len() < 4 || !matches!(fields[2], "Rx" | "Tx") {
+            return Ok(None);
+        }
+        let (id, extended) = self.parse_id(fields[1])?;
+        let frame = match fields[3] {
+            "d" => {
+                let dlc = fields.get(4).ok_or("missing DLC")?;
+                let dlc = u8::from_str_radix(dlc, 16).map_err(|e| format!("{}: {}", dlc, e))?;
+                let data = self.parse_data(&fields[5..], (dlc as usize).min(8))?;
+                CanFrame::with_format(id, extended, &data)
+            },
+            "r" => {
+                let dlc = fields.get(4).and_then(|dlc| u8::from_str_radix(dlc, 16).ok()).unwrap_or(0);
+                CanFrame::new_remote(id, extended, dlc)
+            },
+            _ => return Ok(None),
+        };
+        Ok(Some((fields[0].to_owned(), frame)))
+    }
+
+    // "<channel> <dir> <id> [<name>] <brs> <esi> <dlc> <length> <data> <duration> <bit count> <flags> ..."
+    fn parse_fd(&self, fields: &[&str]) -> Result<Option<(String, CanFrame)>, String> {
+        if fields.len() < 3 || !matches!(fields[1], "Rx" | "Tx") {
+            return Ok(None);
+        }
+        let (id, extended) = self.parse_id(fields[2])?;
+        // The symbolic message name is optional
+        let mut i = 3;
+        if !matches!(fields.get(i), Some(&"0") | Some(&"1")) {
+            i += 1;
+        }
+        if fields.len() < i + 4 {
+            return Err(String::from("truncated CAN FD frame"));
+        }
+        let brs = fields[i] == "1";
+        let esi = fields[i + 1] == "1";
+        let dlc = u8::from_str_radix(fields[i + 2], 16).map_err(|e| format!("{}: {}", fields[i + 2], e))?;
+        let len = fields[i + 3].parse::<usize>().map_err(|e| format!("{}: {}", fields[i + 3], e))?;
+        let data = self.parse_data(&fields[i + 4..], len)?;
+        let flags = fields.get(i + 4 + len + 2).and_then(|flags| u32::from_str_radix(flags, 16).ok()).unwrap_or(FD_EDL);
+
+        let frame = if flags & FD_EDL != 0 {
+            let mut frame = CanFrame::new_fd(id, extended, brs, &data);
+            frame.esi = esi;
+            frame
+        } else if flags & FD_REMOTE != 0 {
+            CanFrame::new_remote(id, extended, dlc)
+        } else {
+            CanFrame::with_format(id, extended, &data)
+        };
+        Ok(Some((fields[0].to_owned(), frame)))
+    }
+
+    // Parse a line, None for header lines and events that are not CAN frames
+    fn parse_line(&mut self, line: &str) -> Result<Option<LogEntry>, String> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let time = match fields.first().and_then(|time| parse_time(time)) {
+            Some(time) => time,
+            None => {
+                if fields.first() == Some(&"base") {
+                    self.hex = fields.get(1) != Some(&"dec");
+                    self.relative = fields.get(3) == Some(&"relative");
+                }
+                return Ok(None);
+            },
+        };
+        let time_us = if self.relative { self.last_time_us + time } else { time };
+        self.last_time_us = time_us;
+
+        let frame = match fields.get(1) {
+            Some(&"CANFD") => self.parse_fd(&fields[2..])?,
+            Some(channel) if channel.bytes().all(|b| b.is_ascii_digit()) => self.parse_classic(&fields[1..])?,
+            _ => None,
+        };
+        Ok(frame.map(|(channel, frame)| LogEntry { channel, time_us, frame }))
+    }
+}
+
+impl<R: BufRead + Debug + Send> LogReader for AscReader<R> {
+    fn read_entry(&mut self) -> Result<Option<LogEntry>, Error> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            self.line += 1;
+            if let Some(entry) = self.parse_line(&line).map_err(|e| Error::Parse(self.line, e))? {
+                return Ok(Some(entry));
+            }
+        }
+    }
+}
+
+pub fn format_line(entry: &LogEntry) -> String {
+    let frame = &entry.frame;
+    let time = format!("{:>4}.{:06}", entry.time_us / 1_000_000, entry.time_us % 1_000_000);
+    let channel = entry.channel.parse::<u32>().unwrap_or(1);
+    let id = if frame.extended { format!("{:X}x", frame.id) } else { format!("{:X}", frame.id) };
+    let data: Vec<String> = frame.data.iter().map(|b| format!("{:02X}", b)).collect();
+    if frame.fd {
+        let flags = FD_EDL | if frame.brs { FD_BRS } else { 0 } | if frame.esi { FD_ESI } else { 0 };
+        format!("{} CANFD {:>3} Tx   {:>9} {} {} {:x} {:>2} {} {:>8} {:>4} {:>8X} {:>8} {:>8} {:>8} {:>8} {:>8}",
+            time, channel, id, frame.brs as u8, frame.esi as u8, frame.dlc, frame.data.len(), data.join(" "),
+            0, 0, flags, 0, 0, 0, 0, 0)
+    } else if frame.remote {
+        format!("{} {:<2} {:<15} Tx   r {:x}", time, channel, id, frame.dlc)
+    } else {
+        format!("{} {:<2} {:<15} Tx   d {:x} {}", time, channel, id, frame.dlc, data.join(" ")).trim_end().to_owned()
+    }
+}
+
+// Frames written to an ASC file are logged as transmitted by the emulated ECU
+#[derive(Debug)]
+pub struct AscWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> AscWriter<W> {
+    pub fn new(mut writer: W) -> Result<Self, Error> {
+        writer.write_all(HEADER.as_bytes())?;
+        Ok(Self { writer })
+    }
+}
+
+impl<W: Write + Debug + Send> LogWriter for AscWriter<W> {
+    fn write_entry(&mut self, entry: &LogEntry) -> Result<(), Error> {
+        writeln!(self.writer, "{}", format_line(entry))?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+// The trigger block is closed when the log is complete, readers accept logs without it
+impl<W: Write> Drop for AscWriter<W> {
+    fn drop(&mut self) {
+        let _ = writeln!(self.writer, "End TriggerBlock");
+        let _ = self.writer.flush();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_vector_lines() -> Result<(), String> {
+        let log = "date Mon Oct 19 10:00:00.000 am 2026\n\
+                   base hex  timestamps absolute\n\
+                   Begin Triggerblock Mon Oct 19 10:00:00.000 am 2026\n   \
+                   0.010000 1  123             Rx   d 8 01 02 03 04 05 06 07 08  Length = 0 BitCount = 0 ID = 291\n   \
+                   0.020000 2  12345678x       Rx   d 2 AA BB\n   \
+                   0.030000 1  7DF             Rx   r\n   \
+                   0.035000 1  ErrorFrame\n   \
+                   0.040000 CANFD   1 Rx        100                                   1 0 9 12 00 01 02 03 04 05 06 07 08 09 0A 0B   0    0     3000        0        0        0        0        0\n\
+                   End TriggerBlock\n";
+        let mut reader = AscReader::new(log.as_bytes());
+        let mut entries = Vec::new();
+        while let Some(entry) = reader.read_entry().map_err(|e| e.to_string())? {
+            entries.push(entry);
+        }
+        if entries.len() != 4 {
+            return Err(format!("Expected 4 frames, read {:?}", entries));
+        }
+        if entries[1].channel != "2" || !entries[1].frame.extended || entries[1].frame.id != 0x12345678 || entries[1].time_us != 20_000 {
+            return Err(format!("Unexpected extended frame {:?}", entries[1]));
+        }
+        if !entries[2].frame.remote || !entries[3].frame.fd || !entries[3].frame.brs || entries[3].frame.data.len() != 12 {
+            return Err(format!("Unexpected remote or FD frame {:?}", &entries[2..]));
+        }
+
+        // Written lines read back as the same entries
+        for entry in entries.iter() {
+            let read = reader.parse_line(&format_line(entry))?;
+            if read.as_ref() != Some(entry) {
+                return Err(format!("Wrote {:?}, read {:?}", entry, read));
+            }
+        }
+        Ok(())
+    }
+}
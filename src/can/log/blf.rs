@@ -0,0 +1,425 @@
+// Vector binary logging format (.blf)
+//
+// A file header is followed by LOG_CONTAINER objects holding the zlib compressed log
+// objects. Objects may span container boundaries and are followed by (object size % 4)
+// padding bytes. CAN_MESSAGE(2) and CAN_FD_MESSAGE(_64) objects are read, every other
+// object is skipped. Channels are numbered from 1.
+
+use std::fmt::Debug;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use byteorder::{ByteOrder, LittleEndian};
+use flate2::Compression;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+
+use crate::can::{CanFrame, Error};
+use crate::can::frame::{EFF_MASK, SFF_MASK};
+use super::{LogEntry, LogReader, LogWriter};
+
+const FILE_SIGNATURE: &[u8] = b"LOGG";
+const OBJ_SIGNATURE: &[u8] = b"LOBJ";
+const FILE_HEADER_SIZE: usize = 144;
+const OBJ_HEADER_BASE_SIZE: usize = 16;
+const OBJ_HEADER_V1_SIZE: usize = 16;
+const LOG_CONTAINER_HEADER_SIZE: usize = 16;
+const MAX_CONTAINER_SIZE: usize = 128 * 1024;
+
+// Object types
+const CAN_MESSAGE: u32 = 1;
+const LOG_CONTAINER: u32 = 10;
+const CAN_MESSAGE2: u32 = 86;
+const CAN_FD_MESSAGE: u32 = 100;
+const CAN_FD_MESSAGE_64: u32 = 101;
+
+// Compression of log containers
+const NO_COMPRESSION: u16 = 0;
+const ZLIB_DEFLATE: u16 = 2;
+
+// Object header flags
+const TIME_TEN_MICS: u32 = 0x1;
+const TIME_ONE_NANS: u32 = 0x2;
+
+const CAN_MSG_EXT: u32 = 0x80000000;
+const DIR_TX: u8 = 0x1;
+const REMOTE_FLAG: u8 = 0x80;
+// fd_flags of CAN_FD_MESSAGE
+const EDL: u8 = 0x1;
+const BRS: u8 = 0x2;
+const ESI: u8 = 0x4;
+// flags of CAN_FD_MESSAGE_64
+const FD64_REMOTE: u32 = 0x0010;
+const FD64_EDL: u32 = 0x1000;
+const FD64_BRS: u32 = 0x2000;
+const FD64_ESI: u32 = 0x4000;
+
+const APPLICATION_ID: u8 = 5;
+const BIN_LOG_VERSION: [u8; 4] = [2, 6, 8, 1];
+
+fn can_id(raw: u32) -> (u32, bool) {
+    let extended = raw & CAN_MSG_EXT != 0;
+    (raw & if extended { EFF_MASK } else { SFF_MASK }, extended)
+}
+
+fn check_size(object: usize, payload: &[u8], size: usize) -> Result<(), Error> {
+    if payload.len() < size {
+        return Err(Error::Parse(object, format!("object of {} bytes is too short", payload.len())));
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+pub struct BlfReader<R> {
+    reader: R,
+    data: Vec<u8>,          // Uncompressed contents of the containers read so far
+    pos: usize,             // Start of the next object in data
+    object: usize,
+}
+
+impl<R: Read> BlfReader<R> {
+    pub fn new(mut reader: R) -> Result<Self, Error> {
+        let mut header = [0u8; 8];
+        reader.read_exact(&mut header)?;
+        if &header[0..4] != FILE_SIGNATURE {
+            return Err(Error::Parse(0, String::from("not a BLF file")));
+        }
+        let size = LittleEndian::read_u32(&header[4..8]) as u64;
+        io::copy(&mut (&mut reader).take(size.saturating_sub(8)), &mut io::sink())?;
+        Ok(Self {
+            reader,
+            data: Vec::new(),
+            pos: 0,
+            object: 0,
+        })
+    }
+
+    // Append the contents of the next container to data, false at the end of the file
+    fn read_container(&mut self) -> Result<bool, Error> {
+        loop {
+            let mut header = [0u8; OBJ_HEADER_BASE_SIZE];
+            match self.reader.read_exact(&mut header) {
+                Ok(()) => (),
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+                Err(e) => return Err(e.into()),
+            }
+            if &header[0..4] != OBJ_SIGNATURE {
+                return Err(Error::Parse(self.object, String::from("missing object signature")));
+            }
+            let size = LittleEndian::read_u32(&header[8..12]) as usize;
+            let kind = LittleEndian::read_u32(&header[12..16]);
+            let mut body = vec![0u8; size.saturating_sub(OBJ_HEADER_BASE_SIZE)];
+            self.reader.read_exact(&mut body)?;
+            // The padding of the last object may be missing
+            io::copy(&mut (&mut self.reader).take((size % 4) as u64), &mut io::sink())?;
+
+            if kind != LOG_CONTAINER {
+                continue;
+            }
+            check_size(self.object, &body, LOG_CONTAINER_HEADER_SIZE)?;
+            let contents = &body[LOG_CONTAINER_HEADER_SIZE..];
+            self.data.drain(..self.pos);
+            self.pos = 0;
+            match LittleEndian::read_u16(&body[0..2]) {
+                NO_COMPRESSION => self.data.extend_from_slice(contents),
+                ZLIB_DEFLATE => {
+                    ZlibDecoder::new(contents).read_to_end(&mut self.data)?;
+                },
+                method => return Err(Error::Parse(self.object, format!("unknown compression method {}", method))),
+            }
+            return Ok(true);
+        }
+    }
+
+    // Next complete object: type, time in microseconds and payload
+    fn next_object(&mut self) -> Result<Option<(u32, u64, Vec<u8>)>, Error> {
+        loop {
+            // Skip the padding of the previous object
+            let available = &self.data[self.pos..];
+            let start = available.windows(4).take(8).position(|w| w == OBJ_SIGNATURE);
+            let start = match start {
+                Some(start) => self.pos + start,
+                None if available.len() < 12 => {
+                    if !self.read_container()? {
+                        return Ok(None);
+                    }
+                    continue;
+                },
+                None => return Err(Error::Parse(self.object, String::from("missing object signature"))),
+            };
+
+            let available = &self.data[start..];
+            if available.len() < OBJ_HEADER_BASE_SIZE + OBJ_HEADER_V1_SIZE
+                || available.len() < LittleEndian::read_u32(&available[8..12]) as usize {
+                if !self.read_container()? {
+                    return Ok(None);
+                }
+                continue;
+            }
+            let header_size = LittleEndian::read_u16(&available[4..6]) as usize;
+            let size = LittleEndian::read_u32(&available[8..12]) as usize;
+            if size < OBJ_HEADER_BASE_SIZE {
+                // The object would be read again and again
+                return Err(Error::Parse(self.object, format!("object of {} bytes is smaller than its header", size)));
+            }
+            let kind = LittleEndian::read_u32(&available[12..16]);
+            // Version 1 and 2 headers both start with the flags and have the timestamp at 24
+            let flags = LittleEndian::read_u32(&available[16..20]);
+            let timestamp = LittleEndian::read_u64(&available[24..32]);
+            if header_size > size {
+                return Err(Error::Parse(self.object, format!("object header of {} bytes exceeds the object", header_size)));
+            }
+            let payload = available[header_size..size].to_vec();
+            self.pos = start + size;
+            self.object += 1;
+
+            let time_us = if flags == TIME_TEN_MICS { timestamp * 10 } else { timestamp / 1000 };
+            return Ok(Some((kind, time_us, payload)));
+        }
+    }
+}
+
+impl<R: Read + Debug + Send> LogReader for BlfReader<R> {
+    fn read_entry(&mut self) -> Result<Option<LogEntry>, Error> {
+        while let Some((kind, time_us, p)) = self.next_object()? {
+            let (channel, frame) = match kind {
+                CAN_MESSAGE | CAN_MESSAGE2 => {
+                    check_size(self.object, &p, 16)?;
+                    let (id, extended) = can_id(LittleEndian::read_u32(&p[4..8]));
+                    let frame = if p[2] & REMOTE_FLAG != 0 {
+                        CanFrame::new_remote(id, extended, p[3])
+                    } else {
+                        CanFrame::with_format(id, extended, &p[8..8 + (p[3] as usize).min(8)])
+                    };
+                    (LittleEndian::read_u16(&p[0..2]), frame)
+                },
+                CAN_FD_MESSAGE => {
+                    check_size(self.object, &p, 84)?;
+                    let (id, extended) = can_id(LittleEndian::read_u32(&p[4..8]));
+                    let fd_flags = p[13];
+                    let data = &p[20..20 + (p[14] as usize).min(64)];
+                    let frame = if fd_flags & EDL != 0 {
+                        let mut frame = CanFrame::new_fd(id, extended, fd_flags & BRS != 0, data);
+                        frame.esi = fd_flags & ESI != 0;
+                        frame
+                    } else if p[2] & REMOTE_FLAG != 0 {
+                        CanFrame::new_remote(id, extended, p[3])
+                    } else {
+                        CanFrame::with_format(id, extended, data)
+                    };
+                    (LittleEndian::read_u16(&p[0..2]), frame)
+                },
+                CAN_FD_MESSAGE_64 => {
+                    check_size(self.object, &p, 40 + (p.get(2).copied().unwrap_or(0) as usize))?;
+                    let (id, extended) = can_id(LittleEndian::read_u32(&p[4..8]));
+                    let flags = LittleEndian::read_u32(&p[12..16]);
+                    let data = &p[40..40 + p[2] as usize];
+                    let frame = if flags & FD64_EDL != 0 {
+                        let mut frame = CanFrame::new_fd(id, extended, flags & FD64_BRS != 0, data);
+                        frame.esi = flags & FD64_ESI != 0;
+                        frame
+                    } else if flags & FD64_REMOTE != 0 {
+                        CanFrame::new_remote(id, extended, p[1])
+                    } else {
+                        CanFrame::with_format(id, extended, data)
+                    };
+                    (p[0] as u16, frame)
+                },
+                _ => continue,
+            };
+            return Ok(Some(LogEntry {
+                channel: channel.to_string(),
+                time_us,
+                frame,
+            }));
+        }
+        Ok(None)
+    }
+}
+
+// Frames written to a BLF file are logged as transmitted by the emulated ECU. The file
+// header holds the file size and object count, it is rewritten on every flush.
+#[derive(Debug)]
+pub struct BlfWriter<W: Write + Seek> {
+    writer: W,
+    pending: Vec<u8>,           // Objects not written to a container yet
+    objects: u32,
+    file_size: u64,
+    uncompressed_size: u64,
+}
+
+impl<W: Write + Seek> BlfWriter<W> {
+    pub fn new(writer: W) -> Result<Self, Error> {
+        let mut slf = Self {
+            writer,
+            pending: Vec::new(),
+            objects: 0,
+            file_size: FILE_HEADER_SIZE as u64,
+            uncompressed_size: FILE_HEADER_SIZE as u64,
+        };
+        slf.write_header()?;
+        Ok(slf)
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let mut header = [0u8; FILE_HEADER_SIZE];
+        header[0..4].copy_from_slice(FILE_SIGNATURE);
+        LittleEndian::write_u32(&mut header[4..8], FILE_HEADER_SIZE as u32);
+        header[8] = APPLICATION_ID;
+        header[12..16].copy_from_slice(&BIN_LOG_VERSION);
+        LittleEndian::write_u64(&mut header[16..24], self.file_size);
+        LittleEndian::write_u64(&mut header[24..32], self.uncompressed_size);
+        LittleEndian::write_u32(&mut header[32..36], self.objects);
+        // Start and stop time (SYSTEMTIME) are left zero, the emulator has no wall clock
+        self.writer.seek(SeekFrom::Start(0))?;
+        self.writer.write_all(&header)?;
+        self.writer.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+
+    fn push_object(&mut self, kind: u32, time_us: u64, payload: &[u8]) {
+        let size = OBJ_HEADER_BASE_SIZE + OBJ_HEADER_V1_SIZE + payload.len();
+        let mut header = [0u8; OBJ_HEADER_BASE_SIZE + OBJ_HEADER_V1_SIZE];
+        header[0..4].copy_from_slice(OBJ_SIGNATURE);
+        LittleEndian::write_u16(&mut header[4..6], (OBJ_HEADER_BASE_SIZE + OBJ_HEADER_V1_SIZE) as u16);
+        LittleEndian::write_u16(&mut header[6..8], 1);
+        LittleEndian::write_u32(&mut header[8..12], size as u32);
+        LittleEndian::write_u32(&mut header[12..16], kind);
+        LittleEndian::write_u32(&mut header[16..20], TIME_ONE_NANS);
+        LittleEndian::write_u64(&mut header[24..32], time_us * 1000);
+        self.pending.extend_from_slice(&header);
+        self.pending.extend_from_slice(payload);
+        self.pending.resize(self.pending.len() + size % 4, 0);
+        self.objects += 1;
+    }
+
+    fn write_container(&mut self) -> io::Result<()> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&self.pending)?;
+        let compressed = encoder.finish()?;
+
+        let size = OBJ_HEADER_BASE_SIZE + LOG_CONTAINER_HEADER_SIZE + compressed.len();
+        let mut header = [0u8; OBJ_HEADER_BASE_SIZE + LOG_CONTAINER_HEADER_SIZE];
+        header[0..4].copy_from_slice(OBJ_SIGNATURE);
+        LittleEndian::write_u16(&mut header[4..6], OBJ_HEADER_BASE_SIZE as u16);
+        LittleEndian::write_u16(&mut header[6..8], 1);
+        LittleEndian::write_u32(&mut header[8..12], size as u32);
+        LittleEndian::write_u32(&mut header[12..16], LOG_CONTAINER);
+        LittleEndian::write_u16(&mut header[16..18], ZLIB_DEFLATE);
+        LittleEndian::write_u32(&mut header[24..28], self.pending.len() as u32);
+        self.writer.write_all(&header)?;
+        self.writer.write_all(&compressed)?;
+        self.writer.write_all(&[0u8; 3][..size % 4])?;
+
+        self.file_size += (size + size % 4) as u64;
+        self.uncompressed_size += (OBJ_HEADER_BASE_SIZE + LOG_CONTAINER_HEADER_SIZE + self.pending.len()) as u64;
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek + Debug + Send> LogWriter for BlfWriter<W> {
+    fn write_entry(&mut self, entry: &LogEntry) -> Result<(), Error> {
+        let frame = &entry.frame;
+        let channel = entry.channel.parse::<u16>().unwrap_or(1);
+        let id = frame.id | if frame.extended { CAN_MSG_EXT } else { 0 };
+        if frame.fd {
+            let mut payload = [0u8; 84];
+            LittleEndian::write_u16(&mut payload[0..2], channel);
+            payload[2] = DIR_TX;
+            payload[3] = frame.dlc;
+            LittleEndian::write_u32(&mut payload[4..8], id);
+            payload[13] = EDL | if frame.brs { BRS } else { 0 } | if frame.esi { ESI } else { 0 };
+            payload[14] = frame.data.len() as u8;
+            payload[20..20 + frame.data.len()].copy_from_slice(&frame.data);
+            self.push_object(CAN_FD_MESSAGE, entry.time_us, &payload);
+        } else {
+            let mut payload = [0u8; 16];
+            LittleEndian::write_u16(&mut payload[0..2], channel);
+            payload[2] = DIR_TX | if frame.remote { REMOTE_FLAG } else { 0 };
+            payload[3] = frame.dlc;
+            LittleEndian::write_u32(&mut payload[4..8], id);
+            payload[8..8 + frame.data.len()].copy_from_slice(&frame.data);
+            self.push_object(CAN_MESSAGE, entry.time_us, &payload);
+        }
+        if self.pending.len() >= MAX_CONTAINER_SIZE {
+            self.write_container()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        if !self.pending.is_empty() {
+            self.write_container()?;
+        }
+        self.write_header()?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn round_trip() -> Result<(), String> {
+        let entries = vec![
+            LogEntry { channel: String::from("1"), time_us: 10, frame: CanFrame::with_format(0x123, false, &[1, 2, 3]) },
+            LogEntry { channel: String::from("2"), time_us: 20, frame: CanFrame::new_remote(0x10, true, 4) },
+            LogEntry { channel: String::from("1"), time_us: 30, frame: CanFrame::new_fd(0x7FF, false, true, &[0x55; 12]) },
+        ];
+
+        // Flushing after the first entry splits the objects over two containers
+        let mut writer = BlfWriter::new(Cursor::new(Vec::new())).map_err(|e| e.to_string())?;
+        for (i, entry) in entries.iter().enumerate() {
+            writer.write_entry(entry).map_err(|e| e.to_string())?;
+            if i == 0 {
+                writer.flush().map_err(|e| e.to_string())?;
+            }
+        }
+        writer.flush().map_err(|e| e.to_string())?;
+        let file = writer.writer.into_inner();
+        if LittleEndian::read_u64(&file[16..24]) != file.len() as u64 || LittleEndian::read_u32(&file[32..36]) != 3 {
+            return Err(String::from("File header does not describe the file"));
+        }
+
+        let mut reader = BlfReader::new(&file[..]).map_err(|e| e.to_string())?;
+        for entry in entries.iter() {
+            let read = reader.read_entry().map_err(|e| e.to_string())?;
+            if read.as_ref() != Some(entry) {
+                return Err(format!("Wrote {:?}, read {:?}", entry, read));
+            }
+        }
+        if reader.read_entry().map_err(|e| e.to_string())?.is_some() {
+            return Err(String::from("Read more entries than written"));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn empty_object_is_rejected() -> Result<(), String> {
+        let mut file = Vec::new();
+        file.extend_from_slice(FILE_SIGNATURE);
+        file.extend_from_slice(&8u32.to_le_bytes());
+
+        // Uncompressed container holding an object of size 0
+        let mut object = [0u8; OBJ_HEADER_BASE_SIZE + OBJ_HEADER_V1_SIZE];
+        object[0..4].copy_from_slice(OBJ_SIGNATURE);
+        LittleEndian::write_u16(&mut object[4..6], (OBJ_HEADER_BASE_SIZE + OBJ_HEADER_V1_SIZE) as u16);
+        LittleEndian::write_u32(&mut object[12..16], CAN_MESSAGE);
+        let size = OBJ_HEADER_BASE_SIZE + LOG_CONTAINER_HEADER_SIZE + object.len();
+        let mut header = [0u8; OBJ_HEADER_BASE_SIZE + LOG_CONTAINER_HEADER_SIZE];
+        header[0..4].copy_from_slice(OBJ_SIGNATURE);
+        LittleEndian::write_u32(&mut header[8..12], size as u32);
+        LittleEndian::write_u32(&mut header[12..16], LOG_CONTAINER);
+        file.extend_from_slice(&header);
+        file.extend_from_slice(&object);
+
+        let mut reader = BlfReader::new(&file[..]).map_err(|e| e.to_string())?;
+        match reader.read_entry() {
+            Err(Error::Parse(_, _)) => Ok(()),
+            read => Err(format!("Read {:?} from an object of size 0", read)),
+        }
+    }
+}
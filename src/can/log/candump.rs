@@ -0,0 +1,157 @@
+// candump -l log files: "(1436509052.249713) vcan0 123#DEADBEEF"
+//
+// Standard identifiers have 3 hex digits, extended identifiers 8. Remote frames are
+// written as "123#R" with an optional length, CAN FD frames as "123##<flags><data>".
+
+use std::fmt::Debug;
+use std::io::{BufRead, Write};
+
+use crate::can::{CanFrame, Error};
+use super::{LogEntry, LogReader, LogWriter};
+
+const CANFD_BRS: u8 = 0x01;
+const CANFD_ESI: u8 = 0x02;
+
+fn parse_hex(s: &str) -> Result<Vec<u8>, String> {
+    let digits: Vec<char> = s.chars().filter(|c| *c != '.').collect();
+    if digits.len() % 2 != 0 {
+        return Err(format!("odd number of hex digits in {}", s));
+    }
+    digits.chunks(2)
+        .map(|pair| u8::from_str_radix(&pair.iter().collect::<String>(), 16).map_err(|e| e.to_string()))
+        .collect()
+}
+
+// Parse the "<id>#<data>" part of a line
+pub fn parse_frame(s: &str) -> Result<CanFrame, String> {
+    let (id, rest) = s.split_once('#').ok_or_else(|| format!("missing '#' in {}", s))?;
+    let extended = id.len() > 3;
+    let id = u32::from_str_radix(id, 16).map_err(|e| e.to_string())?;
+
+    if let Some(fd) = rest.strip_prefix('#') {
+        let mut chars = fd.chars();
+        let flags = chars.next().and_then(|c| c.to_digit(16)).ok_or_else(|| format!("missing CAN FD flags in {}", s))? as u8;
+        let mut frame = CanFrame::new_fd(id, extended, flags & CANFD_BRS != 0, &parse_hex(chars.as_str())?);
+        frame.esi = flags & CANFD_ESI != 0;
+        return Ok(frame);
+    }
+    if let Some(len) = rest.strip_prefix('R').or_else(|| rest.strip_prefix('r')) {
+        let dlc = if len.is_empty() { 0 } else { len.parse::<u8>().map_err(|e| e.to_string())? };
+        return Ok(CanFrame::new_remote(id, extended, dlc));
+    }
+    Ok(CanFrame::with_format(id, extended, &parse_hex(rest)?))
+}
+
+pub fn format_frame(frame: &CanFrame) -> String {
+    let id = if frame.extended { format!("{:08X}", frame.id) } else { format!("{:03X}", frame.id) };
+    let data: String = frame.data.iter().map(|b| format!("{:02X}", b)).collect();
+    if frame.fd {
+        let flags = if frame.brs { CANFD_BRS } else { 0 } | if frame.esi { CANFD_ESI } else { 0 };
+        format!("{}##{:X}{}", id, flags, data)
+    } else if frame.remote {
+        if frame.dlc > 0 { format!("{}#R{}", id, frame.dlc) } else { format!("{}#R", id) }
+    } else {
+        format!("{}#{}", id, data)
+    }
+}
+
+// Parse a log line, None for empty lines
+pub fn parse_line(line: &str) -> Result<Option<LogEntry>, String> {
+    let mut fields = line.split_whitespace();
+    let time = match fields.next() {
+        Some(time) => time,
+        None => return Ok(None),
+    };
+    let time = time.strip_prefix('(').and_then(|t| t.strip_suffix(')')).ok_or_else(|| format!("malformed time {}", time))?;
+    let (secs, usecs) = time.split_once('.').unwrap_or((time, "0"));
+    let secs = secs.parse::<u64>().map_err(|e| e.to_string())?;
+    let usecs = format!("{:0<6}", usecs)[..6].parse::<u64>().map_err(|e| e.to_string())?;
+    let channel = fields.next().ok_or("missing interface")?.to_owned();
+    let frame = parse_frame(fields.next().ok_or("missing frame")?)?;
+    Ok(Some(LogEntry {
+        channel,
+        time_us: secs * 1_000_000 + usecs,
+        frame,
+    }))
+}
+
+pub fn format_line(entry: &LogEntry) -> String {
+    format!("({}.{:06}) {} {}", entry.time_us / 1_000_000, entry.time_us % 1_000_000, entry.channel, format_frame(&entry.frame))
+}
+
+#[derive(Debug)]
+pub struct CandumpReader<R> {
+    reader: R,
+    line: usize,
+}
+
+impl<R: BufRead> CandumpReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self { reader, line: 0 }
+    }
+}
+
+impl<R: BufRead + Debug + Send> LogReader for CandumpReader<R> {
+    fn read_entry(&mut self) -> Result<Option<LogEntry>, Error> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            self.line += 1;
+            if let Some(entry) = parse_line(&line).map_err(|e| Error::Parse(self.line, e))? {
+                return Ok(Some(entry));
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct CandumpWriter<W> {
+    writer: W,
+}
+
+impl<W: Write> CandumpWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+}
+
+impl<W: Write + Debug + Send> LogWriter for CandumpWriter<W> {
+    fn write_entry(&mut self, entry: &LogEntry) -> Result<(), Error> {
+        writeln!(self.writer, "{}", format_line(entry))?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() -> Result<(), String> {
+        let lines = [
+            "(1436509052.249713) vcan0 123#DEADBEEF",
+            "(1436509052.250000) vcan1 12345678#0102",
+            "(1436509052.250001) vcan0 7DF#R",
+            "(1436509052.250002) can0 0AB##10001020304050607",
+        ];
+        for line in lines.iter() {
+            let entry = parse_line(line)?.ok_or("empty entry")?;
+            if format_line(&entry) != *line {
+                return Err(format!("{} formatted as {}", line, format_line(&entry)));
+            }
+        }
+        let entry = parse_line(lines[1])?.unwrap();
+        if !entry.frame.extended || entry.frame.id != 0x12345678 || entry.time_us != 1436509052250000 {
+            return Err(format!("Unexpected entry {:?}", entry));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,77 @@
+// Reading and writing CAN capture files
+
+use std::fmt::Debug;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+
+use super::{CanFrame, Error};
+
+pub mod candump;
+pub mod pcap;
+pub mod asc;
+pub mod blf;
+
+// A captured frame, the channel is the interface name the frame was captured on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogEntry {
+    pub channel: String,
+    pub time_us: u64,       // Capture time in microseconds
+    pub frame: CanFrame,
+}
+
+pub trait LogReader: Debug + Send {
+    // Next entry of the capture, None at the end
+    fn read_entry(&mut self) -> Result<Option<LogEntry>, Error>;
+}
+
+pub trait LogWriter: Debug + Send {
+    fn write_entry(&mut self, entry: &LogEntry) -> Result<(), Error>;
+    fn flush(&mut self) -> Result<(), Error>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Candump,        // candump -l
+    Pcap,           // LINKTYPE_CAN_SOCKETCAN
+    Asc,            // Vector ASCII log
+    Blf,            // Vector binary log
+}
+
+impl LogFormat {
+    // Format of a capture file by its extension
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Self> {
+        match path.as_ref().extension()?.to_str()?.to_ascii_lowercase().as_str() {
+            "log" | "candump" => Some(LogFormat::Candump),
+            "pcap" => Some(LogFormat::Pcap),
+            "asc" => Some(LogFormat::Asc),
+            "blf" => Some(LogFormat::Blf),
+            _ => None,
+        }
+    }
+
+    fn for_path<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Self::from_path(&path).ok_or_else(|| Error::Parse(0, format!("unknown CAN log format of {}", path.as_ref().display())))
+    }
+}
+
+pub fn open_reader<P: AsRef<Path>>(path: P) -> Result<Box<dyn LogReader>, Error> {
+    let file = BufReader::new(File::open(&path)?);
+    Ok(match LogFormat::for_path(&path)? {
+        LogFormat::Candump => Box::new(candump::CandumpReader::new(file)),
+        LogFormat::Pcap => Box::new(pcap::PcapReader::new(file)?),
+        LogFormat::Asc => Box::new(asc::AscReader::new(file)),
+        LogFormat::Blf => Box::new(blf::BlfReader::new(file)?),
+    })
+}
+
+pub fn create_writer<P: AsRef<Path>>(path: P) -> Result<Box<dyn LogWriter>, Error> {
+    let format = LogFormat::for_path(&path)?;
+    let file = BufWriter::new(File::create(&path)?);
+    Ok(match format {
+        LogFormat::Candump => Box::new(candump::CandumpWriter::new(file)),
+        LogFormat::Pcap => Box::new(pcap::PcapWriter::new(file)?),
+        LogFormat::Asc => Box::new(asc::AscWriter::new(file)?),
+        LogFormat::Blf => Box::new(blf::BlfWriter::new(file)?),
+    })
+}
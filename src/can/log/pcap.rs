@@ -0,0 +1,214 @@
+// pcap files with LINKTYPE_CAN_SOCKETCAN (227), as written by Wireshark and tcpdump
+//
+// Every packet is a SocketCAN frame header (identifier and flags in network byte order,
+// payload length, CAN FD flags, two reserved bytes) followed by the payload.
+
+use std::fmt::Debug;
+use std::io::{Read, Write};
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::can::{CanFrame, Error};
+use crate::can::frame::{EFF_MASK, SFF_MASK};
+use super::{LogEntry, LogReader, LogWriter};
+
+const MAGIC_USEC: u32 = 0xa1b2c3d4;
+const MAGIC_NSEC: u32 = 0xa1b23c4d;
+const LINKTYPE_CAN_SOCKETCAN: u32 = 227;
+
+const CAN_EFF_FLAG: u32 = 0x80000000;
+const CAN_RTR_FLAG: u32 = 0x40000000;
+const CAN_ERR_FLAG: u32 = 0x20000000;
+
+const CANFD_BRS: u8 = 0x01;
+const CANFD_ESI: u8 = 0x02;
+const CANFD_FDF: u8 = 0x04;
+
+// SocketCAN header and the payload of a CAN FD frame
+const MAX_PACKET_SIZE: usize = 72;
+
+// Channel name of frames read from a pcap file, it does not record the interface
+pub const PCAP_CHANNEL: &str = "pcap";
+
+#[derive(Debug)]
+pub struct PcapReader<R> {
+    reader: R,
+    big_endian: bool,
+    nanoseconds: bool,
+    packet: usize,
+}
+
+impl<R: Read> PcapReader<R> {
+    pub fn new(mut reader: R) -> Result<Self, Error> {
+        let mut header = [0u8; 24];
+        reader.read_exact(&mut header)?;
+        let (big_endian, nanoseconds) = match (LittleEndian::read_u32(&header[0..4]), BigEndian::read_u32(&header[0..4])) {
+            (MAGIC_USEC, _) => (false, false),
+            (MAGIC_NSEC, _) => (false, true),
+            (_, MAGIC_USEC) => (true, false),
+            (_, MAGIC_NSEC) => (true, true),
+            _ => return Err(Error::Parse(0, String::from("not a pcap file"))),
+        };
+        let linktype = if big_endian { BigEndian::read_u32(&header[20..24]) } else { LittleEndian::read_u32(&header[20..24]) };
+        if linktype & 0xFFFF != LINKTYPE_CAN_SOCKETCAN {
+            return Err(Error::Parse(0, format!("pcap link type {} is not SocketCAN", linktype)));
+        }
+        Ok(Self { reader, big_endian, nanoseconds, packet: 0 })
+    }
+
+    fn read_u32(&mut self) -> std::io::Result<u32> {
+        if self.big_endian {
+            self.reader.read_u32::<BigEndian>()
+        } else {
+            self.reader.read_u32::<LittleEndian>()
+        }
+    }
+}
+
+impl<R: Read + Debug + Send> LogReader for PcapReader<R> {
+    fn read_entry(&mut self) -> Result<Option<LogEntry>, Error> {
+        loop {
+            let secs = match self.read_u32() {
+                Ok(secs) => secs as u64,
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e.into()),
+            };
+            let fraction = self.read_u32()? as u64;
+            let captured = self.read_u32()? as usize;
+            let _original = self.read_u32()?;
+            if captured > MAX_PACKET_SIZE {
+                return Err(Error::Parse(self.packet + 1, format!("packet of {} bytes is larger than a CAN FD frame", captured)));
+            }
+            let mut packet = vec![0u8; captured];
+            self.reader.read_exact(&mut packet)?;
+            self.packet += 1;
+
+            if packet.len() < 8 {
+                return Err(Error::Parse(self.packet, format!("packet of {} bytes is too short", packet.len())));
+            }
+            let can_id = BigEndian::read_u32(&packet[0..4]);
+            if can_id & CAN_ERR_FLAG != 0 {
+                continue;
+            }
+            let len = (packet[4] as usize).min(packet.len() - 8);
+            let flags = packet[5];
+            let data = &packet[8..8 + len];
+
+            let extended = can_id & CAN_EFF_FLAG != 0;
+            let id = can_id & if extended { EFF_MASK } else { SFF_MASK };
+            let frame = if flags & CANFD_FDF != 0 || len > 8 {
+                let mut frame = CanFrame::new_fd(id, extended, flags & CANFD_BRS != 0, data);
+                frame.esi = flags & CANFD_ESI != 0;
+                frame
+            } else if can_id & CAN_RTR_FLAG != 0 {
+                CanFrame::new_remote(id, extended, packet[4])
+            } else {
+                CanFrame::with_format(id, extended, data)
+            };
+            let time_us = secs * 1_000_000 + if self.nanoseconds { fraction / 1000 } else { fraction };
+            return Ok(Some(LogEntry {
+                channel: String::from(PCAP_CHANNEL),
+                time_us,
+                frame,
+            }));
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct PcapWriter<W> {
+    writer: W,
+}
+
+impl<W: Write> PcapWriter<W> {
+    pub fn new(mut writer: W) -> Result<Self, Error> {
+        writer.write_u32::<LittleEndian>(MAGIC_USEC)?;
+        writer.write_u16::<LittleEndian>(2)?;
+        writer.write_u16::<LittleEndian>(4)?;
+        writer.write_u32::<LittleEndian>(0)?;                   // thiszone
+        writer.write_u32::<LittleEndian>(0)?;                   // sigfigs
+        writer.write_u32::<LittleEndian>(MAX_PACKET_SIZE as u32)?;  // snaplen
+        writer.write_u32::<LittleEndian>(LINKTYPE_CAN_SOCKETCAN)?;
+        Ok(Self { writer })
+    }
+}
+
+impl<W: Write + Debug + Send> LogWriter for PcapWriter<W> {
+    fn write_entry(&mut self, entry: &LogEntry) -> Result<(), Error> {
+        let frame = &entry.frame;
+        let mut can_id = frame.id;
+        if frame.extended {
+            can_id |= CAN_EFF_FLAG;
+        }
+        if frame.remote && !frame.fd {
+            can_id |= CAN_RTR_FLAG;
+        }
+        let mut flags = 0;
+        if frame.fd {
+            flags |= CANFD_FDF;
+            if frame.brs {
+                flags |= CANFD_BRS;
+            }
+            if frame.esi {
+                flags |= CANFD_ESI;
+            }
+        }
+        let data: &[u8] = if frame.remote { &[] } else { &frame.data };
+        let len = if frame.remote { frame.dlc } else { data.len() as u8 };
+
+        let size = 8 + data.len() as u32;
+        self.writer.write_u32::<LittleEndian>((entry.time_us / 1_000_000) as u32)?;
+        self.writer.write_u32::<LittleEndian>((entry.time_us % 1_000_000) as u32)?;
+        self.writer.write_u32::<LittleEndian>(size)?;
+        self.writer.write_u32::<LittleEndian>(size)?;
+        self.writer.write_u32::<BigEndian>(can_id)?;
+        self.writer.write_all(&[len, flags, 0, 0])?;
+        self.writer.write_all(data)?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() -> Result<(), String> {
+        let entries = vec![
+            LogEntry { channel: String::from(PCAP_CHANNEL), time_us: 1_500_000, frame: CanFrame::with_format(0x123, false, &[1, 2, 3]) },
+            LogEntry { channel: String::from(PCAP_CHANNEL), time_us: 1_500_010, frame: CanFrame::new_remote(0x10, true, 4) },
+            LogEntry { channel: String::from(PCAP_CHANNEL), time_us: 1_500_020, frame: CanFrame::new_fd(0x7FF, false, true, &[0x55; 12]) },
+        ];
+
+        let mut writer = PcapWriter::new(Vec::new()).map_err(|e| e.to_string())?;
+        for entry in entries.iter() {
+            writer.write_entry(entry).map_err(|e| e.to_string())?;
+        }
+        let mut reader = PcapReader::new(&writer.writer[..]).map_err(|e| e.to_string())?;
+        for entry in entries.iter() {
+            let read = reader.read_entry().map_err(|e| e.to_string())?;
+            if read.as_ref() != Some(entry) {
+                return Err(format!("Wrote {:?}, read {:?}", entry, read));
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn oversized_packet_is_rejected() -> Result<(), String> {
+        let mut file = PcapWriter::new(Vec::new()).map_err(|e| e.to_string())?.writer;
+        for field in [0u32, 0, u32::MAX, u32::MAX] {
+            file.write_u32::<LittleEndian>(field).map_err(|e| e.to_string())?;
+        }
+        let mut reader = PcapReader::new(&file[..]).map_err(|e| e.to_string())?;
+        match reader.read_entry() {
+            Err(Error::Parse(1, _)) => Ok(()),
+            read => Err(format!("Read {:?} from a packet of 4 GiB", read)),
+        }
+    }
+}
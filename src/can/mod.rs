@@ -0,0 +1,55 @@
+//! CAN buses that emulated CAN controllers attach to.
+//!
+//! A controller model only talks to the `CanBus` trait, so the same firmware run can be
+//! connected to a SocketCAN interface, to other emulated ECUs in the same process, to a
+//! capture file or to a scripted test driver. Time on the bus is virtual time: the
+//! controller passes its current time (emulation steps) to `try_recv` and stamps the
+//! frames it sends with it.
+
+use std::fmt::Debug;
+use thiserror::Error;
+
+pub mod frame;
+pub use frame::CanFrame;
+pub mod socket;
+pub mod receiver;
+pub mod socketcan_bus;
+pub use socketcan_bus::SocketCanBus;
+pub mod virtual_bus;
+pub use virtual_bus::{VirtualBus, VirtualNode};
+pub mod detached;
+pub use detached::DetachedBus;
+pub mod log;
+pub mod file;
+pub use file::{FileSource, FileSink, Timing};
+pub mod scripted;
+pub use scripted::{ScriptedBus, Transcript};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("CAN bus I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("CAN bus can not be forked: {0}")]
+    NotForkable(String),
+    #[error("malformed CAN log entry {0}: {1}")]
+    Parse(usize, String),
+}
+
+pub trait CanBus: Debug + Send {
+    /// Put a frame on the bus, `frame.timestamp` is the sender's current time.
+    fn send(&mut self, frame: &CanFrame) -> Result<(), Error>;
+
+    /// Next frame from the other nodes that is due at time `now`, `None` if there is none yet.
+    fn try_recv(&mut self, now: u64) -> Result<Option<CanFrame>, Error>;
+
+    /// A private copy for a snapshot cloned controller. Frames already pending for this
+    /// connection are pending for the fork as well, but the fork never sees frames sent by
+    /// this connection or its other forks, and nothing it sends reaches them.
+    fn fork(&self) -> Result<Box<dyn CanBus>, Error>;
+
+    /// Another connection to the same bus for a controller cloned with
+    /// `CloneMode::Shared`: the clone takes part in the bus like any other node.
+    fn join(&self) -> Result<Box<dyn CanBus>, Error> {
+        Err(Error::NotForkable(format!("{:?} can not be joined", self)))
+    }
+}
@@ -0,0 +1,95 @@
+// Background receiver draining a SocketCAN interface
+//
+// Reading the socket from the emulation thread either blocks the emulation or misses
+// frames, so every SocketCAN bus gets a thread that reads its socket and hands the
+// frames over a channel. The bus is drained whenever the controller attached to it
+// polls for received frames.
+//
+// The thread reads the socket the bus transmits on. A second socket on the interface
+// would receive every frame the firmware sends through SocketCAN loopback.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+use super::{CanFrame, Error};
+use super::socket::FdSocket;
+
+// How often the thread checks whether it has been stopped
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone)]
+pub struct ReceivedFrame {
+    pub frame: CanFrame,
+    pub elapsed: Duration,          // Time since the receiver was started
+}
+
+#[derive(Debug)]
+pub struct SocketReceiver {
+    frames: Receiver<ReceivedFrame>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl SocketReceiver {
+    // Read socket on a new thread, socket must be the one the frames are sent on
+    pub fn start(socket: Arc<FdSocket>, interface: &str) -> Result<Self, Error> {
+        socket.set_read_timeout(READ_TIMEOUT)?;
+
+        let (sender, frames) = mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let name = interface.to_owned();
+        let started = Instant::now();
+
+        let thread = thread::Builder::new()
+            .name(format!("can-rx-{}", interface))
+            .spawn(move || {
+                while !thread_stop.load(Ordering::Relaxed) {
+                    match socket.read_frame() {
+                        Ok(frame) => {
+                            let received = ReceivedFrame { frame, elapsed: started.elapsed() };
+                            if sender.send(received).is_err() {
+                                // The bus is gone
+                                break;
+                            }
+                        },
+                        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut => (),
+                        Err(e) => {
+                            warn!("Receiving from {} failed, receiver stopped: {}", name, e);
+                            break;
+                        }
+                    }
+                }
+                info!("Receiver of {} stopped", name);
+            })?;
+
+        Ok(Self {
+            frames,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    // Next frame received by the thread, if any
+    pub fn try_next(&mut self) -> Option<ReceivedFrame> {
+        match self.frames.try_recv() {
+            Ok(frame) => Some(frame),
+            // A stopped thread has logged why it stopped
+            Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => None,
+        }
+    }
+}
+
+impl Drop for SocketReceiver {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
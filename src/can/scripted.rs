@@ -0,0 +1,136 @@
+use std::sync::Arc;
+use parking_lot::Mutex;
+
+use super::{CanBus, CanFrame, Error};
+
+// Frames sent by the controller, readable after the bus has been handed to it
+#[derive(Debug, Clone, Default)]
+pub struct Transcript {
+    frames: Arc<Mutex<Vec<CanFrame>>>,
+}
+
+impl Transcript {
+    pub fn frames(&self) -> Vec<CanFrame> {
+        self.frames.lock().clone()
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.lock().is_empty()
+    }
+
+    fn push(&self, frame: CanFrame) {
+        self.frames.lock().push(frame);
+    }
+
+    fn deep_clone(&self) -> Self {
+        Self {
+            frames: Arc::new(Mutex::new(self.frames())),
+        }
+    }
+}
+
+// A test driver: delivers frames at given times and answers frames sent by the controller
+//
+// let bus = ScriptedBus::new()
+//     .at(1000, CanFrame::new(0x7DF, &[0x02, 0x01, 0x00]))
+//     .on_id(0x7E8, CanFrame::new(0x7E0, &[0x30, 0x00, 0x00]));
+// let transcript = bus.transcript();
+#[derive(Debug, Clone, Default)]
+pub struct ScriptedBus {
+    scheduled: Vec<CanFrame>,           // Sorted by timestamp
+    responses: Vec<(u32, CanFrame)>,    // Frame sent in reply to a frame with the ID
+    replies: Vec<CanFrame>,             // Replies waiting for delivery
+    transcript: Transcript,
+}
+
+impl ScriptedBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Deliver frame once the controller's time reaches time
+    pub fn at(mut self, time: u64, frame: CanFrame) -> Self {
+        let frame = frame.at(time);
+        let position = self.scheduled.iter().position(|f| f.timestamp > time).unwrap_or(self.scheduled.len());
+        self.scheduled.insert(position, frame);
+        self
+    }
+
+    // Deliver reply every time the controller sends a frame with id
+    pub fn on_id(mut self, id: u32, reply: CanFrame) -> Self {
+        self.responses.push((id, reply));
+        self
+    }
+
+    pub fn transcript(&self) -> Transcript {
+        self.transcript.clone()
+    }
+
+    // Scheduled frames that have not been delivered yet
+    pub fn remaining(&self) -> usize {
+        self.scheduled.len() + self.replies.len()
+    }
+}
+
+impl CanBus for ScriptedBus {
+    fn send(&mut self, frame: &CanFrame) -> Result<(), Error> {
+        self.transcript.push(frame.clone());
+        for (id, reply) in self.responses.iter() {
+            if *id == frame.id {
+                self.replies.push(reply.clone().at(frame.timestamp));
+            }
+        }
+        Ok(())
+    }
+
+    fn try_recv(&mut self, now: u64) -> Result<Option<CanFrame>, Error> {
+        if !self.replies.is_empty() {
+            return Ok(Some(self.replies.remove(0)));
+        }
+        match self.scheduled.first() {
+            Some(frame) if frame.timestamp <= now => Ok(Some(self.scheduled.remove(0))),
+            _ => Ok(None),
+        }
+    }
+
+    // The fork continues the script on its own and keeps its own transcript
+    fn fork(&self) -> Result<Box<dyn CanBus>, Error> {
+        Ok(Box::new(Self {
+            transcript: self.transcript.deep_clone(),
+            ..self.clone()
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn schedule_and_reply() -> Result<(), String> {
+        let mut bus = ScriptedBus::new()
+            .at(20, CanFrame::new(0x200, &[2]))
+            .at(10, CanFrame::new(0x100, &[1]))
+            .on_id(0x7E0, CanFrame::new(0x7E8, &[0x50]));
+        let transcript = bus.transcript();
+
+        if bus.try_recv(5).map_err(|e| e.to_string())?.is_some() {
+            return Err(String::from("Frame delivered before its time"));
+        }
+        let first = bus.try_recv(15).map_err(|e| e.to_string())?.map(|frame| frame.id);
+        if first != Some(0x100) {
+            return Err(format!("Expected 0x100 first, got {:?}", first));
+        }
+
+        bus.send(&CanFrame::new(0x7E0, &[0x10]).at(16)).map_err(|e| e.to_string())?;
+        let reply = bus.try_recv(16).map_err(|e| e.to_string())?.map(|frame| frame.id);
+        if reply != Some(0x7E8) || transcript.len() != 1 {
+            return Err(format!("Expected reply 0x7E8, got {:?}", reply));
+        }
+        Ok(())
+    }
+}
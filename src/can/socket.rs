@@ -0,0 +1,174 @@
+// Raw SocketCAN socket carrying classical and CAN FD frames
+//
+// The socketcan crate only knows classical frames and derives the identifier format
+// from the identifier value, so frames are read and written here as the kernel's
+// can_frame/canfd_frame structures with CAN_RAW_FD_FRAMES enabled.
+
+use std::ffi::CString;
+use std::io;
+use std::mem::size_of;
+use std::os::unix::io::RawFd;
+use std::time::Duration;
+
+use super::frame::{CanFrame, dlc_to_len, len_to_dlc, EFF_MASK, SFF_MASK};
+
+const CAN_RAW: libc::c_int = 1;
+const SOL_CAN_RAW: libc::c_int = 101;
+const CAN_RAW_FD_FRAMES: libc::c_int = 5;
+const CAN_RAW_RECV_OWN_MSGS: libc::c_int = 4;
+
+const CAN_EFF_FLAG: u32 = 0x80000000;
+const CAN_RTR_FLAG: u32 = 0x40000000;
+const CAN_ERR_FLAG: u32 = 0x20000000;
+
+const CANFD_BRS: u8 = 0x01;
+const CANFD_ESI: u8 = 0x02;
+
+const CAN_MTU: usize = 16;
+const CANFD_MTU: usize = size_of::<CanFdFrame>();
+
+#[repr(C)]
+struct SockaddrCan {
+    can_family: libc::sa_family_t,
+    can_ifindex: libc::c_int,
+    can_addr: [u64; 2],
+}
+
+// struct canfd_frame, the first 16 bytes are layout compatible with struct can_frame
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct CanFdFrame {
+    can_id: u32,
+    len: u8,        // can_dlc of a classical frame
+    flags: u8,
+    res0: u8,
+    res1: u8,
+    data: [u8; 64],
+}
+
+#[derive(Debug)]
+pub struct FdSocket {
+    fd: RawFd,
+}
+
+impl FdSocket {
+    pub fn open(interface: &str) -> io::Result<Self> {
+        let name = CString::new(interface).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let ifindex = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        if ifindex == 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let fd = unsafe { libc::socket(libc::PF_CAN, libc::SOCK_RAW, CAN_RAW) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Closes fd on the error paths below
+        let socket = Self { fd };
+
+        let enable: libc::c_int = 1;
+        socket.setsockopt(SOL_CAN_RAW, CAN_RAW_FD_FRAMES, &enable)?;
+        // Frames written to this socket are not read back from it. Other sockets on the
+        // interface still see them, which is why one socket both sends and receives.
+        let disable: libc::c_int = 0;
+        socket.setsockopt(SOL_CAN_RAW, CAN_RAW_RECV_OWN_MSGS, &disable)?;
+
+        let addr = SockaddrCan {
+            can_family: libc::AF_CAN as libc::sa_family_t,
+            can_ifindex: ifindex as libc::c_int,
+            can_addr: [0; 2],
+        };
+        let ret = unsafe {
+            libc::bind(fd, &addr as *const SockaddrCan as *const libc::sockaddr, size_of::<SockaddrCan>() as libc::socklen_t)
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(socket)
+    }
+
+    fn setsockopt<T>(&self, level: libc::c_int, name: libc::c_int, value: &T) -> io::Result<()> {
+        let ret = unsafe {
+            libc::setsockopt(self.fd, level, name, value as *const T as *const libc::c_void, size_of::<T>() as libc::socklen_t)
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    // Reads fail with WouldBlock after timeout without a frame
+    pub fn set_read_timeout(&self, timeout: Duration) -> io::Result<()> {
+        let tv = libc::timeval {
+            tv_sec: timeout.as_secs() as libc::time_t,
+            tv_usec: timeout.subsec_micros() as libc::suseconds_t,
+        };
+        self.setsockopt(libc::SOL_SOCKET, libc::SO_RCVTIMEO, &tv)
+    }
+
+    // Next data or remote frame, error frames are skipped
+    pub fn read_frame(&self) -> io::Result<CanFrame> {
+        loop {
+            let mut raw = CanFdFrame { can_id: 0, len: 0, flags: 0, res0: 0, res1: 0, data: [0; 64] };
+            let size = unsafe {
+                libc::read(self.fd, &mut raw as *mut CanFdFrame as *mut libc::c_void, CANFD_MTU)
+            };
+            if size < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if raw.can_id & CAN_ERR_FLAG != 0 {
+                continue;
+            }
+
+            let extended = raw.can_id & CAN_EFF_FLAG != 0;
+            let id = raw.can_id & if extended { EFF_MASK } else { SFF_MASK };
+            return match size as usize {
+                CAN_MTU if raw.can_id & CAN_RTR_FLAG != 0 => Ok(CanFrame::new_remote(id, extended, raw.len)),
+                CAN_MTU => Ok(CanFrame::with_format(id, extended, &raw.data[..(raw.len as usize).min(8)])),
+                CANFD_MTU => {
+                    let mut frame = CanFrame::new_fd(id, extended, raw.flags & CANFD_BRS != 0, &raw.data[..(raw.len as usize).min(64)]);
+                    frame.esi = raw.flags & CANFD_ESI != 0;
+                    Ok(frame)
+                },
+                size => Err(io::Error::new(io::ErrorKind::InvalidData, format!("unexpected CAN frame size {}", size))),
+            };
+        }
+    }
+
+    pub fn write_frame(&self, frame: &CanFrame) -> io::Result<()> {
+        let mut raw = CanFdFrame { can_id: frame.id, len: 0, flags: 0, res0: 0, res1: 0, data: [0; 64] };
+        if frame.extended {
+            raw.can_id |= CAN_EFF_FLAG;
+        }
+        let len = dlc_to_len(frame.dlc, frame.fd).min(frame.data.len());
+        raw.data[..len].copy_from_slice(&frame.data[..len]);
+
+        let size = if frame.fd {
+            raw.len = dlc_to_len(len_to_dlc(len), true) as u8;
+            raw.flags = if frame.brs { CANFD_BRS } else { 0 } | if frame.esi { CANFD_ESI } else { 0 };
+            CANFD_MTU
+        } else {
+            if frame.remote {
+                raw.can_id |= CAN_RTR_FLAG;
+                raw.len = frame.dlc.min(8);
+            } else {
+                raw.len = len as u8;
+            }
+            CAN_MTU
+        };
+
+        let written = unsafe {
+            libc::write(self.fd, &raw as *const CanFdFrame as *const libc::c_void, size)
+        };
+        if written < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for FdSocket {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd); }
+    }
+}
@@ -0,0 +1,58 @@
+use std::sync::Arc;
+
+use log::info;
+
+use super::{CanBus, CanFrame, Error};
+use super::socket::FdSocket;
+use super::receiver::SocketReceiver;
+
+// A SocketCAN interface, e.g. vcan0. Frames are received on a background thread and
+// stamped with the virtual time at which the controller picks them up.
+#[derive(Debug)]
+pub struct SocketCanBus {
+    interface: String,
+    socket: Arc<FdSocket>,
+    receiver: SocketReceiver,
+}
+
+impl SocketCanBus {
+    pub fn open(interface: &str) -> Result<Self, Error> {
+        let socket = Arc::new(FdSocket::open(interface)?);
+        let receiver = SocketReceiver::start(socket.clone(), interface)?;
+        Ok(Self {
+            interface: interface.to_owned(),
+            socket,
+            receiver,
+        })
+    }
+
+    pub fn interface(&self) -> &str {
+        &self.interface
+    }
+}
+
+impl CanBus for SocketCanBus {
+    fn send(&mut self, frame: &CanFrame) -> Result<(), Error> {
+        self.socket.write_frame(frame)?;
+        Ok(())
+    }
+
+    fn try_recv(&mut self, now: u64) -> Result<Option<CanFrame>, Error> {
+        Ok(self.receiver.try_next().map(|received| {
+            info!("CAN frame received from {} after {:?}: {:?}", self.interface, received.elapsed, received.frame);
+            received.frame.at(now)
+        }))
+    }
+
+    // Frames a fork sends would go out on the interface, and the frames pending in the
+    // receiver can not be copied without taking them from this bus
+    fn fork(&self) -> Result<Box<dyn CanBus>, Error> {
+        Err(Error::NotForkable(format!("SocketCAN interface {}", self.interface)))
+    }
+
+    // The joined bus opens its own socket and only sees frames sent after it was opened,
+    // including the frames sent on this bus
+    fn join(&self) -> Result<Box<dyn CanBus>, Error> {
+        Ok(Box::new(Self::open(&self.interface)?))
+    }
+}
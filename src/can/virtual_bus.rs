@@ -0,0 +1,158 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Arc;
+use parking_lot::Mutex;
+
+use super::{CanBus, CanFrame, DetachedBus, Error};
+
+#[derive(Debug, Default)]
+struct Hub {
+    queues: BTreeMap<usize, VecDeque<CanFrame>>,    // Frames pending for each attached node
+    next_node: usize,
+}
+
+impl Hub {
+    fn attach(&mut self, pending: VecDeque<CanFrame>) -> usize {
+        let node = self.next_node;
+        self.next_node += 1;
+        self.queues.insert(node, pending);
+        node
+    }
+}
+
+// An in-process bus connecting several emulated controllers, e.g. a multi-ECU simulation.
+// Every frame sent by a node is delivered, in order, to all other nodes.
+#[derive(Debug, Clone, Default)]
+pub struct VirtualBus {
+    hub: Arc<Mutex<Hub>>,
+}
+
+impl VirtualBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Attach a new node, it receives every frame sent from now on
+    pub fn node(&self) -> VirtualNode {
+        let index = self.hub.lock().attach(VecDeque::new());
+        VirtualNode {
+            hub: self.hub.clone(),
+            index,
+        }
+    }
+
+    pub fn num_nodes(&self) -> usize {
+        self.hub.lock().queues.len()
+    }
+}
+
+// A node leaves the bus when it is dropped
+#[derive(Debug)]
+pub struct VirtualNode {
+    hub: Arc<Mutex<Hub>>,
+    index: usize,
+}
+
+impl VirtualNode {
+    // Number of frames waiting to be received by this node
+    pub fn pending(&self) -> usize {
+        self.hub.lock().queues.get(&self.index).map_or(0, VecDeque::len)
+    }
+}
+
+impl CanBus for VirtualNode {
+    fn send(&mut self, frame: &CanFrame) -> Result<(), Error> {
+        let mut hub = self.hub.lock();
+        for (index, queue) in hub.queues.iter_mut() {
+            if *index != self.index {
+                queue.push_back(frame.clone());
+            }
+        }
+        Ok(())
+    }
+
+    fn try_recv(&mut self, _now: u64) -> Result<Option<CanFrame>, Error> {
+        Ok(self.hub.lock().queues.get_mut(&self.index).and_then(VecDeque::pop_front))
+    }
+
+    // The fork is not on the bus, it only gets the frames pending for this node
+    fn fork(&self) -> Result<Box<dyn CanBus>, Error> {
+        let pending = self.hub.lock().queues.get(&self.index).cloned().unwrap_or_default();
+        Ok(Box::new(DetachedBus::new(pending)))
+    }
+
+    // A new node on the same bus, starting with the frames pending for this node
+    fn join(&self) -> Result<Box<dyn CanBus>, Error> {
+        let mut hub = self.hub.lock();
+        let pending = hub.queues.get(&self.index).cloned().unwrap_or_default();
+        let index = hub.attach(pending);
+        Ok(Box::new(VirtualNode {
+            hub: self.hub.clone(),
+            index,
+        }))
+    }
+}
+
+impl Drop for VirtualNode {
+    fn drop(&mut self) {
+        self.hub.lock().queues.remove(&self.index);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn frames_reach_the_other_nodes() -> Result<(), String> {
+        let bus = VirtualBus::new();
+        let mut ecu1 = bus.node();
+        let mut ecu2 = bus.node();
+        let mut ecu3 = bus.node();
+
+        ecu1.send(&CanFrame::new(0x123, &[1, 2, 3])).map_err(|e| e.to_string())?;
+        if ecu1.pending() != 0 || ecu2.pending() != 1 || ecu3.pending() != 1 {
+            return Err(String::from("Frame not delivered to exactly the other nodes"));
+        }
+        let received = ecu2.try_recv(0).map_err(|e| e.to_string())?;
+        if received.map(|frame| frame.id) != Some(0x123) {
+            return Err(String::from("Wrong frame received"));
+        }
+        if ecu1.try_recv(0).map_err(|e| e.to_string())?.is_some() {
+            return Err(String::from("Sender received its own frame"));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn forks_are_detached() -> Result<(), String> {
+        let bus = VirtualBus::new();
+        let mut ecu1 = bus.node();
+        let ecu2 = bus.node();
+        ecu1.send(&CanFrame::new(0x100, &[1])).map_err(|e| e.to_string())?;
+
+        // The fork gets the frame pending for ecu2, but neither sends nor receives on the bus
+        let mut fork = ecu2.fork().map_err(|e| e.to_string())?;
+        fork.send(&CanFrame::new(0x200, &[2])).map_err(|e| e.to_string())?;
+        ecu1.send(&CanFrame::new(0x300, &[3])).map_err(|e| e.to_string())?;
+        if ecu1.pending() != 0 || ecu2.pending() != 2 || bus.num_nodes() != 2 {
+            return Err(String::from("Fork joined the bus"));
+        }
+        let received: Vec<u32> = std::iter::from_fn(|| fork.try_recv(0).ok().flatten()).map(|frame| frame.id).collect();
+        if received != [0x100] {
+            return Err(format!("Fork received {:x?}", received));
+        }
+
+        // A joined node is on the bus until it is dropped
+        let mut joined = ecu2.join().map_err(|e| e.to_string())?;
+        joined.send(&CanFrame::new(0x400, &[4])).map_err(|e| e.to_string())?;
+        if ecu1.pending() != 1 || bus.num_nodes() != 3 {
+            return Err(String::from("Joined node is not on the bus"));
+        }
+        drop(joined);
+        ecu1.send(&CanFrame::new(0x500, &[5])).map_err(|e| e.to_string())?;
+        if bus.num_nodes() != 2 || ecu2.pending() != 4 {
+            return Err(String::from("Dropped node kept its queue"));
+        }
+        Ok(())
+    }
+}
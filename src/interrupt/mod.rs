@@ -22,7 +22,9 @@ use metaemu::concrete::hooks::{ClonableHookConcrete, HookConcrete};
 use metaemu::hooks::types::{HookStepAction, HookOutcome, Error as HookError};
 
 use crate::backend::InterruptHandlerOverrider;
+use crate::snapshot::PeripheralSnapshot;
 use std::convert::TryInto;
+use serde::{Serialize, Deserialize};
 
 // This case handles both "soft" and "hard" interrupts. Soft interrupts
 // are those that we wish to handle by redirecting control to a new location
@@ -31,7 +33,7 @@ use std::convert::TryInto;
 //
 
 // Status of an interrupt
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub enum Status {
     Disabled,
     Fired(usize),
@@ -147,6 +149,38 @@ where
     }
 }
 
+// The trigger status and handler/return stacks of an InterruptWrapper.
+// The state of the wrapped overrider is owned by the user and is not included.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InterruptWrapperSnapshot {
+    status: Status,
+    handler_stack: Vec<Address>,
+    returns_stack: Vec<Address>,
+}
+
+impl<I, S, O> PeripheralSnapshot for InterruptWrapper<I, S, O>
+where
+    I: InterruptHandlerOverrider<State = S>,
+    S: AsState<PCodeState<u8, O>>,
+    O: Order
+{
+    type Snapshot = InterruptWrapperSnapshot;
+
+    fn snapshot(&self) -> Self::Snapshot {
+        InterruptWrapperSnapshot {
+            status: self.status,
+            handler_stack: self.handler_stack.clone(),
+            returns_stack: self.returns_stack.clone(),
+        }
+    }
+
+    fn restore(&mut self, snapshot: &Self::Snapshot) {
+        self.status = snapshot.status;
+        self.handler_stack = snapshot.handler_stack.clone();
+        self.returns_stack = snapshot.returns_stack.clone();
+    }
+}
+
 impl<I: 'static, S: 'static, O> HookConcrete for InterruptWrapper<I, S, O>
 where
     I: Clone + InterruptHandlerOverrider<State = S>,
@@ -219,6 +253,42 @@ where I: Clone + InterruptHandlerOverrider<State = S>,
 {
 }
 
+#[cfg(test)]
+mod test {
+    use super::*;
+    use fugue::bytes::LE;
+
+    #[derive(Clone)]
+    struct NeverFires;
+
+    impl InterruptHandlerOverrider for NeverFires {
+        type State = PCodeState<u8, LE>;
+        type Endian = LE;
+
+        fn fire(&mut self, _trigger_count: u128, _state: &mut Self::State, _address: &Address, _operation: &StepState) -> Result<Option<Address>, InterruptError> {
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn snapshot_saved_as_json() -> Result<(), String> {
+        // Fired twice, the inner handler still running
+        let mut interrupt = InterruptWrapper::new(NeverFires, true);
+        interrupt.status = Status::Fired(2);
+        interrupt.handler_stack = vec![Address::from(0x2000u32)];
+        interrupt.returns_stack = vec![Address::from(0x100u32), Address::from(0x2010u32)];
+        let json = serde_json::to_string(&interrupt.snapshot()).map_err(|e| e.to_string())?;
+
+        let mut restored = InterruptWrapper::new(NeverFires, true);
+        restored.restore(&serde_json::from_str::<InterruptWrapperSnapshot>(&json).map_err(|e| e.to_string())?);
+        if restored.status == Status::Fired(2) && restored.handler_stack == interrupt.handler_stack && restored.returns_stack == interrupt.returns_stack {
+            Ok(())
+        } else {
+            Err(format!("Interrupt status and stacks not restored from {}", json))
+        }
+    }
+}
+
 // #[cfg(test)]
 // mod test {
 //     use super::*;
@@ -5,3 +5,6 @@ pub mod SuperH;
 pub mod interrupt;
 pub mod backend;
 pub mod bypass;
+pub mod snapshot;
+pub mod replay;
+pub mod can;
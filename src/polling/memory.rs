@@ -1,5 +1,3 @@
-use std::sync::Mutex;
-use std::sync::Arc;
 // use std::collections::{HashMap, HashSet};
 use std::marker::PhantomData;
 use thiserror::Error;
@@ -8,19 +6,21 @@ use crate::polling::{
     PollingPeripheralHandler,
     Error as PoolingHandlerError,
 };
+use crate::snapshot::PeripheralSnapshot;
 
 use fugue::ir::{
     Address,
 };
 use metaemu::state::{
-    State,
+    AsState,
     pcode::PCodeState,
     StateOps,
     pcode::Error as PCodeError
 };
 use metaemu::concrete::hooks::{ClonableHookConcrete, HookConcrete};
 use fugue::bytes::{Order};
-use metaemu::hooks::types::{HookAction, HookOutcome, Error as HookError};
+use metaemu::hooks::types::{HookAction, HookStepAction, HookOutcome, Error as HookError};
+use metaemu::machine::StepState;
 
 #[derive(Debug, Error)]
 pub enum MyError {
@@ -42,31 +42,64 @@ impl From<PoolingHandlerError> for HookError<PCodeError> {
 }
 
 // S: State which impl AsState<PCodeState<u8, Order>>
-// P: PollingPeripheral<Input=Address, Output=Address, State=S>
+// P: PollingPeripheral<Input=Address, Output=Address, Order=O>
 // Order: Endian
-#[derive(Debug, Clone)]
+//
+// Cloning (e.g. when a machine state is forked) clones the handler itself, so the
+// fork gets a snapshot of the peripheral. Use a SharedPeripheral as the handler
+// to have every fork drive the same device instead.
+#[derive(Debug)]
 pub struct MemoryPollingPeripheral<S, P, O> 
     where P: PollingPeripheralHandler<Input=Address, Output=Address, Order=O>,
-          S: StateOps,
+          S: AsState<PCodeState<u8, O>>,
           O: Order,
 {
     address_range: (Address, Address),
-    peripheral: Arc<Mutex<P>>, // TODO: maybe use Cell?
+    peripheral: P,      // Owned directly; wrap it in a SharedPeripheral to reach it from another thread
     state: PhantomData<S>,
 }
 
+impl<S, P, O> Clone for MemoryPollingPeripheral<S, P, O> 
+    where P: PollingPeripheralHandler<Input=Address, Output=Address, Order=O>,
+          S: AsState<PCodeState<u8, O>>,
+          O: Order,
+{
+    fn clone(&self) -> Self {
+        Self {
+            address_range: self.address_range,
+            peripheral: self.peripheral.clone(),
+            state: PhantomData,
+        }
+    }
+}
+
 impl<S, P, O> MemoryPollingPeripheral<S, P, O> 
     where P: PollingPeripheralHandler<Input=Address, Output=Address, Order=O>,
-          S: StateOps,
+          S: AsState<PCodeState<u8, O>>,
           O: Order,
 {
-    pub fn peripheral(&self) -> Arc<Mutex<P>> {
-        self.peripheral.clone()
-        // &self.peripheral
+    pub fn peripheral(&self) -> &P {
+        &self.peripheral
     }
 
-    pub fn peripheral_mut(&mut self) -> Arc<Mutex<P>> {
-        self.peripheral.clone()
+    pub fn peripheral_mut(&mut self) -> &mut P {
+        &mut self.peripheral
+    }
+}
+
+impl<S, P, O> PeripheralSnapshot for MemoryPollingPeripheral<S, P, O> 
+    where P: PollingPeripheralHandler<Input=Address, Output=Address, Order=O> + PeripheralSnapshot,
+          S: AsState<PCodeState<u8, O>>,
+          O: Order,
+{
+    type Snapshot = P::Snapshot;
+
+    fn snapshot(&self) -> Self::Snapshot {
+        self.peripheral.snapshot()
+    }
+
+    fn restore(&mut self, snapshot: &Self::Snapshot) {
+        self.peripheral.restore(snapshot)
     }
 }
 
@@ -80,16 +113,16 @@ pub struct MemoryPollingPeripheralBuilder<S, P, O>
 // Address_range: (start, end)
 impl<S, P, O> MemoryPollingPeripheralBuilder<S, P, O> 
     where P: PollingPeripheralHandler<Input=Address, Output=Address, Order=O>,
-          S: State + StateOps,
+          S: AsState<PCodeState<u8, O>>,
           O: Order,
 {
-    pub fn new(peripheral_in: P, muexe_state: &mut PCodeState<u8, O>, address_range: (Address, Address)) -> Result<Self, PCodeError> {
+    pub fn new(peripheral_in: P, muexe_state: &mut S, address_range: (Address, Address)) -> Result<Self, PCodeError> {
         let mut sel = Self {
             peripheral : peripheral_in,
             state: PhantomData,
             address_range
         };
-        sel.peripheral.init(muexe_state).unwrap();
+        sel.peripheral.init(muexe_state.state_mut()).unwrap();
         Ok(sel)
     }
 
@@ -102,24 +135,29 @@ impl<S, P, O> MemoryPollingPeripheralBuilder<S, P, O>
         Ok(MemoryPollingPeripheral {
             address_range: self.address_range,
             // regisiters: self.registers,
-            peripheral: Arc::new(Mutex::new(self.peripheral)),
+            peripheral: self.peripheral,
             state: self.state,
         })
     }
 }
 
 impl<S: 'static, P: 'static, O> HookConcrete for MemoryPollingPeripheral<S, P, O>
-where S: State + StateOps ,
+where S: AsState<PCodeState<u8, O>> + StateOps,
       P: PollingPeripheralHandler<Input=Address, Output=Address, Order=O> , 
       O: Order 
 {
-    type State = PCodeState<u8, O>;        // TOOD: make it useful for universal endian
+    type State = S;
     type Error = PCodeError;
     type Outcome = String;
+    fn hook_architectural_step(&mut self, state: &mut Self::State, address: &Address, _operation: &StepState) -> Result<HookOutcome<HookStepAction<Self::Outcome>>, HookError<Self::Error>> {
+        self.peripheral.handle_step(state.state_mut(), address)?;
+        Ok(HookStepAction::Pass.into())
+    }
+
     fn hook_memory_read(&mut self, state: &mut Self::State, address: &Address, size: usize) -> Result<HookOutcome<HookAction<Self::Outcome>>, HookError<Self::Error>> {
         let (min, max) = self.address_range;
         if min<= *address && *address<= max {
-            self.peripheral.lock().unwrap().handle_input(state, &address, size)?;
+            self.peripheral.handle_input(state.state_mut(), &address, size)?;
         }
         Ok(HookAction::Pass.into())
     }
@@ -127,12 +165,12 @@ where S: State + StateOps ,
     fn hook_memory_write(&mut self, state: &mut Self::State, address: &Address, size: usize, value: &[u8]) ->  Result<HookOutcome<HookAction<Self::Outcome>>, HookError<Self::Error>>{
         let (min, max) = self.address_range;
         if min<= *address && *address <= max {
-            self.peripheral.lock().unwrap().handle_output(state, &address, value, size)?;
+            self.peripheral.handle_output(state.state_mut(), &address, value, size)?;
         }
         Ok(HookAction::Pass.into())
     }
 }
 
 impl<S: 'static, P: 'static, O> ClonableHookConcrete for MemoryPollingPeripheral<S, P, O>
-where S: State + StateOps,
+where S: AsState<PCodeState<u8, O>> + StateOps,
       P: PollingPeripheralHandler<Input=Address, Output=Address, Order= O>, O: Order { }
@@ -1,4 +1,5 @@
 use fugue::bytes::{Order};
+use fugue::ir::Address;
 use thiserror::Error;
 use thiserror;
 
@@ -6,8 +7,16 @@ use metaemu::state::{
     pcode::PCodeState,
 };
 
+use crate::backend;
+
 pub mod memory;
 pub use memory::{MemoryPollingPeripheral, MemoryPollingPeripheralBuilder};
+pub mod shared;
+pub use shared::SharedPeripheral;
+pub mod record_replay;
+pub use record_replay::RecordReplay;
+pub mod synthesized;
+pub use synthesized::{ReadRule, SynthesizedModel, SynthesizedPeripheral};
 #[derive(Debug, Error)]
 pub enum Error {
     // #[error(transparent)]
@@ -22,11 +31,30 @@ pub enum Error {
     InitFailed,
     #[error("OtherError: faile at component {0}")]
     HandlerError(String),
-    
+    #[error("Replay diverged from the recorded run: {0}")]
+    ReplayDiverged(String),
+
 }
 
 
 
+// Memory the registers of a peripheral are mapped in: the emulator state, or a plain byte
+// map when the peripheral is driven without an emulator
+pub trait RegisterMemory {
+    fn read_bytes(&self, address: Address, size: usize) -> Vec<u8>;
+    fn write_bytes(&mut self, address: Address, values: &[u8]);
+}
+
+impl<O: Order> RegisterMemory for PCodeState<u8, O> {
+    fn read_bytes(&self, address: Address, size: usize) -> Vec<u8> {
+        self.view_values(address, size).unwrap().to_vec()
+    }
+
+    fn write_bytes(&mut self, address: Address, values: &[u8]) {
+        self.set_values(address, values).unwrap();
+    }
+}
+
 pub trait PollingPeripheralHandler: Clone {
     type Input;
     type Output;
@@ -35,4 +63,15 @@ pub trait PollingPeripheralHandler: Clone {
     fn init(&mut self, state: &mut  PCodeState<u8, Self::Order>) -> Result<(), Error>;
     fn handle_input(&mut self, state: &mut  PCodeState<u8, Self::Order>, input: &Self::Input, size: usize) -> Result<(), Error>;
     fn handle_output(&mut self, state: &mut  PCodeState<u8, Self::Order>, output: &Self::Output, value: &[u8], size: usize) -> Result<(), Error>;
+
+    // Called once per executed instruction, before the instruction at address runs.
+    // This is the virtual clock of the peripheral; each step is one clock cycle.
+    fn handle_step(&mut self, _state: &mut  PCodeState<u8, Self::Order>, _address: &Address) -> Result<(), Error> {
+        Ok(())
+    }
+
+    // Visit every interrupt the peripheral raises, so wrappers such as RecordReplay can
+    // log and raise them again
+    fn for_each_interrupt(&mut self, _visit: &mut dyn FnMut(&mut backend::Interrupt)) {
+    }
 }
@@ -0,0 +1,168 @@
+use fugue::ir::Address;
+use metaemu::state::pcode::PCodeState;
+
+use crate::backend;
+use crate::polling::{PollingPeripheralHandler, RegisterMemory, Error};
+use crate::replay::{InputEvent, InputReplay, InterruptEvent};
+
+/// Wraps a polling peripheral to record the values it returns to the firmware, or
+/// to replay a recorded run.
+///
+/// When replaying, the wrapped peripheral is never called: reads are answered from
+/// the log and writes, steps and init are dropped, so nothing outside the emulator
+/// (e.g. a SocketCAN interface) is touched. The interrupts the peripheral raised are
+/// raised again in the same hook call as in the recorded run.
+#[derive(Debug, Clone)]
+pub struct RecordReplay<P> {
+    peripheral: P,
+    replay: InputReplay,
+    clock: u128,    // Virtual time in executed instructions
+    hooks: u128,    // Hook calls so far, interrupts are replayed by hook call
+}
+
+impl<P> RecordReplay<P> {
+    pub fn new(peripheral: P, replay: InputReplay) -> Self {
+        Self {
+            peripheral,
+            replay,
+            clock: 0,
+            hooks: 0,
+        }
+    }
+
+    pub fn peripheral(&self) -> &P {
+        &self.peripheral
+    }
+
+    pub fn peripheral_mut(&mut self) -> &mut P {
+        &mut self.peripheral
+    }
+
+    pub fn replay(&self) -> &InputReplay {
+        &self.replay
+    }
+
+    pub fn into_replay(self) -> InputReplay {
+        self.replay
+    }
+}
+
+// The hooks on any memory the registers are mapped in. hook runs the wrapped peripheral,
+// it is not called when replaying.
+impl<P> RecordReplay<P>
+where P: PollingPeripheralHandler<Input=Address, Output=Address>
+{
+    pub(crate) fn input<M, F>(&mut self, memory: &mut M, input: &Address, size: usize, pc: Address, hook: F) -> Result<(), Error>
+    where M: RegisterMemory,
+          F: FnOnce(&mut P, &mut M) -> Result<(), Error>
+    {
+        self.hooks += 1;
+        if self.replay.is_replaying() {
+            let event = self.replay.next_event(input).map_err(Error::ReplayDiverged)?;
+            if event.value.len() != size {
+                return Err(Error::ReplayDiverged(format!("read of {} with size {}, recorded size {}", input, size, event.value.len())));
+            }
+            memory.write_bytes(*input, &event.value);
+            return self.raise_recorded();
+        }
+
+        self.record(|peripheral| hook(peripheral, memory))?;
+
+        if self.replay.is_recording() {
+            self.replay.record_event(InputEvent {
+                timestamp: self.clock,
+                pc,
+                address: *input,
+                value: memory.read_bytes(*input, size),
+            });
+        }
+        Ok(())
+    }
+
+    // Init, a write or a step
+    pub(crate) fn call<F>(&mut self, hook: F) -> Result<(), Error>
+    where F: FnOnce(&mut P) -> Result<(), Error> {
+        self.hooks += 1;
+        if self.replay.is_replaying() {
+            return self.raise_recorded();
+        }
+        self.record(hook)
+    }
+
+    pub(crate) fn step<F>(&mut self, hook: F) -> Result<(), Error>
+    where F: FnOnce(&mut P) -> Result<(), Error> {
+        self.clock += 1;
+        self.call(hook)
+    }
+
+    // Run hook, logging the interrupts the peripheral raises in it when recording
+    fn record<F>(&mut self, hook: F) -> Result<(), Error>
+    where F: FnOnce(&mut P) -> Result<(), Error> {
+        if !self.replay.is_recording() {
+            return hook(&mut self.peripheral);
+        }
+        let mut before = Vec::new();
+        self.peripheral.for_each_interrupt(&mut |interrupt| before.push(interrupt.get_trigger_count()));
+        let result = hook(&mut self.peripheral);
+
+        let (replay, timestamp, call) = (&mut self.replay, self.clock, self.hooks);
+        let mut index = 0;
+        self.peripheral.for_each_interrupt(&mut |interrupt| {
+            let raised = interrupt.get_trigger_count().saturating_sub(before.get(index).copied().unwrap_or(0));
+            for _ in 0..raised {
+                replay.record_interrupt(InterruptEvent { timestamp, hook: call, name: interrupt.get_name().to_owned() });
+            }
+            index += 1;
+        });
+        result
+    }
+
+    // Raise the interrupts the peripheral raised in this hook call of the recorded run.
+    // The peripheral only raises enabled interrupts, so they are enabled as well.
+    fn raise_recorded(&mut self) -> Result<(), Error> {
+        for event in self.replay.next_interrupts(self.hooks) {
+            let mut found = false;
+            self.peripheral.for_each_interrupt(&mut |interrupt| {
+                if !found && interrupt.get_name() == event.name {
+                    interrupt.set_enable(true);
+                    interrupt.set_triggered(true);
+                    interrupt.add_trigger_count();
+                    found = true;
+                }
+            });
+            if !found {
+                return Err(Error::ReplayDiverged(format!("recorded interrupt {} is not raised by the peripheral", event.name)));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<P> PollingPeripheralHandler for RecordReplay<P>
+where P: PollingPeripheralHandler<Input=Address, Output=Address>
+{
+    type Input = Address;
+    type Output = Address;
+    type Order = P::Order;
+
+    fn init(&mut self, state: &mut PCodeState<u8, Self::Order>) -> Result<(), Error> {
+        self.call(|peripheral| peripheral.init(state))
+    }
+
+    fn handle_input(&mut self, state: &mut PCodeState<u8, Self::Order>, input: &Self::Input, size: usize) -> Result<(), Error> {
+        let pc = state.program_counter_value().map_err(|_| Error::HandleInputFailed)?;
+        self.input(state, input, size, pc, |peripheral, state| peripheral.handle_input(state, input, size))
+    }
+
+    fn handle_output(&mut self, state: &mut PCodeState<u8, Self::Order>, output: &Self::Output, value: &[u8], size: usize) -> Result<(), Error> {
+        self.call(|peripheral| peripheral.handle_output(state, output, value, size))
+    }
+
+    fn handle_step(&mut self, state: &mut PCodeState<u8, Self::Order>, address: &Address) -> Result<(), Error> {
+        self.step(|peripheral| peripheral.handle_step(state, address))
+    }
+
+    fn for_each_interrupt(&mut self, visit: &mut dyn FnMut(&mut backend::Interrupt)) {
+        self.peripheral.for_each_interrupt(visit)
+    }
+}
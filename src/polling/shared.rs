@@ -0,0 +1,88 @@
+use std::sync::Arc;
+use parking_lot::{Mutex, MutexGuard};
+
+use fugue::ir::Address;
+use metaemu::state::pcode::PCodeState;
+
+use crate::backend;
+use crate::polling::{PollingPeripheralHandler, Error};
+use crate::snapshot::PeripheralSnapshot;
+
+/// A handle to a polling peripheral that can be reached from more than one place,
+/// e.g. a test driver thread injecting CAN frames while the emulator runs.
+///
+/// `MemoryPollingPeripheral` owns its handler directly so single-threaded emulation
+/// does not pay for any locking. Only wrap a handler in `SharedPeripheral` when
+/// another thread needs access to it; every clone of the handle refers to the same
+/// peripheral.
+#[derive(Debug)]
+pub struct SharedPeripheral<P> {
+    peripheral: Arc<Mutex<P>>,
+}
+
+impl<P> Clone for SharedPeripheral<P> {
+    fn clone(&self) -> Self {
+        Self {
+            peripheral: self.peripheral.clone(),
+        }
+    }
+}
+
+impl<P> SharedPeripheral<P> {
+    pub fn new(peripheral: P) -> Self {
+        Self {
+            peripheral: Arc::new(Mutex::new(peripheral)),
+        }
+    }
+
+    // Lock the peripheral, blocking until it is available
+    pub fn lock(&self) -> MutexGuard<'_, P> {
+        self.peripheral.lock()
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, P>> {
+        self.peripheral.try_lock()
+    }
+}
+
+impl<P> PollingPeripheralHandler for SharedPeripheral<P>
+where P: PollingPeripheralHandler
+{
+    type Input = P::Input;
+    type Output = P::Output;
+    type Order = P::Order;
+
+    fn init(&mut self, state: &mut PCodeState<u8, Self::Order>) -> Result<(), Error> {
+        self.peripheral.lock().init(state)
+    }
+
+    fn handle_input(&mut self, state: &mut PCodeState<u8, Self::Order>, input: &Self::Input, size: usize) -> Result<(), Error> {
+        self.peripheral.lock().handle_input(state, input, size)
+    }
+
+    fn handle_output(&mut self, state: &mut PCodeState<u8, Self::Order>, output: &Self::Output, value: &[u8], size: usize) -> Result<(), Error> {
+        self.peripheral.lock().handle_output(state, output, value, size)
+    }
+
+    fn handle_step(&mut self, state: &mut PCodeState<u8, Self::Order>, address: &Address) -> Result<(), Error> {
+        self.peripheral.lock().handle_step(state, address)
+    }
+
+    fn for_each_interrupt(&mut self, visit: &mut dyn FnMut(&mut backend::Interrupt)) {
+        self.peripheral.lock().for_each_interrupt(visit)
+    }
+}
+
+impl<P> PeripheralSnapshot for SharedPeripheral<P>
+where P: PeripheralSnapshot
+{
+    type Snapshot = P::Snapshot;
+
+    fn snapshot(&self) -> Self::Snapshot {
+        self.peripheral.lock().snapshot()
+    }
+
+    fn restore(&mut self, snapshot: &Self::Snapshot) {
+        self.peripheral.lock().restore(snapshot)
+    }
+}
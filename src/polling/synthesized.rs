@@ -0,0 +1,203 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::marker::PhantomData;
+use std::path::Path;
+
+use fugue::bytes::Order;
+use fugue::ir::Address;
+use metaemu::state::pcode::PCodeState;
+use serde::{Serialize, Deserialize};
+
+use crate::polling::{PollingPeripheralHandler, Error};
+use crate::snapshot::PeripheralSnapshot;
+
+/// The value returned by a read of a peripheral register.
+///
+/// A rule without a PC applies at every access site, a rule without a prior write
+/// whatever was last written to the peripheral.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReadRule {
+    pub address: Address,
+    pub pc: Option<Address>,
+    pub after_write: Option<(Address, u64)>,    // The last write to the peripheral before the read
+    pub value: u64,
+    pub size: usize,
+}
+
+/// Read responses learned by solving the firmware's polling loops, see
+/// `DummyPeripheral::synthesize_model`. Stored as JSON so that it can be reviewed
+/// and edited by hand.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SynthesizedModel {
+    pub rules: Vec<ReadRule>,
+}
+
+impl SynthesizedModel {
+    pub fn load<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let reader = BufReader::new(File::open(path)?);
+        serde_json::from_reader(reader).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        let writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(writer, self).map_err(std::io::Error::other)
+    }
+
+    /// The most specific rule for a read of address at pc: matching PC and prior
+    /// write, then PC, then prior write, then the register alone.
+    pub fn response(&self, address: Address, pc: Address, last_write: Option<(Address, u64)>) -> Option<&ReadRule> {
+        let candidates = [
+            (Some(pc), last_write),
+            (Some(pc), None),
+            (None, last_write),
+            (None, None),
+        ];
+        candidates.iter()
+            .find_map(|(pc, after_write)| self.rules.iter()
+                .find(|rule| rule.address == address && rule.pc == *pc && rule.after_write == *after_write))
+    }
+}
+
+// Value of bytes in target memory order
+fn value_of<O: Order>(bytes: &[u8]) -> u64 {
+    let mut buffer = [0u8; 8];
+    let len = bytes.len().min(8);
+    if O::read_u16(&[0, 1]) == 1 {
+        buffer[8 - len..].copy_from_slice(&bytes[bytes.len() - len..]);
+    } else {
+        buffer[..len].copy_from_slice(&bytes[..len]);
+    }
+    O::read_u64(&buffer)
+}
+
+// The size least significant bytes of value in target memory order
+fn bytes_of<O: Order>(value: u64, size: usize) -> Vec<u8> {
+    let mut buffer = [0u8; 8];
+    O::write_u64(&mut buffer, value);
+    let size = size.min(8);
+    if O::read_u16(&[0, 1]) == 1 {
+        buffer[8 - size..].to_vec()
+    } else {
+        buffer[..size].to_vec()
+    }
+}
+
+/// Answers reads from a `SynthesizedModel`, so firmware runs with the learned
+/// behaviour of a peripheral without the solver. Reads without a rule return what
+/// is in memory.
+#[derive(Debug, Clone)]
+pub struct SynthesizedPeripheral<O> {
+    model: SynthesizedModel,
+    last_write: Option<(Address, u64)>,
+    order: PhantomData<O>,
+}
+
+impl<O: Order> SynthesizedPeripheral<O> {
+    pub fn new(model: SynthesizedModel) -> Self {
+        Self {
+            model,
+            last_write: None,
+            order: PhantomData,
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        Ok(Self::new(SynthesizedModel::load(path)?))
+    }
+
+    pub fn model(&self) -> &SynthesizedModel {
+        &self.model
+    }
+}
+
+impl<O: Order> PollingPeripheralHandler for SynthesizedPeripheral<O> {
+    type Input = Address;
+    type Output = Address;
+    type Order = O;
+
+    fn init(&mut self, _state: &mut PCodeState<u8, Self::Order>) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn handle_input(&mut self, state: &mut PCodeState<u8, Self::Order>, input: &Self::Input, size: usize) -> Result<(), Error> {
+        let pc = state.program_counter_value().map_err(|_| Error::HandleInputFailed)?;
+        if let Some(rule) = self.model.response(*input, pc, self.last_write) {
+            log::trace!("Read of {} at {} answered with {:#x}", input, pc, rule.value);
+            state.set_values(*input, &bytes_of::<O>(rule.value, size)).map_err(|_| Error::HandleInputFailed)?;
+        }
+        Ok(())
+    }
+
+    fn handle_output(&mut self, _state: &mut PCodeState<u8, Self::Order>, output: &Self::Output, value: &[u8], size: usize) -> Result<(), Error> {
+        self.last_write = Some((*output, value_of::<O>(&value[..size.min(value.len())])));
+        Ok(())
+    }
+}
+
+impl<O> PeripheralSnapshot for SynthesizedPeripheral<O> {
+    type Snapshot = Option<(Address, u64)>;
+
+    fn snapshot(&self) -> Self::Snapshot {
+        self.last_write
+    }
+
+    fn restore(&mut self, snapshot: &Self::Snapshot) {
+        self.last_write = *snapshot;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use fugue::bytes::{BE, LE};
+
+    #[test]
+    fn most_specific_rule() -> Result<(), String> {
+        let status = Address::from(0xffd00000u32);
+        let control = Address::from(0xffd00004u32);
+        let rule = |pc: Option<u32>, after_write: Option<u64>, value| ReadRule {
+            address: status,
+            pc: pc.map(Address::from),
+            after_write: after_write.map(|v| (control, v)),
+            value,
+            size: 4,
+        };
+        let model = SynthesizedModel {
+            rules: vec![rule(None, None, 1), rule(Some(0x100), None, 2), rule(None, Some(3), 3), rule(Some(0x100), Some(3), 4)],
+        };
+        let cases = [
+            (0x100u32, Some((control, 3)), 4),
+            (0x100, Some((control, 5)), 2),
+            (0x200, Some((control, 3)), 3),
+            (0x200, None, 1),
+        ];
+        for (pc, last_write, expected) in cases.iter() {
+            let value = model.response(status, Address::from(*pc), *last_write).map(|rule| rule.value);
+            if value != Some(*expected) {
+                return Err(format!("Read at {:#x} after {:?} returned {:?}, expected {}", pc, last_write, value, expected));
+            }
+        }
+
+        if bytes_of::<LE>(0x1234, 2) != [0x34, 0x12] || bytes_of::<BE>(0x1234, 2) != [0x12, 0x34] {
+            return Err(String::from("Unexpected byte order"));
+        }
+        if value_of::<BE>(&[0x12, 0x34]) != 0x1234 || value_of::<LE>(&[0x34, 0x12]) != 0x1234 {
+            return Err(String::from("Unexpected written value"));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot_saved_as_json() -> Result<(), String> {
+        let mut peripheral = SynthesizedPeripheral::<LE>::new(SynthesizedModel::default());
+        peripheral.last_write = Some((Address::from(0xffd00004u32), 3));
+        let json = serde_json::to_string(&peripheral.snapshot()).map_err(|e| e.to_string())?;
+
+        let mut restored = SynthesizedPeripheral::<LE>::new(SynthesizedModel::default());
+        restored.restore(&serde_json::from_str(&json).map_err(|e| e.to_string())?);
+        if restored.snapshot() != peripheral.snapshot() {
+            return Err(format!("Last write not restored from {}", json));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,230 @@
+//! Deterministic record and replay of the values peripherals return to the CPU.
+//!
+//! In record mode every value a peripheral hands to the firmware is logged with the
+//! virtual time and the PC of the access. In replay mode the same values are fed
+//! back in order without touching the outside world (sockets, clocks, solvers), so
+//! a run can be reproduced bit-exactly on another machine. The interrupts a peripheral
+//! raises are logged as well and raised again at the same point of the replayed run.
+
+use fugue::ir::Address;
+use serde::{Serialize, Deserialize};
+
+// A single value returned by a peripheral to the firmware
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InputEvent {
+    pub timestamp: u128,    // Virtual time (executed instructions) of the access
+    pub pc: Address,        // PC of the instruction accessing the peripheral
+    pub address: Address,   // Address read by the firmware
+    pub value: Vec<u8>,     // Bytes returned, in target memory order
+}
+
+// An interrupt raised by a peripheral
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InterruptEvent {
+    pub timestamp: u128,    // Virtual time (executed instructions) it was raised at
+    pub hook: u128,         // Index of the peripheral hook call (init, read, write or step) raising it
+    pub name: String,       // Name of the backend::Interrupt, e.g. CAN0ERR
+}
+
+// The ordered list of values returned and interrupts raised during a run
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InputLog {
+    events: Vec<InputEvent>,
+    #[serde(default)]
+    interrupts: Vec<InterruptEvent>,
+}
+
+impl InputLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, event: InputEvent) {
+        self.events.push(event);
+    }
+
+    pub fn events(&self) -> &[InputEvent] {
+        &self.events
+    }
+
+    pub fn push_interrupt(&mut self, event: InterruptEvent) {
+        self.interrupts.push(event);
+    }
+
+    pub fn interrupts(&self) -> &[InterruptEvent] {
+        &self.interrupts
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReplayMode {
+    Passthrough,    // Neither record nor replay
+    Record,         // Log every value returned to the firmware
+    Replay,         // Return logged values instead of asking the peripheral
+}
+
+impl Default for ReplayMode {
+    fn default() -> Self {
+        Self::Passthrough
+    }
+}
+
+// Keeps the log and the replay position for one peripheral
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct InputReplay {
+    mode: ReplayMode,
+    log: InputLog,
+    cursor: usize,
+    #[serde(default)]
+    interrupt_cursor: usize,
+}
+
+impl InputReplay {
+    pub fn passthrough() -> Self {
+        Self::default()
+    }
+
+    pub fn record() -> Self {
+        Self {
+            mode: ReplayMode::Record,
+            log: InputLog::new(),
+            cursor: 0,
+            interrupt_cursor: 0,
+        }
+    }
+
+    pub fn replay(log: InputLog) -> Self {
+        Self {
+            mode: ReplayMode::Replay,
+            log,
+            cursor: 0,
+            interrupt_cursor: 0,
+        }
+    }
+
+    pub fn mode(&self) -> ReplayMode {
+        self.mode
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.mode == ReplayMode::Record
+    }
+
+    pub fn is_replaying(&self) -> bool {
+        self.mode == ReplayMode::Replay
+    }
+
+    pub fn log(&self) -> &InputLog {
+        &self.log
+    }
+
+    pub fn into_log(self) -> InputLog {
+        self.log
+    }
+
+    // Number of logged events not replayed yet
+    pub fn remaining(&self) -> usize {
+        self.log.len().saturating_sub(self.cursor)
+    }
+
+    // Log an event; ignored unless recording
+    pub fn record_event(&mut self, event: InputEvent) {
+        if self.is_recording() {
+            self.log.push(event);
+        }
+    }
+
+    // Log an interrupt; ignored unless recording
+    pub fn record_interrupt(&mut self, event: InterruptEvent) {
+        if self.is_recording() {
+            self.log.push_interrupt(event);
+        }
+    }
+
+    // The logged interrupts raised up to hook call hook that were not replayed yet
+    pub fn next_interrupts(&mut self, hook: u128) -> Vec<InterruptEvent> {
+        let start = self.interrupt_cursor;
+        while self.log.interrupts.get(self.interrupt_cursor).is_some_and(|event| event.hook <= hook) {
+            self.interrupt_cursor += 1;
+        }
+        self.log.interrupts[start..self.interrupt_cursor].to_vec()
+    }
+
+    // Return the next logged event, which must be a read of address.
+    // A read of any other address, or running past the end of the log, means the
+    // firmware took a different path than in the recorded run.
+    pub fn next_event(&mut self, address: &Address) -> Result<&InputEvent, String> {
+        let event = self.log.events.get(self.cursor)
+            .ok_or_else(|| format!("read of {} after the end of the log ({} events)", address, self.log.len()))?;
+        if event.address != *address {
+            return Err(format!("event {} expected a read of {}, firmware read {}", self.cursor, event.address, address));
+        }
+        self.cursor += 1;
+        Ok(event)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn event(timestamp: u128, address: u32, value: u8) -> InputEvent {
+        InputEvent {
+            timestamp,
+            pc: Address::from(0x1000u32),
+            address: Address::from(address),
+            value: vec![value],
+        }
+    }
+
+    #[test]
+    fn record_then_replay() -> Result<(), String> {
+        let mut recorder = InputReplay::record();
+        recorder.record_event(event(1, 0xffd0008c, 0x0d));
+        recorder.record_event(event(5, 0xffd0008c, 0x00));
+
+        let mut replay = InputReplay::replay(recorder.into_log());
+        let first = replay.next_event(&Address::from(0xffd0008cu32))?.value.clone();
+        let second = replay.next_event(&Address::from(0xffd0008cu32))?.value.clone();
+
+        if first != vec![0x0d] || second != vec![0x00] || replay.remaining() != 0 {
+            return Err(String::from("Replayed values differ from the recorded ones"));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn replay_detects_divergence() -> Result<(), String> {
+        let mut log = InputLog::new();
+        log.push(event(1, 0xffd0008c, 0x0d));
+        let mut replay = InputReplay::replay(log);
+
+        if replay.next_event(&Address::from(0xffd00008u32)).is_ok() {
+            return Err(String::from("Read of another address was not reported"));
+        }
+        replay.next_event(&Address::from(0xffd0008cu32))?;
+        if replay.next_event(&Address::from(0xffd0008cu32)).is_ok() {
+            return Err(String::from("Read past the end of the log was not reported"));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn passthrough_does_not_record() -> Result<(), String> {
+        let mut replay = InputReplay::passthrough();
+        replay.record_event(event(1, 0xffd0008c, 0x0d));
+        if !replay.log().is_empty() {
+            return Err(String::from("Passthrough mode recorded an event"));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,18 @@
+use serde::{Serialize, de::DeserializeOwned};
+
+/// Checkpoint and restore the internal state of a peripheral.
+///
+/// A snapshot only holds the state the peripheral keeps outside of the emulated
+/// memory (counters, flags, queues, caches); the register contents themselves are
+/// saved with the machine state. Configuration set up at construction time, such
+/// as register maps and address ranges, is not part of the snapshot, so a snapshot
+/// must be restored into a peripheral built the same way.
+///
+/// Snapshots are plain serde types, so they can be written to disk in any serde
+/// format alongside the CPU and memory state.
+pub trait PeripheralSnapshot {
+    type Snapshot: Serialize + DeserializeOwned + Clone;
+
+    fn snapshot(&self) -> Self::Snapshot;
+    fn restore(&mut self, snapshot: &Self::Snapshot);
+}
@@ -0,0 +1,340 @@
+// CAN protocol error model of an RSCAN channel
+//
+// The emulated bus never produces errors by itself, they are injected by the test harness.
+// Error counters follow ISO 11898-1: an error seen while transmitting adds 8 to TEC, an
+// error seen while receiving adds 1 to REC, and every successful frame decrements the
+// counter again. Counters above 95 raise the error warning flag, above 127 the channel is
+// error passive and a TEC above 255 takes it bus-off until it recovers according to
+// CmCTR.BOM.
+
+use serde::{Serialize, Deserialize};
+use log::info;
+
+use crate::backend;
+use super::mode::ChannelMode;
+
+// CmERFL
+pub const ERFL_BEF: u32 = 1 << 0;       // Bus error
+pub const ERFL_EWF: u32 = 1 << 1;       // Error warning
+pub const ERFL_EPF: u32 = 1 << 2;       // Error passive
+pub const ERFL_BOEF: u32 = 1 << 3;      // Bus-off entry
+pub const ERFL_BORF: u32 = 1 << 4;      // Bus-off recovery
+pub const ERFL_OVLF: u32 = 1 << 5;      // Overload
+pub const ERFL_BLF: u32 = 1 << 6;       // Bus lock
+pub const ERFL_ALF: u32 = 1 << 7;       // Arbitration lost
+pub const ERFL_SERR: u32 = 1 << 8;      // Stuff error
+pub const ERFL_FERR: u32 = 1 << 9;      // Form error
+pub const ERFL_AERR: u32 = 1 << 10;     // ACK error
+pub const ERFL_CERR: u32 = 1 << 11;     // CRC error
+pub const ERFL_B1ERR: u32 = 1 << 12;    // Recessive bit error
+pub const ERFL_B0ERR: u32 = 1 << 13;    // Dominant bit error
+pub const ERFL_ADERR: u32 = 1 << 14;    // ACK delimiter error
+const ERFL_PROTOCOL_ERRORS: u32 = 0x7F00;
+const ERFL_FLAGS: u32 = 0x7FFF;
+
+// CmCTR
+pub const CCTR_RTBO: u32 = 1 << 3;      // Forcible return from bus-off
+const CCTR_IE_SHIFT: u32 = 8;           // BEIE(8) .. ALIE(15), in the order of the CmERFL flags
+const CCTR_IE_MASK: u32 = 0xFF << CCTR_IE_SHIFT;
+const CCTR_BOM_SHIFT: u32 = 21;
+const CCTR_ERRD: u32 = 1 << 23;         // Display all protocol errors instead of the first one
+
+// CmSTS
+pub const CSTS_EPSTS: u32 = 1 << 3;
+pub const CSTS_BOSTS: u32 = 1 << 4;
+
+const WARNING_LIMIT: u16 = 95;
+const PASSIVE_LIMIT: u16 = 127;
+const BUS_OFF_LIMIT: u16 = 255;
+
+// Steps until a bus-off channel has seen 128 occurrences of 11 recessive bits
+pub const BUS_OFF_RECOVERY_STEPS: u32 = 128 * 11;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BusErrorKind {
+    Stuff,
+    Form,
+    Ack,
+    Crc,
+    RecessiveBit,
+    DominantBit,
+    AckDelimiter,
+    Overload,
+    ArbitrationLost,
+    BusLock,
+}
+
+impl BusErrorKind {
+    fn flag(&self) -> u32 {
+        match self {
+            Self::Stuff => ERFL_SERR,
+            Self::Form => ERFL_FERR,
+            Self::Ack => ERFL_AERR,
+            Self::Crc => ERFL_CERR,
+            Self::RecessiveBit => ERFL_B1ERR,
+            Self::DominantBit => ERFL_B0ERR,
+            Self::AckDelimiter => ERFL_ADERR,
+            Self::Overload => ERFL_OVLF,
+            Self::ArbitrationLost => ERFL_ALF,
+            Self::BusLock => ERFL_BLF,
+        }
+    }
+
+    // Protocol errors are counted, the other events only raise their flag
+    fn is_protocol_error(&self) -> bool {
+        self.flag() & ERFL_PROTOCOL_ERRORS != 0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Direction {
+    Transmit,
+    Receive,
+}
+
+// Bus-off recovery mode selected by CmCTR.BOM
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BusOffRecovery {
+    Iso11898,           // Recover automatically
+    HaltOnEntry,        // Enter channel halt mode when going bus-off
+    HaltOnEnd,          // Enter channel halt mode after recovering
+    Program,            // Recover automatically, the firmware requests halt mode itself
+}
+
+impl BusOffRecovery {
+    pub fn from_cctr(cctr: u32) -> Self {
+        match (cctr >> CCTR_BOM_SHIFT) & 0x3 {
+            0 => Self::Iso11898,
+            1 => Self::HaltOnEntry,
+            2 => Self::HaltOnEnd,
+            _ => Self::Program,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChannelErrors {
+    tec: u16,
+    rec: u16,
+    erfl: u32,
+    bus_off: Option<u32>,           // Remaining recovery steps while bus-off
+    interrupt: backend::Interrupt,  // Channel error interrupt
+}
+
+impl ChannelErrors {
+    pub fn new(channel: usize) -> Self {
+        Self {
+            tec: 0,
+            rec: 0,
+            erfl: 0,
+            bus_off: None,
+            interrupt: backend::Interrupt::new(&format!("CAN{}ERR", channel)),
+        }
+    }
+
+    pub fn tec(&self) -> u16 {
+        self.tec
+    }
+
+    pub fn rec(&self) -> u16 {
+        self.rec
+    }
+
+    pub fn erfl(&self) -> u32 {
+        self.erfl
+    }
+
+    pub fn is_error_passive(&self) -> bool {
+        self.bus_off.is_none() && (self.tec > PASSIVE_LIMIT || self.rec > PASSIVE_LIMIT)
+    }
+
+    pub fn is_bus_off(&self) -> bool {
+        self.bus_off.is_some()
+    }
+
+    pub fn interrupt(&self) -> &backend::Interrupt {
+        &self.interrupt
+    }
+
+    pub fn interrupt_mut(&mut self) -> &mut backend::Interrupt {
+        &mut self.interrupt
+    }
+
+    // Error bits of CmSTS
+    pub fn status(&self) -> u32 {
+        let mut status = (self.tec.min(BUS_OFF_LIMIT) as u32) << 24 | (self.rec.min(BUS_OFF_LIMIT) as u32) << 16;
+        if self.is_error_passive() {
+            status |= CSTS_EPSTS;
+        }
+        if self.is_bus_off() {
+            status |= CSTS_BOSTS;
+        }
+        status
+    }
+
+    // Counters and flags are cleared in channel reset mode
+    pub fn reset(&mut self) {
+        self.tec = 0;
+        self.rec = 0;
+        self.erfl = 0;
+        self.bus_off = None;
+    }
+
+    // Firmware wrote CmCTR, update the interrupt enables and handle a forced bus-off recovery.
+    // Returns the mode the channel is forced into by the recovery.
+    pub fn write_cctr(&mut self, cctr: u32) -> Option<ChannelMode> {
+        self.interrupt.set_enable(cctr & CCTR_IE_MASK != 0);
+        if cctr & CCTR_RTBO != 0 && self.is_bus_off() {
+            return self.recover(cctr);
+        }
+        None
+    }
+
+    // Firmware wrote CmERFL, flags are cleared by writing 0
+    pub fn write_erfl(&mut self, erfl: u32) {
+        self.erfl &= erfl | !ERFL_FLAGS;
+    }
+
+    fn raise(&mut self, flags: u32, cctr: u32) {
+        let new = flags & !self.erfl;
+        self.erfl |= flags;
+        if new & (cctr >> CCTR_IE_SHIFT) & 0xFF != 0 && self.interrupt.is_enabled() {
+            self.interrupt.set_triggered(true);
+            self.interrupt.add_trigger_count();
+        }
+    }
+
+    // Inject an error on the bus, returns the mode the channel is forced into, if any
+    pub fn inject(&mut self, kind: BusErrorKind, direction: Direction, cctr: u32) -> Option<ChannelMode> {
+        if self.is_bus_off() {
+            return None;
+        }
+        if !kind.is_protocol_error() {
+            self.raise(kind.flag(), cctr);
+            return None;
+        }
+
+        // Without ERRD only the first protocol error since the flags were cleared is shown
+        let mut flags = ERFL_BEF;
+        if cctr & CCTR_ERRD != 0 || self.erfl & ERFL_PROTOCOL_ERRORS == 0 {
+            flags |= kind.flag();
+        }
+        let was_warning = self.tec > WARNING_LIMIT || self.rec > WARNING_LIMIT;
+        let was_passive = self.is_error_passive();
+        match direction {
+            Direction::Transmit => self.tec += 8,
+            Direction::Receive => self.rec = (self.rec + 1).min(BUS_OFF_LIMIT + 1),
+        }
+        if !was_warning && (self.tec > WARNING_LIMIT || self.rec > WARNING_LIMIT) {
+            flags |= ERFL_EWF;
+        }
+
+        let mut forced = None;
+        if self.tec > BUS_OFF_LIMIT {
+            info!("{} bus-off, TEC {}", self.interrupt.get_name(), self.tec);
+            flags |= ERFL_BOEF;
+            self.bus_off = Some(BUS_OFF_RECOVERY_STEPS);
+            if BusOffRecovery::from_cctr(cctr) == BusOffRecovery::HaltOnEntry {
+                forced = Some(ChannelMode::Halt);
+            }
+        } else if !was_passive && self.is_error_passive() {
+            flags |= ERFL_EPF;
+        }
+        self.raise(flags, cctr);
+        forced
+    }
+
+    fn recover(&mut self, cctr: u32) -> Option<ChannelMode> {
+        info!("{} recovered from bus-off", self.interrupt.get_name());
+        self.tec = 0;
+        self.rec = 0;
+        self.bus_off = None;
+        self.raise(ERFL_BORF, cctr);
+        match BusOffRecovery::from_cctr(cctr) {
+            BusOffRecovery::HaltOnEnd => Some(ChannelMode::Halt),
+            _ => None,
+        }
+    }
+
+    // Advance the bus-off recovery by one step
+    pub fn step(&mut self, cctr: u32) -> Option<ChannelMode> {
+        match self.bus_off {
+            Some(0) => self.recover(cctr),
+            Some(remaining) => {
+                self.bus_off = Some(remaining - 1);
+                None
+            },
+            None => None,
+        }
+    }
+
+    pub fn transmitted(&mut self) {
+        self.tec = self.tec.saturating_sub(1);
+    }
+
+    pub fn received(&mut self) {
+        if self.rec > PASSIVE_LIMIT {
+            self.rec = 119;
+        } else {
+            self.rec = self.rec.saturating_sub(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn error_passive_and_bus_off() -> Result<(), String> {
+        let cctr = 1 << 21 | 0xFF << 8;        // Halt on bus-off entry, all interrupts enabled
+        let mut errors = ChannelErrors::new(0);
+        errors.write_cctr(cctr);
+
+        for _ in 0..16 {
+            errors.inject(BusErrorKind::Ack, Direction::Transmit, cctr);
+        }
+        if !errors.is_error_passive() || errors.erfl() & (ERFL_EWF | ERFL_EPF | ERFL_AERR) != ERFL_EWF | ERFL_EPF | ERFL_AERR {
+            return Err(format!("Not error passive, TEC {} ERFL {:#x}", errors.tec(), errors.erfl()));
+        }
+        if !errors.interrupt().is_triggered() {
+            return Err(String::from("Error interrupt not triggered"));
+        }
+
+        let forced = (0..16).filter_map(|_| errors.inject(BusErrorKind::Stuff, Direction::Transmit, cctr)).next();
+        if !errors.is_bus_off() || forced != Some(ChannelMode::Halt) || errors.status() & CSTS_BOSTS == 0 {
+            return Err(format!("Not bus-off, TEC {} status {:#x}", errors.tec(), errors.status()));
+        }
+        // Only the first protocol error is displayed without ERRD
+        if errors.erfl() & ERFL_SERR != 0 {
+            return Err(format!("Stuff error displayed after ACK error, ERFL {:#x}", errors.erfl()));
+        }
+
+        (0..=BUS_OFF_RECOVERY_STEPS).for_each(|_| { errors.step(cctr); });
+        if errors.is_bus_off() || errors.tec() != 0 || errors.erfl() & ERFL_BORF == 0 {
+            return Err(String::from("Bus-off recovery did not happen"));
+        }
+
+        errors.write_erfl(0);
+        if errors.erfl() != 0 {
+            return Err(format!("Flags not cleared: {:#x}", errors.erfl()));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn forced_recovery_halts_on_end() -> Result<(), String> {
+        let cctr = 2 << CCTR_BOM_SHIFT;         // Halt after recovering
+        let mut errors = ChannelErrors::new(0);
+        if errors.write_cctr(cctr | CCTR_RTBO).is_some() {
+            return Err(String::from("Recovered without being bus-off"));
+        }
+        while !errors.is_bus_off() {
+            errors.inject(BusErrorKind::Stuff, Direction::Transmit, cctr);
+        }
+        match errors.write_cctr(cctr | CCTR_RTBO) {
+            Some(ChannelMode::Halt) if !errors.is_bus_off() => Ok(()),
+            forced => Err(format!("Forced recovery returned {:?}", forced)),
+        }
+    }
+}
@@ -0,0 +1,202 @@
+// Global receive rule table (acceptance filter) of the RSCAN module
+//
+// Rules are assigned to the channels in order: the first GAFLCFG.RNC0 rules belong to
+// channel 0, the next RNC1 rules to channel 1 and so on. A received frame is checked
+// against the rules of its channel in ascending order and the first matching rule
+// decides where the frame is stored; frames matching no rule are dropped.
+
+use serde::{Serialize, Deserialize};
+
+use super::message::CanMessage;
+use super::regs::{NUM_CHANNELS, NUM_RULES_PER_PAGE};
+
+pub const NUM_RULE_PAGES: usize = 12;
+pub const NUM_RULES: usize = NUM_RULE_PAGES * NUM_RULES_PER_PAGE;
+
+// GAFLECTR
+pub const GAFLECTR_AFLPN_MASK: u32 = 0x1F;
+pub const GAFLECTR_AFLDAE: u32 = 1 << 8;
+
+// One entry of the rule table, as written through GAFLIDj/GAFLMj/GAFLP0j/GAFLP1j
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReceiveRule {
+    pub id: u32,    // GAFLIDj: GAFLIDE(31), GAFLRTR(30), GAFLLB(29), GAFLID(28:0)
+    pub mask: u32,  // GAFLMj: GAFLIDEM(31), GAFLRTRM(30), GAFLIDM(28:0)
+    pub p0: u32,    // GAFLP0j: GAFLDLC(31:28), GAFLPTR(27:16), GAFLRMV(15), GAFLRMDP(14:8)
+    pub p1: u32,    // GAFLP1j: GAFLFDP, common FIFOs(25:8), receive FIFOs(7:0)
+}
+
+impl ReceiveRule {
+    const IDE: u32 = 1 << 31;
+    const RTR: u32 = 1 << 30;
+    const LB: u32 = 1 << 29;
+    const ID_MASK: u32 = 0x1FFFFFFF;
+
+    // Check the ID, IDE and RTR of a received frame against the rule
+    pub fn matches(&self, message: &CanMessage) -> bool {
+        // Loopback rules only accept frames transmitted by the module itself
+        if self.id & Self::LB != 0 {
+            return false;
+        }
+        if self.mask & Self::IDE != 0 && (self.id & Self::IDE != 0) != message.extended {
+            return false;
+        }
+        if self.mask & Self::RTR != 0 && (self.id & Self::RTR != 0) != message.remote {
+            return false;
+        }
+        (self.id ^ message.id) & self.mask & Self::ID_MASK == 0
+    }
+
+    pub fn min_dlc(&self) -> u8 {
+        (self.p0 >> 28) as u8
+    }
+
+    pub fn label(&self) -> u16 {
+        ((self.p0 >> 16) & 0xFFF) as u16
+    }
+
+    // Receive buffer selected by the rule
+    pub fn rx_buffer(&self) -> Option<usize> {
+        if self.p0 & (1 << 15) != 0 {
+            Some(((self.p0 >> 8) & 0x7F) as usize)
+        } else {
+            None
+        }
+    }
+
+    // Receive FIFOs selected by the rule
+    pub fn rx_fifos(&self) -> impl Iterator<Item = usize> {
+        let p1 = self.p1;
+        (0..8).filter(move |x| p1 & (1 << x) != 0)
+    }
+
+    // Common FIFOs selected by the rule
+    pub fn common_fifos(&self) -> impl Iterator<Item = usize> {
+        let p1 = self.p1;
+        (0..18).filter(move |k| p1 & (1 << (k + 8)) != 0)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReceiveRuleTable {
+    rules: Vec<ReceiveRule>,
+}
+
+impl Default for ReceiveRuleTable {
+    fn default() -> Self {
+        Self {
+            rules: vec![ReceiveRule::default(); NUM_RULES],
+        }
+    }
+}
+
+impl ReceiveRuleTable {
+    // Rule j of the page selected by GAFLECTR
+    pub fn entry(&self, gaflectr: u32, j: usize) -> Option<&ReceiveRule> {
+        let page = (gaflectr & GAFLECTR_AFLPN_MASK) as usize;
+        self.rules.get(page * NUM_RULES_PER_PAGE + j)
+    }
+
+    // Rule j of the page selected by GAFLECTR, only writable while GAFLECTR.AFLDAE is set
+    pub fn entry_mut(&mut self, gaflectr: u32, j: usize) -> Option<&mut ReceiveRule> {
+        if gaflectr & GAFLECTR_AFLDAE == 0 {
+            return None;
+        }
+        let page = (gaflectr & GAFLECTR_AFLPN_MASK) as usize;
+        self.rules.get_mut(page * NUM_RULES_PER_PAGE + j)
+    }
+
+    // Number of rules of every channel, from GAFLCFG0 (RNC0-RNC3) and GAFLCFG1 (RNC4-RNC5)
+    fn rule_counts(gaflcfg: [u32; 2]) -> [usize; NUM_CHANNELS] {
+        let mut counts = [0usize; NUM_CHANNELS];
+        for (channel, count) in counts.iter_mut().enumerate() {
+            let shift = 24 - 8 * (channel % 4);
+            *count = ((gaflcfg[channel / 4] >> shift) & 0xFF) as usize;
+        }
+        counts
+    }
+
+    // First rule of channel accepting the message, None if the message is dropped.
+    // With dlc_check (GCFG.DCE) a message shorter than the rule DLC is dropped even
+    // though its ID matches.
+    pub fn find(&self, channel: usize, message: &CanMessage, gaflcfg: [u32; 2], dlc_check: bool) -> Option<&ReceiveRule> {
+        let counts = Self::rule_counts(gaflcfg);
+        let first: usize = counts[..channel].iter().sum();
+        let last = std::cmp::min(first + counts[channel], self.rules.len());
+
+        let rule = self.rules[first.min(last)..last].iter().find(|rule| rule.matches(message))?;
+        if dlc_check && message.dlc < rule.min_dlc() {
+            return None;
+        }
+        Some(rule)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rule(id: u32, mask: u32, p0: u32, p1: u32) -> ReceiveRule {
+        ReceiveRule { id, mask, p0, p1 }
+    }
+
+    fn table(rules: &[(usize, ReceiveRule)]) -> ReceiveRuleTable {
+        let mut table = ReceiveRuleTable::default();
+        for (index, rule) in rules {
+            *table.entry_mut(GAFLECTR_AFLDAE | (index / NUM_RULES_PER_PAGE) as u32, index % NUM_RULES_PER_PAGE).unwrap() = *rule;
+        }
+        table
+    }
+
+    #[test]
+    fn rules_match_per_channel() -> Result<(), String> {
+        // Channel 0 owns rule 0, channel 1 owns rules 1 and 2
+        let table = table(&[
+            (0, rule(0x100, 0x1FFFFFFF, 0, 0x01)),
+            (1, rule(0x200, 0x1FFFFF00, 0, 0x02)),
+            (2, rule(0x000, 0x00000000, 0, 0x04)),
+        ]);
+        let gaflcfg = [0x01020000, 0];
+
+        let exact = table.find(0, &CanMessage::new(0x100, &[0; 8]), gaflcfg, false);
+        if exact.map(|r| r.rx_fifos().collect::<Vec<_>>()) != Some(vec![0]) {
+            return Err(String::from("Exact ID rule did not match"));
+        }
+        if table.find(0, &CanMessage::new(0x101, &[0; 8]), gaflcfg, false).is_some() {
+            return Err(String::from("Frame matching no rule of the channel was accepted"));
+        }
+        let masked = table.find(1, &CanMessage::new(0x2AB, &[0; 8]), gaflcfg, false);
+        if masked.map(|r| r.rx_fifos().collect::<Vec<_>>()) != Some(vec![1]) {
+            return Err(String::from("Masked rule did not take priority"));
+        }
+        let catch_all = table.find(1, &CanMessage::new(0x100, &[0; 8]), gaflcfg, false);
+        if catch_all.map(|r| r.rx_fifos().collect::<Vec<_>>()) != Some(vec![2]) {
+            return Err(String::from("Catch-all rule did not match"));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn ide_rtr_and_dlc_checks() -> Result<(), String> {
+        // Extended data frames only, at least 4 bytes, stored in receive buffer 3
+        let table = table(&[
+            (0, rule(0x80012345, 0xDFFFFFFF, 0x4000_8300, 0)),
+        ]);
+        let gaflcfg = [0x01000000, 0];
+
+        let mut remote = CanMessage::new(0x12345, &[0; 8]);
+        remote.remote = true;
+        if table.find(0, &remote, gaflcfg, true).is_some() {
+            return Err(String::from("Remote frame matched a data frame rule"));
+        }
+        if table.find(0, &CanMessage::new(0x12345, &[0; 2]), gaflcfg, true).is_some() {
+            return Err(String::from("Short frame passed the DLC check"));
+        }
+        let accepted = table.find(0, &CanMessage::new(0x12345, &[0; 4]), gaflcfg, true);
+        if accepted.and_then(|r| r.rx_buffer()) != Some(3) {
+            return Err(String::from("Frame was not routed to the receive buffer"));
+        }
+        Ok(())
+    }
+}
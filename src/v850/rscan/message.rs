@@ -0,0 +1,92 @@
+use serde::{Serialize, Deserialize};
+
+use crate::can::CanFrame;
+pub use crate::can::frame::{dlc_to_len, len_to_dlc, SFF_MASK, EFF_MASK};
+
+// A CAN message as stored in the receive/transmit buffers and FIFOs of the model
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CanMessage {
+    pub id: u32,            // 11 bit standard or 29 bit extended identifier
+    pub extended: bool,     // IDE
+    pub remote: bool,       // RTR
+    pub dlc: u8,
+    pub data: Vec<u8>,
+    pub label: u16,         // Receive rule label (GAFLPTR), 0 when not received through a rule
+    pub timestamp: u16,
+    pub fd: bool,           // FDF
+    pub brs: bool,          // Bit rate switch of a CAN FD frame
+    pub esi: bool,          // Error state indicator of a CAN FD frame
+}
+
+impl CanMessage {
+    // Identifiers above 0x7FF are treated as extended identifiers
+    pub fn new(id: u32, data: &[u8]) -> Self {
+        Self::from(&CanFrame::new(id, data))
+    }
+
+    // Classical data frame with an explicit identifier format
+    pub fn with_format(id: u32, extended: bool, data: &[u8]) -> Self {
+        Self::from(&CanFrame::with_format(id, extended, data))
+    }
+
+    // Classical remote frame requesting dlc bytes
+    pub fn new_remote(id: u32, extended: bool, dlc: u8) -> Self {
+        Self::from(&CanFrame::new_remote(id, extended, dlc))
+    }
+
+    // CAN FD frame, the payload is padded to the next valid length
+    pub fn new_fd(id: u32, extended: bool, brs: bool, data: &[u8]) -> Self {
+        Self::from(&CanFrame::new_fd(id, extended, brs, data))
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    // Data word as seen through the xxDFb registers, byte 0 is the least significant
+    pub fn data_word(&self, word: usize) -> u32 {
+        let mut bytes = [0u8; 4];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = self.data.get(word * 4 + i).copied().unwrap_or(0);
+        }
+        u32::from_le_bytes(bytes)
+    }
+}
+
+// Label and timestamp are assigned by the receive path of the controller
+impl From<&CanFrame> for CanMessage {
+    fn from(frame: &CanFrame) -> Self {
+        Self {
+            id: frame.id,
+            extended: frame.extended,
+            remote: frame.remote,
+            dlc: frame.dlc,
+            data: frame.data.clone(),
+            label: 0,
+            timestamp: 0,
+            fd: frame.fd,
+            brs: frame.brs,
+            esi: frame.esi,
+        }
+    }
+}
+
+impl From<&CanMessage> for CanFrame {
+    fn from(message: &CanMessage) -> Self {
+        Self {
+            id: message.id,
+            extended: message.extended,
+            remote: message.remote,
+            dlc: message.dlc,
+            data: message.data.clone(),
+            fd: message.fd,
+            brs: message.brs,
+            esi: message.esi,
+            timestamp: 0,
+        }
+    }
+}
@@ -0,0 +1,1559 @@
+use std::collections::VecDeque;
+use crate::polling::PollingPeripheralHandler;
+pub use crate::polling::RegisterMemory;
+use crate::polling;
+use crate::backend;
+use crate::backend::CloneMode;
+use crate::snapshot::PeripheralSnapshot;
+use serde::{Serialize, Deserialize};
+
+use fugue::ir::{
+    Address,
+};
+use metaemu::state::{
+    AsState,
+    pcode::PCodeState, StateOps
+};
+use fugue::bytes::{Order};
+use std::marker::PhantomData;
+use std::collections::{HashMap};
+use thiserror::Error;
+
+use std::convert::TryInto;
+
+use log::{info, warn};
+
+pub mod regs;
+pub mod message;
+pub mod filter;
+pub mod mode;
+pub mod bus_error;
+pub mod timestamp;
+pub mod transmit;
+pub use regs::{Register, Layout};
+pub use message::CanMessage;
+pub use filter::{ReceiveRule, ReceiveRuleTable};
+pub use mode::{ModeController, GlobalMode, ChannelMode, IllegalTransition};
+pub use bus_error::{ChannelErrors, BusErrorKind, Direction};
+pub use timestamp::TimestampCounter;
+pub use transmit::{TransmitUnit, TxSource, TxMessage};
+use transmit::Outcome;
+use message::{EFF_MASK, SFF_MASK};
+use crate::can::{self, CanBus, CanFrame, SocketCanBus};
+use regs::{
+    fifo_depth,
+    ID_IDE,
+    ID_RTR,
+    FDSTS_ESI,
+    FDSTS_BRS,
+    FDSTS_FDF,
+    FDSTS_PTR_SHIFT,
+    GCFG_DCE,
+    GCFG_DRE,
+    FIFO_ENABLE,
+    FIFO_EMPTY,
+    FIFO_MSG_LOST,
+    FIFO_POINTER_NEXT,
+    CFCC_CFM_SHIFT,
+    CFCC_CFM_TRANSMIT,
+    RSCAN0_BASE,
+    NUM_CHANNELS,
+    NUM_RX_BUFFERS,
+    NUM_RX_FIFOS,
+    NUM_COMMON_FIFOS,
+    NUM_TX_BUFFERS_PER_CHANNEL,
+};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error(transparent)]
+    Bus(#[from] can::Error),
+    #[error("CAN: Can not get regisiter with name: {0}")]
+    RSCanReg(String),
+    #[error("CAN: channel {0} does not exist")]
+    RSCanChannel(usize),
+}
+
+impl From<Error> for polling::Error {
+    fn from(error: Error) -> polling::Error {
+        polling::Error::HandlerError("RSCan-peripheral".to_string() + format!("{:?}",error).as_str() )
+    }
+}
+
+// A CAN channel of the controller and the bus it is attached to
+#[derive(Debug, Default)]
+struct Channel {
+    bus: Option<Box<dyn CanBus>>,   // None if the channel is not attached, sent frames go to the sent queue
+    clone_mode: CloneMode,          // Whether a clone gets a private fork of the bus or joins it
+}
+
+// A snapshot cloned channel gets a private fork of the bus, a shared one joins the bus
+// as another node. Buses that can do neither (e.g. a capture file being written) leave
+// the clone detached.
+impl Clone for Channel {
+    fn clone(&self) -> Self {
+        let bus = self.bus.as_ref().and_then(|bus| {
+            let clone = match self.clone_mode {
+                CloneMode::Snapshot => bus.fork(),
+                CloneMode::Shared => bus.join(),
+            };
+            match clone {
+                Ok(clone) => Some(clone),
+                Err(e) => {
+                    warn!("Cloned CAN channel is detached from its bus: {}", e);
+                    None
+                }
+            }
+        });
+        Self { bus, clone_mode: self.clone_mode }
+    }
+}
+
+// Receive buffer q
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RxBuffer {
+    message: Option<CanMessage>,
+    new_data: bool,     // RMNDy bit of the buffer
+}
+
+#[derive(Debug)]
+pub struct RSCan<S, O>
+    where S: AsState<PCodeState<u8, O>> + StateOps,
+          O: Order
+{
+    channels: Vec<Channel>,
+    state: PhantomData<S>,
+    regisiters: HashMap<String, Address>,
+    layout: Layout,                             // Classical CAN or RS-CANFD register map
+    rx_fifos: Vec<VecDeque<CanMessage>>,        // Receive FIFO x
+    rx_buffers: Vec<RxBuffer>,                  // Receive buffer q
+    common_fifos: Vec<VecDeque<CanMessage>>,    // Transmit/receive FIFO k
+    sent_queue: VecDeque<(usize, CanMessage)>,  // (channel, message) sent by the firmware on detached channels
+    rx_fifo_lost: Vec<bool>,                    // RFMLT of receive FIFO x
+    common_fifo_lost: Vec<bool>,                // CFMLT of common FIFO k
+    rules: ReceiveRuleTable,
+    shadow_regs: HashMap<Register, u32>,        // Last value written by the firmware to each register
+    modes: ModeController,
+    errors: Vec<ChannelErrors>,                 // Protocol error state of channel m
+    timestamp: TimestampCounter,
+    transmits: TransmitUnit,
+    now: u64,                                   // Virtual time on the buses, in emulation steps
+    order: PhantomData<O>,
+}
+
+impl<S, O> Default for RSCan<S, O>
+    where S: AsState<PCodeState<u8, O>> + StateOps,
+          O: Order
+{
+    fn default() -> Self {
+        Self::detached()
+    }
+
+}
+
+impl<S, O> Clone for RSCan<S, O>
+    where S: AsState<PCodeState<u8, O>> + StateOps,
+          O: Order
+{
+    // Cloning takes a snapshot of the controller: pending messages are copied so a
+    // forked machine state sees the same receive queue but never the other fork's
+    // dequeues. Attached buses are forked privately, frames a fork sends reach neither
+    // the bus nor the other forks; see set_bus_clone_mode to have clones join the bus.
+    // Wrap the RSCan in a SharedPeripheral to have all forks drive the same controller
+    // instead.
+    fn clone(&self) -> Self {
+        Self {
+            channels: self.channels.clone(),
+            state: PhantomData,
+            regisiters: self.regisiters.clone(),
+            layout: self.layout,
+            rx_fifos: self.rx_fifos.clone(),
+            rx_buffers: self.rx_buffers.clone(),
+            common_fifos: self.common_fifos.clone(),
+            sent_queue: self.sent_queue.clone(),
+            rx_fifo_lost: self.rx_fifo_lost.clone(),
+            common_fifo_lost: self.common_fifo_lost.clone(),
+            rules: self.rules.clone(),
+            shadow_regs: self.shadow_regs.clone(),
+            modes: self.modes.clone(),
+            errors: self.errors.clone(),
+            timestamp: self.timestamp.clone(),
+            transmits: self.transmits.clone(),
+            now: self.now,
+            order: PhantomData
+        }
+    }
+}
+
+
+impl<S, O> RSCan<S, O>
+    where S: AsState<PCodeState<u8, O>> + StateOps,
+            O: Order
+{
+    fn get_peripheral_regs(layout: Layout) -> HashMap<String, Address>{
+        layout.registers().into_iter()
+            .filter_map(|reg| Some((reg.name(), Address::from(RSCAN0_BASE + layout.offset(&reg)?))))
+            .collect()
+    }
+
+    fn detached() -> Self {
+        Self {
+            channels: vec![Channel::default(); NUM_CHANNELS],
+            state: PhantomData,
+            regisiters : Self::get_peripheral_regs(Layout::Classic),
+            layout: Layout::Classic,
+            rx_fifos: vec![VecDeque::new(); NUM_RX_FIFOS],
+            rx_buffers: vec![RxBuffer::default(); NUM_RX_BUFFERS],
+            common_fifos: vec![VecDeque::new(); NUM_COMMON_FIFOS],
+            sent_queue: VecDeque::new(),
+            rx_fifo_lost: vec![false; NUM_RX_FIFOS],
+            common_fifo_lost: vec![false; NUM_COMMON_FIFOS],
+            rules: ReceiveRuleTable::default(),
+            shadow_regs: HashMap::new(),
+            modes: ModeController::default(),
+            errors: (0..NUM_CHANNELS).map(ChannelErrors::new).collect(),
+            timestamp: TimestampCounter::default(),
+            transmits: TransmitUnit::default(),
+            now: 0,
+            order: PhantomData
+        }
+    }
+
+    // Channel 0 attached to the given SocketCAN interface
+    pub fn new<I: AsRef<str>>(virtual_can_interface: I) -> Result<Self, Error> {
+        Self::new_channels(&[virtual_can_interface])
+    }
+
+    // Channel m attached to the m-th SocketCAN interface
+    pub fn new_channels<I: AsRef<str>>(virtual_can_interfaces: &[I]) -> Result<Self, Error> {
+        if virtual_can_interfaces.len() > NUM_CHANNELS {
+            return Err(Error::RSCanChannel(NUM_CHANNELS));
+        }
+        let mut slf = Self::detached();
+        for (channel, interface) in virtual_can_interfaces.iter().enumerate() {
+            slf.attach_bus(channel, Box::new(SocketCanBus::open(interface.as_ref())?))?;
+        }
+        Ok(slf)
+    }
+
+    // No channel attached to a bus, messages are pushed with enqueue_message and sent
+    // messages are read with dequeue_sent_msg
+    pub fn new_queued() -> Result<Self, Error> {
+        Ok(Self::detached())
+    }
+
+    // Attach channel to bus, replacing the bus it was attached to
+    pub fn attach_bus(&mut self, channel: usize, bus: Box<dyn CanBus>) -> Result<(), Error> {
+        let channel = self.channels.get_mut(channel).ok_or(Error::RSCanChannel(channel))?;
+        channel.bus = Some(bus);
+        Ok(())
+    }
+
+    // With CloneMode::Shared a clone of the controller joins the buses of its channels as
+    // another node, so forks see each other's frames and SocketCAN forks send on the
+    // interface. CloneMode::Snapshot (the default) keeps forks apart.
+    pub fn set_bus_clone_mode(&mut self, mode: CloneMode) {
+        for channel in self.channels.iter_mut() {
+            channel.clone_mode = mode;
+        }
+    }
+
+    pub fn detach_bus(&mut self, channel: usize) -> Option<Box<dyn CanBus>> {
+        self.channels.get_mut(channel)?.bus.take()
+    }
+
+    // Move every frame due on the attached buses into the receive path
+    fn poll_buses(&mut self) -> Result<(), Error> {
+        for channel in 0..NUM_CHANNELS {
+            loop {
+                let frame = match self.channels[channel].bus.as_mut() {
+                    Some(bus) => bus.try_recv(self.now)?,
+                    None => None,
+                };
+                match frame {
+                    Some(frame) => self.receive(channel, CanMessage::from(&frame)),
+                    None => break,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // A message received by channel from the bus, stored according to the receive rule table
+    fn receive(&mut self, channel: usize, mut message: CanMessage) {
+        if !self.modes.can_communicate(channel) {
+            info!("CAN message {:#x} on channel {} dropped, channel is not in communication mode", message.id, channel);
+            return;
+        }
+        if message.fd && self.layout == Layout::Classic {
+            warn!("CAN FD message {:#x} on channel {} dropped, the classical CAN layout can not store it", message.id, channel);
+            return;
+        }
+        let gcfg = self.shadow(Register::GCfg);
+        let gaflcfg = [self.shadow(Register::GAflCfg(0)), self.shadow(Register::GAflCfg(1))];
+        let dlc_check = gcfg & GCFG_DCE != 0;
+        let rule = match self.rules.find(channel, &message, gaflcfg, dlc_check) {
+            Some(rule) => *rule,
+            None => {
+                info!("CAN message {:#x} on channel {} matches no receive rule, dropped", message.id, channel);
+                return;
+            }
+        };
+
+        self.errors[channel].received();
+        message.label = rule.label();
+        message.timestamp = self.timestamp_value();
+        if dlc_check && gcfg & GCFG_DRE != 0 {
+            message.dlc = rule.min_dlc();
+        }
+
+        if let Some(q) = rule.rx_buffer() {
+            if q < NUM_RX_BUFFERS {
+                self.rx_buffers[q] = RxBuffer {
+                    message: Some(message.clone()),
+                    new_data: true,
+                };
+            } else {
+                warn!("Receive rule selects receive buffer {} which is not modelled", q);
+            }
+        }
+        for x in rule.rx_fifos() {
+            let rfcc = self.shadow(Register::RfCc(x));
+            if rfcc & FIFO_ENABLE == 0 {
+                continue;
+            }
+            if self.rx_fifos[x].len() < fifo_depth(rfcc >> 8) {
+                self.rx_fifos[x].push_back(message.clone());
+            } else {
+                self.rx_fifo_lost[x] = true;
+            }
+        }
+        for k in rule.common_fifos() {
+            let cfcc = self.shadow(Register::CfCc(k));
+            if cfcc & FIFO_ENABLE == 0 || (cfcc >> CFCC_CFM_SHIFT) & 0x3 == CFCC_CFM_TRANSMIT {
+                continue;
+            }
+            if self.common_fifos[k].len() < fifo_depth(cfcc >> 21) {
+                self.common_fifos[k].push_back(message.clone());
+            } else {
+                self.common_fifo_lost[k] = true;
+            }
+        }
+    }
+
+    // Last value the firmware wrote to reg
+    fn shadow(&self, reg: Register) -> u32 {
+        self.shadow_regs.get(&reg).copied().unwrap_or(0)
+    }
+
+    // Merge a (possibly partial) write at byte position into the old register value
+    fn merge_written(old: u32, position: u32, value: &[u8]) -> u32 {
+        let written = Self::written_value(value);
+        let mask = if value.len() >= 4 { 0xFFFFFFFFu32 } else { (1u32 << (8 * value.len())) - 1 };
+        let shift = 8 * position;
+        (old & !(mask << shift)) | ((written & mask) << shift)
+    }
+
+    pub fn receive_rules(&self) -> &ReceiveRuleTable {
+        &self.rules
+    }
+
+    pub fn modes(&self) -> &ModeController {
+        &self.modes
+    }
+
+    pub fn bus_errors(&self, channel: usize) -> &ChannelErrors {
+        &self.errors[channel]
+    }
+
+    // Error interrupt of channel, raised when an error flag enabled in CmCTR is set
+    pub fn error_interrupt_mut(&mut self, channel: usize) -> &mut backend::Interrupt {
+        self.errors[channel].interrupt_mut()
+    }
+
+    // Simulate an error on the bus of channel while it transmits or receives a frame
+    pub fn inject_bus_error(&mut self, channel: usize, kind: BusErrorKind, direction: Direction) -> Result<(), Error> {
+        if channel >= NUM_CHANNELS {
+            return Err(Error::RSCanChannel(channel));
+        }
+        let cctr = self.shadow(Register::CCtr(channel));
+        if let Some(mode) = self.errors[channel].inject(kind, direction, cctr) {
+            self.modes.force_channel(channel, mode);
+        }
+        Ok(())
+    }
+
+    // Peripheral clock cycles that pass during one emulated instruction, drives the timestamp counter
+    pub fn set_clocks_per_step(&mut self, clocks: u64) {
+        self.timestamp.set_clocks_per_step(clocks);
+    }
+
+    // Current value of the timestamp counter (GTSC)
+    pub fn timestamp_value(&self) -> u16 {
+        let ccfg: Vec<u32> = (0..NUM_CHANNELS).map(|m| self.shadow(Register::CCfg(m))).collect();
+        self.timestamp.value(self.shadow(Register::GCfg), |m| ccfg.get(m).copied().unwrap_or(0))
+    }
+
+    fn step_bus_errors(&mut self) {
+        for m in 0..NUM_CHANNELS {
+            let cctr = self.shadow(Register::CCtr(m));
+            if let Some(mode) = self.errors[m].step(cctr) {
+                self.modes.force_channel(m, mode);
+            }
+        }
+    }
+
+    /// CANID: only lower 11bits/29bits are used
+    pub fn enqueue_can_msg(&mut self, can_id: u32, data: u64) -> Result<(), &str>{
+        self.enqueue_can_msg_on_channel(0, can_id, data)
+    }
+
+    pub fn enqueue_can_msg_on_channel(&mut self, channel: usize, can_id: u32, data: u64) -> Result<(), &str>{
+        if channel >= NUM_CHANNELS {
+            return Err("CAN channel does not exist");
+        }
+        let mut data_tmp = [0u8; 8];
+        O::write_u64(&mut data_tmp, data);
+        self.receive(channel, CanMessage::new(can_id, &data_tmp));
+        Ok(())
+    }
+
+    // Receive an arbitrary message (extended or remote frame, CAN FD) on channel
+    pub fn enqueue_message(&mut self, channel: usize, message: CanMessage) -> Result<(), &str> {
+        if channel >= NUM_CHANNELS {
+            return Err("CAN channel does not exist");
+        }
+        self.receive(channel, message);
+        Ok(())
+    }
+
+    pub fn dequeue_can_msg(&mut self) -> Option<CanMessage> {
+        self.rx_fifos[0].pop_front()
+    }
+
+    pub fn clear_can_msg_queue(&mut self) {
+        self.rx_fifos[0].clear();
+    }
+
+    pub fn peek_can_msg(&self) -> Option<&CanMessage> {
+        self.rx_fifos[0].front()
+    }
+
+    // Messages sent by the firmware on channels without a bus, with the channel they were sent on
+    pub fn dequeue_sent_msg(&mut self) -> Option<(usize, CanMessage)> {
+        self.sent_queue.pop_front()
+    }
+
+    pub fn get_reg_val(&self, state: &PCodeState<u8, O>, name: &str) -> Result<u32, Error>{
+        let reg_addr = self.regisiters.get(name).ok_or(Error::RSCanReg(name.to_string()))?;
+        let reg_val : u32 = O::read_u32(state.view_values(*reg_addr, 4).unwrap());
+
+        return Ok(reg_val);
+    }
+
+    // value: u8 in LE
+    pub fn set_reg_value_u8(&self, state: &mut PCodeState<u8, O>, name: &str, value: u8)-> (){
+        let reg_addr = self.regisiters.get(name).unwrap();
+        state.set_values(*reg_addr, &[value]).unwrap();
+        return ();
+    }
+
+    pub fn get_reg_addr(&self, name: &str) -> Result<&Address, Error>{
+        let addr = self.regisiters.get(name).ok_or(Error::RSCanReg(name.to_string()))?;
+
+        return Ok(addr);
+    }
+
+    pub fn get_regs (&self) -> &HashMap<String, Address>{
+        return &self.regisiters;
+    }
+
+    pub fn get_regs_range (&self) -> (Address, Address){
+        return (Address::from(RSCAN0_BASE), Address::from(RSCAN0_BASE + self.layout.size()));
+    }
+
+    // Use the RS-CANFD register map and message buffers
+    pub fn with_layout(mut self, layout: Layout) -> Self {
+        self.layout = layout;
+        self.regisiters = Self::get_peripheral_regs(layout);
+        self
+    }
+
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    // Address of reg, None if the register map of the layout does not have it
+    pub fn reg_addr(&self, reg: Register) -> Option<Address> {
+        Some(Address::from(RSCAN0_BASE + self.layout.offset(&reg)?))
+    }
+
+    // Decode an address inside the module to the register it belongs to
+    pub fn decode(&self, address: &Address) -> Option<(Register, u32)> {
+        let offset = u64::from(*address).checked_sub(RSCAN0_BASE as u64)?;
+        self.layout.decode(offset.try_into().ok()?)
+    }
+
+    fn read_reg<M: RegisterMemory>(&self, state: &M, reg: Register) -> u32 {
+        let address = match self.reg_addr(reg) {
+            Some(address) => address,
+            None => {
+                warn!("Reading {}, which is not part of the {:?} layout", reg.name(), self.layout);
+                return 0;
+            }
+        };
+        let values = state.read_bytes(address, reg.width());
+        match reg.width() {
+            1 => values[0] as u32,
+            _ => O::read_u32(&values),
+        }
+    }
+
+    fn write_reg<M: RegisterMemory>(&self, state: &mut M, reg: Register, value: u32) {
+        let address = match self.reg_addr(reg) {
+            Some(address) => address,
+            None => {
+                warn!("Writing {}, which is not part of the {:?} layout", reg.name(), self.layout);
+                return;
+            }
+        };
+        match reg.width() {
+            1 => state.write_bytes(address, &[value as u8]),
+            _ => {
+                let mut val_tmp = [0u8; 4];
+                O::write_u32(&mut val_tmp, value);
+                state.write_bytes(address, &val_tmp);
+            }
+        }
+    }
+
+    // Value written by the firmware, in target byte order
+    fn written_value(value: &[u8]) -> u32 {
+        match value.len() {
+            1 => value[0] as u32,
+            2 => O::read_u16(value) as u32,
+            _ => O::read_u32(value),
+        }
+    }
+
+    // RFSTSx/CFSTSk: update the empty flag and message counter, keep the other bits
+    fn fifo_status(reg_val: u32, msg_counter: usize) -> u32 {
+        let msg_counter: u8 = msg_counter.try_into().expect("Too many messages in the CAN FIFO");
+        if msg_counter > 0 {
+            let reg_val = reg_val & 0xFFFF00FE;     // Clear RFEMP bits and RFMC bits indicate there are unread message
+            reg_val | ((msg_counter as u32) << 8)   // Update RFMC bits with the number of unread message
+        } else {
+            (reg_val & 0xFFFF00FE) | FIFO_EMPTY
+        }
+    }
+
+    // RFIDx/CFIDk/RMIDq value of a received message: IDE, RTR and the identifier
+    fn id_value(message: &CanMessage) -> u32 {
+        let mut reg_val = message.id & if message.extended { EFF_MASK } else { SFF_MASK };
+        if message.extended {
+            reg_val |= ID_IDE;
+        }
+        if message.remote {
+            reg_val |= ID_RTR;
+        }
+        reg_val
+    }
+
+    // RFPTRx/CFPTRk/RMPTRq value of a received message: DLC, timestamp and, in the
+    // classical layout, the label
+    fn ptr_value(&self, message: &CanMessage) -> u32 {
+        let reg_val = ((message.dlc as u32) << 28) | message.timestamp as u32;
+        match self.layout {
+            Layout::Classic => reg_val | ((message.label as u32) << 16),
+            Layout::Fd => reg_val,
+        }
+    }
+
+    // RFFDSTSx/CFFDSTSk/RMFDSTSq value of a received message
+    fn fd_status_value(message: &CanMessage) -> u32 {
+        let mut reg_val = (message.label as u32) << FDSTS_PTR_SHIFT;
+        if message.esi {
+            reg_val |= FDSTS_ESI;
+        }
+        if message.brs {
+            reg_val |= FDSTS_BRS;
+        }
+        if message.fd {
+            reg_val |= FDSTS_FDF;
+        }
+        reg_val
+    }
+
+    // Fill the ID, pointer, FD status or data register of a message window
+    fn read_message_reg<M: RegisterMemory>(&self, state: &mut M, reg: Register, message: Option<&CanMessage>) {
+        let message = match message {
+            Some(message) => message,
+            None => {
+                warn!("Reading {} while there is no message", reg.name());
+                self.write_reg(state, reg, 0);
+                return;
+            }
+        };
+        let reg_val = match reg {
+            Register::RfId(_) | Register::CfId(_) | Register::RmId(_) => Self::id_value(message),
+            Register::RfPtr(_) | Register::CfPtr(_) | Register::RmPtr(_) => self.ptr_value(message),
+            Register::RfFdSts(_) | Register::CfFdSts(_) | Register::RmFdSts(_) => Self::fd_status_value(message),
+            Register::RfDf(_, word) | Register::CfDf(_, word) | Register::RmDf(_, word) => message.data_word(word),
+            _ => unreachable!(),
+        };
+        self.write_reg(state, reg, reg_val);
+        info!("Reading from {}, returning 0x{:08x}", reg.name(), reg_val);
+    }
+
+    // Message in a transmit buffer or transmit FIFO window as set up by the firmware
+    fn window_message<M: RegisterMemory>(&self, state: &M, id: Register, ptr: Register, fdctr: Register, df: impl Fn(usize) -> Register) -> TxMessage {
+        let idr = self.read_reg(state, id);
+        let ptr = self.read_reg(state, ptr);
+        let extended = idr & ID_IDE != 0;
+        let id = idr & EFF_MASK;
+        let dlc = (ptr >> 28) as u8;
+
+        let (fd, brs, esi, label) = match self.layout {
+            Layout::Classic => (false, false, false, (ptr >> 16) & 0xFF),
+            Layout::Fd => {
+                let fdctr = self.read_reg(state, fdctr);
+                (fdctr & FDSTS_FDF != 0, fdctr & FDSTS_BRS != 0, fdctr & FDSTS_ESI != 0, fdctr >> FDSTS_PTR_SHIFT)
+            }
+        };
+        let mut message = if !fd && idr & ID_RTR != 0 {
+            // CAN FD frames have no remote frame format
+            CanMessage::new_remote(id, extended, dlc)
+        } else {
+            let len = message::dlc_to_len(dlc, fd);
+            let mut data = Vec::with_capacity(len);
+            for word in 0..(len + 3) / 4 {
+                data.extend_from_slice(&self.read_reg(state, df(word)).to_le_bytes());
+            }
+            data.truncate(len);
+
+            if fd {
+                let mut message = CanMessage::new_fd(id, extended, brs, &data);
+                message.esi = esi;
+                message
+            } else {
+                let mut message = CanMessage::with_format(id, extended, &data);
+                // Keep DLC values above 8, they are sent as 8 data bytes
+                message.dlc = dlc;
+                message
+            }
+        };
+        message.label = label as u16;
+        TxMessage {
+            message,
+            history: idr & transmit::ID_THLEN != 0,
+        }
+    }
+
+    // Message in transmit buffer p
+    fn buffer_message<M: RegisterMemory>(&self, state: &M, p: usize) -> TxMessage {
+        self.window_message(state, Register::TmId(p), Register::TmPtr(p), Register::TmFdCtr(p), |word| Register::TmDf(p, word))
+    }
+
+    // Whether common FIFO k is enabled in transmit mode
+    fn is_transmit_fifo(&self, k: usize) -> bool {
+        let cfcc = self.shadow(Register::CfCc(k));
+        cfcc & FIFO_ENABLE != 0 && (cfcc >> CFCC_CFM_SHIFT) & 0x3 == CFCC_CFM_TRANSMIT
+    }
+
+    // The next transmission on channel fails with a bus error of kind. Normal requests are
+    // retried, one-shot requests complete as aborted.
+    pub fn fail_next_transmission(&mut self, channel: usize, kind: BusErrorKind) -> Result<(), Error> {
+        if channel >= NUM_CHANNELS {
+            return Err(Error::RSCanChannel(channel));
+        }
+        self.transmits.fail_next(channel, kind);
+        Ok(())
+    }
+
+    pub fn transmit_unit(&self) -> &TransmitUnit {
+        &self.transmits
+    }
+
+    // Transmit interrupt of channel: transmit buffers, transmit FIFOs, the transmit queue
+    // and the transmit history list
+    pub fn transmit_interrupt_mut(&mut self, channel: usize) -> &mut backend::Interrupt {
+        self.transmits.interrupt_mut(channel)
+    }
+
+    // Send the pending message of every channel that wins arbitration
+    fn step_transmit<M: RegisterMemory>(&mut self, state: &M) -> Result<(), Error> {
+        let by_id = self.shadow(Register::GCfg) & transmit::GCFG_TPRI == 0;
+        for channel in 0..NUM_CHANNELS {
+            if self.errors[channel].is_bus_off() || !self.modes.can_communicate(channel) {
+                continue;
+            }
+            let winner = self.transmits.pending(channel).into_iter()
+                .filter_map(|source| {
+                    let message = match source {
+                        TxSource::Buffer(p) => self.buffer_message(state, p),
+                        TxSource::Fifo(_) | TxSource::Queue(_) => self.transmits.queued_message(source)?.clone(),
+                    };
+                    let number = match source {
+                        TxSource::Fifo(k) => source.number(self.shadow(Register::CfCc(k))),
+                        _ => source.number(0),
+                    };
+                    Some((transmit::priority(&message.message, number, by_id), source, message))
+                })
+                .min_by_key(|(priority, _, _)| *priority);
+            let (_, source, sent) = match winner {
+                Some(winner) => winner,
+                None => continue,
+            };
+
+            if let Some(kind) = self.transmits.take_failure(channel) {
+                info!("Transmission of {:?} on channel {} failed with {:?}", source, channel, kind);
+                self.inject_bus_error(channel, kind, Direction::Transmit)?;
+                if self.transmits.is_one_shot(source) {
+                    self.transmits.complete(source, &sent, Outcome::Aborted, shadow_of(&self.shadow_regs));
+                }
+                continue;
+            }
+
+            let message = sent.message.clone();
+            info!("Sending CAN message: {:?} from {:?} on channel {}", message, source, channel);
+            if let Some(bus) = self.channels[channel].bus.as_mut() {
+                bus.send(&CanFrame::from(&message).at(self.now))?;
+            } else {
+                info!("Firmware sending out CAN data: {:?} at id {:#x} on channel {}", message.data, message.id, channel);
+                self.sent_queue.push_back((channel, message));
+            }
+            self.errors[channel].transmitted();
+            self.transmits.complete(source, &sent, Outcome::Completed, shadow_of(&self.shadow_regs));
+        }
+        Ok(())
+    }
+
+}
+
+// Register values last written by the firmware, for the controller models
+fn shadow_of(shadow_regs: &HashMap<Register, u32>) -> impl Fn(Register) -> u32 + '_ {
+    move |reg| shadow_regs.get(&reg).copied().unwrap_or(0)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RSCanSnapshot {
+    rx_fifos: Vec<Vec<CanMessage>>,
+    rx_buffers: Vec<RxBuffer>,
+    common_fifos: Vec<Vec<CanMessage>>,
+    sent_queue: Vec<(usize, CanMessage)>,
+    rx_fifo_lost: Vec<bool>,
+    common_fifo_lost: Vec<bool>,
+    rules: ReceiveRuleTable,
+    shadow_regs: Vec<(Register, u32)>,
+    modes: ModeController,
+    errors: Vec<ChannelErrors>,
+    timestamp: TimestampCounter,
+    transmits: TransmitUnit,
+}
+
+impl<S, O> PeripheralSnapshot for RSCan<S, O>
+where S: AsState<PCodeState<u8, O>> + StateOps,
+      O: Order
+{
+    type Snapshot = RSCanSnapshot;
+
+    fn snapshot(&self) -> Self::Snapshot {
+        RSCanSnapshot {
+            rx_fifos: self.rx_fifos.iter().map(|fifo| fifo.iter().cloned().collect()).collect(),
+            rx_buffers: self.rx_buffers.clone(),
+            common_fifos: self.common_fifos.iter().map(|fifo| fifo.iter().cloned().collect()).collect(),
+            sent_queue: self.sent_queue.iter().cloned().collect(),
+            rx_fifo_lost: self.rx_fifo_lost.clone(),
+            common_fifo_lost: self.common_fifo_lost.clone(),
+            rules: self.rules.clone(),
+            shadow_regs: self.shadow_regs.iter().map(|(reg, val)| (*reg, *val)).collect(),
+            modes: self.modes.clone(),
+            errors: self.errors.clone(),
+            timestamp: self.timestamp.clone(),
+            transmits: self.transmits.clone(),
+        }
+    }
+
+    fn restore(&mut self, snapshot: &Self::Snapshot) {
+        self.rx_fifos = snapshot.rx_fifos.iter().map(|fifo| fifo.iter().cloned().collect()).collect();
+        self.rx_buffers = snapshot.rx_buffers.clone();
+        self.common_fifos = snapshot.common_fifos.iter().map(|fifo| fifo.iter().cloned().collect()).collect();
+        self.sent_queue = snapshot.sent_queue.iter().cloned().collect();
+        self.rx_fifo_lost = snapshot.rx_fifo_lost.clone();
+        self.common_fifo_lost = snapshot.common_fifo_lost.clone();
+        self.rules = snapshot.rules.clone();
+        self.shadow_regs = snapshot.shadow_regs.iter().cloned().collect();
+        self.modes = snapshot.modes.clone();
+        self.errors = snapshot.errors.clone();
+        self.timestamp = snapshot.timestamp.clone();
+        self.transmits = snapshot.transmits.clone();
+    }
+}
+
+// The register behaviour of the polling peripheral, on any memory the registers are mapped in
+impl<S, O> RSCan<S, O>
+where S: AsState<PCodeState<u8, O>> + StateOps,
+      O: Order
+{
+    fn init_registers<M: RegisterMemory>(&mut self, state: &mut M) -> std::result::Result<(), polling::Error> {
+        // Init CAN regs to default values
+        for reg in self.layout.registers() {
+            match reg {
+                Register::TmSts(_) => self.write_reg(state, reg, 0x00),
+                // Set Receive FIFO Buffer Empty status and set everythings else as normal
+                Register::RfSts(_) | Register::CfSts(_) => self.write_reg(state, reg, 0x01),
+                // Init gloabl and channel status regs to stop mode
+                Register::GSts => self.write_reg(state, reg, self.modes.global_status()),
+                Register::CSts(m) => self.write_reg(state, reg, self.modes.channel_status(m)),
+                _ => (),
+            }
+        }
+
+        // Create CAN interface
+        return Ok(());
+    }
+    fn step_registers<M: RegisterMemory>(&mut self, state: &mut M) -> std::result::Result<(), polling::Error> {
+        self.modes.step();
+        // The timestamp counter only runs in global operating and test mode
+        if let GlobalMode::Operating | GlobalMode::Test = self.modes.global() {
+            self.timestamp.step();
+        }
+        self.step_bus_errors();
+        self.now += 1;
+        self.step_transmit(state)?;
+        self.poll_buses()?;
+        Ok(())
+    }
+
+    // Handle firmware reading from address
+    // Peripheral -> Firmware
+    fn read_register<M: RegisterMemory>(&mut self, state: &mut M, input: &Address) -> std::result::Result<(), polling::Error> {
+        let reg = match self.decode(input) {
+            Some((reg, _)) => reg,
+            None => {
+                warn!("Reading from RSCAN address {} which is not a register", input);
+                return Ok(());
+            }
+        };
+
+        match reg {
+            Register::TmSts(p) => {
+                self.write_reg(state, reg, self.transmits.status(p));
+            },
+            Register::TmC(p) => {
+                self.write_reg(state, reg, self.transmits.tmc(p));
+            },
+            Register::TmTrSts(y) => {
+                self.write_reg(state, reg, self.transmits.requests(y));
+            },
+            Register::TmTarSts(y) => {
+                self.write_reg(state, reg, self.transmits.abort_requests(y));
+            },
+            Register::TmTcSts(y) => {
+                self.write_reg(state, reg, self.transmits.completions(y));
+            },
+            Register::TmTaSts(y) => {
+                self.write_reg(state, reg, self.transmits.aborts(y));
+            },
+            Register::CfTiSts => {
+                self.write_reg(state, reg, self.transmits.fifo_flags());
+            },
+            Register::TxqSts(m) => {
+                self.write_reg(state, reg, self.transmits.queue_status(m, self.shadow(Register::TxqCc(m))));
+            },
+            Register::ThlSts(m) => {
+                self.write_reg(state, reg, self.transmits.history_status(m));
+            },
+            Register::ThlAcc(m) => {
+                self.write_reg(state, reg, self.transmits.history_entry(m));
+            },
+            Register::CSts(m) => {
+                self.write_reg(state, reg, self.modes.channel_status(m) | self.errors[m].status());
+            },
+            Register::CErfl(m) => {
+                self.write_reg(state, reg, self.errors[m].erfl());
+            },
+            Register::GTsc => {
+                self.write_reg(state, reg, self.timestamp_value() as u32);
+            },
+            Register::GSts => {
+                // Firmware polls GRAMINIT, finish the RAM initialisation even without steps
+                self.modes.step();
+                self.write_reg(state, reg, self.modes.global_status());
+            },
+            Register::RfSts(x) => {
+                self.poll_buses()?;
+                let mut reg_val = Self::fifo_status(self.read_reg(state, reg), self.rx_fifos[x].len());
+                if self.rx_fifo_lost[x] {
+                    reg_val |= FIFO_MSG_LOST;
+                }
+                self.write_reg(state, reg, reg_val);
+                info!("Reading from {}, Number of unread CAN msg: {}, changed: {:X}", reg.name(), self.rx_fifos[x].len(), reg_val);
+            },
+            Register::CfSts(k) if self.is_transmit_fifo(k) => {
+                self.write_reg(state, reg, self.transmits.fifo_status(k, self.shadow(Register::CfCc(k))));
+            },
+            Register::CfSts(k) => {
+                self.poll_buses()?;
+                let mut reg_val = Self::fifo_status(self.read_reg(state, reg), self.common_fifos[k].len());
+                if self.common_fifo_lost[k] {
+                    reg_val |= FIFO_MSG_LOST;
+                }
+                self.write_reg(state, reg, reg_val);
+                info!("Reading from {}, Number of unread CAN msg: {}, changed: {:X}", reg.name(), self.common_fifos[k].len(), reg_val);
+            },
+            Register::RmNd(y) => {
+                self.poll_buses()?;
+                let reg_val = self.rx_buffers.iter().enumerate()
+                    .filter(|(q, buffer)| q / 32 == y && buffer.new_data)
+                    .fold(0u32, |val, (q, _)| val | (1 << (q % 32)));
+                self.write_reg(state, reg, reg_val);
+            },
+            Register::RfId(x) | Register::RfPtr(x) | Register::RfDf(x, _) => {
+                // if code is reading RFID then the code is preparing to read can data
+                if let Register::RfId(_) = reg {
+                    self.poll_buses()?;
+                }
+                self.read_message_reg(state, reg, self.rx_fifos[x].front());
+            },
+            // The window of a transmit FIFO holds the message the firmware is writing
+            Register::CfId(k) | Register::CfPtr(k) | Register::CfDf(k, _) | Register::CfFdSts(k) if self.is_transmit_fifo(k) => (),
+            Register::CfId(k) | Register::CfPtr(k) | Register::CfDf(k, _) => {
+                if let Register::CfId(_) = reg {
+                    self.poll_buses()?;
+                }
+                self.read_message_reg(state, reg, self.common_fifos[k].front());
+            },
+            Register::RfFdSts(x) => {
+                self.read_message_reg(state, reg, self.rx_fifos[x].front());
+            },
+            Register::CfFdSts(k) => {
+                self.read_message_reg(state, reg, self.common_fifos[k].front());
+            },
+            Register::RmId(q) | Register::RmPtr(q) | Register::RmFdSts(q) | Register::RmDf(q, _) => {
+                self.read_message_reg(state, reg, self.rx_buffers[q].message.as_ref());
+            },
+            Register::GAflId(j) | Register::GAflM(j) | Register::GAflP0(j) | Register::GAflP1(j) => {
+                let rule = self.rules.entry(self.shadow(Register::GAflEctr), j).copied().unwrap_or_default();
+                let reg_val = match reg {
+                    Register::GAflId(_) => rule.id,
+                    Register::GAflM(_) => rule.mask,
+                    Register::GAflP0(_) => rule.p0,
+                    _ => rule.p1,
+                };
+                self.write_reg(state, reg, reg_val);
+            },
+            _ => {
+                warn!("Reading from RSCAN register {} have not been implemented yet", reg.name());
+            }
+        }
+
+        Ok(())
+    }
+
+    // Handle firmware writting to address
+    // TODO: Check size of data
+    // Firmware -> Peripheral
+    fn write_register<M: RegisterMemory>(&mut self, state: &mut M, output: &Address, value: &[u8]) -> std::result::Result<(), polling::Error> {
+        let (reg, position) = match self.decode(output) {
+            Some(decoded) => decoded,
+            None => {
+                warn!("writting to RSCAN address {} which is not a register", output);
+                return Ok(());
+            }
+        };
+        let written = Self::merge_written(self.shadow(reg), position, value);
+        self.shadow_regs.insert(reg, written);
+
+        match reg {
+            Register::GCtr => {
+                self.modes.write_gctr(written);
+                if written & timestamp::GCTR_TSRST != 0 || self.modes.global() == GlobalMode::Reset {
+                    self.timestamp.reset();
+                }
+                if written & timestamp::GCTR_TSRST != 0 {
+                    // TSRST always reads as 0
+                    self.write_reg(state, reg, written & !timestamp::GCTR_TSRST);
+                    self.shadow_regs.insert(reg, written & !timestamp::GCTR_TSRST);
+                }
+                self.write_reg(state, Register::GSts, self.modes.global_status());
+                for m in 0..NUM_CHANNELS {
+                    if self.modes.channel(m) == ChannelMode::Reset {
+                        self.errors[m].reset();
+                        self.transmits.reset_channel(m);
+                    }
+                    self.write_reg(state, Register::CSts(m), self.modes.channel_status(m) | self.errors[m].status());
+                }
+            },
+            Register::CCtr(m) => {
+                self.modes.write_cctr(m, written);
+                if self.modes.channel(m) == ChannelMode::Reset {
+                    self.errors[m].reset();
+                    self.transmits.reset_channel(m);
+                }
+                if let Some(mode) = self.errors[m].write_cctr(written) {
+                    self.modes.force_channel(m, mode);
+                }
+                if written & bus_error::CCTR_RTBO != 0 {
+                    // RTBO always reads as 0
+                    self.write_reg(state, reg, written & !bus_error::CCTR_RTBO);
+                    self.shadow_regs.insert(reg, written & !bus_error::CCTR_RTBO);
+                }
+                self.write_reg(state, Register::CSts(m), self.modes.channel_status(m) | self.errors[m].status());
+            },
+            Register::CErfl(m) => {
+                self.errors[m].write_erfl(written);
+                self.write_reg(state, reg, self.errors[m].erfl());
+            },
+            // Clear the transmit result flags
+            Register::TmSts(p) => {
+                self.transmits.write_tmsts(p, written);
+                self.write_reg(state, reg, self.transmits.status(p));
+            },
+            Register::TmC(p) => {
+                let channel = p / NUM_TX_BUFFERS_PER_CHANNEL;
+                let queue = TransmitUnit::queue_buffers(channel, self.shadow(Register::TxqCc(channel)));
+                if matches!(queue, Some(buffers) if buffers.contains(&p)) {
+                    warn!("Writing to {} while the buffer is used by the transmit queue, ignored", reg.name());
+                } else {
+                    self.transmits.write_tmc(p, written, shadow_of(&self.shadow_regs));
+                }
+                self.write_reg(state, reg, self.transmits.tmc(p));
+                self.write_reg(state, Register::TmSts(p), self.transmits.status(p));
+            },
+            // Message windows and transmit configuration, read back when used
+            Register::TmId(_) | Register::TmPtr(_) | Register::TmDf(_, _) | Register::TmFdCtr(_)
+                | Register::CfId(_) | Register::CfPtr(_) | Register::CfDf(_, _) | Register::CfFdSts(_)
+                | Register::TmIec(_) | Register::CfCc(_) | Register::TxqCc(_) | Register::ThlCc(_) => (),
+            Register::CfPctr(k) if self.is_transmit_fifo(k) && written & 0xFF == FIFO_POINTER_NEXT => {
+                // Writing 0xFF to CFPCTRk of a transmit FIFO stores the message of the window
+                let message = self.window_message(state, Register::CfId(k), Register::CfPtr(k), Register::CfFdSts(k), |word| Register::CfDf(k, word));
+                if !self.transmits.push_fifo(k, message, self.shadow(Register::CfCc(k))) {
+                    warn!("Writing to {} while the transmit FIFO is full, message dropped", reg.name());
+                }
+            },
+            Register::TxqPctr(m) => {
+                // The transmit queue is written through the last transmit buffer of the channel
+                let p = (m + 1) * NUM_TX_BUFFERS_PER_CHANNEL - 1;
+                let message = self.buffer_message(state, p);
+                if !self.transmits.push_queue(m, message, self.shadow(Register::TxqCc(m))) {
+                    warn!("Writing to {} while the transmit queue is disabled or full, message dropped", reg.name());
+                }
+            },
+            Register::TxqSts(m) => {
+                self.transmits.write_txqsts(m, written);
+            },
+            Register::ThlPctr(m) => {
+                self.transmits.pop_history(m);
+            },
+            Register::ThlSts(m) => {
+                self.transmits.write_thlsts(m, written);
+            },
+            Register::RfPctr(x) if written & 0xFF == FIFO_POINTER_NEXT => {
+                // When writting 0xFF to RFPCTRx dequeue msg
+                self.rx_fifos[x].pop_front();
+                info!("Writing to {}, dequeueing message, msg_left in FIFO: {}", reg.name(), self.rx_fifos[x].len());
+            },
+            Register::CfPctr(k) if written & 0xFF == FIFO_POINTER_NEXT => {
+                self.common_fifos[k].pop_front();
+                info!("Writing to {}, dequeueing message, msg_left in FIFO: {}", reg.name(), self.common_fifos[k].len());
+            },
+            Register::RfPctr(_) | Register::CfPctr(_) => {
+                warn!("Writing {:#x} to {}, only 0xFF moves the FIFO pointer", written, reg.name());
+            },
+            Register::RfSts(x) => {
+                if written & FIFO_MSG_LOST == 0 {
+                    self.rx_fifo_lost[x] = false;
+                }
+            },
+            Register::CfSts(k) => {
+                if written & FIFO_MSG_LOST == 0 {
+                    self.common_fifo_lost[k] = false;
+                }
+                self.transmits.write_cfsts(k, written);
+            },
+            Register::GAflId(j) | Register::GAflM(j) | Register::GAflP0(j) | Register::GAflP1(j) => {
+                let gaflectr = self.shadow(Register::GAflEctr);
+                match self.rules.entry_mut(gaflectr, j) {
+                    Some(rule) => {
+                        let field = match reg {
+                            Register::GAflId(_) => &mut rule.id,
+                            Register::GAflM(_) => &mut rule.mask,
+                            Register::GAflP0(_) => &mut rule.p0,
+                            _ => &mut rule.p1,
+                        };
+                        *field = Self::merge_written(*field, position, value);
+                    },
+                    None => {
+                        warn!("Writing to {} while the receive rule table is not writable (GAFLECTR: {:#x})", reg.name(), gaflectr);
+                    }
+                }
+            },
+            Register::RmNd(y) => {
+                // Writing 0 to a bit clears the new data flag of the buffer
+                for (q, buffer) in self.rx_buffers.iter_mut().enumerate() {
+                    if q / 32 == y && written & (1 << (q % 32)) == 0 {
+                        buffer.new_data = false;
+                    }
+                }
+            },
+            _ => {
+                warn!("writting to RSCAN register {} have not been implemented yet", reg.name());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Implement as Polling Peripheral
+impl<S, O> PollingPeripheralHandler for RSCan<S, O>
+where S: AsState<PCodeState<u8, O>> + StateOps,
+      O: Order
+{
+    type Input = Address;
+    type Output = Address;
+    type Order = O;
+
+    fn init(&mut self, state: &mut PCodeState<u8, O>) -> std::result::Result<(), polling::Error>{
+        self.init_registers(state)
+    }
+
+    fn handle_step(&mut self, state: &mut PCodeState<u8, O>, _address: &Address) -> std::result::Result<(), polling::Error> {
+        self.step_registers(state)
+    }
+
+    fn handle_input(&mut self, state: &mut PCodeState<u8, O>, input: &Self::Input, _size: usize) -> std::result::Result<(), polling::Error> {
+        self.read_register(state, input)
+    }
+
+    fn handle_output(&mut self, state: &mut PCodeState<u8, O>, output: &Self::Output, value: &[u8], _size: usize) -> std::result::Result<(), polling::Error> {
+        self.write_register(state, output, value)
+    }
+
+    fn for_each_interrupt(&mut self, visit: &mut dyn FnMut(&mut backend::Interrupt)) {
+        for channel in 0..NUM_CHANNELS {
+            visit(self.errors[channel].interrupt_mut());
+            visit(self.transmits.interrupt_mut(channel));
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::BTreeMap;
+    use fugue::bytes::LE;
+    use crate::polling::{RecordReplay, SharedPeripheral};
+    use crate::replay::{InputEvent, InputLog, InputReplay, InterruptEvent};
+
+    type TestRSCan = RSCan<PCodeState<u8, LE>, LE>;
+
+    // Firmware configuration storing every frame of channel 0 in receive FIFO 0
+    fn accept_all(can: &mut TestRSCan) {
+        can.modes.write_gctr(0x1);
+        can.modes.write_cctr(0, 0x1);
+        can.modes.write_gctr(0x0);
+        can.modes.write_cctr(0, 0x0);
+        can.shadow_regs.insert(Register::GAflCfg(0), 0x01000000);
+        can.shadow_regs.insert(Register::RfCc(0), 0x0301);
+        *can.rules.entry_mut(filter::GAFLECTR_AFLDAE, 0).unwrap() = ReceiveRule { id: 0, mask: 0, p0: 0, p1: 0x01 };
+    }
+
+    // Register memory without an emulator
+    #[derive(Default)]
+    struct TestMemory(BTreeMap<u64, u8>);
+
+    impl RegisterMemory for TestMemory {
+        fn read_bytes(&self, address: Address, size: usize) -> Vec<u8> {
+            (0..size as u64).map(|i| self.0.get(&(u64::from(address) + i)).copied().unwrap_or(0)).collect()
+        }
+
+        fn write_bytes(&mut self, address: Address, values: &[u8]) {
+            for (i, value) in values.iter().enumerate() {
+                self.0.insert(u64::from(address) + i as u64, *value);
+            }
+        }
+    }
+
+    // Firmware writing reg
+    fn write(can: &mut TestRSCan, memory: &mut TestMemory, reg: Register, value: u32) -> Result<(), String> {
+        let address = can.reg_addr(reg).ok_or(format!("{:?} is not mapped", reg))?;
+        can.write_register(memory, &address, &value.to_le_bytes()[..reg.width()]).map_err(|e| e.to_string())
+    }
+
+    // Firmware reading reg
+    fn read(can: &mut TestRSCan, memory: &mut TestMemory, reg: Register) -> Result<u32, String> {
+        let address = can.reg_addr(reg).ok_or(format!("{:?} is not mapped", reg))?;
+        can.read_register(memory, &address).map_err(|e| e.to_string())?;
+        Ok(can.read_reg(memory, reg))
+    }
+
+    #[test]
+    fn transmit_fifo_and_history() -> Result<(), String> {
+        let mut can = TestRSCan::new_queued().unwrap();
+        let mut memory = TestMemory::default();
+        accept_all(&mut can);
+        // Common FIFO 0 in transmit mode with 4 entries, history list of channel 0 enabled
+        write(&mut can, &mut memory, Register::CfCc(0), FIFO_ENABLE | CFCC_CFM_TRANSMIT << CFCC_CFM_SHIFT | 1 << 21)?;
+        write(&mut can, &mut memory, Register::ThlCc(0), transmit::THLCC_THLE)?;
+
+        write(&mut can, &mut memory, Register::CfId(0), 0x123 | transmit::ID_THLEN)?;
+        write(&mut can, &mut memory, Register::CfPtr(0), 2 << 28 | 0x5A << 16)?;
+        write(&mut can, &mut memory, Register::CfDf(0, 0), 0xBBAA)?;
+        write(&mut can, &mut memory, Register::CfPctr(0), 0xFF)?;
+        if read(&mut can, &mut memory, Register::CfSts(0))? != 1 << 8 {
+            return Err(String::from("CFPCTR0 did not push the message to the transmit FIFO"));
+        }
+
+        can.step_registers(&mut memory).map_err(|e| e.to_string())?;
+        match can.dequeue_sent_msg() {
+            Some((0, message)) if message.id() == 0x123 && message.data == [0xAA, 0xBB] => (),
+            sent => return Err(format!("Sent {:?} instead of the FIFO message", sent)),
+        }
+        if read(&mut can, &mut memory, Register::CfSts(0))? & transmit::CFSTS_CFEMP == 0 {
+            return Err(String::from("Transmit FIFO not empty after sending"));
+        }
+
+        // History entry: FIFO buffer type, FIFO 0 and the label of the message
+        if read(&mut can, &mut memory, Register::ThlSts(0))? >> 8 & 0x3F != 1 {
+            return Err(String::from("Sent message not stored in the history list"));
+        }
+        let entry = read(&mut can, &mut memory, Register::ThlAcc(0))?;
+        if entry != 0x5A02 {
+            return Err(format!("History entry {:#x} instead of 0x5a02", entry));
+        }
+        write(&mut can, &mut memory, Register::ThlPctr(0), 0xFF)?;
+        if read(&mut can, &mut memory, Register::ThlSts(0))? != transmit::THLSTS_THLEMP {
+            return Err(String::from("THLPCTR0 did not remove the history entry"));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn transmit_queue_and_buffer_completion() -> Result<(), String> {
+        let mut can = TestRSCan::new_queued().unwrap();
+        let mut memory = TestMemory::default();
+        accept_all(&mut can);
+        // Transmit queue of channel 0 with 2 entries, written through the last transmit buffer
+        write(&mut can, &mut memory, Register::TxqCc(0), transmit::TXQCC_TXQE | 1 << 8)?;
+        let last = NUM_TX_BUFFERS_PER_CHANNEL - 1;
+        write(&mut can, &mut memory, Register::TmId(last), 0x200)?;
+        write(&mut can, &mut memory, Register::TmPtr(last), 1 << 28)?;
+        write(&mut can, &mut memory, Register::TmDf(last, 0), 0x11)?;
+        write(&mut can, &mut memory, Register::TxqPctr(0), 0xFF)?;
+        if read(&mut can, &mut memory, Register::TxqSts(0))? >> 8 & 0xFF != 1 {
+            return Err(String::from("TXQPCTR0 did not push the message to the transmit queue"));
+        }
+
+        // Transmit buffer 0 wins arbitration with the lower ID
+        write(&mut can, &mut memory, Register::TmId(0), 0x100)?;
+        write(&mut can, &mut memory, Register::TmPtr(0), 1 << 28)?;
+        write(&mut can, &mut memory, Register::TmDf(0, 0), 0x22)?;
+        write(&mut can, &mut memory, Register::TmC(0), transmit::TMC_TMTR)?;
+        if can.read_reg(&memory, Register::TmSts(0)) != transmit::TMSTS_TMTSTS | transmit::TMSTS_TMTRM {
+            return Err(String::from("TMC0 did not request the transmission"));
+        }
+
+        can.step_registers(&mut memory).map_err(|e| e.to_string())?;
+        match can.dequeue_sent_msg() {
+            Some((0, message)) if message.id() == 0x100 && message.data == [0x22] => (),
+            sent => return Err(format!("Sent {:?} instead of transmit buffer 0", sent)),
+        }
+        // TMTRF: transmission completed
+        if read(&mut can, &mut memory, Register::TmSts(0))? != 0b10 << 1 || read(&mut can, &mut memory, Register::TmC(0))? != 0 {
+            return Err(String::from("Transmit buffer 0 not completed"));
+        }
+
+        can.step_registers(&mut memory).map_err(|e| e.to_string())?;
+        match can.dequeue_sent_msg() {
+            Some((0, message)) if message.id() == 0x200 && message.data == [0x11] => (),
+            sent => return Err(format!("Sent {:?} instead of the queued message", sent)),
+        }
+        if read(&mut can, &mut memory, Register::TxqSts(0))? & transmit::TXQSTS_TXQEMP == 0 {
+            return Err(String::from("Transmit queue not empty after sending"));
+        }
+        Ok(())
+    }
+
+    type Recorder = RecordReplay<TestRSCan>;
+
+    // Firmware writing reg through the recorder
+    fn write_through(recorder: &mut Recorder, memory: &mut TestMemory, reg: Register, value: u32) -> Result<(), String> {
+        let address = recorder.peripheral().reg_addr(reg).ok_or(format!("{:?} is not mapped", reg))?;
+        let value = value.to_le_bytes();
+        recorder.call(|can| can.write_register(memory, &address, &value[..reg.width()])).map_err(|e| e.to_string())
+    }
+
+    // Firmware reading size bytes of reg through the recorder
+    fn read_through(recorder: &mut Recorder, memory: &mut TestMemory, reg: Register, size: usize) -> Result<u32, String> {
+        let address = recorder.peripheral().reg_addr(reg).ok_or(format!("{:?} is not mapped", reg))?;
+        recorder.input(memory, &address, size, Address::from(0x1000u32), |can, memory| can.read_register(memory, &address))
+            .map_err(|e| e.to_string())?;
+        let mut value = [0u8; 4];
+        value[..size].copy_from_slice(&memory.read_bytes(address, size));
+        Ok(u32::from_le_bytes(value))
+    }
+
+    // Transmit buffer 0 sends a frame at the first step and raises the transmit interrupt,
+    // the firmware polls TMSTS0 for the completion
+    fn transmit_and_poll(recorder: &mut Recorder, memory: &mut TestMemory) -> Result<(u32, u128), String> {
+        write_through(recorder, memory, Register::TmIec(0), 1)?;
+        write_through(recorder, memory, Register::TmId(0), 0x100)?;
+        write_through(recorder, memory, Register::TmPtr(0), 1 << 28)?;
+        write_through(recorder, memory, Register::TmC(0), transmit::TMC_TMTR)?;
+        if recorder.peripheral_mut().transmit_interrupt_mut(0).is_triggered() {
+            return Err(String::from("Transmit interrupt raised before the transmission"));
+        }
+        recorder.step(|can| can.step_registers(memory)).map_err(|e| e.to_string())?;
+        let status = read_through(recorder, memory, Register::TmSts(0), 1)?;
+        Ok((status, recorder.peripheral_mut().transmit_interrupt_mut(0).get_trigger_count()))
+    }
+
+    #[test]
+    fn record_then_replay() -> Result<(), String> {
+        let mut can = TestRSCan::new_queued().unwrap();
+        accept_all(&mut can);
+        let mut recorder = RecordReplay::new(can, InputReplay::record());
+        let recorded = transmit_and_poll(&mut recorder, &mut TestMemory::default())?;
+        if recorded != (0b10 << 1, 1) || recorder.peripheral_mut().dequeue_sent_msg().is_none() {
+            return Err(format!("Recorded TMSTS0 and transmit interrupts {:?}", recorded));
+        }
+
+        // The replaying controller is not even configured to send: the completion and the
+        // interrupt come from the log
+        let log = recorder.into_replay().into_log();
+        let mut replayer = RecordReplay::new(TestRSCan::new_queued().unwrap(), InputReplay::replay(log));
+        let replayed = transmit_and_poll(&mut replayer, &mut TestMemory::default())?;
+        if replayed != recorded || replayer.peripheral_mut().dequeue_sent_msg().is_some() || replayer.replay().remaining() != 0 {
+            return Err(format!("Replayed TMSTS0 and transmit interrupts {:?}, recorded {:?}", replayed, recorded));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn replay_divergence() -> Result<(), String> {
+        let can = TestRSCan::new_queued().unwrap();
+        let mut log = InputLog::new();
+        log.push(InputEvent { timestamp: 0, pc: Address::from(0x1000u32), address: can.reg_addr(Register::TmSts(0)).ok_or("TmSts(0) is not mapped")?, value: vec![0x04] });
+        let replayer = |log: &InputLog| RecordReplay::new(can.clone(), InputReplay::replay(log.clone()));
+        let mut memory = TestMemory::default();
+
+        if read_through(&mut replayer(&log), &mut memory, Register::TmSts(1), 1).is_ok() {
+            return Err(String::from("Read of another register was replayed"));
+        }
+        if read_through(&mut replayer(&log), &mut memory, Register::TmSts(0), 2).is_ok() {
+            return Err(String::from("Read of another size was replayed"));
+        }
+        let mut ok = replayer(&log);
+        read_through(&mut ok, &mut memory, Register::TmSts(0), 1)?;
+        if read_through(&mut ok, &mut memory, Register::TmSts(0), 1).is_ok() {
+            return Err(String::from("Read past the end of the log was replayed"));
+        }
+
+        // An interrupt of a channel the controller does not have
+        log.push_interrupt(InterruptEvent { timestamp: 1, hook: 1, name: String::from("CAN9TRX") });
+        if replayer(&log).step(|_| Ok(())).is_ok() {
+            return Err(String::from("Unknown interrupt was replayed"));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot_clone_does_not_leak() -> Result<(), String> {
+        let mut can = TestRSCan::new_queued().unwrap();
+        accept_all(&mut can);
+        can.enqueue_can_msg(0x123, 0x1122334455667788).unwrap();
+
+        let mut fork = can.clone();
+        fork.dequeue_can_msg();
+        fork.enqueue_can_msg(0x456, 0x0).unwrap();
+        fork.enqueue_can_msg(0x789, 0x0).unwrap();
+
+        if can.rx_fifos[0].len() != 1 || can.peek_can_msg().map(|f| f.id()) != Some(0x123) {
+            return Err(String::from("Fork changed the receive queue of the original"));
+        }
+        if fork.rx_fifos[0].len() != 2 {
+            return Err(String::from("Fork did not get its own receive queue"));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot_saved_as_json() -> Result<(), String> {
+        let mut can = TestRSCan::new_queued().unwrap();
+        accept_all(&mut can);
+        can.enqueue_can_msg(0x123, 0x1122334455667788).unwrap();
+        let json = serde_json::to_string(&can.snapshot()).map_err(|e| e.to_string())?;
+
+        let mut restored = TestRSCan::new_queued().unwrap();
+        restored.restore(&serde_json::from_str::<RSCanSnapshot>(&json).map_err(|e| e.to_string())?);
+        if restored.peek_can_msg().map(|f| f.id()) != Some(0x123) || restored.shadow_regs != can.shadow_regs {
+            return Err(format!("Receive FIFO and registers not restored from {}", json));
+        }
+        // The restored receive rules and modes accept frames on channel 0
+        restored.enqueue_can_msg(0x456, 0x0).unwrap();
+        if restored.rx_fifos[0].len() != 2 {
+            return Err(String::from("Restored controller dropped a frame"));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn clones_fork_or_join_the_bus() -> Result<(), String> {
+        let bus = crate::can::VirtualBus::new();
+        let mut can = TestRSCan::new_queued().unwrap();
+        can.attach_bus(0, Box::new(bus.node())).map_err(|e| e.to_string())?;
+
+        let fork = can.clone();
+        if bus.num_nodes() != 1 {
+            return Err(String::from("Snapshot clone joined the bus"));
+        }
+        drop(fork);
+
+        can.set_bus_clone_mode(CloneMode::Shared);
+        let joined = can.clone();
+        if bus.num_nodes() != 2 {
+            return Err(String::from("Shared clone did not join the bus"));
+        }
+        drop(joined);
+        if bus.num_nodes() != 1 {
+            return Err(String::from("Dropped clone stayed on the bus"));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn shared_clone_drives_same_device() -> Result<(), String> {
+        let mut can = TestRSCan::new_queued().unwrap();
+        accept_all(&mut can);
+        let can = SharedPeripheral::new(can);
+        let fork = can.clone();
+
+        fork.lock().enqueue_can_msg(0x123, 0x0).unwrap();
+
+        if can.lock().peek_can_msg().map(|f| f.id()) != Some(0x123) {
+            return Err(String::from("Shared clone does not refer to the same device"));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn decode_indexed_registers() -> Result<(), String> {
+        let cases = [
+            (0x0018, Register::CSts(1)),
+            (0x00dc, Register::RfSts(1)),
+            (0x0251, Register::TmC(1)),
+            (0x0261, Register::TmC(17)),
+            (0x0e18, Register::RfDf(1, 0)),
+            (0x06fc, Register::RmDf(15, 1)),
+            (0x1110, Register::TmId(17)),
+            (0x03e4, Register::TxqPctr(1)),
+        ];
+        for (offset, reg) in cases.iter() {
+            if Register::decode(*offset).map(|(r, _)| r) != Some(*reg) {
+                return Err(format!("0x{:x} not decoded as {:?}", offset, reg));
+            }
+            if reg.offset() != Some(*offset) {
+                return Err(format!("{:?} not at offset 0x{:x}", reg, offset));
+            }
+        }
+        if Register::decode(0x000a) != Some((Register::CSts(0), 2)) {
+            return Err(String::from("Partial register access not decoded"));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn rx_fifo_pointer_and_status() -> Result<(), String> {
+        let mut can = TestRSCan::new_queued().unwrap();
+        let mut memory = TestMemory::default();
+        accept_all(&mut can);
+        can.enqueue_can_msg_on_channel(0, 0x123, 0x0).unwrap();
+        can.enqueue_can_msg_on_channel(0, 0x124, 0x0).unwrap();
+        if read(&mut can, &mut memory, Register::RfSts(0))? != 2 << 8 {
+            return Err(String::from("Two messages not counted in RFSTS0"));
+        }
+
+        // Only 0xFF in the low byte moves the pointer
+        write(&mut can, &mut memory, Register::RfPctr(0), 0x100)?;
+        if read(&mut can, &mut memory, Register::RfSts(0))? != 2 << 8 {
+            return Err(String::from("RFPCTR0 dequeued without 0xFF"));
+        }
+        write(&mut can, &mut memory, Register::RfPctr(0), 0xFF)?;
+        write(&mut can, &mut memory, Register::RfPctr(0), 0xFF)?;
+
+        // The empty FIFO keeps its interrupt request flag
+        let rfif = 1 << 3;
+        can.write_reg(&mut memory, Register::RfSts(0), 1 << 8 | rfif);
+        let status = read(&mut can, &mut memory, Register::RfSts(0))?;
+        if status != FIFO_EMPTY | rfif {
+            return Err(format!("RFSTS0 {:#x} after emptying the FIFO", status));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn unmatched_frames_are_dropped() -> Result<(), String> {
+        let mut can = TestRSCan::new_queued().unwrap();
+        accept_all(&mut can);
+        // Only channel 0 has receive rules
+        can.enqueue_can_msg_on_channel(1, 0x123, 0x0).unwrap();
+        if can.peek_can_msg().is_some() {
+            return Err(String::from("Frame of a channel without rules was stored"));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn fd_layout_and_extended_ids() -> Result<(), String> {
+        let cases = [
+            (0x3080, Register::RfId(1)),
+            (0x3088, Register::RfFdSts(1)),
+            (0x308c, Register::RfDf(1, 0)),
+            (0x30c8, Register::RfDf(1, 15)),
+            (0x1010, Register::GAflId(1)),
+            (0x0524, Register::CFdCfg(1)),
+        ];
+        for (offset, reg) in cases.iter() {
+            if Layout::Fd.decode(*offset).map(|(r, _)| r) != Some(*reg) || Layout::Fd.offset(reg) != Some(*offset) {
+                return Err(format!("0x{:x} not decoded as {:?} in the FD layout", offset, reg));
+            }
+        }
+        if Layout::Classic.offset(&Register::CFdCfg(1)).is_some() || Layout::Fd.decode(0x30cc).is_some() {
+            return Err(String::from("Register outside the layout mapped"));
+        }
+
+        let message = CanMessage::with_format(0x123, true, &[1, 2]);
+        if TestRSCan::id_value(&message) != 0x80000123 {
+            return Err(format!("Extended ID encoded as {:#x}", TestRSCan::id_value(&message)));
+        }
+        let message = CanMessage::new_remote(0x7FF, false, 4);
+        if TestRSCan::id_value(&message) != 0x400007FF {
+            return Err(format!("Remote frame ID encoded as {:#x}", TestRSCan::id_value(&message)));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn frames_from_attached_bus() -> Result<(), String> {
+        let mut can = TestRSCan::new_queued().unwrap();
+        accept_all(&mut can);
+        let bus = can::ScriptedBus::new()
+            .at(0, CanFrame::new(0x100, &[1]))
+            .at(5, CanFrame::new(0x200, &[2]));
+        can.attach_bus(0, Box::new(bus)).map_err(|e| e.to_string())?;
+
+        can.poll_buses().map_err(|e| e.to_string())?;
+        if can.rx_fifos[0].len() != 1 || can.peek_can_msg().map(|f| f.id()) != Some(0x100) {
+            return Err(String::from("Frame due at time 0 not received"));
+        }
+        can.now = 5;
+        can.poll_buses().map_err(|e| e.to_string())?;
+        if can.rx_fifos[0].len() != 2 {
+            return Err(String::from("Frame due at time 5 not received"));
+        }
+        Ok(())
+    }
+}
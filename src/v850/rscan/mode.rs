@@ -0,0 +1,301 @@
+// Global and channel mode state machines of the RSCAN module
+//
+// After reset the module is in global stop mode with all channels in channel stop mode
+// and the CAN RAM being initialised. The firmware leaves stop mode through global
+// reset, configures the module and then switches to global operating mode, after which
+// the channels can be taken from channel reset to communication mode. Requests the
+// hardware does not allow are not executed, they are logged and kept for inspection.
+
+use serde::{Serialize, Deserialize};
+use log::warn;
+
+use super::regs::NUM_CHANNELS;
+
+// GCTR
+pub const GCTR_GMDC_MASK: u32 = 0x3;
+pub const GCTR_GSLPR: u32 = 1 << 2;
+
+// GSTS
+pub const GSTS_GRSTSTS: u32 = 1 << 0;
+pub const GSTS_GHLTSTS: u32 = 1 << 1;
+pub const GSTS_GSLPSTS: u32 = 1 << 2;
+pub const GSTS_GRAMINIT: u32 = 1 << 3;
+
+// CmCTR
+pub const CCTR_CHMDC_MASK: u32 = 0x3;
+pub const CCTR_CSLPR: u32 = 1 << 2;
+
+// CmSTS
+pub const CSTS_CRSTSTS: u32 = 1 << 0;
+pub const CSTS_CHLTSTS: u32 = 1 << 1;
+pub const CSTS_CSLPSTS: u32 = 1 << 2;
+pub const CSTS_COMSTS: u32 = 1 << 7;
+
+// Number of steps (or GSTS reads) until the CAN RAM initialisation is finished
+const RAM_INIT_STEPS: u32 = 16;
+
+// Number of illegal requests kept, firmware retrying a request would grow the list
+// (and every fork and snapshot) without end
+const MAX_ILLEGAL_KEPT: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GlobalMode {
+    Operating,
+    Reset,
+    Test,
+    Stop,
+}
+
+impl GlobalMode {
+    // Mode requested by a write to GCTR, None for the prohibited GMDC setting
+    pub fn requested(gctr: u32) -> Option<Self> {
+        if gctr & GCTR_GSLPR != 0 {
+            return Some(Self::Stop);
+        }
+        match gctr & GCTR_GMDC_MASK {
+            0 => Some(Self::Operating),
+            1 => Some(Self::Reset),
+            2 => Some(Self::Test),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChannelMode {
+    Communication,
+    Reset,
+    Halt,
+    Stop,
+}
+
+impl ChannelMode {
+    // Mode requested by a write to CmCTR, None for the prohibited CHMDC setting
+    pub fn requested(cctr: u32) -> Option<Self> {
+        if cctr & CCTR_CSLPR != 0 {
+            return Some(Self::Stop);
+        }
+        match cctr & CCTR_CHMDC_MASK {
+            0 => Some(Self::Communication),
+            1 => Some(Self::Reset),
+            2 => Some(Self::Halt),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum IllegalTransition {
+    GlobalSetting { gctr: u32 },
+    ChannelSetting { channel: usize, cctr: u32 },
+    Global { from: GlobalMode, to: GlobalMode },
+    Channel { channel: usize, global: GlobalMode, from: ChannelMode, to: ChannelMode },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModeController {
+    global: GlobalMode,
+    channels: Vec<ChannelMode>,
+    ram_init: u32,                      // Remaining steps of the CAN RAM initialisation
+    illegal: Vec<IllegalTransition>,   // Last MAX_ILLEGAL_KEPT illegal requests
+    illegal_count: u64,                 // Illegal requests since reset
+}
+
+impl Default for ModeController {
+    fn default() -> Self {
+        Self {
+            global: GlobalMode::Stop,
+            channels: vec![ChannelMode::Stop; NUM_CHANNELS],
+            ram_init: RAM_INIT_STEPS,
+            illegal: Vec::new(),
+            illegal_count: 0,
+        }
+    }
+}
+
+impl ModeController {
+    pub fn global(&self) -> GlobalMode {
+        self.global
+    }
+
+    pub fn channel(&self, channel: usize) -> ChannelMode {
+        self.channels[channel]
+    }
+
+    pub fn ram_initialised(&self) -> bool {
+        self.ram_init == 0
+    }
+
+    // Last requests that were not executed, in the order the firmware made them
+    pub fn illegal_transitions(&self) -> &[IllegalTransition] {
+        &self.illegal
+    }
+
+    // Number of requests that were not executed, including the ones no longer kept
+    pub fn illegal_count(&self) -> u64 {
+        self.illegal_count
+    }
+
+    // Advance the CAN RAM initialisation
+    pub fn step(&mut self) {
+        self.ram_init = self.ram_init.saturating_sub(1);
+    }
+
+    fn reject(&mut self, transition: IllegalTransition) {
+        warn!("RSCAN ignores illegal mode transition {:?}", transition);
+        if self.illegal.len() == MAX_ILLEGAL_KEPT {
+            self.illegal.remove(0);
+        }
+        self.illegal.push(transition);
+        self.illegal_count += 1;
+    }
+
+    // Firmware wrote GCTR
+    pub fn write_gctr(&mut self, gctr: u32) {
+        let to = match GlobalMode::requested(gctr) {
+            Some(to) => to,
+            None => return self.reject(IllegalTransition::GlobalSetting { gctr }),
+        };
+        let from = self.global;
+        if from == to {
+            return;
+        }
+
+        let allowed = match (from, to) {
+            // Stop mode is only left and entered through reset, with all channels stopped
+            (GlobalMode::Stop, GlobalMode::Reset) => true,
+            (GlobalMode::Stop, _) => false,
+            (GlobalMode::Reset, GlobalMode::Stop) => self.channels.iter().all(|mode| *mode == ChannelMode::Stop),
+            (_, GlobalMode::Stop) => false,
+            _ => true,
+        };
+        if !allowed {
+            return self.reject(IllegalTransition::Global { from, to });
+        }
+
+        self.global = to;
+        for mode in self.channels.iter_mut() {
+            match (to, *mode) {
+                (_, ChannelMode::Stop) => (),
+                (GlobalMode::Reset, _) => *mode = ChannelMode::Reset,
+                (GlobalMode::Test, ChannelMode::Communication) => *mode = ChannelMode::Halt,
+                _ => (),
+            }
+        }
+    }
+
+    // Firmware wrote CmCTR
+    pub fn write_cctr(&mut self, channel: usize, cctr: u32) {
+        let to = match ChannelMode::requested(cctr) {
+            Some(to) => to,
+            None => return self.reject(IllegalTransition::ChannelSetting { channel, cctr }),
+        };
+        let from = self.channels[channel];
+        let global = self.global;
+        if from == to {
+            return;
+        }
+
+        let allowed = match (from, to) {
+            (ChannelMode::Stop, ChannelMode::Reset) => true,
+            (ChannelMode::Stop, _) => false,
+            (ChannelMode::Reset, ChannelMode::Stop) => true,
+            (_, ChannelMode::Stop) => false,
+            (_, ChannelMode::Reset) => true,
+            (_, ChannelMode::Communication) => global == GlobalMode::Operating,
+            (_, ChannelMode::Halt) => global == GlobalMode::Operating || global == GlobalMode::Test,
+        };
+        if !allowed {
+            return self.reject(IllegalTransition::Channel { channel, global, from, to });
+        }
+
+        self.channels[channel] = to;
+    }
+
+    // Transition made by the hardware itself, e.g. on bus-off
+    pub fn force_channel(&mut self, channel: usize, mode: ChannelMode) {
+        self.channels[channel] = mode;
+    }
+
+    // Value of GSTS
+    pub fn global_status(&self) -> u32 {
+        let mut status = match self.global {
+            GlobalMode::Operating => 0,
+            GlobalMode::Reset => GSTS_GRSTSTS,
+            GlobalMode::Test => GSTS_GHLTSTS,
+            GlobalMode::Stop => GSTS_GRSTSTS | GSTS_GSLPSTS,
+        };
+        if !self.ram_initialised() {
+            status |= GSTS_GRAMINIT;
+        }
+        status
+    }
+
+    // Mode bits of CmSTS, the bus is always idle so communication is ready immediately
+    pub fn channel_status(&self, channel: usize) -> u32 {
+        match self.channels[channel] {
+            ChannelMode::Communication => CSTS_COMSTS,
+            ChannelMode::Reset => CSTS_CRSTSTS,
+            ChannelMode::Halt => CSTS_CHLTSTS,
+            ChannelMode::Stop => CSTS_CRSTSTS | CSTS_CSLPSTS,
+        }
+    }
+
+    // Frames are only sent and received by channels in communication mode
+    pub fn can_communicate(&self, channel: usize) -> bool {
+        self.channels[channel] == ChannelMode::Communication
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn init_sequence() -> Result<(), String> {
+        let mut modes = ModeController::default();
+        if modes.global_status() != GSTS_GRSTSTS | GSTS_GSLPSTS | GSTS_GRAMINIT {
+            return Err(format!("Unexpected GSTS after reset: {:#x}", modes.global_status()));
+        }
+        (0..RAM_INIT_STEPS).for_each(|_| modes.step());
+
+        modes.write_gctr(0x1);                  // Global reset
+        modes.write_cctr(0, 0x1);               // Channel reset
+        modes.write_cctr(0, 0x0);               // Global mode still reset
+        if modes.channel(0) != ChannelMode::Reset {
+            return Err(String::from("Channel entered communication mode in global reset"));
+        }
+
+        modes.write_gctr(0x0);                  // Global operating
+        modes.write_cctr(0, 0x0);
+        if modes.global_status() != 0 || modes.channel_status(0) != CSTS_COMSTS {
+            return Err(format!("Unexpected GSTS {:#x} / C0STS {:#x}", modes.global_status(), modes.channel_status(0)));
+        }
+
+        modes.write_gctr(0x1);                  // Global reset forces the channels to reset
+        if modes.channel(0) != ChannelMode::Reset || modes.channel(1) != ChannelMode::Stop {
+            return Err(String::from("Global reset did not reset the active channels"));
+        }
+
+        match modes.illegal_transitions() {
+            [IllegalTransition::Channel { channel: 0, global: GlobalMode::Reset, from: ChannelMode::Reset, to: ChannelMode::Communication }] => Ok(()),
+            illegal => Err(format!("Unexpected illegal transitions {:?}", illegal)),
+        }
+    }
+
+    #[test]
+    fn retried_requests_are_capped() -> Result<(), String> {
+        let mut modes = ModeController::default();
+        for _ in 0..100 {
+            modes.write_gctr(0x3);              // Prohibited GMDC setting
+        }
+        modes.write_cctr(0, 0x3);
+        if modes.illegal_count() != 101 || modes.illegal_transitions().len() != MAX_ILLEGAL_KEPT {
+            return Err(format!("{} illegal requests counted, {} kept", modes.illegal_count(), modes.illegal_transitions().len()));
+        }
+        match modes.illegal_transitions().last() {
+            Some(IllegalTransition::ChannelSetting { channel: 0, cctr: 0x3 }) => Ok(()),
+            last => Err(format!("Last illegal request {:?}", last)),
+        }
+    }
+}
@@ -0,0 +1,469 @@
+// Register map of the RH850 RSCAN0 module, in the classical CAN layout and in the
+// RS-CANFD layout with 64 byte message buffers
+//
+// Every register that exists once per channel, FIFO or buffer carries its index,
+// e.g. Register::RfId(2) is RSCAN0RFID2. Data registers also carry the word index,
+// Register::RfDf(2, 1) is RSCAN0RFDF12.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::OnceLock;
+
+use serde::{Serialize, Deserialize};
+
+pub const RSCAN0_BASE: u32 = 0xffd00000;
+
+pub const NUM_CHANNELS: usize = 6;
+pub const NUM_RX_BUFFERS: usize = 16;
+pub const NUM_RX_FIFOS: usize = 8;
+pub const NUM_COMMON_FIFOS_PER_CHANNEL: usize = 3;
+pub const NUM_COMMON_FIFOS: usize = NUM_CHANNELS * NUM_COMMON_FIFOS_PER_CHANNEL;
+pub const NUM_TX_BUFFERS_PER_CHANNEL: usize = 16;
+pub const NUM_TX_BUFFERS: usize = NUM_CHANNELS * NUM_TX_BUFFERS_PER_CHANNEL;
+pub const NUM_RULES_PER_PAGE: usize = 16;
+
+// Number of 32 bit data registers of a classical CAN message (8 bytes)
+pub const NUM_DATA_WORDS: usize = 2;
+// Number of 32 bit data registers of a CAN FD message (64 bytes)
+pub const NUM_FD_DATA_WORDS: usize = 16;
+
+// RFIDx / CFIDk / RMIDq / TMIDp
+pub const ID_IDE: u32 = 1 << 31;
+pub const ID_RTR: u32 = 1 << 30;
+
+// RFFDSTSx / CFFDSTSk / RMFDSTSq / TMFDCTRp
+pub const FDSTS_ESI: u32 = 1 << 0;
+pub const FDSTS_BRS: u32 = 1 << 1;
+pub const FDSTS_FDF: u32 = 1 << 2;
+pub const FDSTS_PTR_SHIFT: u32 = 16;    // Label of the message
+
+// GCFG
+pub const GCFG_DCE: u32 = 1 << 1;       // DLC check enable
+pub const GCFG_DRE: u32 = 1 << 2;       // DLC replacement enable
+
+// RFCCx / CFCCk
+pub const FIFO_ENABLE: u32 = 1 << 0;    // RFE / CFE
+pub const CFCC_CFM_SHIFT: u32 = 8;      // Common FIFO mode: 0 receive, 1 transmit, 2 gateway
+pub const CFCC_CFM_TRANSMIT: u32 = 1;
+
+// RFSTSx / CFSTSk
+pub const FIFO_EMPTY: u32 = 1 << 0;     // RFEMP / CFEMP
+pub const FIFO_MSG_LOST: u32 = 1 << 2;  // RFMLT / CFMLT
+
+// RFPCTRx / CFPCTRk: the FIFO pointer advances when the low byte written is 0xFF
+pub const FIFO_POINTER_NEXT: u32 = 0xFF;
+
+// Number of messages a FIFO holds for the RFDC/CFDC depth setting
+pub fn fifo_depth(dc: u32) -> usize {
+    match dc & 0x7 {
+        0 => 0,
+        1 => 4,
+        2 => 8,
+        3 => 16,
+        4 => 32,
+        5 => 48,
+        6 => 64,
+        _ => 128,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Register {
+    // Channel m
+    CCfg(usize),
+    CCtr(usize),
+    CSts(usize),
+    CErfl(usize),
+    // Global
+    GCfg,
+    GCtr,
+    GSts,
+    GErfl,
+    GTsc,
+    GAflEctr,
+    GAflCfg(usize),
+    RmNb,
+    RmNd(usize),
+    // Receive FIFO x
+    RfCc(usize),
+    RfSts(usize),
+    RfPctr(usize),
+    // Common FIFO k
+    CfCc(usize),
+    CfSts(usize),
+    CfPctr(usize),
+    // FIFO status summaries
+    FeSts,
+    FfSts,
+    FmSts,
+    RfiSts,
+    CfRiSts,
+    CfTiSts,
+    // Transmit buffer p
+    TmC(usize),
+    TmSts(usize),
+    // Transmit buffer status summaries, 32 buffers per register
+    TmTrSts(usize),
+    TmTarSts(usize),
+    TmTcSts(usize),
+    TmTaSts(usize),
+    TmIec(usize),
+    // Transmit queue of channel m
+    TxqCc(usize),
+    TxqSts(usize),
+    TxqPctr(usize),
+    // Transmit history of channel m
+    ThlCc(usize),
+    ThlSts(usize),
+    ThlPctr(usize),
+    // Global interrupt status and test
+    GtIntSts(usize),
+    GtstCfg,
+    GtstCtr,
+    GLockK,
+    // Receive rule j of the page selected by GAFLECTR
+    GAflId(usize),
+    GAflM(usize),
+    GAflP0(usize),
+    GAflP1(usize),
+    // Receive buffer q
+    RmId(usize),
+    RmPtr(usize),
+    RmDf(usize, usize),
+    // Receive FIFO x access window
+    RfId(usize),
+    RfPtr(usize),
+    RfDf(usize, usize),
+    // Common FIFO k access window
+    CfId(usize),
+    CfPtr(usize),
+    CfDf(usize, usize),
+    // Transmit buffer p message
+    TmId(usize),
+    TmPtr(usize),
+    TmDf(usize, usize),
+    // Transmit history access of channel m
+    ThlAcc(usize),
+    // CAN FD configuration and status of channel m
+    CDCfg(usize),
+    CFdCfg(usize),
+    CFdCtr(usize),
+    CFdSts(usize),
+    CFdCrc(usize),
+    // CAN FD status of a message window
+    RmFdSts(usize),
+    RfFdSts(usize),
+    CfFdSts(usize),
+    TmFdCtr(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Layout {
+    Classic,
+    Fd,
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Layout::Classic
+    }
+}
+
+// (first offset, stride, count, width in bytes, constructor)
+type Array = (u32, u32, usize, u32, fn(usize) -> Register);
+
+// Message window: ID and pointer register, the FD status register in the RS-CANFD
+// layout, then the data registers
+struct Window {
+    start: u32,
+    stride: u32,
+    count: usize,
+    id: fn(usize) -> Register,
+    ptr: fn(usize) -> Register,
+    fd: Option<fn(usize) -> Register>,
+    df: fn(usize, usize) -> Register,
+}
+
+const SINGLE: &[(u32, Register)] = &[
+    (0x0084, Register::GCfg),
+    (0x0088, Register::GCtr),
+    (0x008c, Register::GSts),
+    (0x0090, Register::GErfl),
+    (0x0094, Register::GTsc),
+    (0x0098, Register::GAflEctr),
+    (0x00a4, Register::RmNb),
+    (0x0238, Register::FeSts),
+    (0x023c, Register::FfSts),
+    (0x0240, Register::FmSts),
+    (0x0244, Register::RfiSts),
+    (0x0248, Register::CfRiSts),
+    (0x024c, Register::CfTiSts),
+    (0x0468, Register::GtstCfg),
+    (0x046c, Register::GtstCtr),
+    (0x047c, Register::GLockK),
+];
+
+const ARRAYS: &[Array] = &[
+    (0x0000, 0x10, NUM_CHANNELS, 4, Register::CCfg),
+    (0x0004, 0x10, NUM_CHANNELS, 4, Register::CCtr),
+    (0x0008, 0x10, NUM_CHANNELS, 4, Register::CSts),
+    (0x000c, 0x10, NUM_CHANNELS, 4, Register::CErfl),
+    (0x009c, 0x04, 2, 4, Register::GAflCfg),
+    (0x00a8, 0x04, 3, 4, Register::RmNd),
+    (0x00b8, 0x04, NUM_RX_FIFOS, 4, Register::RfCc),
+    (0x00d8, 0x04, NUM_RX_FIFOS, 4, Register::RfSts),
+    (0x00f8, 0x04, NUM_RX_FIFOS, 4, Register::RfPctr),
+    (0x0118, 0x04, NUM_COMMON_FIFOS, 4, Register::CfCc),
+    (0x0178, 0x04, NUM_COMMON_FIFOS, 4, Register::CfSts),
+    (0x01d8, 0x04, NUM_COMMON_FIFOS, 4, Register::CfPctr),
+    (0x0250, 0x01, NUM_TX_BUFFERS, 1, Register::TmC),
+    (0x02d0, 0x01, NUM_TX_BUFFERS, 1, Register::TmSts),
+    (0x0350, 0x04, 3, 4, Register::TmTrSts),
+    (0x035c, 0x04, 3, 4, Register::TmTarSts),
+    (0x0368, 0x04, 3, 4, Register::TmTcSts),
+    (0x0374, 0x04, 3, 4, Register::TmTaSts),
+    (0x0380, 0x04, 3, 4, Register::TmIec),
+    (0x03a0, 0x04, NUM_CHANNELS, 4, Register::TxqCc),
+    (0x03c0, 0x04, NUM_CHANNELS, 4, Register::TxqSts),
+    (0x03e0, 0x04, NUM_CHANNELS, 4, Register::TxqPctr),
+    (0x0400, 0x04, NUM_CHANNELS, 4, Register::ThlCc),
+    (0x0420, 0x04, NUM_CHANNELS, 4, Register::ThlSts),
+    (0x0440, 0x04, NUM_CHANNELS, 4, Register::ThlPctr),
+    (0x0460, 0x04, 2, 4, Register::GtIntSts),
+];
+
+const CLASSIC_ARRAYS: &[Array] = &[
+    (0x0500, 0x10, NUM_RULES_PER_PAGE, 4, Register::GAflId),
+    (0x0504, 0x10, NUM_RULES_PER_PAGE, 4, Register::GAflM),
+    (0x0508, 0x10, NUM_RULES_PER_PAGE, 4, Register::GAflP0),
+    (0x050c, 0x10, NUM_RULES_PER_PAGE, 4, Register::GAflP1),
+    (0x1800, 0x04, NUM_CHANNELS, 4, Register::ThlAcc),
+];
+
+const FD_ARRAYS: &[Array] = &[
+    (0x0500, 0x20, NUM_CHANNELS, 4, Register::CDCfg),
+    (0x0504, 0x20, NUM_CHANNELS, 4, Register::CFdCfg),
+    (0x0508, 0x20, NUM_CHANNELS, 4, Register::CFdCtr),
+    (0x050c, 0x20, NUM_CHANNELS, 4, Register::CFdSts),
+    (0x0510, 0x20, NUM_CHANNELS, 4, Register::CFdCrc),
+    (0x1000, 0x10, NUM_RULES_PER_PAGE, 4, Register::GAflId),
+    (0x1004, 0x10, NUM_RULES_PER_PAGE, 4, Register::GAflM),
+    (0x1008, 0x10, NUM_RULES_PER_PAGE, 4, Register::GAflP0),
+    (0x100c, 0x10, NUM_RULES_PER_PAGE, 4, Register::GAflP1),
+    (0x8000, 0x04, NUM_CHANNELS, 4, Register::ThlAcc),
+];
+
+const CLASSIC_WINDOWS: &[Window] = &[
+    Window { start: 0x0600, stride: 0x10, count: NUM_RX_BUFFERS, id: Register::RmId, ptr: Register::RmPtr, fd: None, df: Register::RmDf },
+    Window { start: 0x0e00, stride: 0x10, count: NUM_RX_FIFOS, id: Register::RfId, ptr: Register::RfPtr, fd: None, df: Register::RfDf },
+    Window { start: 0x0e80, stride: 0x10, count: NUM_COMMON_FIFOS, id: Register::CfId, ptr: Register::CfPtr, fd: None, df: Register::CfDf },
+    Window { start: 0x1000, stride: 0x10, count: NUM_TX_BUFFERS, id: Register::TmId, ptr: Register::TmPtr, fd: None, df: Register::TmDf },
+];
+
+const FD_WINDOWS: &[Window] = &[
+    Window { start: 0x2000, stride: 0x80, count: NUM_RX_BUFFERS, id: Register::RmId, ptr: Register::RmPtr, fd: Some(Register::RmFdSts), df: Register::RmDf },
+    Window { start: 0x3000, stride: 0x80, count: NUM_RX_FIFOS, id: Register::RfId, ptr: Register::RfPtr, fd: Some(Register::RfFdSts), df: Register::RfDf },
+    Window { start: 0x3400, stride: 0x80, count: NUM_COMMON_FIFOS, id: Register::CfId, ptr: Register::CfPtr, fd: Some(Register::CfFdSts), df: Register::CfDf },
+    Window { start: 0x4000, stride: 0x80, count: NUM_TX_BUFFERS, id: Register::TmId, ptr: Register::TmPtr, fd: Some(Register::TmFdCtr), df: Register::TmDf },
+];
+
+impl Layout {
+    fn arrays(&self) -> impl Iterator<Item = &'static Array> {
+        let specific = match self {
+            Layout::Classic => CLASSIC_ARRAYS,
+            Layout::Fd => FD_ARRAYS,
+        };
+        ARRAYS.iter().chain(specific.iter())
+    }
+
+    fn windows(&self) -> &'static [Window] {
+        match self {
+            Layout::Classic => CLASSIC_WINDOWS,
+            Layout::Fd => FD_WINDOWS,
+        }
+    }
+
+    // Number of data registers of a message window
+    pub fn data_words(&self) -> usize {
+        match self {
+            Layout::Classic => NUM_DATA_WORDS,
+            Layout::Fd => NUM_FD_DATA_WORDS,
+        }
+    }
+
+    // Size of the register area of the module
+    pub fn size(&self) -> u32 {
+        match self {
+            Layout::Classic => 0x19fc,
+            Layout::Fd => 0x8100,
+        }
+    }
+
+    // Register map of the layout, built on first use
+    fn map(&self) -> &'static RegisterMap {
+        static CLASSIC: OnceLock<RegisterMap> = OnceLock::new();
+        static FD: OnceLock<RegisterMap> = OnceLock::new();
+        match self {
+            Layout::Classic => CLASSIC.get_or_init(|| RegisterMap::new(Layout::Classic)),
+            Layout::Fd => FD.get_or_init(|| RegisterMap::new(Layout::Fd)),
+        }
+    }
+
+    // Every register of the layout with its offset and width, in the order of the tables
+    fn entries(&self) -> Vec<(Register, u32, u32)> {
+        let mut entries: Vec<(Register, u32, u32)> = SINGLE.iter().map(|(start, reg)| (*reg, *start, 4)).collect();
+        for (start, stride, count, width, reg) in self.arrays() {
+            entries.extend((0..*count).map(|index| (reg(index), start + stride * index as u32, *width)));
+        }
+        for window in self.windows() {
+            let data = if window.fd.is_some() { 12 } else { 8 };
+            for index in 0..window.count {
+                let base = window.start + window.stride * index as u32;
+                entries.push(((window.id)(index), base, 4));
+                entries.push(((window.ptr)(index), base + 4, 4));
+                entries.extend(window.fd.map(|fd| (fd(index), base + 8, 4)));
+                entries.extend((0..self.data_words()).map(|word| ((window.df)(index, word), base + data + 4 * word as u32, 4)));
+            }
+        }
+        entries
+    }
+
+    // Find the register covering offset (relative to the module base).
+    // Returns the register and the byte position of offset inside it, so partial
+    // accesses to a 32 bit register are decoded as well.
+    pub fn decode(&self, offset: u32) -> Option<(Register, u32)> {
+        let (start, (reg, width)) = self.map().starts.range(..=offset).next_back()?;
+        let position = offset - start;
+        if position < *width {
+            Some((*reg, position))
+        } else {
+            None
+        }
+    }
+
+    // Offset of the register relative to the module base, None if the layout does
+    // not have it
+    pub fn offset(&self, register: &Register) -> Option<u32> {
+        self.map().offsets.get(register).copied()
+    }
+
+    // Every register of the module
+    pub fn registers(&self) -> Vec<Register> {
+        self.entries().into_iter().map(|(reg, _, _)| reg).collect()
+    }
+}
+
+// Lookup tables of a layout: the offset of every register, and every register with
+// its width by first offset for decoding
+struct RegisterMap {
+    offsets: HashMap<Register, u32>,
+    starts: BTreeMap<u32, (Register, u32)>,
+}
+
+impl RegisterMap {
+    fn new(layout: Layout) -> Self {
+        let entries = layout.entries();
+        Self {
+            offsets: entries.iter().map(|(reg, start, _)| (*reg, *start)).collect(),
+            starts: entries.iter().map(|(reg, start, width)| (*start, (*reg, *width))).collect(),
+        }
+    }
+}
+
+impl Register {
+    // Decode offset in the classical CAN layout
+    pub fn decode(offset: u32) -> Option<(Register, u32)> {
+        Layout::Classic.decode(offset)
+    }
+
+    // Offset of the register in the classical CAN layout
+    pub fn offset(&self) -> Option<u32> {
+        Layout::Classic.offset(self)
+    }
+
+    // Size of the register in bytes
+    pub fn width(&self) -> usize {
+        match self {
+            Register::TmC(_) | Register::TmSts(_) => 1,
+            _ => 4,
+        }
+    }
+
+    // Name of the register as in the hardware manual without the "RSCAN0" prefix
+    pub fn name(&self) -> String {
+        match self {
+            Register::CCfg(m) => format!("C{}CFG", m),
+            Register::CCtr(m) => format!("C{}CTR", m),
+            Register::CSts(m) => format!("C{}STS", m),
+            Register::CErfl(m) => format!("C{}ERFL", m),
+            Register::GCfg => String::from("GCFG"),
+            Register::GCtr => String::from("GCTR"),
+            Register::GSts => String::from("GSTS"),
+            Register::GErfl => String::from("GERFL"),
+            Register::GTsc => String::from("GTSC"),
+            Register::GAflEctr => String::from("GAFLECTR"),
+            Register::GAflCfg(i) => format!("GAFLCFG{}", i),
+            Register::RmNb => String::from("RMNB"),
+            Register::RmNd(y) => format!("RMND{}", y),
+            Register::RfCc(x) => format!("RFCC{}", x),
+            Register::RfSts(x) => format!("RFSTS{}", x),
+            Register::RfPctr(x) => format!("RFPCTR{}", x),
+            Register::CfCc(k) => format!("CFCC{}", k),
+            Register::CfSts(k) => format!("CFSTS{}", k),
+            Register::CfPctr(k) => format!("CFPCTR{}", k),
+            Register::FeSts => String::from("FESTS"),
+            Register::FfSts => String::from("FFSTS"),
+            Register::FmSts => String::from("FMSTS"),
+            Register::RfiSts => String::from("RFISTS"),
+            Register::CfRiSts => String::from("CFRISTS"),
+            Register::CfTiSts => String::from("CFTISTS"),
+            Register::TmC(p) => format!("TMC{}", p),
+            Register::TmSts(p) => format!("TMSTS{}", p),
+            Register::TmTrSts(y) => format!("TMTRSTS{}", y),
+            Register::TmTarSts(y) => format!("TMTARSTS{}", y),
+            Register::TmTcSts(y) => format!("TMTCSTS{}", y),
+            Register::TmTaSts(y) => format!("TMTASTS{}", y),
+            Register::TmIec(y) => format!("TMIEC{}", y),
+            Register::TxqCc(m) => format!("TXQCC{}", m),
+            Register::TxqSts(m) => format!("TXQSTS{}", m),
+            Register::TxqPctr(m) => format!("TXQPCTR{}", m),
+            Register::ThlCc(m) => format!("THLCC{}", m),
+            Register::ThlSts(m) => format!("THLSTS{}", m),
+            Register::ThlPctr(m) => format!("THLPCTR{}", m),
+            Register::GtIntSts(i) => format!("GTINTSTS{}", i),
+            Register::GtstCfg => String::from("GTSTCFG"),
+            Register::GtstCtr => String::from("GTSTCTR"),
+            Register::GLockK => String::from("GLOCKK"),
+            Register::GAflId(j) => format!("GAFLID{}", j),
+            Register::GAflM(j) => format!("GAFLM{}", j),
+            Register::GAflP0(j) => format!("GAFLP0{}", j),
+            Register::GAflP1(j) => format!("GAFLP1{}", j),
+            Register::RmId(q) => format!("RMID{}", q),
+            Register::RmPtr(q) => format!("RMPTR{}", q),
+            Register::RmDf(q, w) => format!("RMDF{}{}", w, q),
+            Register::RfId(x) => format!("RFID{}", x),
+            Register::RfPtr(x) => format!("RFPTR{}", x),
+            Register::RfDf(x, w) => format!("RFDF{}{}", w, x),
+            Register::CfId(k) => format!("CFID{}", k),
+            Register::CfPtr(k) => format!("CFPTR{}", k),
+            Register::CfDf(k, w) => format!("CFDF{}{}", w, k),
+            Register::TmId(p) => format!("TMID{}", p),
+            Register::TmPtr(p) => format!("TMPTR{}", p),
+            Register::TmDf(p, w) => format!("TMDF{}{}", w, p),
+            Register::ThlAcc(m) => format!("THLACC{}", m),
+            Register::CDCfg(m) => format!("C{}DCFG", m),
+            Register::CFdCfg(m) => format!("C{}FDCFG", m),
+            Register::CFdCtr(m) => format!("C{}FDCTR", m),
+            Register::CFdSts(m) => format!("C{}FDSTS", m),
+            Register::CFdCrc(m) => format!("C{}FDCRC", m),
+            Register::RmFdSts(q) => format!("RMFDSTS{}", q),
+            Register::RfFdSts(x) => format!("RFFDSTS{}", x),
+            Register::CfFdSts(k) => format!("CFFDSTS{}", k),
+            Register::TmFdCtr(p) => format!("TMFDCTR{}", p),
+        }
+    }
+
+    // Every register of the classical CAN layout
+    pub fn all() -> Vec<Register> {
+        Layout::Classic.registers()
+    }
+}
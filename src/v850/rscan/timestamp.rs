@@ -0,0 +1,99 @@
+// Timestamp counter of the RSCAN module
+//
+// The counter is driven by virtual time: every emulated instruction advances the
+// peripheral clock by a fixed number of cycles. GCFG.TSSS selects the peripheral clock
+// or the bit time clock of the channel in GCFG.TSBTCS as source, GCFG.TSP divides it by
+// 2^TSP. Received messages are stamped with the 16 bit counter value (GTSC).
+
+use serde::{Serialize, Deserialize};
+
+// GCFG
+const GCFG_TSP_SHIFT: u32 = 8;
+const GCFG_TSSS: u32 = 1 << 12;
+const GCFG_TSBTCS_SHIFT: u32 = 13;
+
+// GCTR
+pub const GCTR_TSRST: u32 = 1 << 16;
+
+// Bit time in peripheral clock cycles configured in CmCFG
+fn bit_time(ccfg: u32) -> u64 {
+    let brp = (ccfg & 0x3FF) as u64;
+    let tseg1 = ((ccfg >> 16) & 0xF) as u64;
+    let tseg2 = ((ccfg >> 20) & 0x7) as u64;
+    (brp + 1) * (1 + (tseg1 + 1) + (tseg2 + 1))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimestampCounter {
+    clocks: u64,            // Peripheral clock cycles since the counter was reset
+    clocks_per_step: u64,
+}
+
+impl Default for TimestampCounter {
+    fn default() -> Self {
+        Self {
+            clocks: 0,
+            clocks_per_step: 1,
+        }
+    }
+}
+
+impl TimestampCounter {
+    // Peripheral clock cycles that pass during one emulated instruction
+    pub fn set_clocks_per_step(&mut self, clocks: u64) {
+        self.clocks_per_step = clocks.max(1);
+    }
+
+    pub fn clocks_per_step(&self) -> u64 {
+        self.clocks_per_step
+    }
+
+    pub fn step(&mut self) {
+        self.clocks = self.clocks.wrapping_add(self.clocks_per_step);
+    }
+
+    pub fn reset(&mut self) {
+        self.clocks = 0;
+    }
+
+    // GTSC for the configuration in GCFG, ccfg(m) is CmCFG of channel m
+    pub fn value<F: Fn(usize) -> u32>(&self, gcfg: u32, ccfg: F) -> u16 {
+        let source = if gcfg & GCFG_TSSS != 0 {
+            bit_time(ccfg(((gcfg >> GCFG_TSBTCS_SHIFT) & 0x7) as usize))
+        } else {
+            1
+        };
+        let prescaler = (gcfg >> GCFG_TSP_SHIFT) & 0xF;
+        ((self.clocks / source) >> prescaler) as u16
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn prescaler_and_source() -> Result<(), String> {
+        let mut counter = TimestampCounter::default();
+        counter.set_clocks_per_step(4);
+        (0..1000).for_each(|_| counter.step());
+
+        // Peripheral clock divided by 8
+        let gcfg = 3 << GCFG_TSP_SHIFT;
+        if counter.value(gcfg, |_| 0) != 500 {
+            return Err(format!("Unexpected timestamp {}", counter.value(gcfg, |_| 0)));
+        }
+        // Bit time clock of channel 1: BRP 1, TSEG1 5, TSEG2 2 gives 20 cycles per bit
+        let gcfg = GCFG_TSSS | 1 << GCFG_TSBTCS_SHIFT;
+        let ccfg = |m| if m == 1 { 0x0025_0001 } else { 0 };
+        if counter.value(gcfg, ccfg) != 200 {
+            return Err(format!("Unexpected timestamp {}", counter.value(gcfg, ccfg)));
+        }
+
+        counter.reset();
+        if counter.value(0, |_| 0) != 0 {
+            return Err(String::from("Counter not reset"));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,552 @@
+// Transmit side of an RSCAN: transmit buffers, transmit FIFOs, transmit queues and the
+// transmit history lists
+//
+// A transmit request only marks its source as pending. Every step each channel that can
+// communicate sends the pending message that wins arbitration, either the lowest
+// arbitration field (GCFG.TPRI = 0) or the lowest transmit buffer number (TPRI = 1).
+// Transmission failures are injected by the test harness: a normal request is retried
+// at the next step, a one-shot request completes as aborted.
+
+use std::collections::VecDeque;
+use serde::{Serialize, Deserialize};
+
+use crate::backend;
+use super::message::CanMessage;
+use super::bus_error::BusErrorKind;
+use super::regs::{Register, NUM_CHANNELS, NUM_COMMON_FIFOS, NUM_TX_BUFFERS, NUM_TX_BUFFERS_PER_CHANNEL};
+
+// TMCp
+pub const TMC_TMTR: u32 = 1 << 0;       // Transmit request
+pub const TMC_TMTAR: u32 = 1 << 1;      // Transmit abort request
+pub const TMC_TMOM: u32 = 1 << 2;       // One-shot transmission
+
+// TMSTSp
+pub const TMSTS_TMTSTS: u32 = 1 << 0;   // Transmitting
+const TMSTS_TMTRF_SHIFT: u32 = 1;
+const TMSTS_TMTRF_MASK: u32 = 0x3 << TMSTS_TMTRF_SHIFT;
+pub const TMSTS_TMTRM: u32 = 1 << 3;    // Transmit request mirrored
+pub const TMSTS_TMTARM: u32 = 1 << 4;   // Transmit abort request mirrored
+
+// TMTRF, result of the last request
+const TMTRF_ABORTED: u32 = 0b01;
+const TMTRF_COMPLETED: u32 = 0b10;
+const TMTRF_COMPLETED_ABORT_REQUESTED: u32 = 0b11;
+
+pub const GCFG_TPRI: u32 = 1 << 0;      // Transmit priority by buffer number instead of ID
+pub const CCTR_TAIE: u32 = 1 << 16;     // Transmit abort interrupt enable
+pub const ID_THLEN: u32 = 1 << 29;      // Store the message in the transmit history list
+
+// CFCCk of a FIFO in transmit mode
+pub const CFCC_CFTXIE: u32 = 1 << 2;
+pub const CFCC_CFIM: u32 = 1 << 12;     // Interrupt on every message instead of when empty
+const CFCC_CFTML_SHIFT: u32 = 16;       // Linked transmit buffer
+const CFCC_CFDC_SHIFT: u32 = 21;
+pub const CFSTS_CFEMP: u32 = 1 << 0;
+pub const CFSTS_CFFLL: u32 = 1 << 1;
+pub const CFSTS_CFTXIF: u32 = 1 << 4;
+
+// TXQCCm, TXQSTSm
+pub const TXQCC_TXQE: u32 = 1 << 0;
+const TXQCC_TXQDC_SHIFT: u32 = 8;
+pub const TXQCC_TXQIE: u32 = 1 << 12;
+pub const TXQCC_TXQIM: u32 = 1 << 13;
+pub const TXQSTS_TXQEMP: u32 = 1 << 0;
+pub const TXQSTS_TXQFLL: u32 = 1 << 1;
+pub const TXQSTS_TXQIF: u32 = 1 << 2;
+
+// THLCCm, THLSTSm
+pub const THLCC_THLE: u32 = 1 << 0;
+pub const THLCC_THLIE: u32 = 1 << 8;
+pub const THLCC_THLIM: u32 = 1 << 9;
+pub const THLCC_THLDTE: u32 = 1 << 10;  // Also store messages of transmit buffers
+pub const THLSTS_THLEMP: u32 = 1 << 0;
+pub const THLSTS_THLFLL: u32 = 1 << 1;
+pub const THLSTS_THLELT: u32 = 1 << 2;  // Entry lost
+pub const THLSTS_THLIF: u32 = 1 << 3;
+pub const THL_DEPTH: usize = 8;
+
+// THLACCm: buffer type, buffer number and label of the transmitted message
+const THLACC_BT_BUFFER: u32 = 0b001;
+const THLACC_BT_FIFO: u32 = 0b010;
+const THLACC_BT_QUEUE: u32 = 0b100;
+const THLACC_BN_SHIFT: u32 = 3;
+const THLACC_TID_SHIFT: u32 = 8;
+
+const COMMON_FIFOS_PER_CHANNEL: usize = NUM_COMMON_FIFOS / NUM_CHANNELS;
+
+// Where a message to transmit comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TxSource {
+    Buffer(usize),      // Transmit buffer p
+    Fifo(usize),        // Common FIFO k in transmit mode
+    Queue(usize),       // Transmit queue of channel m
+}
+
+impl TxSource {
+    pub fn channel(&self) -> usize {
+        match *self {
+            TxSource::Buffer(p) => p / NUM_TX_BUFFERS_PER_CHANNEL,
+            TxSource::Fifo(k) => k / COMMON_FIFOS_PER_CHANNEL,
+            TxSource::Queue(m) => m,
+        }
+    }
+
+    // Buffer number deciding the priority with GCFG.TPRI set: transmit FIFOs use their
+    // linked buffer (CFTML), the queue the highest buffer of the channel
+    pub fn number(&self, cfcc: u32) -> usize {
+        match *self {
+            TxSource::Buffer(p) => p % NUM_TX_BUFFERS_PER_CHANNEL,
+            TxSource::Fifo(_) => ((cfcc >> CFCC_CFTML_SHIFT) & 0xF) as usize,
+            TxSource::Queue(_) => NUM_TX_BUFFERS_PER_CHANNEL - 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Completed,
+    Aborted,
+}
+
+// A message waiting for transmission
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TxMessage {
+    pub message: CanMessage,
+    pub history: bool,      // THLEN of the ID register
+}
+
+// Priority key of a pending message, the lowest key wins arbitration. By ID the CAN
+// arbitration field is compared bit by bit: base ID, RTR/SRR, IDE, extended ID, RTR.
+// Equal keys are resolved by the lower buffer number.
+pub fn priority(message: &CanMessage, number: usize, by_id: bool) -> (u32, usize) {
+    if !by_id {
+        return (0, number);
+    }
+    let arbitration = if message.extended {
+        (message.id >> 18) << 21 | 1 << 20 | 1 << 19 | (message.id & 0x3FFFF) << 1 | message.remote as u32
+    } else {
+        (message.id & 0x7FF) << 21 | (message.remote as u32) << 20
+    };
+    (arbitration, number)
+}
+
+// Number of messages a FIFO or queue holds, from its depth configuration field
+fn queue_depth(dc: u32) -> usize {
+    (dc & 0xF) as usize + 1
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct TxBuffer {
+    requested: bool,        // TMTR
+    abort_requested: bool,  // TMTAR
+    one_shot: bool,         // TMOM
+    result: u32,            // TMTRF
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransmitUnit {
+    buffers: Vec<TxBuffer>,                 // Transmit buffer p
+    fifos: Vec<VecDeque<TxMessage>>,        // Common FIFO k in transmit mode
+    fifo_flags: Vec<bool>,                  // CFTXIF of common FIFO k
+    queues: Vec<VecDeque<TxMessage>>,       // Transmit queue of channel m
+    queue_flags: Vec<bool>,                 // TXQIF of channel m
+    history: Vec<VecDeque<u32>>,            // THLACC entries of channel m
+    history_lost: Vec<bool>,                // THLELT of channel m
+    history_flags: Vec<bool>,               // THLIF of channel m
+    failures: Vec<VecDeque<BusErrorKind>>,  // Errors of the next transmissions of channel m
+    interrupts: Vec<backend::Interrupt>,    // Transmit interrupt of channel m
+}
+
+impl Default for TransmitUnit {
+    fn default() -> Self {
+        Self {
+            buffers: vec![TxBuffer::default(); NUM_TX_BUFFERS],
+            fifos: vec![VecDeque::new(); NUM_COMMON_FIFOS],
+            fifo_flags: vec![false; NUM_COMMON_FIFOS],
+            queues: vec![VecDeque::new(); NUM_CHANNELS],
+            queue_flags: vec![false; NUM_CHANNELS],
+            history: vec![VecDeque::new(); NUM_CHANNELS],
+            history_lost: vec![false; NUM_CHANNELS],
+            history_flags: vec![false; NUM_CHANNELS],
+            failures: vec![VecDeque::new(); NUM_CHANNELS],
+            interrupts: (0..NUM_CHANNELS).map(|m| {
+                // Every source has its own enable bit, the channel interrupt is enabled
+                let mut interrupt = backend::Interrupt::new(&format!("CAN{}TRX", m));
+                interrupt.set_enable(true);
+                interrupt
+            }).collect(),
+        }
+    }
+}
+
+impl TransmitUnit {
+    pub fn interrupt(&self, channel: usize) -> &backend::Interrupt {
+        &self.interrupts[channel]
+    }
+
+    pub fn interrupt_mut(&mut self, channel: usize) -> &mut backend::Interrupt {
+        &mut self.interrupts[channel]
+    }
+
+    fn raise(&mut self, channel: usize) {
+        let interrupt = &mut self.interrupts[channel];
+        if interrupt.is_enabled() {
+            interrupt.set_triggered(true);
+            interrupt.add_trigger_count();
+        }
+    }
+
+    // Transmit buffers, queues, FIFOs and history of channel are cleared in channel reset mode
+    pub fn reset_channel(&mut self, channel: usize) {
+        for buffer in self.buffers.iter_mut().skip(channel * NUM_TX_BUFFERS_PER_CHANNEL).take(NUM_TX_BUFFERS_PER_CHANNEL) {
+            *buffer = TxBuffer::default();
+        }
+        for k in channel * COMMON_FIFOS_PER_CHANNEL..(channel + 1) * COMMON_FIFOS_PER_CHANNEL {
+            self.fifos[k].clear();
+            self.fifo_flags[k] = false;
+        }
+        self.queues[channel].clear();
+        self.queue_flags[channel] = false;
+        self.history[channel].clear();
+        self.history_lost[channel] = false;
+        self.history_flags[channel] = false;
+    }
+
+    // The next transmission of channel fails with kind
+    pub fn fail_next(&mut self, channel: usize, kind: BusErrorKind) {
+        self.failures[channel].push_back(kind);
+    }
+
+    pub fn take_failure(&mut self, channel: usize) -> Option<BusErrorKind> {
+        self.failures[channel].pop_front()
+    }
+
+    pub fn is_one_shot(&self, source: TxSource) -> bool {
+        matches!(source, TxSource::Buffer(p) if self.buffers[p].one_shot)
+    }
+
+    // TMCp value
+    pub fn tmc(&self, p: usize) -> u32 {
+        let buffer = &self.buffers[p];
+        let mut tmc = 0;
+        if buffer.requested {
+            tmc |= TMC_TMTR;
+        }
+        if buffer.abort_requested {
+            tmc |= TMC_TMTAR;
+        }
+        if buffer.one_shot {
+            tmc |= TMC_TMOM;
+        }
+        tmc
+    }
+
+    // TMSTSp value
+    pub fn status(&self, p: usize) -> u32 {
+        let buffer = &self.buffers[p];
+        let mut status = buffer.result << TMSTS_TMTRF_SHIFT;
+        if buffer.requested {
+            status |= TMSTS_TMTSTS | TMSTS_TMTRM;
+        }
+        if buffer.abort_requested {
+            status |= TMSTS_TMTARM;
+        }
+        status
+    }
+
+    // Firmware wrote TMCp. A request that has not been sent yet is aborted right away.
+    pub fn write_tmc(&mut self, p: usize, tmc: u32, regs: impl Fn(Register) -> u32) {
+        let buffer = &mut self.buffers[p];
+        buffer.one_shot = tmc & TMC_TMOM != 0;
+        if tmc & TMC_TMTR != 0 && !buffer.requested {
+            buffer.requested = true;
+            buffer.result = 0;
+        }
+        if tmc & TMC_TMTAR != 0 && buffer.requested {
+            buffer.abort_requested = true;
+            self.finish_buffer(p, Outcome::Aborted, regs);
+        }
+    }
+
+    // Firmware wrote TMSTSp, TMTRF is cleared by writing 0
+    pub fn write_tmsts(&mut self, p: usize, tmsts: u32) {
+        self.buffers[p].result &= (tmsts & TMSTS_TMTRF_MASK) >> TMSTS_TMTRF_SHIFT;
+    }
+
+    // TMTRSTSy, TMTARSTSy, TMTCSTSy and TMTASTSy: one bit per transmit buffer
+    fn summary(&self, y: usize, bit: impl Fn(&TxBuffer) -> bool) -> u32 {
+        self.buffers.iter().enumerate()
+            .filter(|(p, buffer)| p / 32 == y && bit(buffer))
+            .fold(0, |val, (p, _)| val | 1 << (p % 32))
+    }
+
+    pub fn requests(&self, y: usize) -> u32 {
+        self.summary(y, |buffer| buffer.requested)
+    }
+
+    pub fn abort_requests(&self, y: usize) -> u32 {
+        self.summary(y, |buffer| buffer.abort_requested)
+    }
+
+    pub fn completions(&self, y: usize) -> u32 {
+        self.summary(y, |buffer| buffer.result & TMTRF_COMPLETED != 0)
+    }
+
+    pub fn aborts(&self, y: usize) -> u32 {
+        self.summary(y, |buffer| buffer.result == TMTRF_ABORTED)
+    }
+
+    // Firmware advanced the write pointer of transmit FIFO k, false if the FIFO is full
+    pub fn push_fifo(&mut self, k: usize, message: TxMessage, cfcc: u32) -> bool {
+        if self.fifos[k].len() >= super::regs::fifo_depth(cfcc >> CFCC_CFDC_SHIFT) {
+            return false;
+        }
+        self.fifos[k].push_back(message);
+        true
+    }
+
+    // CFSTSk value of a FIFO in transmit mode
+    pub fn fifo_status(&self, k: usize, cfcc: u32) -> u32 {
+        let len = self.fifos[k].len();
+        let mut status = (len as u32) << 8;
+        if len == 0 {
+            status |= CFSTS_CFEMP;
+        }
+        if len >= super::regs::fifo_depth(cfcc >> CFCC_CFDC_SHIFT) {
+            status |= CFSTS_CFFLL;
+        }
+        if self.fifo_flags[k] {
+            status |= CFSTS_CFTXIF;
+        }
+        status
+    }
+
+    pub fn write_cfsts(&mut self, k: usize, cfsts: u32) {
+        if cfsts & CFSTS_CFTXIF == 0 {
+            self.fifo_flags[k] = false;
+        }
+    }
+
+    // CFTISTS: transmit interrupt flag of every common FIFO
+    pub fn fifo_flags(&self) -> u32 {
+        self.fifo_flags.iter().enumerate()
+            .filter(|(_, flag)| **flag)
+            .fold(0, |val, (k, _)| val | 1 << k)
+    }
+
+    // Firmware advanced the write pointer of the transmit queue of channel, false if the
+    // queue is disabled or full
+    pub fn push_queue(&mut self, channel: usize, message: TxMessage, txqcc: u32) -> bool {
+        if txqcc & TXQCC_TXQE == 0 || self.queues[channel].len() >= queue_depth(txqcc >> TXQCC_TXQDC_SHIFT) {
+            return false;
+        }
+        self.queues[channel].push_back(message);
+        true
+    }
+
+    // Transmit buffers used by the queue of channel, they can not be requested by TMCp
+    pub fn queue_buffers(channel: usize, txqcc: u32) -> Option<std::ops::RangeInclusive<usize>> {
+        if txqcc & TXQCC_TXQE == 0 {
+            return None;
+        }
+        let last = (channel + 1) * NUM_TX_BUFFERS_PER_CHANNEL - 1;
+        Some(last + 1 - queue_depth(txqcc >> TXQCC_TXQDC_SHIFT)..=last)
+    }
+
+    // TXQSTSm value
+    pub fn queue_status(&self, channel: usize, txqcc: u32) -> u32 {
+        let len = self.queues[channel].len();
+        let mut status = (len as u32) << 8;
+        if len == 0 {
+            status |= TXQSTS_TXQEMP;
+        }
+        if len >= queue_depth(txqcc >> TXQCC_TXQDC_SHIFT) {
+            status |= TXQSTS_TXQFLL;
+        }
+        if self.queue_flags[channel] {
+            status |= TXQSTS_TXQIF;
+        }
+        status
+    }
+
+    pub fn write_txqsts(&mut self, channel: usize, txqsts: u32) {
+        if txqsts & TXQSTS_TXQIF == 0 {
+            self.queue_flags[channel] = false;
+        }
+    }
+
+    // THLSTSm value
+    pub fn history_status(&self, channel: usize) -> u32 {
+        let len = self.history[channel].len();
+        let mut status = (len as u32) << 8;
+        if len == 0 {
+            status |= THLSTS_THLEMP;
+        }
+        if len >= THL_DEPTH {
+            status |= THLSTS_THLFLL;
+        }
+        if self.history_lost[channel] {
+            status |= THLSTS_THLELT;
+        }
+        if self.history_flags[channel] {
+            status |= THLSTS_THLIF;
+        }
+        status
+    }
+
+    pub fn write_thlsts(&mut self, channel: usize, thlsts: u32) {
+        if thlsts & THLSTS_THLELT == 0 {
+            self.history_lost[channel] = false;
+        }
+        if thlsts & THLSTS_THLIF == 0 {
+            self.history_flags[channel] = false;
+        }
+    }
+
+    // THLACCm: oldest entry of the transmit history list
+    pub fn history_entry(&self, channel: usize) -> u32 {
+        self.history[channel].front().copied().unwrap_or(0)
+    }
+
+    pub fn pop_history(&mut self, channel: usize) {
+        self.history[channel].pop_front();
+    }
+
+    // Sources of channel with a message waiting for transmission
+    pub fn pending(&self, channel: usize) -> Vec<TxSource> {
+        let buffers = channel * NUM_TX_BUFFERS_PER_CHANNEL..(channel + 1) * NUM_TX_BUFFERS_PER_CHANNEL;
+        let fifos = channel * COMMON_FIFOS_PER_CHANNEL..(channel + 1) * COMMON_FIFOS_PER_CHANNEL;
+        buffers.filter(|p| self.buffers[*p].requested).map(TxSource::Buffer)
+            .chain(fifos.filter(|k| !self.fifos[*k].is_empty()).map(TxSource::Fifo))
+            .chain(Some(TxSource::Queue(channel)).filter(|_| !self.queues[channel].is_empty()))
+            .collect()
+    }
+
+    // Message of a FIFO or queue source, transmit buffer messages live in the registers
+    pub fn queued_message(&self, source: TxSource) -> Option<&TxMessage> {
+        match source {
+            TxSource::Buffer(_) => None,
+            TxSource::Fifo(k) => self.fifos[k].front(),
+            TxSource::Queue(m) => self.queues[m].front(),
+        }
+    }
+
+    fn finish_buffer(&mut self, p: usize, outcome: Outcome, regs: impl Fn(Register) -> u32) {
+        let buffer = &mut self.buffers[p];
+        buffer.requested = false;
+        buffer.result = match outcome {
+            Outcome::Completed if buffer.abort_requested => TMTRF_COMPLETED_ABORT_REQUESTED,
+            Outcome::Completed => TMTRF_COMPLETED,
+            Outcome::Aborted => TMTRF_ABORTED,
+        };
+        buffer.abort_requested = false;
+        let interrupt = match outcome {
+            Outcome::Completed => regs(Register::TmIec(p / 32)) & 1 << (p % 32) != 0,
+            Outcome::Aborted => regs(Register::CCtr(p / NUM_TX_BUFFERS_PER_CHANNEL)) & CCTR_TAIE != 0,
+        };
+        if interrupt {
+            self.raise(p / NUM_TX_BUFFERS_PER_CHANNEL);
+        }
+    }
+
+    // Store an entry in the transmit history list of channel, true if it raises THLIF
+    fn record(&mut self, channel: usize, entry: u32, thlcc: u32) -> bool {
+        if self.history[channel].len() >= THL_DEPTH {
+            self.history_lost[channel] = true;
+            return false;
+        }
+        self.history[channel].push_back(entry);
+        if thlcc & THLCC_THLIM != 0 || self.history[channel].len() >= THL_DEPTH {
+            self.history_flags[channel] = true;
+            return thlcc & THLCC_THLIE != 0;
+        }
+        false
+    }
+
+    // The message of source has been sent or, for one-shot transmit buffers, given up
+    pub fn complete(&mut self, source: TxSource, sent: &TxMessage, outcome: Outcome, regs: impl Fn(Register) -> u32) {
+        let channel = source.channel();
+        let (interrupt, entry) = match source {
+            TxSource::Buffer(p) => {
+                // finish_buffer raises its own interrupt
+                self.finish_buffer(p, outcome, &regs);
+                let thlcc = regs(Register::ThlCc(channel));
+                let entry = Some(THLACC_BT_BUFFER | ((p % NUM_TX_BUFFERS_PER_CHANNEL) as u32) << THLACC_BN_SHIFT)
+                    .filter(|_| thlcc & THLCC_THLDTE != 0);
+                (false, entry)
+            },
+            TxSource::Fifo(k) => {
+                self.fifos[k].pop_front();
+                let cfcc = regs(Register::CfCc(k));
+                let interrupt = cfcc & CFCC_CFIM != 0 || self.fifos[k].is_empty();
+                self.fifo_flags[k] |= interrupt;
+                (interrupt && cfcc & CFCC_CFTXIE != 0, Some(THLACC_BT_FIFO | ((k % COMMON_FIFOS_PER_CHANNEL) as u32) << THLACC_BN_SHIFT))
+            },
+            TxSource::Queue(m) => {
+                self.queues[m].pop_front();
+                let txqcc = regs(Register::TxqCc(m));
+                let interrupt = txqcc & TXQCC_TXQIM != 0 || self.queues[m].is_empty();
+                self.queue_flags[m] |= interrupt;
+                (interrupt && txqcc & TXQCC_TXQIE != 0, Some(THLACC_BT_QUEUE | ((NUM_TX_BUFFERS_PER_CHANNEL - 1) as u32) << THLACC_BN_SHIFT))
+            },
+        };
+
+        let mut history_interrupt = false;
+        let thlcc = regs(Register::ThlCc(channel));
+        if let Some(entry) = entry {
+            if outcome == Outcome::Completed && sent.history && thlcc & THLCC_THLE != 0 {
+                let tid = (sent.message.label as u32 & 0xFF) << THLACC_TID_SHIFT;
+                history_interrupt = self.record(channel, entry | tid, thlcc);
+            }
+        }
+        if interrupt || history_interrupt {
+            self.raise(channel);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn arbitration_and_status() -> Result<(), String> {
+        // A standard frame wins over an extended frame with the same base ID and data frames
+        // win over remote frames
+        let keys = [
+            priority(&CanMessage::with_format(0x100, false, &[]), 3, true),
+            priority(&CanMessage::new_remote(0x100, false, 0), 2, true),
+            priority(&CanMessage::with_format(0x100 << 18, true, &[]), 1, true),
+            priority(&CanMessage::with_format(0x101, false, &[]), 0, true),
+        ];
+        if !keys.windows(2).all(|pair| pair[0] < pair[1]) {
+            return Err(format!("Unexpected arbitration order {:x?}", keys));
+        }
+        if priority(&CanMessage::with_format(0x7FF, false, &[]), 0, false) >= priority(&CanMessage::with_format(0x1, false, &[]), 1, false) {
+            return Err(String::from("Buffer number priority not applied"));
+        }
+
+        let mut unit = TransmitUnit::default();
+        let regs = |reg: Register| if reg == Register::TmIec(0) { 0x1 } else { 0 };
+        unit.write_tmc(0, TMC_TMTR, regs);
+        unit.write_tmc(1, TMC_TMTR | TMC_TMOM, regs);
+        if unit.pending(0) != [TxSource::Buffer(0), TxSource::Buffer(1)] || unit.status(0) != TMSTS_TMTSTS | TMSTS_TMTRM {
+            return Err(format!("Unexpected requests {:?}, TMSTS0 {:#x}", unit.pending(0), unit.status(0)));
+        }
+
+        let sent = TxMessage { message: CanMessage::new(0x123, &[]), history: false };
+        unit.complete(TxSource::Buffer(0), &sent, Outcome::Completed, regs);
+        unit.write_tmc(1, TMC_TMTAR | TMC_TMOM, regs);
+        if unit.status(0) != TMTRF_COMPLETED << 1 || unit.aborts(0) != 0x2 || !unit.pending(0).is_empty() {
+            return Err(format!("Unexpected TMSTS0 {:#x}, TMTASTS0 {:#x}", unit.status(0), unit.aborts(0)));
+        }
+        if unit.interrupt(0).get_trigger_count() != 1 {
+            return Err(String::from("Only the completion of buffer 0 has its interrupt enabled"));
+        }
+        unit.write_tmsts(0, 0);
+        if unit.completions(0) != 0 {
+            return Err(String::from("TMTRF not cleared by writing 0"));
+        }
+        Ok(())
+    }
+}